@@ -0,0 +1,62 @@
+//! Values that change smoothly over time
+use crate::state::Runtime;
+use crate::util::Cell;
+use std::time::{Duration,Instant};
+
+#[derive(Debug,Clone,Copy)]
+struct Transition {
+    from : f32,
+    to : f32,
+    start : Instant,
+    duration : Duration,
+}
+
+impl Transition {
+    /// The value at the given time, or None if the transition has finished
+    fn at(&self, now : Instant) -> Option<f32> {
+        let t = now.saturating_duration_since(self.start).as_secs_f32() / self.duration.as_secs_f32();
+        if t >= 1.0 {
+            return None;
+        }
+        // ease in and out
+        let t = t * t * (3.0 - 2.0 * t);
+        Some(self.from + (self.to - self.from) * t)
+    }
+}
+
+/// A value moving from one point to another.
+///
+/// Reading a running animation requests another frame, so the bars keep redrawing (as fast as the
+/// compositor and `max-fps` allow) until it finishes.  Animations are skipped while
+/// [Runtime::low_power] is true.
+#[derive(Debug,Default)]
+pub struct Animation {
+    running : Cell<Option<Transition>>,
+}
+
+impl Animation {
+    /// Start moving from `from` to `to`.  If the animation is already running, it continues from
+    /// its current value instead of jumping to `from`.
+    pub fn start(&self, from : f32, to : f32, duration : Duration, rt : &Runtime) {
+        if duration.is_zero() || rt.low_power() {
+            self.running.set(None);
+            return;
+        }
+        let now = Instant::now();
+        let from = self.running.get().and_then(|t| t.at(now)).unwrap_or(from);
+        self.running.set(Some(Transition { from, to, start : now, duration }));
+        rt.request_animation_frame();
+    }
+
+    /// The current value, or None if the animation is not running
+    pub fn value(&self, rt : &Runtime) -> Option<f32> {
+        let value = self.running.get()
+            .filter(|_| !rt.low_power())
+            .and_then(|t| t.at(Instant::now()));
+        match value {
+            Some(_) => rt.request_animation_frame(),
+            None => self.running.set(None),
+        }
+        value
+    }
+}
@@ -0,0 +1,193 @@
+//! Sunrise, sunset, and the phase of the moon, calculated from the configured location
+use crate::data::Value;
+use crate::registry::Source;
+use crate::state::Runtime;
+use crate::timer::schedule_tick;
+use crate::util::{Cell,toml_to_f64};
+use chrono::{DateTime,Datelike,Duration as Span,Local,NaiveDate,TimeZone,Utc};
+use futures_util::future::RemoteHandle;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+/// Julian day of 2000-01-01 12:00 UTC
+const J2000 : f64 = 2451545.0;
+/// Days from 0001-01-01 to 2000-01-01, as counted by [Datelike::num_days_from_ce]
+const J2000_DAYS_FROM_CE : i32 = 730120;
+const SYNODIC_MONTH : f64 = 29.530588853;
+/// Altitude of the sun's center at sunrise, allowing for refraction and the size of the disc
+const SUNRISE_ALTITUDE : f64 = -0.833;
+const CIVIL_ALTITUDE : f64 = -6.0;
+
+static MOON_PHASES : [(&str, &str); 8] = [
+    ("new", "🌑"),
+    ("waxing crescent", "🌒"),
+    ("first quarter", "🌓"),
+    ("waxing gibbous", "🌔"),
+    ("full", "🌕"),
+    ("waning gibbous", "🌖"),
+    ("last quarter", "🌗"),
+    ("waning crescent", "🌘"),
+];
+
+fn julian(t : DateTime<Utc>) -> f64 {
+    t.timestamp() as f64 / 86400.0 + 2440587.5
+}
+
+/// The local time of a Julian day, if it is within the range chrono supports
+fn from_julian(jd : f64) -> Option<DateTime<Local>> {
+    Utc.timestamp_opt(((jd - 2440587.5) * 86400.0).round() as i64, 0).single().map(|t| t.with_timezone(&Local))
+}
+
+/// The sun's path on one day, using the sunrise equation
+struct SolarDay {
+    /// Solar noon, as a Julian day
+    noon : f64,
+    /// Declination of the sun, in radians
+    declination : f64,
+}
+
+impl SolarDay {
+    fn new(date : NaiveDate, longitude : f64) -> Self {
+        let n = (date.num_days_from_ce() - J2000_DAYS_FROM_CE) as f64;
+        let j = n - longitude / 360.0;
+        let m = (357.5291 + 0.98560028 * j).rem_euclid(360.0).to_radians();
+        let center = 1.9148 * m.sin() + 0.0200 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+        let ecliptic = (m.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+        SolarDay {
+            noon : J2000 + j + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic).sin(),
+            declination : (ecliptic.sin() * 23.4397f64.to_radians().sin()).asin(),
+        }
+    }
+
+    /// When the sun rises above and sets below `altitude` degrees, or None if it stays above or
+    /// below it all day
+    fn crossing(&self, latitude : f64, altitude : f64) -> Option<(f64, f64)> {
+        let phi = latitude.to_radians();
+        let cos = (altitude.to_radians().sin() - phi.sin() * self.declination.sin()) / (phi.cos() * self.declination.cos());
+        if !(-1.0..=1.0).contains(&cos) {
+            return None;
+        }
+        let half = cos.acos().to_degrees() / 360.0;
+        Some((self.noon - half, self.noon + half))
+    }
+
+    /// Altitude of the sun in degrees at the given time on this day
+    fn altitude(&self, latitude : f64, jd : f64) -> f64 {
+        let phi = latitude.to_radians();
+        let hour_angle = (jd - self.noon) * 2.0 * PI;
+        let sin = phi.sin() * self.declination.sin() + phi.cos() * self.declination.cos() * hour_angle.cos();
+        sin.asin().to_degrees()
+    }
+}
+
+/// The "astronomy" module
+#[derive(Debug)]
+pub struct Astronomy {
+    latitude : f64,
+    longitude : f64,
+    format : Box<str>,
+    tick : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Astronomy {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let latitude = toml_to_f64(config.get("latitude")).ok_or("The astronomy module needs a 'latitude'")?;
+        let longitude = toml_to_f64(config.get("longitude")).ok_or("The astronomy module needs a 'longitude'")?;
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(format!("Invalid location {}, {}", latitude, longitude));
+        }
+        Ok(Astronomy {
+            latitude,
+            longitude,
+            format : config.get("format").and_then(|v| v.as_str()).unwrap_or("%H:%M").into(),
+            tick : Cell::default(),
+        })
+    }
+
+    /// Redraw at the next sunrise, sunset, or twilight, or at midnight; at least once an hour
+    /// so the moon keeps up
+    fn schedule(&self, day : &SolarDay, now : f64, rt : &Runtime) {
+        // Midnight is skipped on some DST changes; the hourly redraw covers that day
+        let midnight = Local::now().naive_local().date().succ_opt()
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .and_then(|t| Local.from_local_datetime(&t).earliest());
+        let mut next = now + 1.0 / 24.0;
+        if let Some(midnight) = midnight {
+            next = next.min(julian(midnight.with_timezone(&Utc)));
+        }
+        for altitude in [SUNRISE_ALTITUDE, CIVIL_ALTITUDE] {
+            if let Some((rise, set)) = day.crossing(self.latitude, altitude) {
+                for t in [rise, set] {
+                    if t > now && t < next {
+                        next = t;
+                    }
+                }
+            }
+        }
+        let delay = Duration::from_secs_f64((next - now).max(0.0) * 86400.0 + 1.0);
+        schedule_tick(&self.tick, Some(delay), rt);
+    }
+
+    fn time(&self, jd : f64) -> Value<'static> {
+        from_julian(jd).map_or(Value::Null, |t| Value::Owned(t.format(&self.format).to_string()))
+    }
+}
+
+impl Source for Astronomy {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        let utc = Utc::now();
+        let now = julian(utc);
+        let day = SolarDay::new(utc.with_timezone(&Local).naive_local().date(), self.longitude);
+        self.schedule(&day, now, rt);
+        let sun = day.crossing(self.latitude, SUNRISE_ALTITUDE);
+        let civil = day.crossing(self.latitude, CIVIL_ALTITUDE);
+        let altitude = day.altitude(self.latitude, now);
+        // days since a new moon
+        let moon_age = (now - 2451550.1).rem_euclid(SYNODIC_MONTH);
+        let moon = moon_age / SYNODIC_MONTH;
+        let phase = MOON_PHASES[(moon * 8.0 + 0.5) as usize % 8];
+        match key {
+            "" | "text" | "period" => Value::Borrow(match altitude {
+                a if a > SUNRISE_ALTITUDE => "day",
+                a if a > CIVIL_ALTITUDE => "twilight",
+                _ => "night",
+            }),
+            "day" => Value::Bool(altitude > SUNRISE_ALTITUDE),
+            "elevation" => Value::Float(altitude),
+            "sunrise" => sun.map_or(Value::Null, |(rise, _)| self.time(rise)),
+            "sunset" => sun.map_or(Value::Null, |(_, set)| self.time(set)),
+            "dawn" => civil.map_or(Value::Null, |(rise, _)| self.time(rise)),
+            "dusk" => civil.map_or(Value::Null, |(_, set)| self.time(set)),
+            "noon" => self.time(day.noon),
+            "day-length" => {
+                let hours = match sun {
+                    Some((rise, set)) => (set - rise) * 24.0,
+                    // polar day or night
+                    None if day.altitude(self.latitude, day.noon) > SUNRISE_ALTITUDE => 24.0,
+                    None => 0.0,
+                };
+                let minutes = (hours * 60.0).round() as i64;
+                Value::Owned(format!("{}:{:02}", minutes / 60, minutes % 60))
+            }
+            "day-hours" => match sun {
+                Some((rise, set)) => Value::Float((set - rise) * 24.0),
+                None if day.altitude(self.latitude, day.noon) > SUNRISE_ALTITUDE => Value::Float(24.0),
+                None => Value::Float(0.0),
+            },
+            "moon-phase" => Value::Borrow(phase.0),
+            "moon-icon" => Value::Borrow(phase.1),
+            "moon-illumination" => Value::Float((1.0 - (moon * 2.0 * PI).cos()) / 2.0 * 100.0),
+            "moon-age" => Value::Float(moon_age),
+            "next" => {
+                // the next sunrise or sunset
+                let upcoming = sun.into_iter().flat_map(|(rise, set)| [rise, set]).find(|&t| t > now);
+                let upcoming = upcoming.or_else(|| {
+                    let tomorrow = utc.with_timezone(&Local).naive_local().date() + Span::days(1);
+                    SolarDay::new(tomorrow, self.longitude).crossing(self.latitude, SUNRISE_ALTITUDE).map(|(rise, _)| rise)
+                });
+                upcoming.map_or(Value::Null, |t| self.time(t))
+            }
+            _ => Value::Null,
+        }
+    }
+}
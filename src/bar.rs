@@ -1,10 +1,13 @@
-use log::error;
+//! A single bar on a single output, and its popups
+use log::{debug,error};
 use std::convert::TryInto;
-use std::time::Instant;
+use std::error::Error;
+use std::time::{Duration,Instant};
 use std::rc::Rc;
 use smithay_client_toolkit::output::OutputInfo;
 use wayland_client::Attached;
 use wayland_client::protocol::wl_output::WlOutput;
+use wayland_client::protocol::wl_seat::WlSeat;
 use wayland_client::protocol::wl_callback::WlCallback;
 use wayland_client::protocol::wl_compositor::WlCompositor;
 use wayland_protocols::wlr::unstable::layer_shell::v1::client as layer_shell;
@@ -12,17 +15,335 @@ use wayland_protocols::wlr::unstable::layer_shell::v1::client as layer_shell;
 use layer_shell::zwlr_layer_shell_v1::Layer;
 use layer_shell::zwlr_layer_surface_v1::Anchor;
 
+use crate::anim::Animation;
+use crate::debug;
 use crate::event::EventSink;
 use crate::item::*;
-use crate::render::{Align,Render,Renderer};
+use crate::render::{Align,Canvas,Render,Renderer,MIDDLE};
 use crate::state::{NotifierList,Runtime,State};
-use crate::util::spawn_noerr;
+use crate::timing;
+use crate::util::{spawn_noerr,toml_to_f64};
 use crate::wayland::{LayerSurface,Popup,WaylandClient};
 
+/// Distance to scroll a popup for each scroll wheel step
+const SCROLL_STEP : f32 = 40.0;
+
+/// Duration of the slide when a bar is hidden or shown, or gives up its exclusive zone
+const SLIDE_TIME : Duration = Duration::from_millis(150);
+
+/// Upper limit on the number of rectangles in a bar's opaque region
+const MAX_OPAQUE_RECTS : usize = 32;
+
+/// Appearance and placement of popups, from the `popup` table
+#[derive(Debug,Clone)]
+struct PopupStyle {
+    bg : tiny_skia::Color,
+    border : Option<(tiny_skia::Color, f32)>,
+    padding : f32,
+    max_size : (i32, i32),
+    /// Place popups above the bar; if unset, place them on the side away from the screen edge
+    above : Option<bool>,
+    halign : f32,
+}
+
+impl PopupStyle {
+    fn from_toml(cfg : Option<&toml::Value>) -> Self {
+        let get = |key| cfg.and_then(|c| c.get(key));
+        let get_f32 = |key| toml_to_f64(get(key)).map(|v| v as f32);
+        let bg = Formatting::parse_rgba(get("bg").and_then(|v| v.as_str()), get_f32("bg-alpha"))
+            .unwrap_or(tiny_skia::Color::BLACK);
+        let border = Formatting::parse_rgba(get("border").and_then(|v| v.as_str()), get_f32("border-alpha"))
+            .map(|c| (c, get_f32("border-width").unwrap_or(1.0)));
+        let max = |key, default| get(key)
+            .and_then(|v| v.as_integer())
+            .filter(|&v| v > 0 && v < i32::MAX as _)
+            .map_or(default, |v| v as i32);
+        let above = match get("side").and_then(|v| v.as_str()) {
+            None | Some("auto") => None,
+            Some("above") | Some("top") => Some(true),
+            Some("below") | Some("bottom") => Some(false),
+            Some(side) => {
+                error!("Unknown popup side '{}', using auto", side);
+                None
+            }
+        };
+        let halign = match get("align").and_then(|v| v.as_str()) {
+            Some("left") => 0.0,
+            None | Some("center") => MIDDLE,
+            Some("right") => 1.0,
+            Some(align) => {
+                error!("Unknown popup align '{}', using center", align);
+                MIDDLE
+            }
+        };
+        PopupStyle {
+            bg,
+            border,
+            padding : get_f32("padding").unwrap_or(0.0),
+            max_size : (max("max-width", i32::MAX), max("max-height", 600)),
+            above,
+            halign,
+        }
+    }
+
+    /// Convert a measured content size to a popup size
+    fn popup_size(&self, size : (i32, i32)) -> (i32, i32) {
+        let pad = (self.padding * 2.0).ceil() as i32;
+        ((size.0 + pad).min(self.max_size.0), (size.1 + pad).min(self.max_size.1))
+    }
+}
+
+/// Decorations drawn across the whole bar, outside of any item
+#[derive(Debug,Clone,Default)]
+pub struct BarStyle {
+    /// Gradient colors and whether the gradient runs from top to bottom
+    gradient : Option<(Vec<tiny_skia::Color>, bool)>,
+    /// Color, width, and whether the line is on the top edge
+    line : Option<(tiny_skia::Color, f32, bool)>,
+}
+
+impl BarStyle {
+    pub fn from_toml(cfg : &toml::Value, anchor_top : bool) -> Self {
+        let get_f32 = |key| toml_to_f64(cfg.get(key)).map(|v| v as f32);
+        let colors : Vec<_> = cfg.get("bg-gradient")
+            .and_then(|v| v.as_array())
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| {
+                let color = Formatting::parse_rgba(v.as_str(), None);
+                if color.is_none() {
+                    error!("Invalid color in bg-gradient: {}", v);
+                }
+                color
+            })
+            .collect();
+        let gradient = if colors.is_empty() {
+            None
+        } else {
+            let vertical = match cfg.get("bg-gradient-direction").and_then(|v| v.as_str()) {
+                None | Some("vertical") => true,
+                Some("horizontal") => false,
+                Some(dir) => {
+                    error!("Unknown bg-gradient-direction '{}', using vertical", dir);
+                    true
+                }
+            };
+            Some((colors, vertical))
+        };
+        let line = Formatting::parse_rgba(cfg.get("edge-line").and_then(|v| v.as_str()), get_f32("edge-line-alpha"))
+            .map(|color| {
+                let at_top = match cfg.get("edge-line-side").and_then(|v| v.as_str()) {
+                    None | Some("inner") => !anchor_top,
+                    Some("outer") => anchor_top,
+                    Some("top") => true,
+                    Some("bottom") => false,
+                    Some(side) => {
+                        error!("Unknown edge-line-side '{}', using inner", side);
+                        !anchor_top
+                    }
+                };
+                (color, get_f32("edge-line-width").unwrap_or(1.0), at_top)
+            });
+        BarStyle { gradient, line }
+    }
+
+    /// Draw the background; this must be done before any items are rendered
+    fn draw_bg(&self, ctx : &mut Render) {
+        let (width, height) = (ctx.render_extents.1.x, ctx.render_extents.1.y);
+        let (colors, vertical) = match &self.gradient {
+            Some(g) => g,
+            None => return,
+        };
+        let shader = if colors.len() == 1 {
+            Some(tiny_skia::Shader::SolidColor(colors[0]))
+        } else {
+            let stops = colors.iter().enumerate()
+                .map(|(i, &c)| tiny_skia::GradientStop::new(i as f32 / (colors.len() - 1) as f32, c))
+                .collect();
+            let end = if *vertical {
+                tiny_skia::Point { x: 0.0, y: height }
+            } else {
+                tiny_skia::Point { x: width, y: 0.0 }
+            };
+            tiny_skia::LinearGradient::new(tiny_skia::Point::zero(), end, stops,
+                tiny_skia::SpreadMode::Pad, tiny_skia::Transform::identity())
+        };
+        if let (Some(shader), Some(rect)) = (shader, tiny_skia::Rect::from_xywh(0.0, 0.0, width, height)) {
+            let paint = tiny_skia::Paint { shader, ..Default::default() };
+            ctx.canvas.fill_rect(rect, &paint, ctx.render_xform);
+        }
+    }
+
+    /// Draw the edge line over the rendered items
+    fn draw_line(&self, ctx : &mut Render) {
+        let (width, height) = (ctx.render_extents.1.x, ctx.render_extents.1.y);
+        if let Some((color, size, at_top)) = self.line {
+            let y = if at_top { 0.0 } else { height - size };
+            if let Some(rect) = tiny_skia::Rect::from_xywh(0.0, y, width, size) {
+                let paint = tiny_skia::Paint {
+                    shader : tiny_skia::Shader::SolidColor(color),
+                    ..Default::default()
+                };
+                ctx.canvas.fill_rect(rect, &paint, ctx.render_xform);
+            }
+        }
+    }
+}
+
+/// Find the parts of the surface that are visible (if `full` is false, any pixel that is not fully
+/// transparent) or fully opaque (if `full` is true), as rectangles in surface coordinates.  Rows
+/// with identical spans are merged into a single rectangle.
+fn alpha_region(pixmap : &tiny_skia::Pixmap, scale : i32, full : bool) -> Vec<(i32, i32, i32, i32)> {
+    let scale = scale.max(1) as usize;
+    let width = pixmap.width() as usize;
+    let data = pixmap.data();
+    let opaque = |x : usize, y : usize| {
+        let mut pixels = (y * scale .. (y + 1) * scale).flat_map(|py| {
+            (x * scale .. (x + 1) * scale).map(move |px| data[(py * width + px) * 4 + 3])
+        });
+        if full {
+            pixels.all(|a| a == 255)
+        } else {
+            pixels.any(|a| a != 0)
+        }
+    };
+
+    let mut rv = Vec::new();
+    let mut band : Vec<(i32, i32)> = Vec::new();
+    let mut band_start = 0;
+    let mut spans = Vec::new();
+    let (w, h) = (width / scale, pixmap.height() as usize / scale);
+    for y in 0..=h {
+        spans.clear();
+        if y < h {
+            let mut start = None;
+            for x in 0..=w {
+                match (start, x < w && opaque(x, y)) {
+                    (None, true) => start = Some(x),
+                    (Some(s), false) => {
+                        spans.push((s as i32, (x - s) as i32));
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if spans != band {
+            for &(x, len) in &band {
+                rv.push((x, band_start, len, y as i32 - band_start));
+            }
+            std::mem::swap(&mut band, &mut spans);
+            band_start = y as i32;
+        }
+    }
+    rv
+}
+
 pub struct BarPopup {
     pub wl : Popup,
     desc : PopupDesc,
     vanish : Option<Instant>,
+    /// Pointer position, if it is inside the popup
+    hover : Option<(f32, f32)>,
+    /// Clickable regions from the last render, in content coordinates
+    hot : Vec<tiny_skia::Rect>,
+    /// The region currently highlighted
+    highlight : Option<tiny_skia::Rect>,
+    scroll : f32,
+    content_height : f32,
+    padding : f32,
+    /// The entry selected using the keyboard; overrides the pointer highlight
+    selected : Option<usize>,
+    /// Opened by a click; stays open until clicked again or dismissed
+    pinned : bool,
+}
+
+fn find_hot(hot : &[tiny_skia::Rect], hover : Option<(f32, f32)>, scroll : f32) -> Option<tiny_skia::Rect> {
+    let (x, y) = hover?;
+    let y = y + scroll;
+    hot.iter().find(|r| x >= r.left() && x < r.right() && y >= r.top() && y < r.bottom()).copied()
+}
+
+impl BarPopup {
+    fn highlight(&self) -> Option<tiny_skia::Rect> {
+        match self.selected {
+            Some(i) => self.hot.get(i).copied(),
+            None => find_hot(&self.hot, self.hover, self.scroll),
+        }
+    }
+
+    /// Height of the visible part of the content
+    fn visible_height(&self) -> f32 {
+        self.wl.surf.pixel_height() as f32 / self.wl.surf.scale as f32 - self.padding * 2.0
+    }
+
+    /// Limit the scroll position to the content that is not visible
+    fn clamp_scroll(&mut self) {
+        let visible = self.visible_height();
+        self.scroll = self.scroll.min(self.content_height - visible).max(0.0);
+    }
+}
+
+/// The height of a bar, from the `size` key
+pub fn config_size(cfg : &toml::Value) -> u32 {
+    cfg.get("size")
+        .and_then(|v| v.as_integer())
+        .filter(|&v| v > 0 && v < i32::MAX as _)
+        .and_then(|v| v.try_into().ok())
+        .unwrap_or(20)
+}
+
+/// True if the bar is placed at the top of its output, from the `side` key
+pub fn config_anchor_top(cfg : &toml::Value) -> bool {
+    match cfg.get("side").and_then(|v| v.as_str()) {
+        Some("top") => true,
+        None | Some("bottom") => false,
+        Some(side) => {
+            error!("Unknown side '{}', defaulting to bottom", side);
+            false
+        }
+    }
+}
+
+/// Record the drawing of a bar's contents, independent of the surface it will be shown on.
+///
+/// The size is in logical pixels.  Returns the recorded canvas and the bar's click handlers.
+pub fn draw(runtime : &mut Runtime, item : &mut Rc<Item>, style : &BarStyle, size : (u32, u32), scale : i32) -> (Canvas, EventSink) {
+    let rt_item = runtime.items.entry("bar".into()).or_insert_with(|| Rc::new(Item::none()));
+    std::mem::swap(item, rt_item);
+
+    let mut canvas = Canvas::new(size.0 * scale as u32, size.1 * scale as u32);
+    let font = &runtime.fonts[0];
+
+    let mut ctx = Render {
+        canvas : &mut canvas,
+        cache : &runtime.cache,
+        render_extents : (tiny_skia::Point::zero(), tiny_skia::Point { x: size.0 as f32, y: size.1 as f32 }),
+        render_pos : tiny_skia::Point::zero(),
+        render_flex : false,
+        measure_only : false,
+        render_xform: tiny_skia::Transform::from_scale(scale as f32, scale as f32),
+
+        font,
+        font_size : 16.0,
+        line_spacing : 1.0,
+        font_color : tiny_skia::Color::BLACK,
+        align : Align::bar_default(),
+        err_name: "bar",
+        text_stroke : None,
+        text_stroke_size : None,
+        text_shadow : None,
+        runtime,
+    };
+    style.draw_bg(&mut ctx);
+    let sink = ctx.runtime.items["bar"].render(&mut ctx);
+    style.draw_line(&mut ctx);
+    debug::draw_guides(&mut ctx);
+    timing::draw_overlay(&mut ctx);
+
+    std::mem::swap(item, runtime.items.get_mut("bar").unwrap());
+    (canvas, sink)
 }
 
 /// A single taskbar on a single output
@@ -31,13 +352,39 @@ pub struct Bar {
     pub ls: LayerSurface,
     pub popup : Option<BarPopup>,
     pub sink : EventSink,
+    /// Actions for the parts of the bar not covered by an item's action
+    background : EventSink,
     pub anchor_top : bool,
     click_size : u32,
+    size : u32,
+    /// The configured exclusive zone, restored when a fullscreen window goes away
+    size_excl : i32,
+    /// Fraction of `size_excl` to reserve, and its animation
+    reserve : (f32, Animation),
+    /// Fraction of the bar slid past the edge of the screen, and its animation
+    slide : (f32, Animation),
+    /// The exclusive zone and margin last sent to the compositor
+    placed : (i32, i32),
     pub dirty : bool,
     sparse : bool,
+    /// Only accept clicks on pixels that are not fully transparent
+    click_opaque : bool,
+    /// Take keyboard focus when clicked, so that the modifier keys are known
+    modifier_clicks : bool,
+    /// The input region last computed from the rendered pixels, when click_opaque is set
+    input_region : Vec<(i32, i32, i32, i32)>,
+    /// The opaque region last sent to the compositor
+    opaque_region : Vec<(i32, i32, i32, i32)>,
+    compositor : Attached<WlCompositor>,
+    popup_style : PopupStyle,
+    style : BarStyle,
     throttle : Option<Attached<WlCallback>>,
     pub item : Rc<Item>,
     pub cfg_index : usize,
+    /// The pointer is over the bar
+    hovered : bool,
+    /// The `freeze-on-hover` item that is hovered or has its popup open
+    frozen : Option<Rc<Freeze>>,
 }
 
 impl Bar {
@@ -54,11 +401,7 @@ impl Bar {
         };
         let mut ls = LayerSurface::new(wayland, output, layer);
 
-        let size = cfg.get("size")
-            .and_then(|v| v.as_integer())
-            .filter(|&v| v > 0 && v < i32::MAX as _)
-            .and_then(|v| v.try_into().ok())
-            .unwrap_or(20);
+        let size = config_size(&cfg);
         let size_excl = cfg.get("size-exclusive")
             .and_then(|v| v.as_integer())
             .filter(|&v| v >= -1 && v < i32::MAX as _)
@@ -70,14 +413,7 @@ impl Bar {
             .and_then(|v| v.try_into().ok())
             .or_else(|| size_excl.try_into().ok().filter(|&v| v > 0))
             .unwrap_or(size);
-        let anchor_top = match cfg.get("side").and_then(|v| v.as_str()) {
-            Some("top") => true,
-            None | Some("bottom") => false,
-            Some(side) => {
-                error!("Unknown side '{}', defaulting to bottom", side);
-                false
-            }
-        };
+        let anchor_top = config_anchor_top(&cfg);
         if anchor_top {
             ls.set_anchor(Anchor::Top | Anchor::Left | Anchor::Right);
         } else {
@@ -86,11 +422,23 @@ impl Bar {
         ls.ls_surf.set_size(0, size);
         ls.ls_surf.set_exclusive_zone(size_excl);
         let sparse = cfg.get("sparse-clicks").and_then(|v| v.as_bool()).unwrap_or(true);
-        if size != click_size {
+        let click_opaque = match cfg.get("click-shape").and_then(|v| v.as_str()) {
+            None | Some("band") => false,
+            Some("opaque") => true,
+            Some(shape) => {
+                error!("Unknown click-shape '{}', defaulting to band", shape);
+                false
+            }
+        };
+        let modifier_clicks = cfg.get("modifier-clicks").and_then(|v| v.as_bool()).unwrap_or(false);
+        let background = cfg.get("background-actions").map_or_else(EventSink::default, EventSink::from_toml);
+        let popup_style = PopupStyle::from_toml(cfg.get("popup"));
+        let style = BarStyle::from_toml(&cfg, anchor_top);
+        let compositor : Attached<WlCompositor> = wayland.env.require_global();
+        if size != click_size && !click_opaque {
             // Only handle input in the exclusive region; clicks in the overhang region will go
             // through to the window we cover (hopefully transparently, to avoid confusion)
-            let comp : Attached<WlCompositor> = wayland.env.require_global();
-            let region = comp.create_region();
+            let region = compositor.create_region();
             let yoff = if anchor_top {
                 0
             } else {
@@ -113,101 +461,210 @@ impl Bar {
             ls,
             item : Rc::new(Item::new_bar(cfg)),
             click_size,
+            size,
+            size_excl,
+            reserve : (1.0, Animation::default()),
+            slide : (0.0, Animation::default()),
+            placed : (size_excl, 0),
             anchor_top,
             sink : EventSink::default(),
+            background,
             dirty : false,
             sparse,
+            click_opaque,
+            modifier_clicks,
+            input_region : Vec::new(),
+            opaque_region : Vec::new(),
+            compositor,
+            popup_style,
+            style,
             throttle : None,
             popup : None,
             cfg_index,
+            hovered : false,
+            frozen : None,
         }
     }
 
-    pub fn render_with(&mut self, runtime : &mut Runtime, renderer: &mut Renderer) {
-        if self.dirty && self.throttle.is_none() && self.ls.can_render() {
-            let rt_item = runtime.items.entry("bar".into()).or_insert_with(|| Rc::new(Item::none()));
-            std::mem::swap(&mut self.item, rt_item);
+    /// Give up the exclusive zone while a fullscreen window covers this bar's output
+    pub fn set_overlap(&mut self, overlap : bool, animate : bool, rt : &Runtime) {
+        let to = if overlap { 0.0 } else { 1.0 };
+        if self.reserve.0 != to {
+            self.reserve.0 = to;
+            self.reserve.1.start(1.0 - to, to, if animate { SLIDE_TIME } else { Duration::ZERO }, rt);
+        }
+        self.place(rt);
+    }
 
-            let (canvas, finalize) = renderer.render_be_rgba(&self.ls.surf);
-            let mut canvas = match tiny_skia::PixmapMut::from_bytes(canvas, self.ls.pixel_width() as u32, self.ls.pixel_height() as u32) {
-                Some(canvas) => canvas,
-                None => return,
-            };
-            canvas.fill(tiny_skia::Color::TRANSPARENT);
-            let font = &runtime.fonts[0];
-
-            let mut ctx = Render {
-                canvas : &mut canvas, 
-                cache : &runtime.cache,
-                render_extents : (tiny_skia::Point::zero(), tiny_skia::Point { x: self.ls.config_width() as f32, y: self.ls.config_height() as f32 }),
-                render_pos : tiny_skia::Point::zero(),
-                render_flex : false,
-                render_xform: self.ls.surf.scale_transform(),
-
-                font,
-                font_size : 16.0,
-                font_color : tiny_skia::Color::BLACK,
-                align : Align::bar_default(),
-                err_name: "bar",
-                text_stroke : None,
-                text_stroke_size : None,
-                runtime,
-            };
-            let new_sink = ctx.runtime.items["bar"].render(&mut ctx);
-            finalize(canvas.data_mut());
-
-            if self.sparse {
-                let mut old_regions = Vec::new();
-                let mut new_regions = Vec::new();
-                self.sink.for_active_regions(|lo, hi| {
-                    old_regions.push((lo as i32, (hi - lo) as i32));
-                });
-                new_sink.for_active_regions(|lo, hi| {
-                    new_regions.push((lo as i32, (hi - lo) as i32));
-                });
-
-                if old_regions != new_regions {
-                    let comp : Attached<WlCompositor> = runtime.wayland.env.require_global();
-                    let region = comp.create_region();
-                    let yoff = if self.anchor_top {
-                        0
-                    } else {
-                        self.ls.config_height().saturating_sub(self.click_size) as i32
-                    };
-                    for (lo, len) in new_regions {
-                        region.add(lo, yoff, len, self.click_size as i32);
-                    }
-                    self.ls.surf.wl.set_input_region(Some(&region));
-                    region.destroy();
+    /// Slide the bar off the edge of the screen (and release its exclusive zone), or back
+    pub fn set_hidden(&mut self, hidden : bool, animate : bool, rt : &Runtime) {
+        let to = if hidden { 1.0 } else { 0.0 };
+        if self.slide.0 != to {
+            self.slide.0 = to;
+            self.slide.1.start(1.0 - to, to, if animate { SLIDE_TIME } else { Duration::ZERO }, rt);
+        }
+        self.set_overlap(hidden, animate, rt);
+    }
+
+    /// Apply the current step of any show/hide animation to the layer surface
+    pub fn place(&mut self, rt : &Runtime) {
+        let reserve = self.reserve.1.value(rt).unwrap_or(self.reserve.0);
+        let slide = self.slide.1.value(rt).unwrap_or(self.slide.0);
+        let zone = if self.size_excl > 0 {
+            (self.size_excl as f32 * reserve).round() as i32
+        } else {
+            self.size_excl
+        };
+        let margin = -(self.size as f32 * slide).round() as i32;
+        if self.placed == (zone, margin) {
+            return;
+        }
+        self.placed = (zone, margin);
+        self.ls.ls_surf.set_exclusive_zone(zone);
+        if self.anchor_top {
+            self.ls.ls_surf.set_margin(margin, 0, 0, 0);
+        } else {
+            self.ls.ls_surf.set_margin(0, 0, margin, 0);
+        }
+        self.ls.surf.wl.commit();
+    }
+
+    /// Render the bar's items, if it needs to be redrawn.
+    ///
+    /// This must run on the main thread, but it only records the drawing operations; the returned
+    /// canvas can be rasterized elsewhere and passed to [Bar::present].
+    pub fn record(&mut self, runtime : &mut Runtime) -> Option<Canvas> {
+        if !self.dirty || self.throttle.is_some() || !self.ls.can_render() {
+            return None;
+        }
+        let (canvas, mut new_sink) = draw(runtime, &mut self.item, &self.style,
+            (self.ls.config_width(), self.ls.config_height()), self.ls.surf.scale);
+        new_sink.add_background(&self.background);
+
+        if self.sparse && !self.click_opaque {
+            let mut old_regions = Vec::new();
+            let mut new_regions = Vec::new();
+            self.sink.for_active_regions(|lo, hi| {
+                old_regions.push((lo as i32, (hi - lo) as i32));
+            });
+            new_sink.for_active_regions(|lo, hi| {
+                new_regions.push((lo as i32, (hi - lo) as i32));
+            });
+
+            if old_regions != new_regions {
+                let region = self.compositor.create_region();
+                let yoff = if self.anchor_top {
+                    0
+                } else {
+                    self.ls.config_height().saturating_sub(self.click_size) as i32
+                };
+                for (lo, len) in new_regions {
+                    region.add(lo, yoff, len, self.click_size as i32);
                 }
+                self.ls.surf.wl.set_input_region(Some(&region));
+                region.destroy();
             }
-            self.sink = new_sink;
+        }
+        self.sink = new_sink;
+        Some(canvas)
+    }
 
-            std::mem::swap(&mut self.item, runtime.items.get_mut("bar").unwrap());
+    /// Describe the clickable regions from the last render, for the `regions` control socket
+    /// command
+    pub fn regions_json(&self, runtime : &Runtime) -> json::JsonValue {
+        let mut rv = self.sink.to_json(runtime);
+        rv["name"] = (&*self.name).into();
+        rv["sparse_clicks"] = self.sparse.into();
+        rv["click_shape"] = if self.click_opaque { "opaque" } else { "band" }.into();
+        rv["click_size"] = self.click_size.into();
+        rv
+    }
 
-            let frame = self.ls.surf.wl.frame();
-            let id = frame.as_ref().id();
-            frame.quick_assign(move |_frame, _event, mut data| {
-                let state : &mut State = data.get().unwrap();
-                for bar in &mut state.bars {
-                    let done = match bar.throttle.as_ref() {
-                        Some(cb) if !cb.as_ref().is_alive() => true,
-                        Some(cb) if cb.as_ref().id() == id => true,
-                        _ => false,
-                    };
-                    if done {
-                        bar.throttle.take();
-                    }
+    /// Attach and commit a rasterized frame produced from [Bar::record]
+    pub fn present(&mut self, renderer: &Renderer, pixmap : Option<tiny_skia::Pixmap>) {
+        let scale = self.ls.surf.scale;
+        let mut input = None;
+        let mut opaque = Vec::new();
+        let (canvas, finalize) = renderer.render_be_rgba(&mut self.ls.surf);
+        match pixmap {
+            Some(pixmap) if pixmap.data().len() == canvas.len() => {
+                canvas.copy_from_slice(pixmap.data());
+                if self.click_opaque {
+                    input = Some(alpha_region(&pixmap, scale, false));
                 }
-                state.request_draw();
-            });
+                opaque = alpha_region(&pixmap, scale, true);
+                if opaque.len() > MAX_OPAQUE_RECTS {
+                    // Only a hint; not worth sending a region made of scattered glyph pixels
+                    opaque.clear();
+                }
+            }
+            _ => {
+                error!("Bar {} was not rendered", self.name);
+                canvas.fill(0);
+            }
+        }
+        finalize(canvas);
+
+        match input {
+            Some(rects) if rects != self.input_region => {
+                let region = self.compositor.create_region();
+                for &(x, y, w, h) in &rects {
+                    region.add(x, y, w, h);
+                }
+                self.ls.surf.wl.set_input_region(Some(&region));
+                region.destroy();
+                self.input_region = rects;
+            }
+            _ => {}
+        }
+        if opaque != self.opaque_region {
+            if opaque.is_empty() {
+                self.ls.surf.wl.set_opaque_region(None);
+            } else {
+                let region = self.compositor.create_region();
+                for &(x, y, w, h) in &opaque {
+                    region.add(x, y, w, h);
+                }
+                self.ls.surf.wl.set_opaque_region(Some(&region));
+                region.destroy();
+            }
+            self.opaque_region = opaque;
+        }
+
+        let frame = self.ls.surf.wl.frame();
+        let id = frame.as_ref().id();
+        frame.quick_assign(move |_frame, _event, mut data| {
+            let state : &mut State = data.get().unwrap();
+            for bar in &mut state.bars {
+                let done = match bar.throttle.as_ref() {
+                    Some(cb) if !cb.as_ref().is_alive() => true,
+                    Some(cb) if cb.as_ref().id() == id => true,
+                    _ => false,
+                };
+                if done {
+                    bar.throttle.take();
+                }
+            }
+            state.request_draw();
+        });
+        self.ls.surf.wl.commit();
+        self.throttle = Some(frame.into());
+        self.dirty = false;
+    }
+
+    pub fn render_popup(&mut self, runtime : &mut Runtime, renderer: &Renderer) {
+        // Menus can be navigated using the keyboard and prompts need it for typing, but only ask
+        // for focus while one is open (or always, if modifier clicks were requested)
+        let focusable = self.modifier_clicks ||
+            self.popup.as_ref().is_some_and(|p| !p.hot.is_empty() || p.desc.takes_text());
+        if self.ls.set_keyboard_focusable(focusable) {
             self.ls.surf.wl.commit();
-            self.throttle = Some(frame.into());
-            self.dirty = false;
         }
+        self.release_frozen(runtime);
         if let Some(popup) = &mut self.popup {
             if popup.vanish.map_or(false, |vanish| vanish < Instant::now()) {
                 self.popup = None;
+                self.release_frozen(runtime);
                 return;
             }
             if popup.wl.waiting_on_configure {
@@ -216,23 +673,88 @@ impl Bar {
             let scale = popup.wl.surf.scale;
             let pixel_size = popup.wl.pixel_size();
 
-            let (canvas, finalize) = renderer.render_be_rgba(&popup.wl.surf);
+            popup.clamp_scroll();
+            let (canvas, finalize) = renderer.render_be_rgba(&mut popup.wl.surf);
             if let Some(mut canvas) = tiny_skia::PixmapMut::from_bytes(canvas, pixel_size.0 as u32, pixel_size.1 as u32) {
-                canvas.fill(tiny_skia::Color::TRANSPARENT);
-                let new_size = popup.desc.render_popup(runtime, &mut canvas, scale);
+                let style = &self.popup_style;
+                canvas.fill(style.bg);
+                let mut ops = Canvas::new(pixel_size.0 as u32, pixel_size.1 as u32);
+                let offset = (style.padding, style.padding - popup.scroll);
+                let (new_size, hot) = popup.desc.render_popup(runtime, &mut ops, scale, offset);
+                popup.hot = hot;
+                popup.hot.sort_by(|a, b| (a.top(), a.left()).partial_cmp(&(b.top(), b.left())).unwrap_or(std::cmp::Ordering::Equal));
+                popup.content_height = new_size.1 as f32;
+                popup.highlight = match popup.selected {
+                    Some(i) => popup.hot.get(i).copied(),
+                    None => find_hot(&popup.hot, popup.hover, popup.scroll),
+                };
+                if let Some(rect) = popup.highlight {
+                    let paint = tiny_skia::Paint {
+                        shader: tiny_skia::Shader::SolidColor(tiny_skia::Color::from_rgba8(255, 255, 255, 48)),
+                        ..Default::default()
+                    };
+                    let xform = tiny_skia::Transform::from_scale(scale as f32, scale as f32).pre_translate(offset.0, offset.1);
+                    ops.fill_rect(rect, &paint, xform);
+                }
+                if let Some((color, width)) = style.border {
+                    let paint = tiny_skia::Paint {
+                        shader: tiny_skia::Shader::SolidColor(color),
+                        ..Default::default()
+                    };
+                    let xform = tiny_skia::Transform::from_scale(scale as f32, scale as f32);
+                    let w = pixel_size.0 as f32 / scale as f32;
+                    let h = pixel_size.1 as f32 / scale as f32;
+                    for rect in [
+                        tiny_skia::Rect::from_xywh(0.0, 0.0, w, width),
+                        tiny_skia::Rect::from_xywh(0.0, h - width, w, width),
+                        tiny_skia::Rect::from_xywh(0.0, 0.0, width, h),
+                        tiny_skia::Rect::from_xywh(w - width, 0.0, width, h),
+                    ].into_iter().flatten() {
+                        ops.fill_rect(rect, &paint, xform);
+                    }
+                }
+                ops.replay(&mut canvas);
                 finalize(canvas.data_mut());
                 popup.wl.surf.wl.commit();
+                let new_size = style.popup_size(new_size);
                 if new_size.0 > popup.wl.req_size.0 || new_size.1 > popup.wl.req_size.1 {
-                    runtime.wayland.resize_popup(&self.ls.ls_surf, &mut popup.wl, new_size, scale);
+                    runtime.wayland().resize_popup(&self.ls.ls_surf, &mut popup.wl, new_size, scale);
                 }
             }
         }
     }
 
+    /// Keep the given item still, letting go of the previous one
+    fn set_frozen(&mut self, freeze : Option<Rc<Freeze>>, runtime : &Runtime) {
+        if self.frozen.as_ref().map(Rc::as_ptr) == freeze.as_ref().map(Rc::as_ptr) {
+            return;
+        }
+        if let Some(old) = self.frozen.take() {
+            old.active.set(false);
+            self.dirty = true;
+            runtime.request_draw();
+        }
+        if let Some(new) = &freeze {
+            new.active.set(true);
+        }
+        self.frozen = freeze;
+    }
+
+    /// Let go of the frozen item once neither it nor its popup is under the pointer
+    fn release_frozen(&mut self, runtime : &Runtime) {
+        if !self.hovered && self.popup.is_none() {
+            self.set_frozen(None, runtime);
+        }
+    }
+
     pub fn hover(&mut self, x : f64, y : f64, runtime : &Runtime) {
-        if let Some((min_x, max_x, desc)) = self.sink.get_hover(x as f32, y as f32) {
+        self.hovered = true;
+        self.set_frozen(self.sink.get_freeze(x as f32), runtime);
+        if let Some((_, _, desc)) = self.sink.get_hover(x as f32, y as f32) {
             if let Some(popup) = &self.popup {
-                if x < popup.wl.anchor.0 as f64 || x > (popup.wl.anchor.0 + popup.wl.anchor.2) as f64 {
+                if popup.pinned {
+                    return;
+                } else if x < popup.wl.anchor.0 as f64 || x > (popup.wl.anchor.0 + popup.wl.anchor.2) as f64 {
                     self.popup = None;
                 } else if popup.desc == *desc {
                     return;
@@ -240,27 +762,106 @@ impl Bar {
                     self.popup = None;
                 }
             }
-            let anchor = (min_x as i32, 0, (max_x - min_x) as i32, self.ls.config_height() as i32);
-            let mut canvas = tiny_skia::Pixmap::new(1, 1).unwrap();
-            let size = desc.render_popup(runtime, &mut canvas.as_mut(), self.ls.surf.scale);
-            if size.0 <= 0 || size.1 <= 0 {
-                return;
+            self.popup = self.new_popup(x, y, None, runtime);
+        }
+    }
+
+    /// Create the popup for the item at the given position, if it has one
+    fn new_popup(&mut self, x : f64, y : f64, grab : Option<(&WlSeat, u32)>, runtime : &Runtime) -> Option<BarPopup> {
+        let (min_x, max_x, desc) = self.sink.get_hover(x as f32, y as f32)?;
+        let anchor = (min_x as i32, 0, (max_x - min_x) as i32, self.ls.config_height() as i32);
+        let desc = desc.clone();
+        self.popup_at(anchor, desc, grab, runtime)
+    }
+
+    fn popup_at(&self, anchor : (i32, i32, i32, i32), mut desc : PopupDesc, grab : Option<(&WlSeat, u32)>, runtime : &Runtime) -> Option<BarPopup> {
+        let size = desc.measure_popup(runtime, self.ls.surf.scale);
+        if size.0 <= 0 || size.1 <= 0 {
+            return None;
+        }
+        let size = self.popup_style.popup_size(size);
+        let above = self.popup_style.above.unwrap_or(!self.anchor_top);
+
+        Some(BarPopup {
+            wl : runtime.wayland().new_popup(self, anchor, size, above, self.popup_style.halign, grab),
+            desc,
+            vanish : None,
+            hover : None,
+            hot : Vec::new(),
+            highlight : None,
+            scroll : 0.0,
+            content_height : 0.0,
+            padding : self.popup_style.padding,
+            selected : None,
+            pinned : false,
+        })
+    }
+
+    /// Handle a click, scroll, or tap on the bar itself
+    pub fn button(&mut self, x : f64, y : f64, button : u32, grab : Option<(&WlSeat, u32)>, runtime : &mut Runtime) {
+        runtime.take_popup_toggle();
+        runtime.take_item_popup();
+        self.sink.button(x as f32, y as f32, button, runtime);
+        let toggle = runtime.take_popup_toggle();
+        let is_scroll = (5..9).contains(&button);
+
+        if let Some(popup) = &self.popup {
+            let on_anchor = x >= popup.wl.anchor.0 as f64 && x <= (popup.wl.anchor.0 + popup.wl.anchor.2) as f64;
+            if popup.pinned && !is_scroll || toggle {
+                let was_pinned = popup.pinned;
+                self.popup = None;
+                runtime.request_draw();
+                if was_pinned && on_anchor && toggle {
+                    return;
+                }
+            }
+        }
+        if toggle {
+            self.popup = self.new_popup(x, y, grab, runtime);
+            match &mut self.popup {
+                Some(popup) => popup.pinned = true,
+                None => debug!("Nothing to show in a popup at x={}", x),
+            }
+        }
+        if let Some(name) = runtime.take_item_popup() {
+            if let Err(e) = self.toggle_item_popup(&name, runtime) {
+                error!("Cannot show popup: {}", e);
             }
+        }
+    }
 
-            let desc = desc.clone();
-            let popup = BarPopup {
-                wl : runtime.wayland.new_popup(self, anchor, size),
-                desc,
-                vanish : None,
-            };
-            self.popup = Some(popup);
+    /// Open the popup of the named item as a pinned popup in the middle of the bar, or close it
+    /// if it is already open
+    pub fn toggle_item_popup(&mut self, name : &str, runtime : &Runtime) -> Result<(), Box<dyn Error>> {
+        let item = runtime.items.get(name).ok_or_else(|| format!("no item named '{}'", name))?;
+        let desc = PopupDesc::for_item(item, runtime);
+        if let Some(popup) = self.popup.take() {
+            runtime.request_draw();
+            if popup.desc == desc {
+                return Ok(());
+            }
         }
+        let width = self.ls.config_width() as i32;
+        let anchor = (width / 2, 0, 1, self.ls.config_height() as i32);
+        let mut popup = self.popup_at(anchor, desc, None, runtime)
+            .ok_or_else(|| format!("nothing to show in a popup for '{}'", name))?;
+        popup.pinned = true;
+        self.popup = Some(popup);
+        Ok(())
     }
 
     pub fn no_hover(&mut self, runtime : &mut Runtime) {
+        self.hovered = false;
+        self.release_frozen(runtime);
         if let Some(popup) = &mut self.popup {
+            if popup.selected.is_some() || popup.pinned {
+                // being navigated using the keyboard, or opened by a click
+                popup.hover = None;
+                return;
+            }
             let vanish = Instant::now() + std::time::Duration::from_millis(100);
             popup.vanish = Some(vanish);
+            popup.hover = None;
             let mut notify = NotifierList::active(runtime);
             spawn_noerr(async move {
                 tokio::time::sleep_until(vanish.into()).await;
@@ -269,16 +870,85 @@ impl Bar {
         }
     }
 
-    pub fn hover_popup(&mut self, x : f64, y : f64, _runtime : &Runtime) {
+    pub fn hover_popup(&mut self, x : f64, y : f64, runtime : &Runtime) {
         if let Some(popup) = &mut self.popup {
             popup.vanish = None;
-            let _ = (x, y);
+            popup.hover = Some((x as f32 - popup.padding, y as f32 - popup.padding));
+            popup.selected = None;
+            if popup.highlight() != popup.highlight {
+                runtime.request_draw();
+            }
+        }
+    }
+
+    pub fn popup_key(&mut self, keysym : u32, text : Option<&str>, runtime : &mut Runtime) {
+        use smithay_client_toolkit::seat::keyboard::keysyms;
+        let popup = match &mut self.popup {
+            Some(popup) => popup,
+            None => return,
+        };
+        let count = popup.hot.len();
+        match keysym {
+            keysyms::XKB_KEY_Escape => {
+                self.popup = None;
+                runtime.request_draw();
+                return;
+            }
+            _ if popup.desc.takes_text() => {
+                if popup.desc.key(keysym, text, runtime) {
+                    self.popup = None;
+                }
+                runtime.request_draw();
+                return;
+            }
+            keysyms::XKB_KEY_Up | keysyms::XKB_KEY_Left | keysyms::XKB_KEY_ISO_Left_Tab if count != 0 => {
+                popup.selected = Some(popup.selected.map_or(count - 1, |i| (i + count - 1) % count));
+            }
+            keysyms::XKB_KEY_Down | keysyms::XKB_KEY_Right | keysyms::XKB_KEY_Tab if count != 0 => {
+                popup.selected = Some(popup.selected.map_or(0, |i| (i + 1) % count));
+            }
+            keysyms::XKB_KEY_Home if count != 0 => popup.selected = Some(0),
+            keysyms::XKB_KEY_End if count != 0 => popup.selected = Some(count - 1),
+            keysyms::XKB_KEY_Return | keysyms::XKB_KEY_KP_Enter | keysyms::XKB_KEY_space => {
+                if let Some(rect) = popup.highlight() {
+                    let x = (rect.left() + rect.right()) / 2.0;
+                    let y = (rect.top() + rect.bottom()) / 2.0;
+                    if popup.desc.button(x as f64, y as f64, 0, runtime) {
+                        self.popup = None;
+                        runtime.request_draw();
+                    }
+                }
+                return;
+            }
+            _ => return,
         }
+        popup.vanish = None;
+        // Scroll so that the selection is visible
+        if let Some(rect) = popup.highlight() {
+            let visible = popup.visible_height();
+            if rect.top() < popup.scroll {
+                popup.scroll = rect.top();
+            } else if rect.bottom() > popup.scroll + visible {
+                popup.scroll = rect.bottom() - visible;
+            }
+            popup.clamp_scroll();
+        }
+        runtime.request_draw();
     }
 
     pub fn popup_button(&mut self, x : f64, y : f64, button : u32, runtime : &mut Runtime) {
         if let Some(popup) = &mut self.popup {
-            popup.desc.button(x, y, button, runtime);
+            if popup.content_height > popup.visible_height() && (button == 5 || button == 6) {
+                popup.scroll += if button == 5 { -SCROLL_STEP } else { SCROLL_STEP };
+                popup.clamp_scroll();
+                runtime.request_draw();
+                return;
+            }
+            let pad = popup.padding as f64;
+            if popup.desc.button(x - pad, y - pad + popup.scroll as f64, button, runtime) {
+                self.popup = None;
+                runtime.request_draw();
+            }
         }
     }
 }
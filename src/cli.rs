@@ -0,0 +1,109 @@
+//! Command line arguments
+use crate::headless;
+use crate::timing;
+use once_cell::sync::OnceCell;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const USAGE : &str = "\
+Usage: rwaybar [OPTIONS]
+
+Options:
+  -c, --config PATH       Use this configuration file instead of searching for rwaybar.toml
+  -b, --bar ID            Only show the bar with this id (or index); may be repeated
+  -l, --log-level FILTER  Log level or filter, such as 'info' or 'warn,rwaybar::sway=debug'
+      --timings[=MODE]    Report render timings: 'log' (the default) or 'overlay'
+      --render-once[=DIR] Render each bar to DIR/bar-N.png without a compositor, then exit
+      --render-width N    Width of the rendered bars (default 1920)
+      --render-scale N    Scale of the rendered bars (default 1)
+      --render-delay SECS Time for data sources to update before rendering (default 1)
+  -h, --help              Show this help
+  -V, --version           Show the version
+";
+
+static ARGS : OnceCell<Args> = OnceCell::new();
+
+/// The arguments given to the program
+pub fn args() -> &'static Args {
+    ARGS.get_or_init(Args::default)
+}
+
+#[derive(Debug,Default)]
+pub struct Args {
+    pub config : Option<PathBuf>,
+    pub bars : Vec<String>,
+    pub log_level : Option<String>,
+    pub timings : Option<timing::Mode>,
+    pub render_once : Option<headless::Options>,
+    pub help : bool,
+    pub version : bool,
+}
+
+impl Args {
+    pub fn usage() -> &'static str {
+        USAGE
+    }
+
+    /// Make these the arguments returned by [args]
+    pub fn install(self) -> &'static Self {
+        let _ = ARGS.set(self);
+        args()
+    }
+
+    pub fn parse(mut args : impl Iterator<Item=String>) -> Result<Self, String> {
+        let mut rv = Args::default();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_owned(), Some(value.to_owned())),
+                _ => (arg, None),
+            };
+            let mut value = |what : &str| match &inline {
+                Some(v) => Ok(v.clone()),
+                None => args.next().ok_or_else(|| format!("{} requires {}", flag, what)),
+            };
+            fn number<T : std::str::FromStr>(flag : &str, v : String) -> Result<T, String> {
+                v.parse().map_err(|_| format!("Invalid value for {}: {}", flag, v))
+            }
+            match &*flag {
+                "-c" | "--config" => rv.config = Some(value("a path")?.into()),
+                "-b" | "--bar" => rv.bars.push(value("a bar id")?),
+                "-l" | "--log-level" => rv.log_level = Some(value("a level")?),
+                "--timings" => {
+                    let mode = inline.as_deref().unwrap_or("");
+                    rv.timings = Some(timing::Mode::from_str(mode).ok_or_else(|| format!("Unknown timing mode '{}'", mode))?);
+                }
+                "--render-once" => {
+                    let opts = rv.render_once.get_or_insert_with(Default::default);
+                    if let Some(dir) = &inline {
+                        opts.dir = dir.into();
+                    }
+                }
+                "--render-width" => {
+                    let width = number(&flag, value("a width")?)?;
+                    if width == 0 {
+                        return Err("--render-width must be positive".into());
+                    }
+                    rv.render_once.get_or_insert_with(Default::default).width = width;
+                }
+                "--render-scale" => {
+                    let scale = number(&flag, value("a scale")?)?;
+                    if scale <= 0 {
+                        return Err("--render-scale must be positive".into());
+                    }
+                    rv.render_once.get_or_insert_with(Default::default).scale = scale;
+                }
+                "--render-delay" => {
+                    let delay : f64 = number(&flag, value("a number of seconds")?)?;
+                    if !(delay >= 0.0 && delay.is_finite()) {
+                        return Err("--render-delay must not be negative".into());
+                    }
+                    rv.render_once.get_or_insert_with(Default::default).delay = Duration::from_secs_f64(delay);
+                }
+                "-h" | "--help" => rv.help = true,
+                "-V" | "--version" => rv.version = true,
+                _ => return Err(format!("Unknown argument '{}'", flag)),
+            }
+        }
+        Ok(rv)
+    }
+}
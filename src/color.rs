@@ -0,0 +1,349 @@
+//! Parsing of CSS-style color values
+use crate::data::Value;
+use crate::registry::Source;
+use crate::state::Runtime;
+use log::error;
+
+/// CSS named colors, sorted by name.  `green` and `gray`/`grey` keep the values rwaybar always used
+/// for them instead of the CSS ones (#008000 and #808080).
+static NAMED : &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff),
+    ("antiquewhite", 0xfaebd7),
+    ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff),
+    ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff),
+    ("blueviolet", 0x8a2be2),
+    ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887),
+    ("cadetblue", 0x5f9ea0),
+    ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50),
+    ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc),
+    ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b),
+    ("darkcyan", 0x008b8b),
+    ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b),
+    ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a),
+    ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f),
+    ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493),
+    ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0),
+    ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff),
+    ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520),
+    ("gray", 0x7f7f7f),
+    ("green", 0x00ff00),
+    ("greenyellow", 0xadff2f),
+    ("grey", 0x7f7f7f),
+    ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4),
+    ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082),
+    ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5),
+    ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd),
+    ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2),
+    ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3),
+    ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0),
+    ("lime", 0x00ff00),
+    ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6),
+    ("magenta", 0xff00ff),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd),
+    ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc),
+    ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa),
+    ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead),
+    ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500),
+    ("orangered", 0xff4500),
+    ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98),
+    ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5),
+    ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f),
+    ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xff0000),
+    ("rosybrown", 0xbc8f8f),
+    ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072),
+    ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57),
+    ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0),
+    ("skyblue", 0x87ceeb),
+    ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4),
+    ("tan", 0xd2b48c),
+    ("teal", 0x008080),
+    ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0),
+    ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3),
+    ("white", 0xffffff),
+    ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
+/// A parsed color: red, green, and blue from 0 to 1, and the alpha if the color included one
+pub type Rgba = (f32, f32, f32, Option<f32>);
+
+/// Parse a color:
+///
+/// - `#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa`, or the same with four digits per channel
+/// - a CSS color name, or `transparent`
+/// - `rgb(r, g, b)` / `rgba(r, g, b, a)` with channels from 0 to 255 or percentages
+/// - `hsl(h, s, l)` / `hsla(...)` and `hsv(h, s, v)` / `hsva(...)`, with the hue in degrees
+///
+/// Functions accept commas or spaces between values, and the alpha may also follow a `/` (as
+/// in `rgb(255 0 0 / 50%)`).  Any color may be followed by `/ALPHA` to set its alpha, for
+/// example `red/0.5` or `#ff0000/50%`.
+pub fn parse(color : &str) -> Option<Rgba> {
+    let color = color.trim();
+    // a trailing "/alpha" that is not inside a function
+    if let Some((base, alpha)) = color.rsplit_once('/').filter(|(_, a)| !a.contains(')')) {
+        let (r, g, b, _) = parse(base)?;
+        return Some((r, g, b, Some(parse_fraction(alpha)?)));
+    }
+    if let Some(hex) = color.strip_prefix('#') {
+        return parse_hex(hex);
+    }
+    if let Some((func, args)) = color.strip_suffix(')').and_then(|c| c.split_once('(')) {
+        return parse_function(&func.trim().to_ascii_lowercase(), args);
+    }
+    let name = color.to_ascii_lowercase();
+    if name == "transparent" {
+        return Some((0.0, 0.0, 0.0, Some(0.0)));
+    }
+    let rgb = NAMED.binary_search_by_key(&&*name, |&(n, _)| n).ok().map(|i| NAMED[i].1)?;
+    let channel = |shift : u32| ((rgb >> shift) & 0xFF) as f32 / 255.0;
+    Some((channel(16), channel(8), channel(0), None))
+}
+
+fn parse_hex(hex : &str) -> Option<Rgba> {
+    let v = u64::from_str_radix(hex, 16).ok()?;
+    let (digits, has_alpha) = match hex.len() {
+        3 => (1, false),
+        4 => (1, true),
+        6 => (2, false),
+        8 => (2, true),
+        12 => (4, false),
+        16 => (4, true),
+        _ => return None,
+    };
+    let bits = digits * 4;
+    let max = ((1u64 << bits) - 1) as f32;
+    let count = if has_alpha { 4 } else { 3 };
+    let channel = |i : u32| ((v >> (bits * (count - 1 - i))) & ((1 << bits) - 1)) as f32 / max;
+    Some((channel(0), channel(1), channel(2), has_alpha.then(|| channel(3))))
+}
+
+/// An alpha, saturation, or lightness value: a number from 0 to 1, or a percentage
+fn parse_fraction(v : &str) -> Option<f32> {
+    let v = v.trim();
+    let a = match v.strip_suffix('%') {
+        Some(pct) => pct.trim().parse::<f32>().ok()? / 100.0,
+        None => v.parse().ok()?,
+    };
+    Some(a.clamp(0.0, 1.0))
+}
+
+/// An rgb channel: a number from 0 to 255, or a percentage
+fn parse_channel(v : &str) -> Option<f32> {
+    let a = match v.strip_suffix('%') {
+        Some(pct) => pct.parse::<f32>().ok()? / 100.0,
+        None => v.parse::<f32>().ok()? / 255.0,
+    };
+    Some(a.clamp(0.0, 1.0))
+}
+
+/// A hue in degrees, with an optional `deg` or `turn` unit
+fn parse_hue(v : &str) -> Option<f32> {
+    let h = if let Some(deg) = v.strip_suffix("deg") {
+        deg.parse::<f32>().ok()?
+    } else if let Some(turn) = v.strip_suffix("turn") {
+        turn.parse::<f32>().ok()? * 360.0
+    } else {
+        v.parse::<f32>().ok()?
+    };
+    Some(h.rem_euclid(360.0))
+}
+
+fn parse_function(func : &str, args : &str) -> Option<Rgba> {
+    let (args, slash_alpha) = match args.split_once('/') {
+        Some((args, alpha)) => (args, Some(alpha)),
+        None => (args, None),
+    };
+    let args : Vec<&str> = args.split(|c : char| c == ',' || c.is_whitespace())
+        .filter(|a| !a.is_empty())
+        .collect();
+    let alpha = match (args.get(3).copied(), slash_alpha) {
+        (Some(_), Some(_)) => return None,
+        (Some(a), None) | (None, Some(a)) => Some(parse_fraction(a)?),
+        (None, None) => None,
+    };
+    if args.len() != 3 && args.len() != 4 {
+        return None;
+    }
+    let (r, g, b) = match func {
+        "rgb" | "rgba" => (parse_channel(args[0])?, parse_channel(args[1])?, parse_channel(args[2])?),
+        "hsl" | "hsla" => {
+            let (h, s, l) = (parse_hue(args[0])?, parse_fraction(args[1])?, parse_fraction(args[2])?);
+            // convert to hsv
+            let v = l + s * l.min(1.0 - l);
+            let sv = if v == 0.0 { 0.0 } else { 2.0 * (1.0 - l / v) };
+            hsv_to_rgb(h, sv, v)
+        }
+        "hsv" | "hsva" => hsv_to_rgb(parse_hue(args[0])?, parse_fraction(args[1])?, parse_fraction(args[2])?),
+        _ => return None,
+    };
+    Some((r, g, b, alpha))
+}
+
+fn hsv_to_rgb(h : f32, s : f32, v : f32) -> (f32, f32, f32) {
+    let f = |n : f32| {
+        let k = (n + h / 60.0) % 6.0;
+        v - v * s * k.min(4.0 - k).clamp(0.0, 1.0)
+    };
+    (f(5.0), f(3.0), f(1.0))
+}
+
+/// The "color-scale" module: a color picked from a gradient by a numeric value
+#[derive(Debug)]
+pub struct ColorScale {
+    value : Box<str>,
+    /// Positions and colors (with alpha), sorted by position
+    stops : Vec<(f64, [f32; 4])>,
+    default : Option<Box<str>>,
+}
+
+impl ColorScale {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let mut stops = Vec::new();
+        for (pos, color) in value.get("stops").and_then(|v| v.as_table()).into_iter().flatten() {
+            let pos = match pos.trim().parse::<f64>() {
+                Ok(pos) => pos,
+                Err(_) => {
+                    error!("Ignoring color-scale stop '{}': the key must be a number", pos);
+                    continue;
+                }
+            };
+            match color.as_str().and_then(parse) {
+                Some((r, g, b, a)) => stops.push((pos, [r, g, b, a.unwrap_or(1.0)])),
+                None => error!("Ignoring color-scale stop {}: invalid color {}", pos, color),
+            }
+        }
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if stops.is_empty() {
+            error!("A color-scale requires at least one entry in 'stops'");
+        }
+        ColorScale {
+            value : value.get("value").and_then(|v| v.as_str()).unwrap_or("").into(),
+            stops,
+            default : value.get("default").and_then(|v| v.as_str()).map(Into::into),
+        }
+    }
+
+    fn color_at(&self, v : f64) -> Option<[f32; 4]> {
+        let first = self.stops.first()?;
+        let last = self.stops.last()?;
+        if v <= first.0 {
+            return Some(first.1);
+        }
+        if v >= last.0 {
+            return Some(last.1);
+        }
+        let i = self.stops.iter().position(|s| s.0 > v)?;
+        let ((p0, c0), (p1, c1)) = (self.stops[i - 1], self.stops[i]);
+        let t = ((v - p0) / (p1 - p0)) as f32;
+        Some(std::array::from_fn(|j| c0[j] + (c1[j] - c0[j]) * t))
+    }
+}
+
+impl Source for ColorScale {
+    fn read<'a>(&'a self, name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        if !key.is_empty() && key != "text" {
+            return Value::Null;
+        }
+        let v = rt.format_or(&self.value, name).parse_f64();
+        match v.filter(|v| !v.is_nan()).and_then(|v| self.color_at(v)) {
+            Some(c) => {
+                let [r, g, b, a] = c.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+                Value::Owned(format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a))
+            }
+            None => self.default.as_deref().map_or(Value::Null, Value::Borrow),
+        }
+    }
+}
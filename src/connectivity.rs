@@ -0,0 +1,179 @@
+//! Internet connectivity checks using a DNS lookup or an HTTP request
+use crate::data::{Periodic,Value};
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,toml_to_f64};
+use log::debug;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+use tokio::io::{AsyncBufReadExt,AsyncReadExt,AsyncWriteExt,BufReader};
+use tokio::net::TcpStream;
+
+/// How to tell if we are online
+#[derive(Debug)]
+enum Probe {
+    /// Resolve a host name
+    Dns(Box<str>),
+    /// Send a HEAD request and wait for the status line
+    Http { host : Box<str>, port : u16, path : Box<str> },
+}
+
+impl Probe {
+    fn from_url(url : &str) -> Result<Self, String> {
+        // Without TLS, an https check could only connect to the server, which also succeeds
+        // behind a captive portal that intercepts connections
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if url.starts_with("https://") => return Err(format!("Unsupported URL '{}': https is not supported, use an http:// URL", url)),
+            None => return Err(format!("Unsupported URL '{}': only http is supported", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(p) => (&rest[..p], &rest[p..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse().map_err(|_| format!("Invalid port in URL '{}'", url))?;
+                (host, port)
+            }
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("Missing host in URL '{}'", url));
+        }
+        Ok(Probe::Http { host : host.into(), port, path : path.into() })
+    }
+
+    fn target(&self) -> &str {
+        match self {
+            Probe::Dns(host) | Probe::Http { host, .. } => host,
+        }
+    }
+
+    /// Run the check, returning the HTTP status if there was one
+    async fn run(&self) -> io::Result<Option<u16>> {
+        match self {
+            Probe::Dns(host) => {
+                let mut addrs = tokio::net::lookup_host((&**host, 0)).await?;
+                match addrs.next() {
+                    Some(_) => Ok(None),
+                    None => Err(io::Error::new(io::ErrorKind::NotFound, "no addresses found")),
+                }
+            }
+            Probe::Http { host, port, path } => {
+                let mut stream = TcpStream::connect((&**host, *port)).await?;
+                let request = format!("HEAD {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rwaybar\r\nConnection: close\r\n\r\n", path, host);
+                stream.write_all(request.as_bytes()).await?;
+                let mut line = String::new();
+                BufReader::new(stream.take(256)).read_line(&mut line).await?;
+                // "HTTP/1.1 204 No Content"
+                match line.strip_prefix("HTTP/").and_then(|l| l.split_whitespace().nth(1)).and_then(|s| s.parse().ok()) {
+                    Some(status) => Ok(Some(status)),
+                    None => Err(io::Error::new(io::ErrorKind::InvalidData, "not an HTTP response")),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Status {
+    probe : Probe,
+    timeout : Duration,
+    /// None until the first check finishes
+    online : Cell<Option<bool>>,
+    latency : Cell<Option<Duration>>,
+    http_status : Cell<Option<u16>>,
+    error : Cell<Option<String>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl Status {
+    async fn check(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let start = Instant::now();
+        let rv = match tokio::time::timeout(self.timeout, self.probe.run()).await {
+            Ok(rv) => rv,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+        };
+        match rv {
+            Ok(status) => {
+                self.online.set(Some(true));
+                self.latency.set(Some(start.elapsed()));
+                self.http_status.set(status);
+                self.error.set(None);
+            }
+            Err(e) => {
+                debug!("Connectivity check of {} failed: {}", self.probe.target(), e);
+                self.online.set(Some(false));
+                self.latency.set(None);
+                self.http_status.set(None);
+                self.error.set(Some(e.to_string()));
+            }
+        }
+        self.interested.take().notify_data("connectivity");
+        self.running.set(false);
+    }
+}
+
+/// The "connectivity" module
+#[derive(Debug)]
+pub struct Connectivity {
+    poll : Periodic<Rc<Status>>,
+}
+
+impl Connectivity {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let probe = match (config.get("url").and_then(|v| v.as_str()), config.get("host").and_then(|v| v.as_str())) {
+            (Some(_), Some(_)) => return Err("Only one of 'url' and 'host' may be given".into()),
+            (Some(url), None) => Probe::from_url(url)?,
+            (None, Some(host)) => Probe::Dns(host.into()),
+            (None, None) => Probe::Dns("example.com".into()),
+        };
+        let timeout = toml_to_f64(config.get("timeout")).unwrap_or(5.0);
+        let status = Status {
+            probe,
+            timeout : Duration::from_secs_f64(timeout.max(0.1)),
+            online : Cell::new(None),
+            latency : Cell::new(None),
+            http_status : Cell::new(None),
+            error : Cell::new(None),
+            running : Cell::new(false),
+            interested : Default::default(),
+        };
+        let poll = Periodic::new(toml_to_f64(config.get("poll")).unwrap_or(60.0), Rc::new(status));
+        Ok(Connectivity { poll })
+    }
+}
+
+impl Source for Connectivity {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.poll.read_refresh_async(rt, |status| status.clone().check());
+        let status = self.poll.data();
+        status.interested.take_in(|i| i.add(rt));
+        let online = status.online.get();
+        match key {
+            "" | "text" => match online {
+                Some(true) => Value::Borrow("online"),
+                Some(false) => Value::Borrow("offline"),
+                None => Value::Null,
+            },
+            "online" => online.map_or(Value::Null, Value::Bool),
+            "latency" => status.latency.get().map_or(Value::Null, |d| Value::Float(d.as_secs_f64() * 1000.0)),
+            "status" => status.http_status.get().map_or(Value::Null, |s| Value::Float(s as f64)),
+            "error" => status.error.take_in(|e| e.clone()).map_or(Value::Null, Value::Owned),
+            "target" => Value::Borrow(status.probe.target()),
+            _ => Value::Null,
+        }
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.refresh();
+        true
+    }
+}
@@ -1,47 +1,69 @@
 //! Text expansion and data sources
-use crate::item::Item;
+use crate::anim::Animation;
+use crate::item::{Columns,Item};
 #[cfg(feature="dbus")]
 use crate::dbus::DbusValue;
+use crate::debug;
+use crate::event::EventSink;
+use crate::locale;
+use crate::menu;
+use crate::mock;
 #[cfg(feature="dbus")]
 use crate::mpris;
+use crate::pages;
+#[cfg(feature="dbus")]
+use crate::power_profile;
+use crate::process;
+use crate::prompt;
 #[cfg(feature="pulse")]
 use crate::pulse;
+use crate::recorder;
+use crate::registry;
+use crate::screencopy;
+use crate::separator;
 use crate::state::NotifierList;
 use crate::state::Runtime;
 use crate::sway;
+use crate::timer;
 #[cfg(feature="dbus")]
 use crate::tray;
-use crate::util::{Cell,Fd,glob_expand,toml_to_string,toml_to_f64,spawn_noerr,spawn_handle};
+use crate::updates;
+use crate::util::{Cell,glob_expand,read_lines,toml_to_string,toml_to_f64,spawn_noerr,spawn_handle};
 use crate::wlr::ClipboardData;
 use evalexpr::Node as EvalExpr;
 use futures_util::future::RemoteHandle;
 use json::JsonValue;
 use log::{debug,info,warn,error};
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs;
 use std::future::Future;
-use std::io;
 use std::io::Write;
 use std::os::unix::io::{AsRawFd,IntoRawFd};
 use std::process::{Command,Stdio,ChildStdin};
 use std::rc::{Rc,Weak};
 use std::time::{Duration,Instant};
-use tokio::io::unix::AsyncFd;
 use libc;
 
 /// The result of a data source or text expansion
 #[derive(Debug)]
 pub enum Value<'a> {
+    /// Text borrowed from the source
     Borrow(&'a str),
+    /// Text owned by the value
     Owned(String),
+    /// A number
     Float(f64),
+    /// A boolean, shown as `1` or `0`
     Bool(bool),
+    /// No value, shown as empty text
     Null,
 }
 
 #[cfg_attr(not(feature="pulse"),allow(unused))]
 impl<'a> Value<'a> {
+    /// Borrow the value without copying any text
     pub fn as_ref(&self) -> Value {
         match self {
             Value::Borrow(v) => Value::Borrow(v),
@@ -52,6 +74,7 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// The text of the value, or "" if it is not text
     pub fn as_str_fast(&self) -> &str {
         match self {
             Value::Borrow(v) => v,
@@ -60,6 +83,7 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// Copy any borrowed text so the value does not borrow from its source
     pub fn into_owned(self) -> Value<'static> {
         match self {
             Value::Borrow(v) => Value::Owned(v.into()),
@@ -70,6 +94,7 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// Convert the value to text, as it is shown in a format string
     pub fn into_text(self) -> Cow<'a, str> {
         match self {
             Value::Borrow(v) => Cow::Borrowed(v),
@@ -81,6 +106,7 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// The value as a number, parsing text if needed
     pub fn parse_f32(&self) -> Option<f32> {
         match self {
             Value::Borrow(v) => v.parse().ok(),
@@ -92,6 +118,7 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// The value as a number, parsing text if needed
     pub fn parse_f64(&self) -> Option<f64> {
         match self {
             Value::Borrow(v) => v.parse().ok(),
@@ -103,6 +130,7 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// The value as a boolean: `1`, `0`, `true`, and `false` are accepted as text
     pub fn parse_bool(&self) -> Option<bool> {
         match self.as_ref() {
             Value::Borrow(v) if v == "1" => Some(true),
@@ -118,6 +146,7 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// Whether the value is non-empty text, a non-zero number, or true
     pub fn as_bool(&self) -> bool {
         match self {
             Value::Borrow(v) => !v.is_empty(),
@@ -185,18 +214,24 @@ impl<'a> From<String> for Value<'a> {
 pub enum ItemReference {
     New(Box<str>),
     Looped,
-    Found(Weak<Item>),
+    Found(Box<str>, Weak<Item>),
     NotFound,
 }
 
 impl ItemReference {
     pub fn with<F : FnOnce(Option<&Rc<Item>>) -> R, R>(this : &Cell<Self>, rt : &Runtime, f : F) -> R {
         let mut me = this.replace(ItemReference::Looped);
+        if let ItemReference::Found(name, item) = &me {
+            if item.strong_count() == 0 {
+                // The item was replaced; look it up again
+                me = ItemReference::New(name.clone());
+            }
+        }
         let rv = match me {
             ItemReference::New(name) => {
                 match rt.items.get(&*name) {
                     Some(item) => {
-                        me = ItemReference::Found(Rc::downgrade(item));
+                        me = ItemReference::Found(name, Rc::downgrade(item));
                         f(Some(item))
                     }
                     None => {
@@ -206,7 +241,7 @@ impl ItemReference {
                     }
                 }
             }
-            ItemReference::Found(ref v) => f(v.upgrade().as_ref()),
+            ItemReference::Found(_, ref v) => f(v.upgrade().as_ref()),
             ItemReference::NotFound => f(None),
             ItemReference::Looped => {
                 error!("Loop found when resolving reference");
@@ -252,6 +287,12 @@ impl<T : 'static> Periodic<T> {
         &self.shared.data
     }
 
+    /// Read again on the next redraw instead of waiting for the timer
+    pub fn refresh(&self) {
+        self.shared.last_update.set(None);
+        self.shared.interested.take().notify_data("refresh");
+    }
+
     /// Read periodically using the given closure.
     ///
     /// If the closure returns `Some(reason)`, an update will happen; otherwise, the closure will
@@ -316,6 +357,7 @@ impl<T : 'static> Periodic<T> {
         if self.period > 0.0 {
             let weak = Rc::downgrade(&self.shared);
             let period = self.period;
+            let sched = rt.scheduler.clone();
 
             let rh = spawn_handle("Periodic", async move {
                 match fut {
@@ -323,7 +365,10 @@ impl<T : 'static> Periodic<T> {
                     None => {}
                 }
                 loop {
-                    tokio::time::sleep(Duration::from_secs_f64(period)).await;
+                    // Spread out items with the same period by up to 5%, and allow the wakeup to
+                    // be delayed by 10% so it can be combined with others
+                    let period = Duration::from_secs_f64(period);
+                    sched.sleep_jittered(period, period.mul_f64(0.05), period.mul_f64(0.1)).await;
                     let shared = match weak.upgrade() {
                         Some(v) => v,
                         None => return Ok(()),
@@ -387,10 +432,13 @@ pub enum Module {
         zone : Box<str>,
         timer : Cell<Option<RemoteHandle<()>>>,
     },
+    /// A type implemented using [registry::Source]
+    Custom(Box<dyn registry::Source>),
     #[cfg(feature="dbus")]
     DbusCall {
         poll : Periodic<Rc<DbusValue>>,
     },
+    Debug(debug::DebugItem),
     Disk {
         poll : Periodic<(Box<str>, Cell<libc::statvfs>)>,
     },
@@ -404,11 +452,18 @@ pub enum Module {
         value : Cell<Option<Rc<(Cell<JsonValue>, Cell<NotifierList>)>>>,
         handle : Cell<Option<RemoteHandle<()>>>,
     },
+    Fifo {
+        path : Box<str>,
+        value : Rc<(Cell<String>, Cell<NotifierList>)>,
+        handle : Cell<Option<RemoteHandle<()>>>,
+    },
     FocusList {
         source : Box<Module>,
         others : Rc<Item>,
         focused : Rc<Item>,
+        empty : Rc<Item>,
         spacing : Box<str>,
+        same_output : bool,
     },
     Formatted {
         format : Box<str>,
@@ -420,6 +475,12 @@ pub enum Module {
         tooltip : Option<Rc<Item>>,
         spacing : Box<str>,
         vertical: bool,
+        /// Changed by the `toggle-item` action
+        visible : Cell<bool>,
+        /// Duration of the width animation when the group is shown or hidden
+        transition : Duration,
+        animation : Animation,
+        columns : Option<Columns>,
         // TODO crop ordering: allow specific items to be cropped first
         // TODO use min-width to force earlier cropping
     },
@@ -435,16 +496,23 @@ pub enum Module {
         value : Cell<ItemReference>,
     },
     #[cfg(feature="dbus")]
-    MediaPlayer2 { target : Box<str> },
+    MediaPlayer2 { target : Box<str>, text : Box<str>, popup : bool },
+    Menu(menu::Menu),
     Meter {
         min : Box<str>,
         max : Box<str>,
         src : Box<Module>,
         values : Box<[Box<str>]>,
     },
+    Mock(mock::Mock),
+    Pages(pages::Pages),
     ParseError {
         msg : Cow<'static, str>,
     },
+    #[cfg(feature="dbus")]
+    PowerProfile,
+    Processes(process::Processes),
+    Prompt(prompt::Prompt),
     #[cfg(feature="pulse")]
     Pulse {
         target : Box<str>,
@@ -453,13 +521,16 @@ pub enum Module {
         on_err : Box<str>,
         poll : Periodic<(Box<str>, Cell<Option<String>>)>,
     },
+    Recorder(recorder::Recorder),
     Regex {
         regex : regex::Regex,
         text : Box<str>,
         replace : Box<str>,
     },
+    Separator(separator::Separator),
     SwayMode(sway::Mode),
     SwayTree(sway::Tree),
+    SwayWindow(sway::Window),
     SwayWorkspace(sway::Workspace),
     Switch {
         format : Box<Module>,
@@ -470,11 +541,16 @@ pub enum Module {
         poll: Periodic<(Box<str>, Cell<u32>)>,
         label: Option<Box<str>>,
     },
+    Timer(timer::Timer),
+    Timers(timer::Timers),
     Tray {
         passive : Rc<Item>,
         active : Rc<Item>,
         urgent : Rc<Item>,
+        /// Actions for specific tray items, by ID
+        overrides : Rc<HashMap<Box<str>, EventSink>>,
     },
+    Updates(updates::Updates),
     Value {
         value : Cell<Value<'static>>,
         interested : Cell<NotifierList>,
@@ -484,12 +560,15 @@ pub enum Module {
 /// Possible contents of the "item" block
 #[derive(Debug,Clone)]
 pub enum IterationItem {
+    Custom(Rc<dyn registry::Entry>),
     #[cfg(feature="dbus")]
     MediaPlayer2 { target : Rc<str> },
+    Process(Rc<process::ProcessInfo>),
     #[cfg(feature="pulse")]
     Pulse { target : Rc<str> },
     SwayWorkspace(Rc<sway::WorkspaceData>),
     SwayTreeItem(Rc<sway::Node>),
+    Timer(Rc<timer::TimerEntry>),
     #[cfg(feature="dbus")]
     Tray(Rc<tray::TrayItem>),
 }
@@ -498,12 +577,15 @@ impl PartialEq for IterationItem {
     fn eq(&self, rhs : &Self) -> bool {
         use IterationItem::*;
         match (self, rhs) {
+            (Custom(a), Custom(b)) => Rc::ptr_eq(a,b),
             #[cfg(feature="dbus")]
             (MediaPlayer2 { target : a }, MediaPlayer2 { target : b }) => Rc::ptr_eq(a,b),
+            (Process(a), Process(b)) => Rc::ptr_eq(a,b),
             #[cfg(feature="pulse")]
             (Pulse { target : a }, Pulse { target : b }) => Rc::ptr_eq(a,b),
             (SwayWorkspace(a), SwayWorkspace(b)) => Rc::ptr_eq(a,b),
             (SwayTreeItem(a), SwayTreeItem(b)) => Rc::ptr_eq(a,b),
+            (Timer(a), Timer(b)) => Rc::ptr_eq(a,b),
             #[cfg(feature="dbus")]
             (Tray(a), Tray(b)) => Rc::ptr_eq(a, b),
             _ => false,
@@ -511,6 +593,46 @@ impl PartialEq for IterationItem {
     }
 }
 
+impl IterationItem {
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, key : &str, rt : &Runtime, f : F) -> R {
+        self.read_in_as("item", key, rt, f)
+    }
+
+    /// A short name for the kind of item, for debugging output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IterationItem::Custom(e) => e.kind(),
+            #[cfg(feature="dbus")]
+            IterationItem::MediaPlayer2 { .. } => "mpris",
+            IterationItem::Process(_) => "process",
+            #[cfg(feature="pulse")]
+            IterationItem::Pulse { .. } => "pulse",
+            IterationItem::SwayWorkspace(_) => "sway-workspace",
+            IterationItem::SwayTreeItem(_) => "sway-tree",
+            IterationItem::Timer(_) => "timer",
+            #[cfg(feature="dbus")]
+            IterationItem::Tray(_) => "tray",
+        }
+    }
+
+    pub fn read_in_as<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        let _ = name;
+        match self {
+            IterationItem::Custom(e) => f(e.read(key, rt)),
+            #[cfg(feature="dbus")]
+            IterationItem::MediaPlayer2 { target } => mpris::read_in(name, target, key, rt, f),
+            IterationItem::Process(p) => p.read_in(key, rt, f),
+            #[cfg(feature="pulse")]
+            IterationItem::Pulse { target } => pulse::read_in(name, target, key, rt, f),
+            IterationItem::SwayWorkspace(data) => data.read_in(key, rt, f),
+            IterationItem::SwayTreeItem(node) => node.read_in(key, rt, f),
+            IterationItem::Timer(timer) => timer.read_in(key, rt, f),
+            #[cfg(feature="dbus")]
+            IterationItem::Tray(item) => tray::read_in(name, item, key, rt, f),
+        }
+    }
+}
+
 /// The context of a parsed item, used to disambiguate strings
 pub enum ModuleContext {
     Source,
@@ -519,8 +641,12 @@ pub enum ModuleContext {
 
 impl Module {
     pub fn from_toml_in(value : &toml::Value, ctx : ModuleContext) -> Self {
-        match value.get("type").and_then(|v| v.as_str()) {
-            // keep values in alphabetical order
+        let kind = value.get("type").and_then(|v| v.as_str());
+        if let Some(module) = kind.and_then(|kind| registry::create(kind, value)) {
+            return module;
+        }
+        match kind {
+            // keep values in alphabetical order; simple types are in the registry instead
             Some("calendar") => {
                 let day_fmt = value.get("day-format").and_then(|v| v.as_str()).unwrap_or(" %e").into();
                 let today_fmt = value.get("today-format").and_then(|v| v.as_str()).unwrap_or(" <span color='green'><b>%e</b></span>").into();
@@ -546,9 +672,14 @@ impl Module {
                     .filter_map(|v| v.as_str())
                     .map(Into::into)
                     .collect();
+                let history_len = value.get("history").and_then(|v| v.as_integer()).unwrap_or(10).max(0) as usize;
+                let preview_len = value.get("preview").and_then(|v| v.as_integer()).unwrap_or(40).max(1) as usize;
+                let popup = value.get("popup").and_then(|v| v.as_bool()).unwrap_or(false);
                 Module::Clipboard {
                     state: Rc::new(ClipboardData {
                         seat, mime_list, selection,
+                        history_len, preview_len, popup,
+                        history : Default::default(),
                         interested : Default::default(),
                     }),
                 }
@@ -621,6 +752,16 @@ impl Module {
                     handle : Cell::new(None),
                 }
             }
+            Some("fifo") => {
+                let path = match toml_to_string(value.get("path")) {
+                    Some(path) => path.into(),
+                    None => {
+                        return Module::parse_error("A path to the fifo or socket is required");
+                    }
+                };
+                let value = Rc::new((Cell::new(String::new()), Default::default()));
+                Module::Fifo { path, value, handle : Cell::new(None) }
+            }
             Some("focus-list") => {
                 let source = match value.get("source") {
                     Some(s) => Box::new(Module::from_toml_in(s, ModuleContext::Source)),
@@ -634,12 +775,20 @@ impl Module {
                     .map(Item::from_toml_ref)
                     .map(Rc::new)
                     .unwrap_or_else(|| others.clone());
+                let empty = value.get("empty-item")
+                    .map(Item::from_toml_ref)
+                    .map(Rc::new)
+                    .unwrap_or_else(|| others.clone());
+
+                let same_output = value.get("same-output").and_then(|v| v.as_bool()).unwrap_or(false);
 
                 Module::FocusList {
                     source,
                     others,
                     focused,
+                    empty,
                     spacing,
+                    same_output,
                 }
             }
             Some("formatted") | Some("text") => {
@@ -654,6 +803,8 @@ impl Module {
                 let spacing = toml_to_string(value.get("spacing")).unwrap_or_default().into();
                 let tooltip = value.get("tooltip").map(Item::from_toml_format).map(Rc::new);
                 let condition = toml_to_string(value.get("condition")).map(Into::into);
+                let visible = value.get("visible").and_then(|v| v.as_bool()).unwrap_or(true);
+                let transition = toml_to_f64(value.get("transition")).filter(|&v| v > 0.0).unwrap_or(0.0);
                 let vertical = match value.get("orientation").and_then(|v| v.as_str()) {
                     Some("vertical") | Some("v") => true,
                     None | Some("horizontal") | Some("h") => false,
@@ -677,6 +828,10 @@ impl Module {
                     tooltip,
                     spacing,
                     vertical,
+                    visible : Cell::new(visible),
+                    transition : Duration::from_secs_f64(transition),
+                    animation : Animation::default(),
+                    columns : Columns::from_toml(value),
                 }
             }
             Some("icon") => {
@@ -716,7 +871,14 @@ impl Module {
             #[cfg(feature="dbus")]
             Some("mpris") => {
                 let target = toml_to_string(value.get("name")).unwrap_or_default().into();
-                Module::MediaPlayer2 { target }
+                let text = value.get("text").and_then(|v| v.as_str()).unwrap_or("").into();
+                let popup = value.get("popup").and_then(|v| v.as_bool()).unwrap_or(false);
+                Module::MediaPlayer2 { target, text, popup }
+            }
+            #[cfg(feature="dbus")]
+            Some("power-profile") => Module::PowerProfile,
+            Some("processes") => {
+                Module::Processes(process::Processes::from_toml(value))
             }
             #[cfg(feature="pulse")]
             Some("pulse") => {
@@ -766,13 +928,16 @@ impl Module {
                     on_err, poll,
                 }
             }
-            Some("sway-mode") => {
+            Some("sway-mode") | Some("i3-mode") => {
                 Module::SwayMode(sway::Mode::from_toml(value))
             }
-            Some("sway-tree") => {
+            Some("sway-tree") | Some("i3-tree") => {
                 Module::SwayTree(sway::Tree::from_toml(value))
             }
-            Some("sway-workspace") => {
+            Some("sway-window") | Some("i3-window") => {
+                Module::SwayWindow(sway::Window::from_toml(value))
+            }
+            Some("sway-workspace") | Some("i3-workspace") => {
                 Module::SwayWorkspace(sway::Workspace::from_toml(value))
             }
             Some("switch") => {
@@ -869,10 +1034,20 @@ impl Module {
                 }));
                 let passive = Rc::new(value.get("passive").map_or_else(Item::none, Item::from_toml_ref));
                 let urgent = value.get("urgent").map(Item::from_toml_ref).map(Rc::new).unwrap_or_else(|| active.clone());
+                let overrides = value.get("actions").and_then(|v| v.as_table()).into_iter().flatten()
+                    .map(|(id, actions)| (id.as_str().into(), EventSink::from_toml(actions)))
+                    .collect();
                 Module::Tray {
                     passive,
                     active,
                     urgent,
+                    overrides : Rc::new(overrides),
+                }
+            }
+            Some("updates") => {
+                match updates::Updates::from_toml(value) {
+                    Ok(u) => Module::Updates(u),
+                    Err(e) => Module::parse_error(e),
                 }
             }
             Some("value") => {
@@ -914,8 +1089,9 @@ impl Module {
     }
 
     /// One-time setup, if needed
-    pub fn init(&self, name : &str, _rt : &Runtime, from : Option<&Self>) {
+    pub fn init(&self, name : &str, rt : &Runtime, from : Option<&Self>) {
         match (self, from) {
+            (Module::Custom(s), _) => s.init(name, rt),
             (Module::ExecJson { command, stdin, value, handle },
                 Some(Module::ExecJson {
                     command : old_cmd,
@@ -950,6 +1126,14 @@ impl Module {
                     }
                 }
             }
+            (Module::Fifo { path, value, handle }, from) => {
+                if let Some(Module::Fifo { path : old_path, value : old_value, .. }) = from {
+                    if *path == *old_path {
+                        value.0.set(old_value.0.take());
+                    }
+                }
+                handle.set(Some(spawn_handle("Fifo", do_read_fifo(path.clone(), name.to_owned(), value.clone()))));
+            }
             _ => {}
         }
     }
@@ -977,6 +1161,7 @@ impl Module {
         };
 
         match self {
+            Module::Group { visible, .. } if key == "visible" => f(Value::Bool(visible.get())),
             Module::Group { .. } |
             Module::FocusList { .. } |
             Module::Tray { .. } => {
@@ -985,6 +1170,8 @@ impl Module {
             }
 
             Module::Bar { config, .. } => {
+                // the name is always set to the name of the output
+                let key = if key == "output" { "name" } else { key };
                 match toml_to_string(config.get(key)) {
                     Some(value) => f(Value::Owned(value)),
                     None => f(Value::Null),
@@ -1012,13 +1199,14 @@ impl Module {
                 let mut rv = String::with_capacity(3 * 7 * 6 + 6);
                 for _week in 0..6 {
                     for _day in 0..7 {
-                        if date.month() != now.month() {
-                            write!(rv, "{}", date.format(&other_fmt)).unwrap();
+                        let fmt = if date.month() != now.month() {
+                            other_fmt
                         } else if date.day() != now.day() {
-                            write!(rv, "{}", date.format(&day_fmt)).unwrap();
+                            day_fmt
                         } else {
-                            write!(rv, "{}", date.format(&today_fmt)).unwrap();
-                        }
+                            today_fmt
+                        };
+                        write!(rv, "{}", date.format(&locale::localize_format(fmt, &date, None))).unwrap();
                         date = date + Duration::days(1);
                     }
                     rv.push('\n');
@@ -1038,14 +1226,21 @@ impl Module {
                 let subsec = chrono::Timelike::nanosecond(&now) as u64;
                 let next_sec = now + chrono::Duration::seconds(1);
                 let (value, nv);
+                macro_rules! localized {
+                    ($t:expr) => {{
+                        let t = $t;
+                        let fmt = locale::localize_format(&real_format, &t, Some(chrono::Timelike::hour(&t)));
+                        format!("{}", t.format(&fmt))
+                    }}
+                }
                 if real_zone.is_empty() {
-                    value = format!("{}", now.with_timezone(&chrono::Local).format(&real_format));
-                    nv = format!("{}", next_sec.with_timezone(&chrono::Local).format(&real_format));
+                    value = localized!(now.with_timezone(&chrono::Local));
+                    nv = localized!(next_sec.with_timezone(&chrono::Local));
                 } else {
                     match real_zone.parse::<chrono_tz::Tz>() {
                         Ok(tz) => {
-                            value = format!("{}", now.with_timezone(&tz).format(&real_format));
-                            nv = format!("{}", next_sec.with_timezone(&tz).format(&real_format));
+                            value = localized!(now.with_timezone(&tz));
+                            nv = localized!(next_sec.with_timezone(&tz));
                         }
                         Err(e) => {
                             warn!("Could not find timezone '{}': {}", real_zone, e);
@@ -1068,8 +1263,9 @@ impl Module {
                 }
                 let wake = inow + delay.map_or(Duration::from_secs(1), Duration::from_nanos);
                 let mut notify = NotifierList::active(rt);
+                let sched = rt.scheduler.clone();
                 timer.set(Some(spawn_handle("Clock tick", async move {
-                    tokio::time::sleep_until(wake.into()).await;
+                    sched.sleep_until(wake, Duration::ZERO).await;
                     notify.notify_data("clock");
                     Ok(())
                 })));
@@ -1083,6 +1279,8 @@ impl Module {
                 });
                 poll.data().read_in(key, rt, f)
             },
+            Module::Custom(s) => f(s.read(name, key, rt)),
+            Module::Debug(d) => d.read_in(name, key, rt, f),
             Module::Disk { poll } => {
                 poll.read_refresh(rt, |(path, contents)| {
                     let cstr = std::ffi::CString::new(path.as_bytes()).unwrap();
@@ -1216,6 +1414,10 @@ impl Module {
                 value.1.take_in(|i| i.add(rt));
                 rv
             }
+            Module::Fifo { value, .. } => {
+                value.1.take_in(|i| i.add(rt));
+                value.0.take_in(|s| f(Value::Borrow(s)))
+            }
             Module::Formatted { format, tooltip } => {
                 match key {
                     "tooltip" => match tooltip {
@@ -1233,14 +1435,7 @@ impl Module {
             }
             Module::Item { value } => value.take_in(|item| {
                 match item.as_ref() {
-                    #[cfg(feature="dbus")]
-                    Some(IterationItem::MediaPlayer2 { target }) => mpris::read_in(name, target, key, rt, f),
-                    #[cfg(feature="pulse")]
-                    Some(IterationItem::Pulse { target }) => pulse::read_in(name, target, key, rt, f),
-                    Some(IterationItem::SwayWorkspace(data)) => data.read_in(key, rt, f),
-                    Some(IterationItem::SwayTreeItem(node)) => node.read_in(key, rt, f),
-                    #[cfg(feature="dbus")]
-                    Some(IterationItem::Tray(item)) => tray::read_in(name, item, key, rt, f),
+                    Some(item) => item.read_in_as(name, key, rt, f),
                     None => f(Value::Null),
                 }
             }),
@@ -1251,7 +1446,12 @@ impl Module {
                 })
             }
             #[cfg(feature="dbus")]
-            Module::MediaPlayer2 { target } => mpris::read_in(name, target, key, rt, f),
+            Module::MediaPlayer2 { text, .. } if !text.is_empty() && (key.is_empty() || key == "text") => {
+                f(rt.format_or(text, name))
+            }
+            #[cfg(feature="dbus")]
+            Module::MediaPlayer2 { target, .. } => mpris::read_in(name, target, key, rt, f),
+            Module::Menu(m) => m.read_in(name, key, rt, f),
             Module::Meter { min, max, src, values } => {
                 let value = src.read_to_owned(&name, "", rt).parse_f64().unwrap_or(0.0);
                 let min = rt.format_or(&min, &name).parse_f64().unwrap_or(0.0);
@@ -1268,7 +1468,13 @@ impl Module {
                 };
                 f(rt.format_or(&expr, &name))
             }
+            Module::Mock(m) => m.read_in(name, key, rt, f),
+            Module::Pages(p) => p.read_in(name, key, rt, f),
             Module::ParseError { .. } => f(Value::Null),
+            #[cfg(feature="dbus")]
+            Module::PowerProfile => power_profile::read_in(name, key, rt, f),
+            Module::Processes(p) => p.read_in(name, key, rt, f),
+            Module::Prompt(p) => p.read_in(name, key, rt, f),
             #[cfg(feature="pulse")]
             Module::Pulse { target } => pulse::read_in(name, target, key, rt, f),
             Module::ReadFile { on_err, poll } => {
@@ -1304,6 +1510,7 @@ impl Module {
                     })
                 }
             }
+            Module::Recorder(r) => r.read_in(name, key, rt, f),
             Module::Regex { regex, text, replace } => {
                 let text = rt.format_or(&text, &name).into_text();
                 if key == "" || key == "text" {
@@ -1321,8 +1528,10 @@ impl Module {
                     }
                 }
             }
+            Module::Separator(sep) => sep.read_in(name, key, rt, f),
             Module::SwayMode(mode) => mode.read_in(name, key, rt, f),
             Module::SwayTree(tree) => tree.read_in(name, key, rt, f),
+            Module::SwayWindow(win) => win.read_in(name, key, rt, f),
             Module::SwayWorkspace(ws) => ws.read_in(name, key, rt, f),
             Module::Switch { format, cases, default } => {
                 let text = format.read_to_owned(&name, "", rt).into_text();
@@ -1357,6 +1566,9 @@ impl Module {
                 let (_, value) = poll.data();
                 f(Value::Float(value.get() as f64 / 1000.0))
             }
+            Module::Timer(t) => t.read_in(name, key, rt, f),
+            Module::Timers(t) => t.read_in(name, key, rt, f),
+            Module::Updates(u) => u.read_in(name, key, rt, f),
             Module::Value { value, interested } => {
                 interested.take_in(|i| i.add(rt));
                 value.take_in(|s| f(s.as_ref()))
@@ -1368,6 +1580,8 @@ impl Module {
     pub fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
         debug!("Writing {} to {}.{}", value, name, key);
         match self {
+            Module::Custom(s) => s.write(name, key, value, rt),
+            Module::Debug(d) => d.write(name, key, value, rt),
             Module::ExecJson { stdin, .. } => {
                 let w = match stdin.take() {
                     None => {
@@ -1396,24 +1610,35 @@ impl Module {
             }
             Module::Item { value : v } => v.take_in(|item| {
                 match item.as_ref() {
+                    Some(IterationItem::Custom(e)) => e.clone().write(key, value, rt),
                     #[cfg(feature="dbus")]
                     Some(IterationItem::MediaPlayer2 { target }) => mpris::write(name, target, key, value, rt),
+                    Some(IterationItem::Process(p)) => p.write(key, value, rt),
                     #[cfg(feature="pulse")]
                     Some(IterationItem::Pulse { target }) => pulse::do_write(name, target, key, value, rt),
                     Some(IterationItem::SwayWorkspace(data)) => data.write(key, value, rt),
                     Some(IterationItem::SwayTreeItem(node)) => node.write(key, value, rt),
+                    Some(IterationItem::Timer(timer)) => timer.write(key, value, rt),
                     #[cfg(feature="dbus")]
                     Some(IterationItem::Tray(item)) => tray::write(name, item, key, value, rt),
                     None => {}
                 }
             }),
             #[cfg(feature="dbus")]
-            Module::MediaPlayer2 { target } => mpris::write(name, target, key, value, rt),
+            Module::MediaPlayer2 { target, .. } => mpris::write(name, target, key, value, rt),
+            Module::Pages(p) => p.write(name, key, value, rt),
+            #[cfg(feature="dbus")]
+            Module::PowerProfile => power_profile::write(name, key, value, rt),
+            Module::Prompt(p) => p.write(name, key, value, rt),
             #[cfg(feature="pulse")]
             Module::Pulse { target } => pulse::do_write(name, target, key, value, rt),
+            Module::Recorder(r) => r.write(name, key, value, rt),
             Module::SwayMode(_) => sway::write(value, rt),
             Module::SwayTree(_) => sway::write(value, rt),
+            Module::SwayWindow(win) => win.write(name, key, value, rt),
             Module::SwayWorkspace(ws) => ws.write(name, key, value, rt),
+            Module::Timer(t) => t.write(name, key, value, rt),
+            Module::Timers(t) => t.write(name, key, value, rt),
             Module::Value { value : v, interested } if key == "" => {
                 interested.take().notify_data("value");
                 v.set(value.into_owned());
@@ -1434,12 +1659,47 @@ impl Module {
         Module::Item { value : Cell::new(None) }
     }
 
+    /// Read the values of a module again now, restarting its command if it has one; returns false
+    /// if the module has nothing to refresh
+    pub fn refresh(&self, name : &str, rt : &Runtime) -> bool {
+        match self {
+            Module::Custom(s) => return s.refresh(name, rt),
+            #[cfg(feature="dbus")]
+            Module::DbusCall { poll } => poll.refresh(),
+            Module::Disk { poll } => poll.refresh(),
+            Module::ReadFile { poll, .. } => poll.refresh(),
+            Module::Thermal { poll, .. } => poll.refresh(),
+            Module::Processes(p) => p.force_refresh(),
+            Module::Updates(u) => u.refresh(),
+            Module::ExecJson { stdin, value, handle, .. } => {
+                // Dropping the handle stops the reader; the old command gets SIGPIPE or EOF
+                handle.set(None);
+                stdin.set(None);
+                let old = value.take();
+                self.init(name, rt, None);
+                if let (Some(old), Some(new)) = (old, value.take_in(|v| v.clone())) {
+                    new.0.set(old.0.replace(JsonValue::Null));
+                    new.1.set(old.1.take());
+                    new.1.take().notify_data("refresh");
+                }
+            }
+            Module::ItemReference { value } => {
+                return ItemReference::with(value, rt, |v| v.is_some_and(|item| item.data.refresh(name, rt)));
+            }
+            _ => return false,
+        }
+        true
+    }
+
     /// Use this module as the source of a focus-list item
-    pub fn read_focus_list<F : FnMut(bool, IterationItem)>(&self, rt : &Runtime, f : F) {
+    pub fn read_focus_list<F : FnMut(bool, IterationItem)>(&self, rt : &Runtime, mut f : F) {
         match self {
+            Module::Custom(s) => s.read_focus_list(rt, &mut |focus, entry| f(focus, IterationItem::Custom(entry))),
             #[cfg(feature="dbus")]
             Module::MediaPlayer2 { .. } => mpris::read_focus_list(rt, f),
             Module::SwayWorkspace(ws) => ws.read_focus_list(rt, f),
+            Module::Processes(p) => p.read_focus_list(rt, f),
+            Module::Timers(t) => t.read_focus_list(rt, f),
             #[cfg(feature="pulse")]
             Module::Pulse { target } => pulse::read_focus_list(rt, target, f),
             Module::ItemReference { value } => {
@@ -1453,73 +1713,77 @@ impl Module {
             _ => ()
         }
     }
+
+    /// The preview popup for one entry of this focus-list source, if it shows previews
+    pub fn preview(&self, item : &IterationItem, rt : &Runtime) -> Option<screencopy::PreviewPopup> {
+        match (self, item) {
+            (Module::SwayWorkspace(ws), IterationItem::SwayWorkspace(data)) => ws.preview(data),
+            (Module::ItemReference { value }, _) => {
+                ItemReference::with(value, rt, |v| v.and_then(|source| source.data.preview(item, rt)))
+            }
+            _ => None,
+        }
+    }
 }
 
 use std::error::Error;
 async fn do_exec_json(fd : i32, name : String, value : Rc<(Cell<JsonValue>, Cell<NotifierList>)>) -> Result<(), Box<dyn Error>> {
-        let afd = AsyncFd::new(Fd(fd)).expect("Invalid FD from ChildStdin");
-        let mut buffer : Vec<u8> = Vec::with_capacity(1024);
-
-        'waiting : loop {
-            let mut rh = match afd.readable().await {
-                Ok(h) => h,
-                Err(e) => {
-                    warn!("Unable to wait for child read: {}", e);
-                    return Ok(());
-                }
-            };
-            'reading : loop {
-                if buffer.len() == buffer.capacity() {
-                    buffer.reserve(2048);
-                }
-                unsafe { // child pipe read into vec spare capacity
-                    let start = buffer.len();
-                    let max_len = buffer.capacity() - start;
-                    let rv = libc::read(fd, buffer.as_mut_ptr().offset(start as isize) as *mut _, max_len);
-                    match rv {
-                        0 => {
-                            libc::close(fd);
-                            return Ok(());
-                        }
-                        len if rv > 0 && rv <= max_len as _ => {
-                            buffer.set_len(start + len as usize);
-                        }
-                        _ => {
-                            let e = io::Error::last_os_error();
-                            match e.kind() {
-                                io::ErrorKind::Interrupted => continue 'reading,
-                                io::ErrorKind::WouldBlock => {
-                                    rh.clear_ready();
-                                    continue 'waiting;
-                                }
-                                _ => {
-                                    warn!("Got {} on child read; discontinuing", e);
-                                    return Ok(());
-                                }
-                            }
-                        }
-                    }
-                }
-                while let Some(eol) = buffer.iter().position(|&c| c == b'\n') {
-                    let mut json = None;
-                    match std::str::from_utf8(&buffer[..eol]) {
-                        Err(_) => info!("Ignoring bad UTF8 from '{}'", name),
-                        Ok(v) => {
-                            debug!("'{}': {}", name, v);
-                            match json::parse(v) {
-                                Ok(v) => { json = Some(v); }
-                                Err(e) => info!("Ignoring bad JSON from '{}': {}", name, e),
-                            }
-                        }
-                    }
-                    // Note: this is optimized for the normal case where the script sends one line
-                    // at a time, so this drain would empty the buffer.
-                    buffer.drain(..eol + 1);
-                    if let Some(json) = json {
-                        value.0.set(json);
-                        value.1.take().notify_data("exec-json");
-                    }
+    let rv = read_lines(fd, &name, |line| {
+        match json::parse(line) {
+            Ok(json) => {
+                value.0.set(json);
+                value.1.take().notify_data("exec-json");
+            }
+            Err(e) => info!("Ignoring bad JSON from '{}': {}", name, e),
+        }
+    }).await;
+    match rv {
+        Ok(()) => {
+            unsafe { libc::close(fd); }
+        }
+        Err(e) => {
+            warn!("Got {} on child read; discontinuing", e);
+        }
+    }
+    Ok(())
+}
+
+async fn do_read_fifo(path : Box<str>, name : String, value : Rc<(Cell<String>, Cell<NotifierList>)>) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::{FileTypeExt,OpenOptionsExt};
+    use std::os::unix::io::FromRawFd;
+    loop {
+        let file = match fs::metadata(&*path) {
+            Ok(m) if m.file_type().is_socket() => {
+                // the socket returned by into_std is still in non-blocking mode
+                tokio::net::UnixStream::connect(&*path).await
+                    .and_then(|s| s.into_std())
+                    .map(|s| unsafe { fs::File::from_raw_fd(s.into_raw_fd()) })
+            }
+            // Open the fifo read-write so that we never see EOF when a writer closes it
+            Ok(_) => {
+                fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(&*path)
+            }
+            Err(e) => Err(e),
+        };
+        match file {
+            Ok(file) => {
+                let rv = read_lines(file.as_raw_fd(), &name, |line| {
+                    value.0.set(line.to_owned());
+                    value.1.take().notify_data("fifo");
+                }).await;
+                if let Err(e) = rv {
+                    warn!("Error reading '{}': {}", path, e);
                 }
             }
+            Err(e) => {
+                debug!("Could not open '{}': {}", path, e);
+            }
         }
+        // The socket was closed or is not yet available; retry later
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
 }
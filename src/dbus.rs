@@ -1,12 +1,16 @@
+//! Shared D-Bus connections
+//!
+//! Each bus is connected on first use and shared by all modules, which also share the match rules
+//! that subscribe to signals: a rule is only sent to the bus once, however many watchers use it.
 use crate::data::Value;
+use crate::metrics;
 use crate::state::Runtime;
 use crate::state::NotifierList;
 use crate::util;
 use crate::util::{Cell,spawn_noerr};
 use futures_channel::mpsc::{self,UnboundedSender};
-use futures_util::future::RemoteHandle;
 use futures_util::StreamExt;
-use log::{info,warn,error};
+use log::{debug,info,warn,error};
 use once_cell::unsync::OnceCell;
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -31,8 +35,12 @@ pub struct DBus {
     bus: util::Cell<Result<Connection, Vec<task::Waker>>>,
 
     sig_watchers : util::Cell<Vec<Box<dyn SignalWatcherCall>>>,
+    /// Watchers removed while `dispatch` was calling them
+    sig_removed : util::Cell<Vec<NonNull<()>>>,
     prop_watchers : util::Cell<Vec<Box<dyn FnMut(&zbus::MessageHeader, &str, &HashMap<&str, OwnedValue>, &[&str])>>>,
     name_watchers : util::Cell<Vec<Box<dyn FnMut(&BusName, &str, &str)>>>,
+    /// The match rules sent to the bus, with the number of watchers using each
+    matches : util::Cell<HashMap<String, usize>>,
 }
 
 impl fmt::Debug for DBus {
@@ -89,8 +97,10 @@ impl DBus {
             send,
             bus : Cell::new(Err(Vec::new())),
             sig_watchers : Default::default(),
+            sig_removed : Default::default(),
             prop_watchers : Default::default(),
             name_watchers : Default::default(),
+            matches : Default::default(),
         });
 
         // Note: reference cycles don't matter, the DBus object is not freeable
@@ -148,7 +158,7 @@ impl DBus {
         });
 
         let this = tb.clone();
-        util::spawn("DBus Sender", async move {
+        util::spawn(if is_session { "DBus Sender (session bus)" } else { "DBus Sender (system bus)" }, async move {
             use zbus::Address;
             let addr = if is_session {
                 Address::session()?
@@ -163,7 +173,7 @@ impl DBus {
                         .internal_executor(false)
                         .build().await?
                 }
-                _ => panic!(),
+                addr => return Err(format!("Unsupported D-Bus address: {:?}", addr).into()),
             };
             match this.bus.replace(Ok(zbus.clone())) {
                 Ok(_) => unreachable!(),
@@ -189,6 +199,7 @@ impl DBus {
     }
 
     pub fn send(&self, msg : zbus::Message) {
+        metrics::dbus_sent();
         let _ = self.send.unbounded_send(msg);
     }
 
@@ -196,51 +207,111 @@ impl DBus {
         where F : FnMut(&zvariant::ObjectPath, &str, &str, &zbus::Message) + 'static
     {
         if std::mem::size_of::<F>() != 0 {
-            self.do_add_signal_watcher(Box::new(SignalWatcherNZ(f)))
+            self.do_add_signal_watcher(Box::new(SignalWatcherNZ(f)));
         } else {
-            self.do_add_signal_watcher(Box::new(SignalWatcherZST(0, f)))
+            self.do_add_signal_watcher(Box::new(SignalWatcherZST(0, f)));
         }
     }
 
-    fn do_add_signal_watcher(&self, b : Box<dyn SignalWatcherCall>)
+    fn do_add_signal_watcher(&self, b : Box<dyn SignalWatcherCall>) -> Option<NonNull<()>>
     {
+        let id = b.get_sw_ptr();
         self.sig_watchers.take_in(|w| w.push(b));
+        id
+    }
+
+    fn remove_signal_watcher(&self, id : NonNull<()>) {
+        let found = self.sig_watchers.take_in(|w| {
+            let len = w.len();
+            w.retain(|sw| sw.get_sw_ptr() != Some(id));
+            w.len() != len
+        });
+        if !found {
+            // the watchers are being called; dispatch removes it when it is done
+            self.sig_removed.take_in(|r| r.push(id));
+        }
+    }
+
+    /// Ask the bus to send us signals matching the rule, if no other watcher has already done so
+    pub fn add_match(&self, rule : &str) {
+        let count = self.matches.take_in(|m| {
+            let count = m.entry(rule.to_owned()).or_default();
+            *count += 1;
+            *count
+        });
+        if count == 1 {
+            self.send_match("AddMatch", rule);
+        }
+    }
+
+    /// Stop receiving signals matching the rule once no watcher uses it
+    fn remove_match(&self, rule : &str) {
+        let unused = self.matches.take_in(|m| match m.get_mut(rule) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => m.remove(rule).is_some(),
+            None => false,
+        });
+        if unused {
+            self.send_match("RemoveMatch", rule);
+        }
+    }
+
+    fn send_match(&self, method : &str, rule : &str) {
+        self.send(zbus::Message::method(
+            None::<&str>,
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            Some("org.freedesktop.DBus"),
+            method,
+            &rule,
+        ).unwrap());
+    }
+
+    /// Call the function for each broadcast of the signal (`interface.member`), optionally only
+    /// those sent from the given object path, until the returned [SignalWatch] is dropped
+    pub fn watch_signal<F>(self : &Rc<Self>, signal : &str, path : Option<&str>, mut f : F) -> SignalWatch
+        where F : FnMut(&zbus::Message) + 'static
+    {
+        let (iface, memb) = signal.rsplit_once(".").unwrap_or(("", signal));
+        let mut rule = format!("type='signal',interface='{}',member='{}'", iface, memb);
+        if let Some(path) = path {
+            rule.push_str(",path='");
+            rule.push_str(path);
+            rule.push('\'');
+        }
+        self.add_match(&rule);
+
+        let (iface, memb) : (Box<str>, Box<str>) = (iface.into(), memb.into());
+        let path : Option<Box<str>> = path.map(Into::into);
+        let f = move |sig_path : &zvariant::ObjectPath, sig_iface : &str, sig_memb : &str, msg : &zbus::Message| {
+            if sig_iface != &*iface || sig_memb != &*memb {
+                return;
+            }
+            if path.as_deref().is_some_and(|path| sig_path.as_str() != path) {
+                return;
+            }
+            f(msg)
+        };
+        // the closure captures the signal name, so it is never zero-sized
+        let id = self.do_add_signal_watcher(Box::new(SignalWatcherNZ(f)));
+        SignalWatch { bus : self.clone(), id, rule : rule.into() }
     }
 
     pub fn add_property_change_watcher<F>(&self, f : F)
         where F : FnMut(&zbus::MessageHeader, &str, &HashMap<&str, OwnedValue>, &[&str]) + 'static
     {
-        if self.prop_watchers.take_in(|w| {
-            w.push(Box::new(f));
-            w.len() == 1
-        }) {
-            self.send(zbus::Message::method(
-                None::<&str>,
-                Some("org.freedesktop.DBus"),
-                "/org/freedesktop/DBus",
-                Some("org.freedesktop.DBus"),
-                "AddMatch",
-                &("type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'")
-            ).unwrap());
-        }
+        self.prop_watchers.take_in(|w| w.push(Box::new(f)));
+        self.add_match("type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'");
     }
 
     pub fn add_name_watcher<F>(&self, f : F)
         where F : FnMut(&BusName, &str, &str) + 'static
     {
-        if self.name_watchers.take_in(|w| {
-            w.push(Box::new(f));
-            w.len() == 1
-        }) {
-            self.send(zbus::Message::method(
-                None::<&str>,
-                Some("org.freedesktop.DBus"),
-                "/org/freedesktop/DBus",
-                Some("org.freedesktop.DBus"),
-                "AddMatch",
-                &("type='signal',interface='org.freedesktop.DBus',member='NameOwnerChanged'")
-            ).unwrap());
-        }
+        self.name_watchers.take_in(|w| w.push(Box::new(f)));
+        self.add_match("type='signal',interface='org.freedesktop.DBus',member='NameOwnerChanged'");
     }
 
     async fn dispatcher(self : Rc<Self>, mut zbus : MessageStream) -> Result<(), Box<dyn std::error::Error>>  {
@@ -253,6 +324,7 @@ impl DBus {
 
     fn dispatch(&self, msg : Arc<zbus::Message>) -> zbus::Result<()> {
         use zbus::MessageType;
+        metrics::dbus_received();
         match msg.message_type() {
             MessageType::Signal => {
                 let mut watchers = self.sig_watchers.replace(Vec::new());
@@ -266,6 +338,10 @@ impl DBus {
                         log::debug!("Ignoring invalid dbus signal");
                     }
                 }
+                let removed = self.sig_removed.replace(Vec::new());
+                if !removed.is_empty() {
+                    watchers.retain(|sw| !removed.iter().any(|&id| sw.get_sw_ptr() == Some(id)));
+                }
                 self.sig_watchers.take_in(|w| {
                     if w.is_empty() {
                         *w = watchers;
@@ -280,6 +356,29 @@ impl DBus {
     }
 }
 
+/// Remove any variant wrappers around a value.  Properties and dict entries of type `v` arrive
+/// wrapped once or more depending on the sender, which breaks matching on the inner type.
+pub fn unwrap_variant<'a, 'v>(mut value : &'a Variant<'v>) -> &'a Variant<'v> {
+    while let Variant::Value(v) = value {
+        value = v;
+    }
+    value
+}
+
+/// Convert a value to a concrete type, logging a mismatch instead of silently dropping it
+pub fn typed<'a, T>(value : &'a Variant<'a>, what : &str) -> Option<T>
+    where T : TryFrom<&'a Variant<'a>>
+{
+    let value = unwrap_variant(value);
+    match T::try_from(value) {
+        Ok(v) => Some(v),
+        Err(_) => {
+            debug!("Ignoring {} with unexpected type '{}'", what, value.value_signature());
+            None
+        }
+    }
+}
+
 #[derive(Debug)]
 struct AsSocket(UnixStream);
 
@@ -306,6 +405,29 @@ impl zbus::Socket for AsSocket {
     }
 }
 
+/// Keeps a watcher added by [DBus::watch_signal] registered; dropping it removes the watcher
+#[must_use]
+pub struct SignalWatch {
+    bus : Rc<DBus>,
+    id : Option<NonNull<()>>,
+    rule : Box<str>,
+}
+
+impl fmt::Debug for SignalWatch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "SignalWatch")
+    }
+}
+
+impl Drop for SignalWatch {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.bus.remove_signal_watcher(id);
+        }
+        self.bus.remove_match(&self.rule);
+    }
+}
+
 struct SignalWatcherNZ<F : ?Sized>(F);
 struct SignalWatcherZST<F : ?Sized>(u8, F);
 
@@ -348,7 +470,7 @@ pub struct DbusValue {
     sig : Cell<Option<Rc<str>>>,
     value : RefCell<Option<OwnedValue>>,
     interested : Cell<NotifierList>,
-    watch : Cell<Option<RemoteHandle<()>>>,
+    watch : Cell<Option<SignalWatch>>,
 }
 
 impl DbusValue {
@@ -400,47 +522,14 @@ impl DbusValue {
         let watch_path = value.get("watch-path").and_then(|v| v.as_str());
         let watch_method = value.get("watch-method").and_then(|v| v.as_str());
         match watch_method.map(|s| s.rsplit_once(".")) {
-            Some(Some((i,m))) => {
-                let mut expr = format!("type='signal',interface='{}',member='{}'", i, m);
-                if let Some(path) = &watch_path {
-                    expr.push_str(",path='");
-                    expr.push_str(path);
-                    expr.push_str("'");
-                }
-                dbus.send(zbus::Message::method(
-                    None::<&str>,
-                    Some("org.freedesktop.DBus"),
-                    "/org/freedesktop/DBus",
-                    Some("org.freedesktop.DBus"),
-                    "AddMatch",
-                    &expr,
-                ).unwrap());
-
-                let watch_path : Option<Box<str>> = watch_path.map(Into::into);
-                let watch_method = watch_method.unwrap().to_owned();
+            Some(Some(_)) => {
                 let weak = Rc::downgrade(&rc);
-                let h = util::spawn_handle("DBus value watcher", async move {
-                    let zbus = dbus.connection().await;
-                    let mut stream = zbus::MessageStream::from(zbus);
-                    while let Some(Ok(msg)) = stream.next().await {
-                        if let Some(rc) = weak.upgrade() {
-                            let (i,m) = watch_method.rsplit_once(".").unwrap();
-                            if msg.interface().as_deref() != Some(i) ||
-                                msg.member().as_deref() != Some(m)
-                            {
-                                continue;
-                            }
-                            if let Some(path) = &watch_path {
-                                if msg.path().as_deref() != Some(&*path) {
-                                    continue;
-                                }
-                            }
-                            rc.call_now();
-                        }
+                let watch = dbus.watch_signal(watch_method.unwrap(), watch_path, move |_msg| {
+                    if let Some(rc) = weak.upgrade() {
+                        rc.call_now();
                     }
-                    Ok(())
                 });
-                rc.watch.set(Some(h));
+                rc.watch.set(Some(watch));
             }
             Some(None) if watch_method == Some("") => {}
             Some(None) => error!("Invalid dbus watch expression, ignoring"),
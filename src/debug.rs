@@ -0,0 +1,170 @@
+//! Layout debugging overlays, for finding padding, margin, and alignment problems
+use crate::data::Value;
+use crate::font::render_font_item;
+use crate::render::Render;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{toml_to_f64,toml_to_string};
+use log::{error,info};
+use std::cell::Cell;
+use tiny_skia::{Color,Paint,PathBuilder,Rect,Shader,Stroke};
+
+/// Outline colors, cycled by nesting depth
+const PALETTE : [(u8, u8, u8); 4] = [
+    (255, 0, 0),
+    (0, 160, 255),
+    (0, 200, 0),
+    (255, 160, 0),
+];
+
+thread_local! {
+    static ENABLED : Cell<bool> = const { Cell::new(false) };
+    static DEPTH : Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn set_enabled(on : bool) {
+    ENABLED.with(|e| e.set(on));
+    info!("Debug overlay: {}", if on { "on" } else { "off" });
+}
+
+pub fn enabled() -> bool {
+    ENABLED.with(|e| e.get())
+}
+
+pub fn toggle() {
+    set_enabled(!enabled());
+}
+
+fn paint(rgb : (u8, u8, u8), alpha : u8) -> Paint<'static> {
+    Paint {
+        shader : Shader::SolidColor(Color::from_rgba8(rgb.0, rgb.1, rgb.2, alpha)),
+        anti_alias : false,
+        ..Default::default()
+    }
+}
+
+fn stroke_rect(ctx : &mut Render, rect : Rect, paint : &Paint<'static>) {
+    let path = PathBuilder::from_rect(rect);
+    let stroke = Stroke { width : 1.0 / ctx.render_xform.sy.max(1.0), ..Default::default() };
+    ctx.canvas.stroke_path(&path, paint, &stroke, ctx.render_xform);
+}
+
+/// Render an item using `f`, then outline the area it used if the overlay is enabled
+pub fn outline<R>(ctx : &mut Render, f : impl FnOnce(&mut Render) -> R) -> R {
+    if ctx.measure_only || !enabled() {
+        return f(ctx);
+    }
+    let start = ctx.render_pos;
+    let depth = DEPTH.with(|d| d.replace(d.get() + 1));
+    let rv = f(ctx);
+    DEPTH.with(|d| d.set(depth));
+    let end = ctx.render_pos;
+    if let Some(rect) = Rect::from_ltrb(start.x, start.y, end.x, end.y) {
+        stroke_rect(ctx, rect, &paint(PALETTE[depth % PALETTE.len()], 192));
+    }
+    rv
+}
+
+/// Draw the bar's alignment guides (its vertical and horizontal centers), if enabled
+pub fn draw_guides(ctx : &mut Render) {
+    if !enabled() {
+        return;
+    }
+    let (p0, p1) = ctx.render_extents;
+    let (cx, cy) = ((p0.x + p1.x) / 2.0, (p0.y + p1.y) / 2.0);
+    let guide = paint((255, 0, 255), 160);
+    if let Some(rect) = Rect::from_xywh(cx - 0.5, p0.y, 1.0, p1.y - p0.y) {
+        ctx.canvas.fill_rect(rect, &guide, ctx.render_xform);
+    }
+    if let Some(rect) = Rect::from_xywh(p0.x, cy - 0.5, p1.x - p0.x, 1.0) {
+        ctx.canvas.fill_rect(rect, &guide, ctx.render_xform);
+    }
+}
+
+/// The "debug" module: test text, a pixel ruler, and a readout of the layout state
+#[derive(Debug)]
+pub struct DebugItem {
+    text : Box<str>,
+    ruler : Box<str>,
+    step : f32,
+}
+
+impl DebugItem {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        DebugItem {
+            text : value.get("text").and_then(|v| v.as_str()).unwrap_or("").into(),
+            ruler : toml_to_string(value.get("ruler")).unwrap_or_default().into(),
+            step : toml_to_f64(value.get("step")).filter(|&v| v >= 2.0).unwrap_or(10.0) as f32,
+        }
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        match key {
+            "" | "text" => f(rt.format_or(&self.text, name)),
+            "enabled" => f(Value::Bool(enabled())),
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        if !key.is_empty() && key != "enabled" {
+            error!("Ignoring write to {}.{}", name, key);
+            return;
+        }
+        match &*value.into_text() {
+            "on" | "1" | "true" => set_enabled(true),
+            "off" | "0" | "false" => set_enabled(false),
+            "toggle" => toggle(),
+            v => {
+                error!("Unknown command for {}: '{}' (use on, off, or toggle)", name, v);
+                return;
+            }
+        }
+        // The overlay covers every item, so redraw all of them
+        NotifierList::active(rt).notify_data("debug");
+    }
+
+    pub fn render(&self, ctx : &mut Render) {
+        let origin = ctx.render_pos;
+        let mut bottom = origin.y;
+        let text = ctx.runtime.format_or(&self.text, ctx.err_name).into_text();
+        render_font_item(ctx, &text, false);
+        bottom = bottom.max(ctx.render_pos.y);
+        ctx.render_pos.y = origin.y;
+
+        if enabled() {
+            let (p0, p1) = ctx.render_extents;
+            let readout = format!(" @{},{} in {},{}–{},{}", origin.x, origin.y, p0.x, p0.y, p1.x, p1.y);
+            render_font_item(ctx, &readout, false);
+            bottom = bottom.max(ctx.render_pos.y);
+            ctx.render_pos.y = origin.y;
+        }
+
+        let width = ctx.runtime.format_or(&self.ruler, ctx.err_name).parse_f32().unwrap_or(0.0);
+        if width <= 0.0 {
+            ctx.render_pos.y = bottom;
+            return;
+        }
+        let (x, y) = (ctx.render_pos.x, origin.y);
+        let height = (ctx.render_extents.1.y - y).max(0.0);
+        if !ctx.measure_only {
+            let tick = Paint {
+                shader : Shader::SolidColor(ctx.font_color),
+                ..Default::default()
+            };
+            let mut i = 0;
+            while i as f32 * self.step <= width {
+                // Every fifth tick is full height
+                let len = if i % 5 == 0 { height } else { height / 3.0 };
+                if let Some(rect) = Rect::from_xywh(x + i as f32 * self.step, y + height - len, 1.0, len) {
+                    ctx.canvas.fill_rect(rect, &tick, ctx.render_xform);
+                }
+                i += 1;
+            }
+            if let Some(rect) = Rect::from_xywh(x, y + height - 1.0, width, 1.0) {
+                ctx.canvas.fill_rect(rect, &tick, ctx.render_xform);
+            }
+        }
+        ctx.render_pos.x += width + 1.0;
+        ctx.render_pos.y = bottom.max(y + height);
+    }
+}
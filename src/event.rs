@@ -1,12 +1,24 @@
 //! Event handling (click, scroll)
-use crate::data::IterationItem;
-use crate::item::PopupDesc;
+use crate::data::{IterationItem,Value};
+use crate::item::{Freeze,PopupDesc};
+#[cfg(feature="lua")]
+use crate::lua;
+use crate::output_power;
 use crate::state::Runtime;
 #[cfg(feature="dbus")]
 use crate::tray;
+use crate::util;
+use json::JsonValue;
 use log::{info,error};
 use std::rc::Rc;
-use std::process::Command;
+
+/// Priority of actions configured on items
+const PRIORITY_ITEM : u8 = 0;
+/// Priority of the default tray icon actions, so that configured actions replace them
+#[cfg_attr(not(feature="dbus"), allow(unused))]
+const PRIORITY_TRAY : u8 = 1;
+/// Priority of the bar's `background-actions`
+const PRIORITY_BACKGROUND : u8 = 2;
 
 /// A single click action associated with the area that activates it
 #[derive(Debug,Clone)]
@@ -14,8 +26,67 @@ struct EventListener {
     x_min : f32,
     x_max : f32,
     buttons : u32,
+    /// Modifier keys that must be held, or 0 for any
+    mods : u32,
     item : Option<IterationItem>,
     target : Action,
+    /// Only runs if no handler with a lower priority accepts the event
+    priority : u8,
+}
+
+/// Names of the buttons in an [EventListener]'s mask, by bit
+const BUTTON_NAMES : [&str; 10] = [
+    "left", "right", "middle", "backward", "forward",
+    "scroll-up", "scroll-down", "scroll-left", "scroll-right", "tap",
+];
+
+/// The `on-*` configuration keys and the buttons they respond to
+const EVENTS : [(&str, u32); 15] = [
+    ("click", 1 << 0 | 1 << 9),
+    ("click-left", 1 << 0),
+    ("click-right", 1 << 1),
+    ("click-middle", 1 << 2),
+    ("click-back", 1 << 3),
+    ("click-backward", 1 << 3),
+    ("click-forward", 1 << 4),
+    ("scroll-up", 1 << 5),
+    ("scroll-down", 1 << 6),
+    ("vscroll", 3 << 5),
+    ("scroll-left", 1 << 7),
+    ("scroll-right", 1 << 8),
+    ("hscroll", 3 << 7),
+    ("scroll", 15 << 5),
+    ("tap", 1 << 9),
+];
+
+pub const MOD_SHIFT : u32 = 1 << 0;
+pub const MOD_CTRL : u32 = 1 << 1;
+pub const MOD_ALT : u32 = 1 << 2;
+pub const MOD_SUPER : u32 = 1 << 3;
+
+/// Modifier prefixes allowed on event keys, as in `on-ctrl-shift-click`
+const MODIFIERS : [(&str, u32); 4] = [
+    ("shift", MOD_SHIFT),
+    ("ctrl", MOD_CTRL),
+    ("alt", MOD_ALT),
+    ("super", MOD_SUPER),
+];
+
+/// Parse an event key such as `on-shift-click-right` into its index in [EVENTS] and its modifiers
+fn parse_event_key(key : &str) -> Option<(usize, u32)> {
+    let mut rest = key.strip_prefix("on-")?;
+    let mut mods = 0;
+    'prefix: loop {
+        for &(name, bit) in &MODIFIERS {
+            if let Some(tail) = rest.strip_prefix(name).and_then(|r| r.strip_prefix('-')) {
+                mods |= bit;
+                rest = tail;
+                continue 'prefix;
+            }
+        }
+        break;
+    }
+    EVENTS.iter().position(|e| e.0 == rest).map(|i| (i, mods))
 }
 
 /// A list of [EventListener]s
@@ -23,39 +94,33 @@ struct EventListener {
 pub struct EventSink {
     handlers : Vec<EventListener>,
     hovers : Vec<(f32, f32, PopupDesc)>,
+    /// Items with `freeze-on-hover`
+    freezes : Vec<(f32, f32, Rc<Freeze>)>,
 }
 
 impl EventSink {
     pub fn from_toml(value : &toml::Value) -> Self {
         let mut sink = EventSink::default();
-        sink.add_click(value.get("on-click"), 1 << 0 | 1 << 9);
-        sink.add_click(value.get("on-click-left"), 1 << 0);
-        sink.add_click(value.get("on-click-right"), 1 << 1);
-        sink.add_click(value.get("on-click-middle"), 1 << 2);
-        sink.add_click(value.get("on-click-back"), 1 << 3);
-        sink.add_click(value.get("on-click-backward"), 1 << 3);
-        sink.add_click(value.get("on-click-forward"), 1 << 4);
-        sink.add_click(value.get("on-scroll-up"), 1 << 5);
-        sink.add_click(value.get("on-scroll-down"), 1 << 6);
-        sink.add_click(value.get("on-vscroll"), 3 << 5);
-        sink.add_click(value.get("on-scroll-left"), 1 << 7);
-        sink.add_click(value.get("on-scroll-right"), 1 << 8);
-        sink.add_click(value.get("on-hscroll"), 3 << 7);
-        sink.add_click(value.get("on-scroll"), 15 << 5);
-        sink.add_click(value.get("on-tap"), 1 << 9);
+        let mut keys : Vec<_> = value.as_table().into_iter().flatten()
+            .filter_map(|(k, v)| parse_event_key(k).map(|(i, mods)| (i, mods, v)))
+            .collect();
+        keys.sort_by_key(|&(i, mods, _)| (i, mods));
+        for (i, mods, value) in keys {
+            sink.add_click(value, EVENTS[i].1, mods);
+        }
         sink
     }
 
-    fn add_click(&mut self, value : Option<&toml::Value>, buttons : u32) {
-        if let Some(value) = value {
-            self.handlers.push(EventListener {
-                x_min : 0.0,
-                x_max : 1e20,
-                buttons,
-                item : None,
-                target : Action::from_toml(value)
-            })
-        }
+    fn add_click(&mut self, value : &toml::Value, buttons : u32, mods : u32) {
+        self.handlers.push(EventListener {
+            x_min : 0.0,
+            x_max : 1e20,
+            buttons,
+            mods,
+            item : None,
+            target : Action::from_toml(value),
+            priority : PRIORITY_ITEM,
+        })
     }
 
     #[cfg(feature="dbus")]
@@ -65,8 +130,10 @@ impl EventSink {
             x_min : -1e20,
             x_max : 1e20,
             buttons : 7 | (15 << 5),
+            mods : 0,
             item : None,
             target : Action::from_tray(item),
+            priority : PRIORITY_TRAY,
         });
         sink
     }
@@ -81,9 +148,20 @@ impl EventSink {
         }
     }
 
+    /// Add the handlers of `bg` covering the whole bar, below every other handler
+    pub fn add_background(&mut self, bg : &Self) {
+        self.handlers.extend(bg.handlers.iter().map(|h| EventListener {
+            x_min : 0.0,
+            x_max : 1e20,
+            priority : PRIORITY_BACKGROUND,
+            ..h.clone()
+        }));
+    }
+
     pub fn merge(&mut self, sink : Self) {
         self.handlers.extend(sink.handlers);
         self.hovers.extend(sink.hovers);
+        self.freezes.extend(sink.freezes);
     }
 
     pub fn offset_clamp(&mut self, offset : f32, min : f32, max : f32) {
@@ -101,7 +179,9 @@ impl EventSink {
                 h.x_max = max;
             }
         }
-        for (x_min, x_max, _) in &mut self.hovers {
+        let hovers = self.hovers.iter_mut().map(|(x_min, x_max, _)| (x_min, x_max));
+        let freezes = self.freezes.iter_mut().map(|(x_min, x_max, _)| (x_min, x_max));
+        for (x_min, x_max) in hovers.chain(freezes) {
             *x_min += offset;
             *x_max += offset;
             if *x_min < min {
@@ -119,11 +199,16 @@ impl EventSink {
 
     pub fn button(&self, x : f32, y : f32, button : u32, runtime : &mut Runtime) {
         let _ = y;
-        for h in &self.handlers {
-            if x < h.x_min || x > h.x_max {
-                continue;
-            }
-            if (h.buttons & (1 << button)) == 0 {
+        let hit = |h : &&EventListener| x >= h.x_min && x <= h.x_max && (h.buttons & (1 << button)) != 0;
+        // Handlers for the held modifiers take priority; without any, the modifiers are ignored
+        let mods = runtime.modifiers;
+        let mods = match mods != 0 && self.handlers.iter().filter(hit).any(|h| h.mods == mods) {
+            true => mods,
+            false => 0,
+        };
+        let priority = self.handlers.iter().filter(hit).filter(|h| h.mods == mods).map(|h| h.priority).min();
+        for h in self.handlers.iter().filter(hit) {
+            if h.mods != mods || Some(h.priority) != priority {
                 continue;
             }
             if h.item.is_none() {
@@ -137,7 +222,6 @@ impl EventSink {
         }
     }
 
-    #[cfg_attr(not(feature="dbus"), allow(unused))]
     pub fn add_hover(&mut self, min : f32, max : f32, desc : PopupDesc) {
         self.hovers.push((min, max, desc));
     }
@@ -152,6 +236,62 @@ impl EventSink {
         None
     }
 
+    pub fn add_freeze(&mut self, min : f32, max : f32, freeze : &Rc<Freeze>) {
+        self.freezes.push((min, max, freeze.clone()));
+    }
+
+    /// The innermost item at this position that stays still while hovered
+    pub fn get_freeze(&self, x : f32) -> Option<Rc<Freeze>> {
+        // items are added after their children
+        self.freezes.iter()
+            .find(|&&(min, max, _)| x >= min && x < max)
+            .map(|(_, _, freeze)| freeze.clone())
+    }
+
+    /// Remove and return the first tooltip or popup
+    pub fn take_hover(&mut self) -> Option<PopupDesc> {
+        self.hovers.drain(..).next().map(|(_, _, desc)| desc)
+    }
+
+    /// Describe the click handlers and tooltip regions, for the `regions` control socket command
+    pub fn to_json(&self, runtime : &Runtime) -> JsonValue {
+        let handlers : Vec<_> = self.handlers.iter().map(|h| {
+            let buttons : Vec<_> = BUTTON_NAMES.iter().enumerate()
+                .filter(|&(i, _)| h.buttons & (1 << i) != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            let item = h.item.as_ref().map_or(JsonValue::Null, |item| json::object! {
+                kind : item.kind(),
+                text : item.read_in_as("item", "", runtime, |v| v.into_text().into_owned()),
+            });
+            let mods : Vec<_> = MODIFIERS.iter()
+                .filter(|m| h.mods & m.1 != 0)
+                .map(|m| m.0)
+                .collect();
+            json::object! {
+                x_min : h.x_min,
+                x_max : h.x_max,
+                buttons : buttons,
+                modifiers : mods,
+                action : h.target.to_json(),
+                item : item,
+                background : h.priority == PRIORITY_BACKGROUND,
+            }
+        }).collect();
+        let hovers : Vec<_> = self.hovers.iter().map(|(x_min, x_max, desc)| json::object! {
+            x_min : *x_min,
+            x_max : *x_max,
+            popup : desc.kind(),
+        }).collect();
+        let mut regions = Vec::new();
+        self.for_active_regions(|lo, hi| regions.push(JsonValue::from(vec![lo, hi])));
+        json::object! {
+            handlers : handlers,
+            hovers : hovers,
+            active_regions : regions,
+        }
+    }
+
     pub fn for_active_regions(&self, mut f : impl FnMut(f32, f32)) {
         let mut ha = self.handlers.iter().peekable();
         let mut ho = self.hovers.iter().peekable();
@@ -182,12 +322,132 @@ impl EventSink {
     }
 }
 
+/// How the command of an `exec` action is started
+#[derive(Debug,Clone,Default)]
+pub struct ExecOptions {
+    /// Overrides the global `exec-scope` setting
+    scope : Option<bool>,
+    /// Extra environment variables, subject to text expansion
+    env : Vec<(String, String)>,
+    /// Working directory, subject to text expansion
+    dir : Option<String>,
+    /// Split the command into arguments instead of passing it to /bin/sh
+    no_shell : bool,
+}
+
+impl ExecOptions {
+    fn from_toml(value : &toml::Value) -> Self {
+        let env = match value.get("env") {
+            Some(toml::Value::Table(env)) => env.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_owned())))
+                .collect(),
+            Some(env) => {
+                error!("Ignoring invalid env table: {}", env);
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        ExecOptions {
+            scope : value.get("scope").and_then(|v| v.as_bool()),
+            env,
+            dir : value.get("dir").and_then(|v| v.as_str()).map(String::from),
+            no_shell : value.get("shell").and_then(|v| v.as_bool()) == Some(false),
+        }
+    }
+
+    fn to_json(&self, rv : &mut JsonValue) {
+        if let Some(scope) = self.scope {
+            rv["scope"] = scope.into();
+        }
+        if !self.env.is_empty() {
+            let mut env = JsonValue::new_object();
+            for (k, v) in &self.env {
+                env[k.as_str()] = v.as_str().into();
+            }
+            rv["env"] = env;
+        }
+        if let Some(dir) = &self.dir {
+            rv["dir"] = dir.as_str().into();
+        }
+        if self.no_shell {
+            rv["shell"] = false.into();
+        }
+    }
+
+    /// Expand and run the command.  Without a shell, the format is split into arguments before
+    /// expanding each one, so that values containing spaces or quotes stay a single argument.
+    fn run(&self, format : &str, runtime : &Runtime) {
+        let scope = self.scope.unwrap_or(runtime.exec_scope);
+        let (mut command, cmd) = if self.no_shell {
+            let args = match util::split_format_args(format) {
+                Some(args) if !args.is_empty() => args,
+                _ => {
+                    error!("Could not split command '{}' into arguments", format);
+                    return;
+                }
+            };
+            let mut expanded = Vec::with_capacity(args.len());
+            for arg in &args {
+                match runtime.format(arg) {
+                    Ok(v) => expanded.push(v.into_text().into_owned()),
+                    Err(e) => {
+                        error!("Error expanding format for command: {}", e);
+                        return;
+                    }
+                }
+            }
+            info!("Executing {:?}", expanded);
+            let mut command = util::command(&expanded[0], scope);
+            command.args(&expanded[1..]);
+            (command, expanded.join(" "))
+        } else {
+            let cmd = match runtime.format(format) {
+                Ok(cmd) => cmd.into_text().into_owned(),
+                Err(e) => {
+                    error!("Error expanding format for command: {}", e);
+                    return;
+                }
+            };
+            info!("Executing '{}'", cmd);
+            let mut command = util::command("/bin/sh", scope);
+            command.arg("-c").arg(&cmd);
+            (command, cmd)
+        };
+        for (key, value) in &self.env {
+            command.env(key, &*runtime.format_or(value, key).into_text());
+        }
+        if let Some(dir) = &self.dir {
+            command.current_dir(&*runtime.format_or(dir, "dir").into_text());
+        }
+        match command.spawn() {
+            Ok(child) => drop(child),
+            Err(e) => error!("Could not execute {}: {}", cmd, e),
+        }
+    }
+}
+
 /// Handler invoked by a click or touch event
 #[derive(Debug,Clone)]
 pub enum Action {
-    Exec { format : String },
+    Exec { format : String, opts : ExecOptions },
     Write { target : String, format : String },
     List(Vec<Action>),
+    TogglePopup,
+    ToggleItem(String),
+    /// Open the popup of the named item, centered on the bar
+    Popup(String),
+    /// Turn an output on, off, or toggle it
+    OutputPower { output : String, mode : String },
+    /// Write 1 to the item if it is false, or 0 if it is true
+    ToggleVar(String),
+    /// Refresh an item's data now, re-running its poll or command
+    Refresh(String),
+    /// Run one of two actions depending on whether the condition is true
+    If { cond : String, then : Box<Action>, otherwise : Box<Action> },
+    /// Stop running a list of actions unless the condition is true
+    Guard(String),
+    #[cfg(feature="lua")]
+    Lua { function : String },
     #[cfg(feature="dbus")]
     Tray(Rc<tray::TrayItem>),
     None,
@@ -198,6 +458,36 @@ impl Action {
         if let Some(array) = value.as_array() {
             return Action::List(array.iter().map(Action::from_toml).collect());
         }
+        if value.as_str() == Some("toggle-popup") {
+            return Action::TogglePopup;
+        }
+        if let Some(name) = value.as_str().and_then(|v| v.strip_prefix("toggle-item:")) {
+            return Action::ToggleItem(name.into());
+        }
+        if let Some(v) = value.as_str() {
+            let (kind, arg) = v.split_once(':').unwrap_or((v, ""));
+            match kind {
+                "popup" | "show-popup" => return Action::Popup(arg.into()),
+                "exec" => return Action::Exec { format : arg.into(), opts : Default::default() },
+                "set-var" => {
+                    let (target, format) = arg.split_once('=').unwrap_or((arg, ""));
+                    return Action::Write { target : target.into(), format : format.into() };
+                }
+                "toggle-var" => return Action::ToggleVar(arg.into()),
+                "refresh" | "refresh-item" => return Action::Refresh(arg.into()),
+                _ => {}
+            }
+        }
+        if let Some(cond) = value.get("if").and_then(|v| v.as_str()) {
+            return match (value.get("then"), value.get("else")) {
+                (None, None) => Action::Guard(cond.into()),
+                (then, otherwise) => Action::If {
+                    cond : cond.into(),
+                    then : Box::new(then.map_or(Action::None, Action::from_toml)),
+                    otherwise : Box::new(otherwise.map_or(Action::None, Action::from_toml)),
+                },
+            };
+        }
         if let Some(dest) = value.get("write").and_then(|v| v.as_str()).or_else(|| value.get("send").and_then(|v| v.as_str())) {
             let format = value.get("format").and_then(|v| v.as_str())
                 .or_else(|| value.get("msg").and_then(|v| v.as_str()))
@@ -205,7 +495,20 @@ impl Action {
             return Action::Write { target : dest.into(), format };
         }
         if let Some(cmd) = value.get("exec").and_then(|v| v.as_str()) {
-            return Action::Exec { format : cmd.into() };
+            return Action::Exec { format : cmd.into(), opts : ExecOptions::from_toml(value) };
+        }
+        if let Some(name) = value.get("refresh").and_then(|v| v.as_str()) {
+            return Action::Refresh(name.into());
+        }
+        if let Some(output) = value.get("output-power").and_then(|v| v.as_str()) {
+            let mode = value.get("mode").and_then(|v| v.as_str()).unwrap_or("toggle").to_owned();
+            return Action::OutputPower { output : output.into(), mode };
+        }
+        if let Some(function) = value.get("lua").and_then(|v| v.as_str()) {
+            #[cfg(feature="lua")]
+            return Action::Lua { function : function.into() };
+            #[cfg(not(feature="lua"))]
+            error!("Lua actions require rwaybar to be built with the lua feature: {}", function);
         }
         error!("Unknown action: {}", value);
         Action::None
@@ -216,10 +519,41 @@ impl Action {
         Action::Tray(item)
     }
 
+    /// Describe the action in the same form as its configuration
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Action::Exec { format, opts } => {
+                let mut rv = json::object! { exec : format.as_str() };
+                opts.to_json(&mut rv);
+                rv
+            }
+            Action::Write { target, format } => json::object! { send : target.as_str(), msg : format.as_str() },
+            Action::List(actions) => actions.iter().map(Action::to_json).collect::<Vec<_>>().into(),
+            Action::TogglePopup => "toggle-popup".into(),
+            Action::ToggleItem(name) => format!("toggle-item:{}", name).into(),
+            Action::Popup(name) => format!("popup:{}", name).into(),
+            Action::OutputPower { output, mode } => json::object! { "output-power" : output.as_str(), mode : mode.as_str() },
+            Action::ToggleVar(name) => format!("toggle-var:{}", name).into(),
+            Action::Refresh(name) => format!("refresh:{}", name).into(),
+            Action::If { cond, then, otherwise } => json::object! { "if" : cond.as_str(), then : then.to_json(), "else" : otherwise.to_json() },
+            Action::Guard(cond) => json::object! { "if" : cond.as_str() },
+            #[cfg(feature="lua")]
+            Action::Lua { function } => json::object! { lua : function.as_str() },
+            #[cfg(feature="dbus")]
+            Action::Tray(_) => "tray".into(),
+            Action::None => JsonValue::Null,
+        }
+    }
+
     pub fn invoke(&self, runtime : &Runtime, how : u32) {
         match self {
             Action::List(actions) => {
                 for action in actions {
+                    if let Action::Guard(cond) = action {
+                        if !is_true(runtime, cond) {
+                            break;
+                        }
+                    }
                     action.invoke(runtime, how);
                 }
             }
@@ -232,31 +566,53 @@ impl Action {
                     }
                 };
 
-                let (name, key) = match target.find('.') {
-                    Some(p) => (&target[..p], &target[p + 1..]),
-                    None => (&target[..], ""),
-                };
-
-                match runtime.items.get(name) {
-                    Some(item) => {
-                        item.data.write(name, key, value, &runtime);
-                    }
-                    None => error!("Could not find variable {}", target),
+                if let Err(e) = runtime.write_item(target, value) {
+                    error!("{}", e);
                 }
             }
-            Action::Exec { format } => {
-                match runtime.format(&format) {
-                    Ok(cmd) => {
-                        let cmd = cmd.into_text();
-                        info!("Executing '{}'", cmd);
-                        match Command::new("/bin/sh").arg("-c").arg(&cmd[..]).spawn() {
-                            Ok(child) => drop(child),
-                            Err(e) => error!("Could not execute {}: {}", cmd, e),
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error expanding format for command: {}", e);
-                    }
+            Action::Exec { format, opts } => {
+                opts.run(format, runtime);
+            }
+            Action::TogglePopup => {
+                runtime.request_popup_toggle();
+            }
+            Action::ToggleItem(name) => {
+                if let Err(e) = runtime.toggle_item(name) {
+                    error!("Cannot toggle: {}", e);
+                }
+            }
+            Action::Popup(name) => {
+                runtime.request_item_popup(name);
+            }
+            Action::OutputPower { output, mode } => {
+                match runtime.format(output) {
+                    Ok(output) => output_power::set_power(runtime, &output.into_text(), mode),
+                    Err(e) => error!("Error expanding output name: {}", e),
+                }
+            }
+            Action::ToggleVar(name) => {
+                let value = if is_true(runtime, &format!("{{{}}}", name)) { "0" } else { "1" };
+                if let Err(e) = runtime.write_item(name, Value::Borrow(value)) {
+                    error!("{}", e);
+                }
+            }
+            Action::Refresh(name) => {
+                if let Err(e) = runtime.refresh_item(name) {
+                    error!("Cannot refresh: {}", e);
+                }
+            }
+            Action::If { cond, then, otherwise } => {
+                if is_true(runtime, cond) {
+                    then.invoke(runtime, how);
+                } else {
+                    otherwise.invoke(runtime, how);
+                }
+            }
+            Action::Guard(_) => {}
+            #[cfg(feature="lua")]
+            Action::Lua { function } => {
+                if let Err(e) = lua::call(function, how, runtime) {
+                    error!("Lua action {} failed: {}", function, e);
                 }
             }
             #[cfg(feature="dbus")]
@@ -267,3 +623,14 @@ impl Action {
         }
     }
 }
+
+/// Expand the condition of an `if` action; it is false if empty, `0`, or `false`
+fn is_true(runtime : &Runtime, cond : &str) -> bool {
+    match runtime.format(cond) {
+        Ok(v) => !matches!(&*v.into_text(), "" | "0" | "false"),
+        Err(e) => {
+            error!("Error expanding condition '{}': {}", cond, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! RSS and Atom feed headlines
+use crate::data::{Periodic,Value};
+use crate::registry::{self,Source};
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,toml_to_f64};
+use chrono::{DateTime,FixedOffset};
+use log::{debug,error,warn};
+use std::collections::HashSet;
+use std::rc::{Rc,Weak};
+use std::time::Duration;
+use xml::reader::{EventReader,XmlEvent};
+
+#[derive(Debug,Default)]
+struct RawEntry {
+    title : String,
+    link : String,
+    id : String,
+    date : String,
+}
+
+/// Read the title and entries of an RSS (1.0 or 2.0) or Atom feed
+fn parse_feed(text : &str) -> Result<(String, Vec<RawEntry>), xml::reader::Error> {
+    let mut title = String::new();
+    let mut entries = Vec::new();
+    let mut current : Option<RawEntry> = None;
+    let mut path : Vec<String> = Vec::new();
+    for event in EventReader::from_str(text) {
+        match event? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                match &*name.local_name {
+                    "item" | "entry" => current = Some(RawEntry::default()),
+                    // Atom links are attributes; only the alternate (or unlabeled) one is the page
+                    "link" => if let Some(entry) = &mut current {
+                        let attr = |key : &str| attributes.iter().find(|a| a.name.local_name == key).map(|a| &*a.value);
+                        if let (Some(href), None | Some("alternate")) = (attr("href"), attr("rel")) {
+                            if entry.link.is_empty() {
+                                entry.link = href.to_owned();
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                path.push(name.local_name);
+            }
+            XmlEvent::EndElement { name } => {
+                path.pop();
+                if matches!(&*name.local_name, "item" | "entry") {
+                    entries.extend(current.take());
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                let field = path.last().map_or("", |s| s);
+                match &mut current {
+                    Some(entry) => match field {
+                        "title" => entry.title.push_str(&text),
+                        "link" => entry.link.push_str(&text),
+                        "guid" | "id" => entry.id.push_str(&text),
+                        "pubDate" | "published" | "date" => entry.date = text,
+                        "updated" if entry.date.is_empty() => entry.date = text,
+                        _ => {}
+                    },
+                    None => {
+                        let parent = path.len().checked_sub(2).map(|i| &*path[i]);
+                        if field == "title" && matches!(parent, Some("channel" | "feed")) {
+                            title.push_str(&text);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((title.trim().to_owned(), entries))
+}
+
+fn parse_date(date : &str) -> Option<DateTime<FixedOffset>> {
+    let date = date.trim();
+    DateTime::parse_from_rfc2822(date).or_else(|_| DateTime::parse_from_rfc3339(date)).ok()
+}
+
+/// One headline
+#[derive(Debug)]
+pub struct Entry {
+    id : Box<str>,
+    title : Box<str>,
+    link : Box<str>,
+    feed : Rc<str>,
+    url : Rc<str>,
+    date : Option<DateTime<FixedOffset>>,
+    new : Cell<bool>,
+    state : Weak<FeedState>,
+}
+
+impl Entry {
+    fn mark_read(&self) {
+        if self.new.replace(false) {
+            if let Some(state) = self.state.upgrade() {
+                state.interested.take().notify_data("feed");
+            }
+        }
+    }
+
+    /// Open the link in the browser, marking the entry as read
+    pub fn open(&self, rt : &Runtime) {
+        self.mark_read();
+        if self.link.is_empty() {
+            return error!("The entry '{}' has no link", self.title);
+        }
+        let open = match self.state.upgrade() {
+            Some(state) => state.open.clone(),
+            None => return,
+        };
+        match util::command(&open, rt.exec_scope).arg(&*self.link).spawn() {
+            Ok(child) => drop(child),
+            Err(e) => error!("Could not execute {}: {}", open, e),
+        }
+    }
+
+}
+
+impl registry::Entry for Entry {
+    fn kind(&self) -> &'static str {
+        "feed-entry"
+    }
+
+    fn read<'a>(&'a self, key : &str, _rt : &Runtime) -> Value<'a> {
+        match key {
+            "title" | "text" | "" => Value::Borrow(&self.title),
+            "link" => Value::Borrow(&self.link),
+            "feed" => Value::Borrow(&self.feed),
+            "date" => self.date.map_or(Value::Null, |d| Value::Owned(d.format("%Y-%m-%d %H:%M").to_string())),
+            "new" => Value::Bool(self.new.get()),
+            _ => Value::Null,
+        }
+    }
+
+    fn write(self : Rc<Self>, key : &str, value : Value, rt : &Runtime) {
+        match (key, value.as_str_fast()) {
+            ("", "") | ("", "open") => self.open(rt),
+            ("", "mark-read") => self.mark_read(),
+            (key, value) => error!("Ignoring write of '{}' to feed entry {}.{}", value, self.title, key),
+        }
+    }
+}
+
+/// Longest time to wait for the fetch command to download a feed
+const FETCH_TIMEOUT : Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct FeedState {
+    urls : Vec<Rc<str>>,
+    fetch : Box<str>,
+    open : Rc<str>,
+    /// Newest first
+    entries : Cell<Vec<Rc<Entry>>>,
+    /// The IDs of the entries shown after the last fetch; None before the first fetch, whose
+    /// entries are not new
+    seen : Cell<Option<HashSet<Box<str>>>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl FeedState {
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let old = self.entries.take_in(|e| e.clone());
+        let seen = self.seen.take();
+        let mut entries = Vec::new();
+        for url in &self.urls {
+            let text = tokio::time::timeout(FETCH_TIMEOUT, util::shell_output(&self.fetch, &[url])).await
+                .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")));
+            let parsed = match text {
+                Ok(text) => match parse_feed(&text) {
+                    Ok(parsed) => Some(parsed),
+                    Err(e) => {
+                        warn!("Could not parse feed {}: {}", url, e);
+                        None
+                    }
+                },
+                Err(e) => {
+                    warn!("Could not fetch feed {}: {}", url, e);
+                    None
+                }
+            };
+            let (title, raw) = match parsed {
+                Some(parsed) => parsed,
+                None => {
+                    // keep showing what we had
+                    entries.extend(old.iter().filter(|e| e.url == *url).cloned());
+                    continue;
+                }
+            };
+            debug!("Read {} entries from {}", raw.len(), url);
+            let feed : Rc<str> = if title.is_empty() { url.clone() } else { title.into() };
+            for raw in raw {
+                let title = raw.title.trim();
+                let link = raw.link.trim();
+                let id = [raw.id.trim(), link, title].into_iter().find(|s| !s.is_empty()).unwrap_or_default();
+                if let Some(entry) = old.iter().find(|e| &*e.id == id && e.url == *url) {
+                    entries.push(entry.clone());
+                    continue;
+                }
+                let new = seen.as_ref().is_some_and(|seen| !seen.contains(id));
+                entries.push(Rc::new(Entry {
+                    id : id.into(),
+                    title : title.into(),
+                    link : link.into(),
+                    feed : feed.clone(),
+                    url : url.clone(),
+                    date : parse_date(&raw.date),
+                    new : Cell::new(new),
+                    state : Rc::downgrade(&self),
+                }));
+            }
+        }
+        // newest first, then the entries without a date in feed order (the sort is stable)
+        entries.sort_by(|a, b| b.date.cmp(&a.date));
+        // Entries that dropped out of their feeds are forgotten, so this does not keep growing
+        self.seen.set(Some(entries.iter().map(|e| e.id.clone()).collect()));
+
+        let changed = old.len() != entries.len() || old.iter().zip(&entries).any(|(a, b)| !Rc::ptr_eq(a, b));
+        self.entries.set(entries);
+        if changed {
+            self.interested.take().notify_data("feed");
+        }
+        self.running.set(false);
+    }
+}
+
+/// The "feed" module
+#[derive(Debug)]
+pub struct Feeds {
+    count : usize,
+    poll : Periodic<Rc<FeedState>>,
+}
+
+impl Feeds {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let urls = match config.get("url").or_else(|| config.get("urls")) {
+            Some(toml::Value::String(s)) => vec![s.as_str().into()],
+            Some(toml::Value::Array(a)) => a.iter().filter_map(|v| v.as_str()).map(Into::into).collect(),
+            _ => return Err("A feed needs a 'url' or a list of 'urls'".into()),
+        };
+        let str_key = |key : &str, default : &str| config.get(key).and_then(|v| v.as_str()).unwrap_or(default).to_owned();
+        let state = FeedState {
+            urls,
+            fetch : str_key("fetch-command", "curl -sfL --max-time 30").into(),
+            open : str_key("open-command", "xdg-open").into(),
+            entries : Cell::default(),
+            seen : Cell::default(),
+            running : Cell::default(),
+            interested : Cell::default(),
+        };
+        Ok(Feeds {
+            count : config.get("count").and_then(|v| v.as_integer()).map_or(10, |n| n.max(0) as usize),
+            poll : Periodic::new(toml_to_f64(config.get("poll")).unwrap_or(1800.0), Rc::new(state)),
+        })
+    }
+
+    /// The entries to show, reading the feeds if needed
+    fn entries(&self, rt : &Runtime) -> Vec<Rc<Entry>> {
+        self.poll.read_refresh_async(rt, |state| state.clone().refresh());
+        let state = self.poll.data();
+        state.interested.take_in(|i| i.add(rt));
+        state.entries.take_in(|e| e.iter().take(self.count).cloned().collect())
+    }
+}
+
+impl Source for Feeds {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        let entries = self.entries(rt);
+        match key {
+            "" | "text" | "new" => {
+                let all = self.poll.data().entries.take_in(|e| e.iter().filter(|e| e.new.get()).count());
+                Value::Float(all as f64)
+            }
+            "count" => Value::Float(entries.len() as f64),
+            "headline" => match entries.first() {
+                Some(e) => Value::Owned(e.title.to_string()),
+                None => Value::Null,
+            },
+            "ticker" => {
+                let titles : Vec<_> = entries.iter().map(|e| &*e.title).collect();
+                Value::Owned(titles.join(" · "))
+            }
+            "list" | "tooltip" => {
+                let lines : Vec<_> = entries.iter().map(|e| format!("{}: {}", e.feed, e.title)).collect();
+                Value::Owned(lines.join("\n"))
+            }
+            _ => Value::Null,
+        }
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.refresh();
+        true
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
+        match (key, value.as_str_fast()) {
+            ("", "") | ("", "mark-read") => {
+                let entries = self.poll.data().entries.take_in(|e| e.clone());
+                for entry in entries {
+                    entry.mark_read();
+                }
+            }
+            (key, value) => error!("Ignoring write of '{}' to {}.{}", value, name, key),
+        }
+    }
+
+    fn read_focus_list(&self, rt : &Runtime, f : &mut dyn FnMut(bool, Rc<dyn registry::Entry>)) {
+        for entry in self.entries(rt) {
+            f(entry.new.get(), entry);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Filters on values in text expansions: `{name.key|filter:'arg'|...}`
+use crate::locale;
+use crate::state::Runtime;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use strfmt::FmtError;
+
+thread_local! {
+    static REGEX_CACHE : RefCell<HashMap<String, regex::Regex>> = Default::default();
+}
+
+/// Split on a separator, ignoring any separators inside single quotes
+fn split_quoted(s : &str, sep : char) -> Vec<&str> {
+    let mut rv = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                rv.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    rv.push(&s[start..]);
+    rv
+}
+
+/// Remove the quotes from a filter argument
+fn unquote(s : &str) -> Cow<'_, str> {
+    let s = s.trim();
+    match s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+        Some(inner) if inner.contains("\\'") => inner.replace("\\'", "'").into(),
+        Some(inner) => inner.into(),
+        None => s.into(),
+    }
+}
+
+fn with_regex<R>(pattern : &str, f : impl FnOnce(&regex::Regex) -> R) -> Result<R, FmtError> {
+    REGEX_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if !cache.contains_key(pattern) {
+            let re = regex::Regex::new(pattern)
+                .map_err(|e| FmtError::Invalid(format!("Error compiling regex '{}': {}", pattern, e)))?;
+            cache.insert(pattern.to_owned(), re);
+        }
+        Ok(f(&cache[pattern]))
+    })
+}
+
+/// Apply a filter to a value; `source` is the name of the item the value came from
+fn apply(rt : &Runtime, source : &str, value : String, filter : &str) -> Result<String, FmtError> {
+    let args = split_quoted(filter, ':');
+    let arg = |i : usize| args.get(i).map(|a| unquote(a));
+    match args[0].trim() {
+        "map" => {
+            let table = arg(1).filter(|name| !name.is_empty());
+            let table = table.as_deref().unwrap_or(source);
+            let item = rt.items.get(table).ok_or_else(|| FmtError::KeyError(table.to_owned()))?;
+            let map = item.map.as_ref()
+                .ok_or_else(|| FmtError::Invalid(format!("Item '{}' has no map table", table)))?;
+            match map.get(&*value) {
+                Some(v) => Ok(v.to_string()),
+                None => Ok(arg(2).map_or(value, |default| default.into_owned())),
+            }
+        }
+        "number" => {
+            let decimals = match arg(1) {
+                Some(d) => Some(d.parse().map_err(|_| FmtError::Invalid(format!("Invalid number of decimals '{}'", d)))?),
+                None => None,
+            };
+            match value.trim().parse::<f64>() {
+                Ok(v) => Ok(locale::format_number(v, decimals)),
+                Err(_) => Ok(value),
+            }
+        }
+        "regex" => {
+            let pattern = arg(1).ok_or_else(|| FmtError::Invalid("regex filter requires a pattern".into()))?;
+            match arg(2) {
+                Some(replace) => with_regex(&pattern, |re| re.replace_all(&value, &*replace).into_owned()),
+                None => with_regex(&pattern, |re| {
+                    // Extract the first capture group, or the whole match if there are no groups
+                    re.captures(&value)
+                        .and_then(|c| c.get(1).or_else(|| c.get(0)))
+                        .map_or(String::new(), |m| m.as_str().to_owned())
+                }),
+            }
+        }
+        name => Err(FmtError::Invalid(format!("Unknown filter '{}'", name))),
+    }
+}
+
+/// Expand every `{...}` expression that uses a filter, escaping the results so the remaining
+/// expressions can be expanded normally.  Returns None if there were no filters.
+pub fn expand_filters(rt : &Runtime, fmt : &str) -> Result<Option<String>, FmtError> {
+    let mut out = String::with_capacity(fmt.len());
+    let mut found = false;
+    let mut rest = fmt;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        rest = &rest[open..];
+        if rest.starts_with("{{") {
+            out.push_str("{{");
+            rest = &rest[2..];
+            continue;
+        }
+        let mut quoted = false;
+        let mut escaped = false;
+        let close = rest.char_indices().skip(1).find(|&(_, c)| {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '\'' => quoted = !quoted,
+                '}' if !quoted => return true,
+                _ => {}
+            }
+            false
+        }).map(|(i, _)| i);
+        let close = match close {
+            Some(close) => close,
+            None => break,
+        };
+        let inner = &rest[1..close];
+        let parts = split_quoted(inner, '|');
+        if parts.len() > 1 {
+            found = true;
+            let expr = parts[0].trim();
+            let source = expr.split(&['.', ':'] as &[char]).next().unwrap_or(expr);
+            let mut value = rt.format(&format!("{{{}}}", expr))?.into_text().into_owned();
+            for filter in &parts[1..] {
+                value = apply(rt, source, value, filter)?;
+            }
+            out.push_str(&value.replace('{', "{{").replace('}', "}}"));
+        } else {
+            out.push_str(&rest[..=close]);
+        }
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+    Ok(found.then_some(out))
+}
@@ -1,11 +1,16 @@
+//! Font loading, text shaping, and text rendering
 use crate::icon::OwnedImage;
 use crate::item::Formatting;
 use crate::state::Runtime;
 use crate::render::{Render,UID};
 use log::info;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Instant;
 use tiny_skia::{Color,Point,Transform};
 use ttf_parser::{Face,GlyphId};
@@ -17,9 +22,10 @@ pub struct FontMapped {
     // this field must follow parsed for safety (drop order)
     #[allow(unused)]
     mmap : memmap2::Mmap,
-    pub file : PathBuf,
     pub name : String,
     pub uid: UID,
+    /// Glyph outlines in font units, cached on first use
+    paths : RefCell<HashMap<GlyphId, Option<tiny_skia::Path>>>,
 }
 
 impl FontMapped {
@@ -33,7 +39,7 @@ impl FontMapped {
         let parsed = Face::from_slice(&buf, 0)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         let uid = UID::default();
-        Ok(FontMapped { parsed, mmap, file : path, name, uid })
+        Ok(FontMapped { parsed, mmap, name, uid, paths : Default::default() })
     }
 
     pub fn as_ref<'a>(&'a self) -> &'a Face<'a> {
@@ -43,6 +49,35 @@ impl FontMapped {
     pub fn scale_from_pt(&self, pt : f32) -> f32 {
         pt * 1.33333333 / self.as_ref().units_per_em() as f32
     }
+
+    /// Get the outline of a glyph, transformed by the given transform
+    fn outline_glyph(&self, id : GlyphId, xform : Transform) -> Option<tiny_skia::Path> {
+        let mut paths = self.paths.borrow_mut();
+        let path = paths.entry(id).or_insert_with(|| {
+            struct Draw(tiny_skia::PathBuilder);
+            let mut path = Draw(tiny_skia::PathBuilder::new());
+            impl ttf_parser::OutlineBuilder for Draw {
+                fn move_to(&mut self, x: f32, y: f32) {
+                    self.0.move_to(x,-y);
+                }
+                fn line_to(&mut self, x: f32, y: f32) {
+                    self.0.line_to(x, -y);
+                }
+                fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+                    self.0.quad_to(x1, -y1, x, -y);
+                }
+                fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+                    self.0.cubic_to(x1, -y1, x2, -y2, x, -y);
+                }
+                fn close(&mut self) {
+                    self.0.close();
+                }
+            }
+            self.as_ref().outline_glyph(id, &mut path)?;
+            path.0.finish()
+        });
+        path.clone().and_then(|p| p.transform(xform))
+    }
 }
 
 #[derive(Debug,Copy,Clone)]
@@ -57,6 +92,7 @@ pub struct CGlyph<'a> {
 pub fn layout_font<'a>(
     font: &'a FontMapped,
     size_pt: f32,
+    line_spacing: f32,
     runtime : &'a Runtime,
     rgba : Color,
     text : &str,
@@ -81,7 +117,7 @@ pub fn layout_font<'a>(
             if c == '\n' {
                 xmax = xmax.max(xpos);
                 xpos = 0.0;
-                ypos += line_height as f32;
+                ypos += line_height * line_spacing;
                 return None;
             }
             let mut fid = stack.last().map_or(font, |v| v.0);
@@ -165,34 +201,92 @@ pub fn layout_font<'a>(
     (to_draw, (width, height))
 }
 
+/// Key for a cached [ShapedText]
+#[derive(Eq,Hash,PartialEq,Debug)]
+pub struct ShapeKey {
+    font : UID,
+    font_size_millipt : u32,
+    line_spacing_milli : u32,
+    font_color : u32,
+    markup : bool,
+    text : String,
+}
+
+#[derive(Debug)]
+struct ShapedGlyph {
+    id : GlyphId,
+    scale : f32,
+    position : (f32, f32),
+    font : UID,
+    color : Color,
+}
+
+/// The result of [layout_font], without references to the fonts so that it can be cached
+#[derive(Debug)]
+pub struct ShapedText {
+    glyphs : Vec<ShapedGlyph>,
+    width : f32,
+    height : f32,
+    pub last_used : Instant,
+}
+
+/// Like [layout_font], but reuses the result from a previous frame if the text is unchanged
+pub fn shape_font<'a>(ctx : &Render<'a>, text : &str, markup : bool) -> (Vec<CGlyph<'a>>, (f32, f32)) {
+    let font = ctx.font;
+    let runtime = ctx.runtime;
+    let key = ShapeKey {
+        font : font.uid,
+        font_size_millipt : (ctx.font_size * 1000.0).round() as u32,
+        line_spacing_milli : (ctx.line_spacing * 1000.0).round() as u32,
+        font_color : ctx.font_color.to_color_u8().get(),
+        markup,
+        text : text.into(),
+    };
+    let find = |uid| {
+        if uid == font.uid {
+            Some(font)
+        } else {
+            runtime.fonts.iter().find(|f| f.uid == uid)
+        }
+    };
+    if let Some(shaped) = ctx.cache.shaped.borrow_mut().get_mut(&key) {
+        let glyphs : Option<Vec<_>> = shaped.glyphs.iter().map(|g| Some(CGlyph {
+            id : g.id,
+            scale : g.scale,
+            position : g.position,
+            font : find(g.font)?,
+            color : g.color,
+        })).collect();
+        if let Some(glyphs) = glyphs {
+            shaped.last_used = Instant::now();
+            return (glyphs, (shaped.width, shaped.height));
+        }
+    }
+    let (glyphs, (width, height)) = layout_font(font, ctx.font_size, ctx.line_spacing, runtime, ctx.font_color, text, markup);
+    ctx.cache.shaped.borrow_mut().insert(key, ShapedText {
+        glyphs : glyphs.iter().map(|g| ShapedGlyph {
+            id : g.id,
+            scale : g.scale,
+            position : g.position,
+            font : g.font.uid,
+            color : g.color,
+        }).collect(),
+        width,
+        height,
+        last_used : Instant::now(),
+    });
+    (glyphs, (width, height))
+}
+
 pub fn draw_font_with<T>(target : &mut T, xform: Transform, to_draw : &[CGlyph],
     mut draw: impl FnMut(&mut T, &tiny_skia::Path, Color),
     mut draw_img: impl FnMut(&mut T, Transform, OwnedImage),
 ) {
     for &CGlyph { id, scale, position, font, color } in to_draw {
-        struct Draw(tiny_skia::PathBuilder);
-        let mut path = Draw(tiny_skia::PathBuilder::new());
-        impl ttf_parser::OutlineBuilder for Draw {
-            fn move_to(&mut self, x: f32, y: f32) {
-                self.0.move_to(x,-y);
-            }
-            fn line_to(&mut self, x: f32, y: f32) {
-                self.0.line_to(x, -y);
-            }
-            fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
-                self.0.quad_to(x1, -y1, x, -y);
-            }
-            fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
-                self.0.cubic_to(x1, -y1, x2, -y2, x, -y);
-            }
-            fn close(&mut self) {
-                self.0.close();
-            }
-        }
-        if let Some(_bounds) = font.as_ref().outline_glyph(id, &mut path) {
+        if font.as_ref().glyph_raster_image(id, u16::MAX).is_none() && font.as_ref().glyph_svg_image(id).is_none() {
             let xform = xform.pre_translate(position.0, position.1);
             let xform = xform.pre_scale(scale, scale);
-            if let Some(path) = path.0.finish().and_then(|p| p.transform(xform)) {
+            if let Some(path) = font.outline_glyph(id, xform) {
                 draw(target, &path, color);
             }
             continue;
@@ -223,11 +317,14 @@ pub fn draw_font_with<T>(target : &mut T, xform: Transform, to_draw : &[CGlyph],
 }
 
 pub fn render_font(ctx: &mut Render, start: (f32, f32), text: &str, markup: bool) -> (f32, f32) {
-    let (mut to_draw, size) = layout_font(ctx.font, ctx.font_size, ctx.runtime, ctx.font_color, text, markup);
+    let (mut to_draw, size) = shape_font(ctx, text, markup);
     let clip_w = ctx.render_extents.1.x - ctx.render_pos.x;
     if size.1 > clip_w {
         to_draw.retain(|glyph| glyph.position.0 < clip_w);
     }
+    if ctx.measure_only {
+        return size;
+    }
     let xform = ctx.render_xform.pre_translate(start.0, start.1);
     draw_font_with(ctx.canvas, xform, &to_draw, |canvas,path,color| {
         let paint = tiny_skia::Paint {
@@ -235,14 +332,13 @@ pub fn render_font(ctx: &mut Render, start: (f32, f32), text: &str, markup: bool
             anti_alias: true,
             ..tiny_skia::Paint::default()
         };
-        canvas.fill_path(&path, &paint, tiny_skia::FillRule::EvenOdd, Transform::identity(), None);
+        canvas.fill_path(path, &paint, tiny_skia::FillRule::EvenOdd, Transform::identity());
     }, |canvas,xform,img| {
         canvas.draw_pixmap(
             0, 0,
-            img.0.as_ref(),
+            Arc::new(img.0),
             &tiny_skia::PixmapPaint::default(),
-            xform,
-            None);
+            xform);
     });
     size
 }
@@ -255,9 +351,12 @@ pub struct RenderKey {
 
     font : UID,
     font_size_millipt : u32,
+    line_spacing_milli : u32,
     font_color : u32,
     text_stroke : Option<u32>,
     text_stroke_size_milli : Option<u32>,
+    /// Color, x and y offset, and blur (in thousandths of a pixel)
+    text_shadow : Option<(u32, i32, i32, u32)>,
 
     text: String,
 }
@@ -272,7 +371,9 @@ pub struct TextImage {
     width: f32,
     height: f32,
     y_offset_centipixel: u8,
-    pixmap: tiny_skia::Pixmap,
+    /// The margin around the text in the pixmap; see [PIXMAP_MARGIN]
+    margin: i32,
+    pixmap: Arc<tiny_skia::Pixmap>,
     pub last_used: Instant,
 }
 
@@ -291,10 +392,17 @@ impl RenderKey {
             scale,
             font: ctx.font.uid,
             font_size_millipt: (ctx.font_size * 1000.0).round() as u32,
+            line_spacing_milli: (ctx.line_spacing * 1000.0).round() as u32,
             font_color: ctx.font_color.to_color_u8().get(),
 
             text_stroke: ctx.text_stroke.map(|c| c.to_color_u8().get()),
             text_stroke_size_milli,
+            text_shadow: ctx.text_shadow.map(|s| (
+                s.color.to_color_u8().get(),
+                (s.offset.0 * 1000.0).round() as i32,
+                (s.offset.1 * 1000.0).round() as i32,
+                (s.blur * 1000.0).round() as u32,
+            )),
 
             text: text.into(),
         })
@@ -302,11 +410,84 @@ impl RenderKey {
 }
 
 
+/// Blur a single-channel image in one direction using a box filter of radius `r`
+fn box_blur(src : &[u8], dst : &mut [u8], (width, height) : (usize, usize), r : usize, horizontal : bool) {
+    let (lines, len, step, line_step) = if horizontal {
+        (height, width, 1, width)
+    } else {
+        (width, height, width, 1)
+    };
+    let div = (2 * r + 1) as u32;
+    for line in 0..lines {
+        let base = line * line_step;
+        let get = |i : isize| if i < 0 || i >= len as isize { 0 } else { src[base + i as usize * step] as u32 };
+        let mut sum : u32 = (0..=r as isize).map(get).sum();
+        for i in 0..len as isize {
+            dst[base + i as usize * step] = (sum / div) as u8;
+            sum += get(i + r as isize + 1);
+            sum -= get(i - r as isize);
+        }
+    }
+}
+
+/// Convert a mask of the text into a (possibly blurred) shadow of the given color
+fn fill_shadow(mask : &mut tiny_skia::Pixmap, color : Color, blur : f32) {
+    let size = (mask.width() as usize, mask.height() as usize);
+    let mut alpha : Vec<u8> = mask.data().chunks(4).map(|px| px[3]).collect();
+    // three box blurs approximate a gaussian blur with a standard deviation of about r
+    let r = (blur / 2.0).round() as usize;
+    if r > 0 {
+        let mut tmp = vec![0; alpha.len()];
+        for _ in 0..3 {
+            box_blur(&alpha, &mut tmp, size, r, true);
+            box_blur(&tmp, &mut alpha, size, r, false);
+        }
+    }
+    let c = color.premultiply().to_color_u8();
+    for (px, a) in mask.data_mut().chunks_mut(4).zip(alpha) {
+        let scale = |v : u8| ((v as u32 * a as u32 + 127) / 255) as u8;
+        px[0] = scale(c.red());
+        px[1] = scale(c.green());
+        px[2] = scale(c.blue());
+        px[3] = scale(c.alpha());
+    }
+}
+
+/// Shorten plain text to fit in the given width, replacing the removed part with an ellipsis
+pub fn ellipsize<'t>(ctx : &Render, text : Cow<'t, str>, width : f32) -> Cow<'t, str> {
+    let fits = |t : &str| shape_font(ctx, t, false).1.0 <= width;
+    if fits(&text) {
+        return text;
+    }
+    let bounds : Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    // find the longest prefix that fits along with the ellipsis
+    let (mut lo, mut hi) = (0, bounds.len().saturating_sub(1));
+    while lo < hi {
+        let mid = (lo + hi).div_ceil(2);
+        if fits(&format!("{}…", text[..bounds[mid]].trim_end())) {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    if lo == 0 {
+        return Cow::Borrowed("");
+    }
+    format!("{}…", text[..bounds[lo]].trim_end()).into()
+}
+
 pub fn render_font_item(ctx: &mut Render, text: &str, markup: bool) {
     if text.is_empty() {
         return;
     }
 
+    if ctx.measure_only {
+        let (_, (width, height)) = shape_font(ctx, text, markup);
+        ctx.render_pos.x += width;
+        ctx.render_pos.y += height;
+        return;
+    }
+
     let Point { x: xstart, y: ystart } = ctx.render_pos;
 
     let mut xform = ctx.render_xform.pre_translate(xstart, ystart);
@@ -343,13 +524,12 @@ pub fn render_font_item(ctx: &mut Render, text: &str, markup: bool) {
             y: -(ti.y_offset_centipixel as f32 / 100.0),
         } ];
         xform.map_points(&mut origin);
-        let draw_x = origin[0].x.round() as i32 - PIXMAP_MARGIN;
-        let draw_y = origin[0].y.round() as i32 - PIXMAP_MARGIN;
+        let draw_x = origin[0].x.round() as i32 - ti.margin;
+        let draw_y = origin[0].y.round() as i32 - ti.margin;
 
-        ctx.canvas.draw_pixmap(draw_x, draw_y, ti.pixmap.as_ref(),
+        ctx.canvas.draw_pixmap(draw_x, draw_y, ti.pixmap.clone(),
             &tiny_skia::PixmapPaint::default(),
-            tiny_skia::Transform::identity(),
-            None)?;
+            tiny_skia::Transform::identity());
 
         ti.last_used = Instant::now();
 
@@ -360,7 +540,7 @@ pub fn render_font_item(ctx: &mut Render, text: &str, markup: bool) {
         return;
     }
 
-    let (mut to_draw, (width, height)) = layout_font(ctx.font, ctx.font_size, &ctx.runtime, ctx.font_color, &text, markup);
+    let (mut to_draw, (width, height)) = shape_font(ctx, text, markup);
 
     if width > clip_w {
         to_draw.retain(|glyph| glyph.position.0 < clip_w);
@@ -402,10 +582,15 @@ pub fn render_font_item(ctx: &mut Render, text: &str, markup: bool) {
     ];
 
     xform.map_points(&mut bounding);
-    let xsize = bounding[1].x - bounding[0].x + (PIXMAP_MARGIN * 2) as f32;
-    let ysize = bounding[1].y - bounding[0].y + (PIXMAP_MARGIN * 2) as f32;
-    let draw_x = bounding[2].x.round() as i32 - PIXMAP_MARGIN;
-    let draw_y = bounding[2].y.round() as i32 - PIXMAP_MARGIN;
+    // leave room for the shadow to extend beyond the text
+    let margin = PIXMAP_MARGIN + ctx.text_shadow.map_or(0, |s| {
+        let extent = f32::max(s.offset.0.abs(), s.offset.1.abs()) + s.blur * 1.5;
+        (extent * xform.sx.max(xform.sy)).ceil() as i32
+    });
+    let xsize = bounding[1].x - bounding[0].x + (margin * 2) as f32;
+    let ysize = bounding[1].y - bounding[0].y + (margin * 2) as f32;
+    let draw_x = bounding[2].x.round() as i32 - margin;
+    let draw_y = bounding[2].y.round() as i32 - margin;
 
     let render_xform = tiny_skia::Transform {
         sx: xform.sx,
@@ -420,6 +605,29 @@ pub fn render_font_item(ctx: &mut Render, text: &str, markup: bool) {
         None => { log::debug!("Not rendering \"{text}\" ({xsize}, {ysize})"); return }
     };
 
+    if let Some(shadow) = ctx.text_shadow {
+        if let Some(mut mask) = tiny_skia::Pixmap::new(pixmap.width(), pixmap.height()) {
+            let mask_xform = render_xform.post_translate(shadow.offset.0 * xform.sx, shadow.offset.1 * xform.sy);
+            let paint = tiny_skia::Paint {
+                shader: tiny_skia::Shader::SolidColor(Color::BLACK),
+                anti_alias: true,
+                ..tiny_skia::Paint::default()
+            };
+            draw_font_with(&mut mask, mask_xform, &to_draw, |canvas, path, _| {
+                canvas.fill_path(path, &paint, tiny_skia::FillRule::EvenOdd, Transform::identity(), None);
+            }, |canvas,xform,img| {
+                canvas.draw_pixmap(
+                    0, 0,
+                    img.0.as_ref(),
+                    &tiny_skia::PixmapPaint::default(),
+                    xform,
+                    None);
+            });
+            fill_shadow(&mut mask, shadow.color, shadow.blur * xform.sx);
+            pixmap.draw_pixmap(0, 0, mask.as_ref(), &tiny_skia::PixmapPaint::default(), Transform::identity(), None);
+        }
+    }
+
     if let Some(rgba) = ctx.text_stroke {
         let stroke_paint = tiny_skia::Paint {
             shader: tiny_skia::Shader::SolidColor(rgba),
@@ -465,16 +673,17 @@ pub fn render_font_item(ctx: &mut Render, text: &str, markup: bool) {
         });
     }
 
-    ctx.canvas.draw_pixmap(draw_x, draw_y, pixmap.as_ref(),
+    let pixmap = Arc::new(pixmap);
+    ctx.canvas.draw_pixmap(draw_x, draw_y, pixmap.clone(),
         &tiny_skia::PixmapPaint::default(),
-        tiny_skia::Transform::identity(),
-        None);
+        tiny_skia::Transform::identity());
 
     if let Some(key) = key {
         ctx.cache.text.borrow_mut().insert(key, TextImage {
             width,
             height,
             y_offset_centipixel,
+            margin,
             pixmap,
             last_used: Instant::now(),
         });
@@ -0,0 +1,92 @@
+//! Render the bars to PNG files without connecting to a compositor
+//!
+//! This is useful for screenshots of a configuration and for comparing the output of the render
+//! pipeline against known-good images.
+use crate::bar::{self,BarStyle};
+use crate::item::Item;
+use crate::render;
+use crate::state::Runtime;
+use log::error;
+use std::error::Error;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use tiny_skia::Pixmap;
+
+/// Settings for [render]
+#[derive(Debug,Clone)]
+pub struct Options {
+    /// Directory where the images are written
+    pub dir : PathBuf,
+    /// Width of each bar in logical pixels; the height comes from the bar's `size`
+    pub width : u32,
+    /// The output scale; the images are this many times larger than the logical size
+    pub scale : i32,
+    /// Time to let data sources update before the final render
+    pub delay : Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            dir : ".".into(),
+            width : 1920,
+            scale : 1,
+            delay : Duration::from_secs(1),
+        }
+    }
+}
+
+/// Render every bar in the configuration once and write the images to `bar-N.png`
+pub(crate) async fn render_once(opts : Options) -> Result<(), Box<dyn Error>> {
+    let mut runtime = Runtime::new(None)?;
+    let config = runtime.load_config()?;
+    for (i, pixmap) in render_bars(runtime, config, &opts).await.into_iter().enumerate() {
+        let path = opts.dir.join(format!("bar-{}.png", i));
+        match pixmap {
+            Some(pixmap) => {
+                pixmap.save_png(&path).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+                println!("{}", path.display());
+            }
+            None => error!("Bar {} has an empty size", i),
+        }
+    }
+    Ok(())
+}
+
+/// Render every bar in the given configuration file once, returning one image per bar
+///
+/// The bars are rendered in the order they are defined; a bar with an empty size has no image.
+/// This must be called from within a [tokio::task::LocalSet].
+pub async fn render(config : PathBuf, opts : &Options) -> Result<Vec<Option<Pixmap>>, Box<dyn Error>> {
+    let mut runtime = Runtime::new(None)?;
+    let config = runtime.load_config_from(config)?;
+    Ok(render_bars(runtime, config, opts).await)
+}
+
+async fn render_bars(mut runtime : Runtime, config : Vec<toml::Value>, opts : &Options) -> Vec<Option<Pixmap>> {
+    let mut bars : Vec<_> = config.into_iter()
+        .map(|mut cfg| {
+            if let Some(table) = cfg.as_table_mut() {
+                table.entry("name").or_insert_with(|| "headless".into());
+            }
+            let height = bar::config_size(&cfg);
+            let style = BarStyle::from_toml(&cfg, bar::config_anchor_top(&cfg));
+            (Rc::new(Item::new_bar(cfg)), style, height)
+        })
+        .collect();
+
+    // The first render starts any sources that only begin updating when they are read
+    runtime.begin_frame();
+    for (item, style, height) in &mut bars {
+        bar::draw(&mut runtime, item, style, (opts.width, *height), opts.scale);
+    }
+    tokio::time::sleep(opts.delay).await;
+
+    runtime.begin_frame();
+    let frames = bars.iter_mut().enumerate().map(|(i, (item, style, height))| {
+        let (canvas, _) = bar::draw(&mut runtime, item, style, (opts.width, *height), opts.scale);
+        (i, canvas)
+    }).collect();
+    render::rasterize(frames).into_iter().map(|(_, pixmap)| pixmap).collect()
+}
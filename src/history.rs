@@ -0,0 +1,89 @@
+//! Recent values of numeric keys, for smoothing and rates in text expansions
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Number of samples kept for each key
+pub const MAX_SAMPLES : usize = 64;
+
+/// A computation on the recent values of a key, selected by a suffix on the key name
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Op {
+    /// The value before the current one
+    Prev,
+    /// The change since the previous value
+    Delta,
+    /// The change per second since the previous value
+    Rate,
+    Avg(usize),
+    Min(usize),
+    Max(usize),
+}
+
+impl Op {
+    pub fn parse(suffix : &str) -> Option<Self> {
+        let count = |prefix| suffix.strip_prefix(prefix)
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|&n| n > 0)
+            .map(|n| n.min(MAX_SAMPLES));
+        match suffix {
+            "prev" => Some(Op::Prev),
+            "delta" => Some(Op::Delta),
+            "rate" => Some(Op::Rate),
+            _ => None,
+        }.or_else(|| count("avg").map(Op::Avg))
+        .or_else(|| count("min").map(Op::Min))
+        .or_else(|| count("max").map(Op::Max))
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct History {
+    samples : VecDeque<(Instant, f64)>,
+    /// The data update that produced the newest sample
+    seq : u64,
+}
+
+impl History {
+    /// Add a sample for the given data update.  Reading the same key again before the next update
+    /// replaces the sample instead of adding another one.
+    pub fn record(&mut self, seq : u64, value : f64) {
+        if self.seq == seq && !self.samples.is_empty() {
+            self.samples.back_mut().unwrap().1 = value;
+            return;
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((Instant::now(), value));
+        self.seq = seq;
+    }
+
+    pub fn eval(&self, op : Op) -> Option<f64> {
+        let mut newest = self.samples.iter().rev();
+        match op {
+            Op::Prev => newest.nth(1).map(|s| s.1),
+            Op::Delta => {
+                let (cur, prev) = (newest.next()?, newest.next()?);
+                Some(cur.1 - prev.1)
+            }
+            Op::Rate => {
+                let (cur, prev) = (newest.next()?, newest.next()?);
+                let secs = (cur.0 - prev.0).as_secs_f64();
+                if secs > 0.0 {
+                    Some((cur.1 - prev.1) / secs)
+                } else {
+                    None
+                }
+            }
+            Op::Avg(n) => {
+                let len = self.samples.len().min(n);
+                if len == 0 {
+                    return None;
+                }
+                Some(newest.take(n).map(|s| s.1).sum::<f64>() / len as f64)
+            }
+            Op::Min(n) => newest.take(n).map(|s| s.1).reduce(f64::min),
+            Op::Max(n) => newest.take(n).map(|s| s.1).reduce(f64::max),
+        }
+    }
+}
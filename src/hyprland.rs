@@ -0,0 +1,111 @@
+//! Hyprland IPC
+use crate::util::spawn;
+use log::warn;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path,PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt,AsyncReadExt,AsyncWriteExt,BufReader,Lines};
+use tokio::net::UnixStream;
+
+fn socket_dir() -> Option<PathBuf> {
+    let sig = std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE")?;
+    let mut dir = PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR").unwrap_or_default());
+    dir.push("hypr");
+    dir.push(&sig);
+    if !dir.exists() {
+        // older versions of hyprland put their sockets in /tmp
+        dir = PathBuf::from("/tmp/hypr");
+        dir.push(&sig);
+    }
+    Some(dir)
+}
+
+pub fn is_running() -> bool {
+    std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some()
+}
+
+/// Send a request on the command socket and return the reply
+async fn request(cmd : &str) -> std::io::Result<String> {
+    let dir = socket_dir().ok_or(std::io::ErrorKind::NotFound)?;
+    let run = async {
+        let mut sock = UnixStream::connect(dir.join(".socket.sock")).await?;
+        sock.write_all(cmd.as_bytes()).await?;
+        let mut rv = String::new();
+        sock.read_to_string(&mut rv).await?;
+        Ok(rv)
+    };
+    tokio::time::timeout(Duration::from_secs(1), run).await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+}
+
+/// Connect to the event socket, which sends one line per event
+async fn events(dir : &Path) -> std::io::Result<Lines<BufReader<UnixStream>>> {
+    let sock = UnixStream::connect(dir.join(".socket2.sock")).await?;
+    Ok(BufReader::new(sock).lines())
+}
+
+/// Call the function with the name of the focused output, and again whenever it changes
+pub fn watch_focused_output(mut f : impl FnMut(&str) + 'static) {
+    spawn("Hyprland focus", async move {
+        let dir = socket_dir().ok_or("HYPRLAND_INSTANCE_SIGNATURE is not set")?;
+
+        let monitors = json::parse(&request("j/monitors").await?)?;
+        for mon in monitors.members() {
+            if mon["focused"].as_bool() == Some(true) {
+                if let Some(name) = mon["name"].as_str() {
+                    f(name);
+                }
+            }
+        }
+
+        let mut events = events(&dir).await?;
+        while let Some(line) = events.next_line().await? {
+            // focusedmon>>MONITOR,WORKSPACE
+            if let Some(rest) = line.strip_prefix("focusedmon>>") {
+                f(rest.split(',').next().unwrap_or(rest));
+            }
+        }
+        Err("Hyprland event socket closed".into())
+    });
+}
+
+/// Find the outputs whose active workspace has a fullscreen window
+async fn fullscreen_outputs() -> Result<HashSet<String>, Box<dyn Error>> {
+    let monitors = json::parse(&request("j/monitors").await?)?;
+    let workspaces = json::parse(&request("j/workspaces").await?)?;
+    let mut rv = HashSet::new();
+    for mon in monitors.members() {
+        let active = mon["activeWorkspace"]["id"].as_i64();
+        let full = workspaces.members()
+            .any(|ws| ws["id"].as_i64() == active && ws["hasfullscreen"].as_bool() == Some(true));
+        if let (true, Some(name)) = (full, mon["name"].as_str()) {
+            rv.insert(name.to_owned());
+        }
+    }
+    Ok(rv)
+}
+
+/// Call the function with the names of the outputs showing a fullscreen window, and again after
+/// any event that could change that set
+pub fn watch_fullscreen(mut f : impl FnMut(HashSet<String>) + 'static) {
+    spawn("Hyprland fullscreen", async move {
+        let dir = socket_dir().ok_or("HYPRLAND_INSTANCE_SIGNATURE is not set")?;
+
+        f(fullscreen_outputs().await?);
+
+        let mut events = events(&dir).await?;
+        while let Some(line) = events.next_line().await? {
+            let event = line.split(">>").next().unwrap_or_default();
+            if matches!(event, "fullscreen" | "workspace" | "focusedmon" | "moveworkspace"
+                | "openwindow" | "closewindow" | "movewindow")
+            {
+                match fullscreen_outputs().await {
+                    Ok(outputs) => f(outputs),
+                    Err(e) => warn!("Could not query hyprland: {}", e),
+                }
+            }
+        }
+        Err("Hyprland event socket closed".into())
+    });
+}
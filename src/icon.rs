@@ -1,13 +1,41 @@
+//! Loading and drawing icons and images
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::{self,File};
 use std::io;
-use std::path::{PathBuf,Component};
+use std::path::{Path,PathBuf,Component};
+use std::rc::Rc;
+use std::sync::Arc;
 use crate::render::Render;
+use crate::state::Runtime;
 use tiny_skia::Transform;
 
 thread_local! {
-    static CACHE : RefCell<HashMap<(String, u32), Option<OwnedImage>>> = Default::default();
+    static CACHE : RefCell<HashMap<(String, u32), Option<Arc<tiny_skia::Pixmap>>>> = Default::default();
+    static DESKTOP_ICONS : RefCell<HashMap<String, Option<Rc<str>>>> = Default::default();
+    static BACKGROUNDS : RefCell<HashMap<(String, ImageFit, u32, u32), Option<Arc<tiny_skia::Pixmap>>>> = Default::default();
+}
+
+/// How a background image is fitted to the item
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum ImageFit {
+    /// Scale the image to exactly cover the background
+    Stretch,
+    /// Scale the image to fit inside the background, keeping its aspect ratio
+    Fit,
+    /// Repeat the image at its natural size
+    Tile,
+}
+
+impl ImageFit {
+    pub fn from_str(v : &str) -> Option<Self> {
+        match v {
+            "stretch" | "scale" => Some(ImageFit::Stretch),
+            "fit" => Some(ImageFit::Fit),
+            "tile" | "repeat" => Some(ImageFit::Tile),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -26,6 +54,7 @@ impl OwnedImage {
 
     pub fn from_data(buf : &[u8], tsize : u32) -> Option<Self> {
         Self::from_png(buf)
+            .or_else(|| Self::from_jpeg(buf))
             .or_else(|| Self::from_svg(buf, tsize))
     }
 
@@ -59,6 +88,28 @@ impl OwnedImage {
         Some(Self(pixmap))
     }
 
+    pub fn from_jpeg(data : &[u8]) -> Option<Self> {
+        let mut jpeg = jpeg_decoder::Decoder::new(data);
+        let image = jpeg.decode().ok()?;
+        let info = jpeg.info()?;
+        let mut pixmap = tiny_skia::Pixmap::new(info.width as u32, info.height as u32)?;
+        let step = info.pixel_format.pixel_bytes();
+        for (src, pixel) in image.chunks(step).zip(pixmap.pixels_mut()) {
+            let c = match info.pixel_format {
+                jpeg_decoder::PixelFormat::L8 => tiny_skia::ColorU8::from_rgba(src[0], src[0], src[0], 255),
+                jpeg_decoder::PixelFormat::L16 => tiny_skia::ColorU8::from_rgba(src[0], src[0], src[0], 255),
+                jpeg_decoder::PixelFormat::RGB24 => tiny_skia::ColorU8::from_rgba(src[0], src[1], src[2], 255),
+                jpeg_decoder::PixelFormat::CMYK32 => {
+                    let k = 255 - src[3] as u32;
+                    let ch = |c : u8| ((255 - c as u32) * k / 255) as u8;
+                    tiny_skia::ColorU8::from_rgba(ch(src[0]), ch(src[1]), ch(src[2]), 255)
+                }
+            };
+            *pixel = c.premultiply();
+        }
+        Some(Self(pixmap))
+    }
+
     pub fn from_svg(data : &[u8], height : u32) -> Option<Self> {
         let tree = usvg::Tree::from_data(data, &usvg::Options::default().to_ref()).ok()?;
         let svg_width = tree.svg_node().size.width();
@@ -70,9 +121,159 @@ impl OwnedImage {
     }
 }
 
+/// Read the Icon and StartupWMClass keys of a desktop entry
+fn read_desktop_entry(path : &Path) -> Option<(Option<String>, Option<String>)> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut in_entry = false;
+    let mut icon = None;
+    let mut wm_class = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_entry = line == "[Desktop Entry]";
+        } else if !in_entry {
+            continue;
+        } else if let Some(value) = line.strip_prefix("Icon=") {
+            icon = Some(value.trim().to_owned());
+        } else if let Some(value) = line.strip_prefix("StartupWMClass=") {
+            wm_class = Some(value.trim().to_owned());
+        }
+    }
+    Some((icon, wm_class))
+}
+
+/// Find the icon name for an application using its desktop entry.
+///
+/// The app_id (or X11 class) is matched against the name of the desktop file and then against
+/// the StartupWMClass key of all desktop files.  Results are cached.
+pub fn desktop_icon(xdg : &xdg::BaseDirectories, appid : &str) -> Option<Rc<str>> {
+    if appid.is_empty() {
+        return None;
+    }
+    if let Some(rv) = DESKTOP_ICONS.with(|c| c.borrow().get(appid).cloned()) {
+        return rv;
+    }
+
+    let lower = appid.to_lowercase();
+    let mut rv = None;
+    for name in [appid, &lower] {
+        if let Some(path) = xdg.find_data_file(format!("applications/{}.desktop", name)) {
+            rv = read_desktop_entry(&path).and_then(|e| e.0);
+            if rv.is_some() {
+                break;
+            }
+        }
+    }
+    if rv.is_none() {
+        rv = xdg.list_data_files("applications")
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|e| e == "desktop"))
+            .filter_map(|path| read_desktop_entry(&path))
+            .find(|(_, class)| class.as_ref().is_some_and(|c| c.eq_ignore_ascii_case(appid)))
+            .and_then(|e| e.0);
+    }
+
+    let rv : Option<Rc<str>> = rv.filter(|i| !i.is_empty()).map(Into::into);
+    DESKTOP_ICONS.with(|c| c.borrow_mut().insert(appid.to_owned(), rv.clone()));
+    rv
+}
+
+/// Get a background image of the given size (in real pixels).
+///
+/// Relative paths are found in the same directory as the configuration file.
+pub fn background(rt : &Runtime, path : &str, fit : ImageFit, width : u32, height : u32) -> Option<Arc<tiny_skia::Pixmap>> {
+    let key = (path.to_owned(), fit, width, height);
+    if let Some(rv) = BACKGROUNDS.with(|c| c.borrow().get(&key).cloned()) {
+        return rv;
+    }
+
+    let src = rt.find_config_file(path)
+        .and_then(|path| File::open(&path).ok())
+        .and_then(|file| OwnedImage::from_file(file, height));
+    if src.is_none() {
+        log::warn!("Could not load background image {}", path);
+    }
+    let rv = src.and_then(|src| {
+        let mut pixmap = tiny_skia::Pixmap::new(width, height)?;
+        let (sw, sh) = (src.0.width() as f32, src.0.height() as f32);
+        let (w, h) = (width as f32, height as f32);
+        let paint = tiny_skia::PixmapPaint {
+            quality : tiny_skia::FilterQuality::Bilinear,
+            ..Default::default()
+        };
+        match fit {
+            ImageFit::Stretch => {
+                let xform = Transform::from_scale(w / sw, h / sh);
+                pixmap.draw_pixmap(0, 0, src.as_ref(), &paint, xform, None);
+            }
+            ImageFit::Fit => {
+                let scale = f32::min(w / sw, h / sh);
+                let xform = Transform::from_scale(scale, scale)
+                    .post_translate((w - sw * scale) / 2.0, (h - sh * scale) / 2.0);
+                pixmap.draw_pixmap(0, 0, src.as_ref(), &paint, xform, None);
+            }
+            ImageFit::Tile => {
+                let paint = tiny_skia::Paint {
+                    shader : tiny_skia::Pattern::new(
+                        src.as_ref(),
+                        tiny_skia::SpreadMode::Repeat,
+                        tiny_skia::FilterQuality::Nearest,
+                        1.0,
+                        Transform::identity(),
+                    ),
+                    ..Default::default()
+                };
+                let rect = tiny_skia::Rect::from_xywh(0.0, 0.0, w, h)?;
+                pixmap.fill_rect(rect, &paint, Transform::identity(), None);
+            }
+        }
+        Some(Arc::new(pixmap))
+    });
+
+    BACKGROUNDS.with(|c| {
+        let mut cache = c.borrow_mut();
+        // items with changing sizes would otherwise fill the cache
+        if cache.len() > 64 {
+            cache.clear();
+        }
+        cache.insert(key, rv.clone());
+    });
+    rv
+}
+
+/// Find the file for an icon directly in a directory, trying the svg and png extensions
+fn icon_file(mut path : PathBuf, name : &str) -> Option<PathBuf> {
+    for ext in ["svg", "png"] {
+        path.push(format!("{}.{}", name, ext));
+        if path.is_file() {
+            return Some(path);
+        }
+        path.pop();
+    }
+    path.push(name);
+    path.is_file().then_some(path)
+}
+
+/// Find an icon in a directory supplied along with the icon name (like a tray item's
+/// IconThemePath), which may hold the files directly or be laid out like an icon theme
+fn icon_in_dir(dir : &Path, name : &str, target_size : f32) -> Option<PathBuf> {
+    let f = |path : PathBuf| icon_file(path, name);
+    f(dir.to_owned())
+        .or_else(|| iter_icons(&dir.join("hicolor"), target_size, f).ok().flatten())
+        .or_else(|| iter_icons(&dir.to_owned(), target_size, f).ok().flatten())
+}
+
 fn open_icon(xdg : &xdg::BaseDirectories, name : &str, target_size : f32) -> io::Result<PathBuf> {
     if name.contains('/') {
-        return Ok(PathBuf::from(name.to_owned()));
+        let path = PathBuf::from(name);
+        if path.is_file() {
+            return Ok(path);
+        }
+        let found = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+            (Some(dir), Some(name)) => icon_in_dir(dir, name, target_size),
+            _ => None,
+        };
+        return Ok(found.unwrap_or(path));
     }
 
     // return paths in order from highest to lowest priority, unlike how the xdg crate does it
@@ -82,24 +283,7 @@ fn open_icon(xdg : &xdg::BaseDirectories, name : &str, target_size : f32) -> io:
         dirs.into_iter().rev()
     };
 
-    let f = |mut path: PathBuf| {
-        path.push(name);
-        path.set_extension("svg");
-        match File::open(&path) {
-            Ok(_) => {
-                return Some(path);
-            }
-            Err(_) => {}
-        }
-        path.set_extension("png");
-        match File::open(&path) {
-            Ok(_) => {
-                return Some(path);
-            }
-            Err(_) => {}
-        }
-        None
-    };
+    let f = |path : PathBuf| icon_file(path, name);
 
     for path in find_data("pixmaps") {
         match f(path) {
@@ -195,24 +379,26 @@ pub fn render(ctx : &mut Render, name : &str) -> Result<(), ()> {
                     None
                 })
                 .and_then(|file| OwnedImage::from_file(file, tsize))
+                .map(|img| Arc::new(img.0))
             })
         {
             Some(img) => {
-                let xscale = xsize / img.0.width() as f32;
-                let yscale = ysize / img.0.height() as f32;
+                let xscale = xsize / img.width() as f32;
+                let yscale = ysize / img.height() as f32;
                 let scale = f32::min(xscale, yscale);
                 // resize using real pixels
                 let img_xform = Transform::from_scale(scale, scale)
                     .post_translate(extent_points[0].x, extent_points[0].y);
-                ctx.canvas.draw_pixmap(
-                    0, 0,
-                    img.as_ref(),
-                    &Default::default(),
-                    img_xform,
-                    None);
+                if !ctx.measure_only {
+                    ctx.canvas.draw_pixmap(
+                        0, 0,
+                        img.clone(),
+                        &Default::default(),
+                        img_xform);
+                }
                 // convert the sizes back to sclaed pixels (inverse xform)
-                ctx.render_pos.x += img.0.width() as f32 * scale / xform.sx;
-                ctx.render_pos.y += img.0.height() as f32 * scale / xform.sy;
+                ctx.render_pos.x += img.width() as f32 * scale / xform.sx;
+                ctx.render_pos.y += img.height() as f32 * scale / xform.sy;
                 Ok(())
             }
             None => Err(()),
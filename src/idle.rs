@@ -0,0 +1,193 @@
+//! Idle state from logind and the screensaver, and the programs inhibiting idle
+use crate::data::{Periodic,Value};
+use crate::dbus::{DBus,typed};
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,toml_to_f64};
+use log::{debug,error};
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+use std::time::{SystemTime,UNIX_EPOCH};
+use zbus::zvariant::{OwnedFd,OwnedValue};
+
+const LOGIND : &str = "org.freedesktop.login1";
+const LOGIND_PATH : &str = "/org/freedesktop/login1";
+const MANAGER_IFACE : &str = "org.freedesktop.login1.Manager";
+/// The session of the caller
+const SESSION_PATH : &str = "/org/freedesktop/login1/session/auto";
+const SESSION_IFACE : &str = "org.freedesktop.login1.Session";
+const SCREENSAVER : &str = "org.freedesktop.ScreenSaver";
+
+#[derive(Debug,Clone,PartialEq)]
+struct Inhibitor {
+    who : String,
+    why : String,
+}
+
+#[derive(Debug,Default,PartialEq)]
+struct Status {
+    inhibitors : Vec<Inhibitor>,
+    /// Seconds since the session became idle
+    idle : Option<f64>,
+    locked : Option<bool>,
+}
+
+#[derive(Debug)]
+struct State {
+    lock_after : Option<f64>,
+    status : Cell<Status>,
+    /// The bar's own idle inhibitor, held open until released
+    own : Cell<Option<OwnedFd>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl State {
+    async fn query(&self) -> Result<Status, Box<dyn Error>> {
+        let zbus = DBus::get_system().connection().await;
+        let reply = zbus.call_method(Some(LOGIND), LOGIND_PATH, Some(MANAGER_IFACE), "ListInhibitors", &()).await?;
+        let list : Vec<(String, String, String, String, u32, u32)> = reply.body()?;
+        let inhibitors = list.into_iter()
+            .filter(|(what, ..)| what.split(':').any(|w| w == "idle"))
+            .map(|(_, who, why, ..)| Inhibitor { who, why })
+            .collect();
+
+        let reply = zbus.call_method(Some(LOGIND), SESSION_PATH, Some("org.freedesktop.DBus.Properties"), "GetAll", &(SESSION_IFACE,)).await?;
+        let props : HashMap<String, OwnedValue> = reply.body()?;
+        let prop = |key : &str| props.get(key).map(|v| &**v);
+        let locked = prop("LockedHint").and_then(|v| typed::<bool>(v, "LockedHint"));
+        let idle = match prop("IdleHint").and_then(|v| typed::<bool>(v, "IdleHint")) {
+            Some(true) => {
+                let since = prop("IdleSinceHint").and_then(|v| typed::<u64>(v, "IdleSinceHint")).unwrap_or(0);
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+                Some(now.saturating_sub(since) as f64 / 1e6)
+            }
+            Some(false) => Some(0.0),
+            None => None,
+        };
+        Ok(Status { inhibitors, idle, locked })
+    }
+
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let status = match self.query().await {
+            Ok(status) => status,
+            Err(e) => {
+                debug!("Could not read the idle state from logind: {}", e);
+                Status::default()
+            }
+        };
+        if self.status.take_in(|s| *s != status) {
+            self.status.set(status);
+            self.interested.take().notify_data("idle");
+        }
+        self.running.set(false);
+    }
+
+    /// Reset the idle timers, as if the user had pressed a key
+    async fn activity(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        let session = DBus::get_session().connection().await;
+        if let Err(e) = session.call_method(Some(SCREENSAVER), "/org/freedesktop/ScreenSaver", Some(SCREENSAVER), "SimulateUserActivity", &()).await {
+            debug!("Could not simulate activity: {}", e);
+        }
+        let system = DBus::get_system().connection().await;
+        system.call_method(Some(LOGIND), SESSION_PATH, Some(SESSION_IFACE), "SetIdleHint", &(false,)).await?;
+        self.refresh().await;
+        Ok(())
+    }
+
+    async fn inhibit(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        if self.own.take_in(|o| o.is_some()) {
+            return Ok(());
+        }
+        let zbus = DBus::get_system().connection().await;
+        let reply = zbus.call_method(Some(LOGIND), LOGIND_PATH, Some(MANAGER_IFACE), "Inhibit",
+            &("idle", "rwaybar", "Requested from the bar", "block")).await?;
+        self.own.set(Some(reply.body()?));
+        self.refresh().await;
+        Ok(())
+    }
+
+    async fn release(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        // closing the file descriptor ends the inhibitor
+        self.own.set(None);
+        self.refresh().await;
+        Ok(())
+    }
+}
+
+/// The "idle" module
+#[derive(Debug)]
+pub struct Idle {
+    poll : Periodic<Rc<State>>,
+}
+
+impl Idle {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let state = State {
+            lock_after : toml_to_f64(config.get("lock-after")),
+            status : Cell::default(),
+            own : Cell::new(None),
+            running : Cell::new(false),
+            interested : Cell::default(),
+        };
+        Idle {
+            poll : Periodic::new(toml_to_f64(config.get("poll")).unwrap_or(5.0), Rc::new(state)),
+        }
+    }
+}
+
+impl Source for Idle {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.poll.read_refresh_async(rt, |state| state.clone().refresh());
+        let state = self.poll.data();
+        state.interested.take_in(|i| i.add(rt));
+        let inhibiting = state.own.take_in(|o| o.is_some());
+        state.status.take_in(|status| match key {
+            "" | "text" => {
+                let mut who : Vec<_> = status.inhibitors.iter().map(|i| &*i.who).collect();
+                who.sort();
+                who.dedup();
+                Value::Owned(who.join(", "))
+            }
+            "inhibited" => Value::Bool(!status.inhibitors.is_empty()),
+            "inhibitors" => Value::Float(status.inhibitors.len() as f64),
+            "inhibitor-list" => {
+                let lines : Vec<_> = status.inhibitors.iter().map(|i| format!("{}: {}", i.who, i.why)).collect();
+                Value::Owned(lines.join("\n"))
+            }
+            "inhibiting" => Value::Bool(inhibiting),
+            "idle" => status.idle.map_or(Value::Null, Value::Float),
+            "locked" => status.locked.map_or(Value::Null, Value::Bool),
+            "lock-in" => match (state.lock_after, status.idle) {
+                // nothing will lock while inhibited
+                _ if !status.inhibitors.is_empty() => Value::Null,
+                (Some(after), Some(idle)) => Value::Float((after - idle).max(0.0)),
+                _ => Value::Null,
+            },
+            _ => Value::Null,
+        })
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
+        let state = self.poll.data().clone();
+        match (key, value.as_str_fast()) {
+            ("", "" | "activity") => util::spawn("Idle reset", state.activity()),
+            ("", "inhibit") => util::spawn("Idle inhibitor", state.inhibit()),
+            ("", "release" | "clear") => util::spawn("Idle inhibitor", state.release()),
+            ("", "toggle") => match state.own.take_in(|o| o.is_some()) {
+                true => util::spawn("Idle inhibitor", state.release()),
+                false => util::spawn("Idle inhibitor", state.inhibit()),
+            },
+            (key, value) => error!("Ignoring write of '{}' to {}.{}", value, name, key),
+        }
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.refresh();
+        true
+    }
+}
@@ -0,0 +1,335 @@
+//! Connected input devices and their batteries, read from sysfs and updated on hotplug
+use crate::data::Value;
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,spawn_handle};
+use futures_util::future::RemoteHandle;
+use log::debug;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd,FromRawFd,OwnedFd};
+use std::path::{Path,PathBuf};
+use std::rc::Rc;
+use tokio::io::unix::AsyncFd;
+
+const EV_KEY : usize = 1;
+const EV_REL : usize = 2;
+const EV_ABS : usize = 3;
+const REL_X : usize = 0;
+const REL_Y : usize = 1;
+const ABS_X : usize = 0;
+const ABS_Y : usize = 1;
+const KEY_Q : usize = 16;
+const KEY_P : usize = 25;
+const BTN_MOUSE : usize = 0x110;
+const BTN_JOYSTICK : usize = 0x120;
+const BTN_GAMEPAD : usize = 0x130;
+const BTN_TOOL_PEN : usize = 0x140;
+const BTN_TOOL_FINGER : usize = 0x145;
+const BTN_TOUCH : usize = 0x14a;
+const BTN_STYLUS : usize = 0x14b;
+const INPUT_PROP_DIRECT : usize = 1;
+
+const BUS_USB : u16 = 0x03;
+const BUS_BLUETOOTH : u16 = 0x05;
+const BUS_VIRTUAL : u16 = 0x06;
+
+/// Ordered so that a device with several input nodes (such as a tablet's pen and buttons) is
+/// shown as its most specific kind
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+enum Kind {
+    Tablet,
+    Touchscreen,
+    Touchpad,
+    Joystick,
+    Mouse,
+    Keyboard,
+    Other,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Tablet => "tablet",
+            Kind::Touchscreen => "touchscreen",
+            Kind::Touchpad => "touchpad",
+            Kind::Joystick => "joystick",
+            Kind::Mouse => "mouse",
+            Kind::Keyboard => "keyboard",
+            Kind::Other => "other",
+        }
+    }
+
+    fn from_name(name : &str) -> Option<Self> {
+        [Kind::Tablet, Kind::Touchscreen, Kind::Touchpad, Kind::Joystick, Kind::Mouse, Kind::Keyboard, Kind::Other]
+            .into_iter().find(|k| k.name() == name)
+    }
+}
+
+/// A capability bitmap from sysfs: hex words, most significant first
+struct Bits(Vec<u64>);
+
+impl Bits {
+    fn read(path : &Path) -> Self {
+        let text = fs::read_to_string(path).unwrap_or_default();
+        Bits(text.split_whitespace().rev().map(|w| u64::from_str_radix(w, 16).unwrap_or(0)).collect())
+    }
+
+    fn has(&self, bit : usize) -> bool {
+        self.0.get(bit / 64).is_some_and(|w| w & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// Classify an input node the way udev's input_id does, roughly
+fn classify(node : &Path) -> Kind {
+    let caps = node.join("capabilities");
+    let ev = Bits::read(&caps.join("ev"));
+    let key = Bits::read(&caps.join("key"));
+    let abs = Bits::read(&caps.join("abs"));
+    let rel = Bits::read(&caps.join("rel"));
+    let props = Bits::read(&node.join("properties"));
+
+    let pointer = ev.has(EV_ABS) && abs.has(ABS_X) && abs.has(ABS_Y);
+    if pointer && (key.has(BTN_STYLUS) || key.has(BTN_TOOL_PEN)) {
+        Kind::Tablet
+    } else if pointer && key.has(BTN_TOUCH) && props.has(INPUT_PROP_DIRECT) {
+        Kind::Touchscreen
+    } else if pointer && key.has(BTN_TOOL_FINGER) {
+        Kind::Touchpad
+    } else if key.has(BTN_JOYSTICK) || key.has(BTN_GAMEPAD) {
+        Kind::Joystick
+    } else if ev.has(EV_REL) && rel.has(REL_X) && rel.has(REL_Y) && key.has(BTN_MOUSE) {
+        Kind::Mouse
+    } else if ev.has(EV_KEY) && (KEY_Q..=KEY_P).all(|k| key.has(k)) {
+        Kind::Keyboard
+    } else {
+        Kind::Other
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct Device {
+    name : String,
+    kind : Kind,
+    builtin : bool,
+    /// Battery percentage
+    battery : Option<f64>,
+    charging : bool,
+    /// The parent of the device's input nodes and power supplies in /sys/devices
+    parent : PathBuf,
+}
+
+fn read_attr(dir : &Path, attr : &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr)).ok().map(|s| s.trim().to_owned())
+}
+
+/// The device owning a node in /sys/class, such as `.../0005:046D:B023.0003` for
+/// `.../0005:046D:B023.0003/input/input12`
+fn parent_of(class_entry : &Path) -> Option<PathBuf> {
+    Some(fs::canonicalize(class_entry).ok()?.parent()?.parent()?.to_owned())
+}
+
+fn scan() -> Vec<Device> {
+    let mut devices : Vec<Device> = Vec::new();
+    for entry in fs::read_dir("/sys/class/input").into_iter().flatten().flatten() {
+        // skip the event and mouse nodes, which repeat the inputN device
+        if !entry.file_name().to_string_lossy().starts_with("input") {
+            continue;
+        }
+        let node = entry.path();
+        let bus = read_attr(&node, "id/bustype").and_then(|b| u16::from_str_radix(&b, 16).ok()).unwrap_or(0);
+        if bus == BUS_VIRTUAL {
+            continue;
+        }
+        let parent = match parent_of(&node) {
+            Some(parent) => parent,
+            None => continue,
+        };
+        let kind = classify(&node);
+        let name = read_attr(&node, "name").unwrap_or_default();
+        match devices.iter_mut().find(|d| d.parent == parent) {
+            Some(dev) if kind < dev.kind => {
+                dev.kind = kind;
+                dev.name = name;
+            }
+            Some(_) => {}
+            None => devices.push(Device {
+                name,
+                kind,
+                builtin : bus != BUS_USB && bus != BUS_BLUETOOTH,
+                battery : None,
+                charging : false,
+                parent,
+            }),
+        }
+    }
+
+    for entry in fs::read_dir("/sys/class/power_supply").into_iter().flatten().flatten() {
+        let supply = entry.path();
+        // the batteries of peripherals, not of the computer
+        if read_attr(&supply, "scope").as_deref() != Some("Device") {
+            continue;
+        }
+        let parent = match parent_of(&supply) {
+            Some(parent) => parent,
+            None => continue,
+        };
+        if let Some(dev) = devices.iter_mut().find(|d| d.parent == parent) {
+            dev.battery = read_attr(&supply, "capacity").and_then(|c| c.parse().ok());
+            dev.charging = read_attr(&supply, "status").as_deref() == Some("Charging");
+        }
+    }
+    devices.sort_by(|a, b| a.kind.cmp(&b.kind).then_with(|| a.name.cmp(&b.name)));
+    devices
+}
+
+/// Open a socket receiving the kernel's device hotplug events
+fn uevent_socket() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK, libc::NETLINK_KOBJECT_UEVENT) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr : libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = 1;
+    let rv = unsafe {
+        libc::bind(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, std::mem::size_of_val(&addr) as libc::socklen_t)
+    };
+    if rv < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[derive(Debug)]
+struct State {
+    kinds : Option<Vec<Kind>>,
+    builtin : bool,
+    filter : Option<regex::Regex>,
+    devices : Cell<Vec<Device>>,
+    interested : Cell<NotifierList>,
+}
+
+impl State {
+    fn rescan(&self) {
+        let mut devices = scan();
+        devices.retain(|d| {
+            self.kinds.as_ref().map_or(d.kind != Kind::Other, |k| k.contains(&d.kind))
+                && (self.builtin || !d.builtin)
+                && self.filter.as_ref().is_none_or(|re| re.is_match(&d.name))
+        });
+        if self.devices.take_in(|d| *d != devices) {
+            self.devices.set(devices);
+            self.interested.take().notify_data("input-devices");
+        }
+    }
+
+    /// Scan again whenever an input device or its battery changes
+    async fn monitor(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        let afd = AsyncFd::new(uevent_socket()?)?;
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let mut guard = afd.readable().await?;
+            let mut changed = false;
+            loop {
+                let n = unsafe { libc::recv(afd.get_ref().as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+                if n < 0 {
+                    let e = io::Error::last_os_error();
+                    match e.kind() {
+                        io::ErrorKind::Interrupted => continue,
+                        io::ErrorKind::WouldBlock => break,
+                        _ => return Err(e.into()),
+                    }
+                }
+                // "ACTION@DEVPATH", then KEY=VALUE fields, separated by NULs
+                changed |= buf[..n as usize].split(|&c| c == 0)
+                    .any(|field| field == b"SUBSYSTEM=input" || field == b"SUBSYSTEM=power_supply");
+            }
+            guard.clear_ready();
+            if changed {
+                // a device adds several nodes at once
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                debug!("Input devices changed");
+                self.rescan();
+            }
+        }
+    }
+}
+
+/// The "input-devices" module
+#[derive(Debug)]
+pub struct InputDevices {
+    state : Rc<State>,
+    watch : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl InputDevices {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let kinds = match config.get("kind") {
+            None => None,
+            Some(toml::Value::String(s)) => Some(vec![s.as_str()]),
+            Some(toml::Value::Array(a)) => Some(a.iter().filter_map(|v| v.as_str()).collect()),
+            Some(v) => return Err(format!("Invalid kind: {}", v)),
+        };
+        let kinds = kinds.map(|kinds| kinds.into_iter()
+            .map(|k| Kind::from_name(k).ok_or_else(|| format!("Unknown input device kind '{}'", k)))
+            .collect::<Result<Vec<_>, _>>()).transpose()?;
+        let filter = config.get("match").and_then(|v| v.as_str())
+            .map(regex::Regex::new).transpose()
+            .map_err(|e| format!("Invalid match: {}", e))?;
+        Ok(InputDevices {
+            state : Rc::new(State {
+                kinds,
+                builtin : config.get("builtin").and_then(|v| v.as_bool()).unwrap_or(false),
+                filter,
+                devices : Cell::default(),
+                interested : Cell::default(),
+            }),
+            watch : Cell::default(),
+        })
+    }
+}
+
+impl Source for InputDevices {
+    fn init(&self, _name : &str, _rt : &Runtime) {
+        self.state.rescan();
+        self.watch.set(Some(spawn_handle("input device monitor", self.state.clone().monitor())));
+    }
+
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.state.interested.take_in(|i| i.add(rt));
+        self.state.devices.take_in(|devices| {
+            let lowest = devices.iter()
+                .filter_map(|d| Some((d.battery?, d)))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            match key {
+                "" | "text" => {
+                    let names : Vec<_> = devices.iter().map(|d| &*d.name).collect();
+                    Value::Owned(names.join(", "))
+                }
+                "count" => Value::Float(devices.len() as f64),
+                "battery" => lowest.map_or(Value::Null, |(b, _)| Value::Float(b)),
+                "battery-device" => lowest.map_or(Value::Null, |(_, d)| Value::Owned(d.name.clone())),
+                "charging" => Value::Bool(devices.iter().any(|d| d.charging)),
+                "list" => {
+                    let lines : Vec<_> = devices.iter().map(|d| match d.battery {
+                        Some(b) => format!("{} ({}): {}%", d.name, d.kind.name(), b),
+                        None => format!("{} ({})", d.name, d.kind.name()),
+                    }).collect();
+                    Value::Owned(lines.join("\n"))
+                }
+                key => match Kind::from_name(key) {
+                    Some(kind) => Value::Float(devices.iter().filter(|d| d.kind == kind).count() as f64),
+                    None => Value::Null,
+                },
+            }
+        })
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.state.rescan();
+        true
+    }
+}
@@ -0,0 +1,182 @@
+//! Control socket for changing a running bar
+//!
+//! Each connection sends one request and then closes its write side.  The first line of the
+//! request is the command; the rest is its body.  The reply is either `ok`, followed by any output
+//! of the command, or `error: <message>`.
+//!
+//! Requests are read by a task for each client and then run by the bar's main loop, in the order
+//! they arrive.
+use crate::data::Value;
+use crate::debug;
+use crate::metrics;
+use crate::state::State;
+use crate::timing;
+use crate::util;
+use futures_channel::{mpsc,oneshot};
+use log::info;
+use std::cell::RefCell;
+use std::error::Error;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt,AsyncWriteExt};
+use tokio::net::{UnixListener,UnixStream};
+
+/// Largest request accepted on the socket
+const MAX_REQUEST : u64 = 1 << 20;
+
+/// The path of the control socket for the current wayland display
+pub fn socket_path(xdg : &xdg::BaseDirectories) -> Option<PathBuf> {
+    let display = std::env::var("WAYLAND_DISPLAY").unwrap_or_else(|_| "wayland-0".into());
+    xdg.place_runtime_file(format!("rwaybar-{}.sock", display.replace('/', "_"))).ok()
+}
+
+/// The socket file of a running bar, which is removed when this is dropped
+pub struct Socket {
+    path : PathBuf,
+    ino : u64,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // A bar started by reload-exec replaces the file with its own socket, which must be left
+        // for it; only remove the file if it is still the one this bar created.
+        match std::fs::metadata(&self.path) {
+            Ok(meta) if meta.ino() == self.ino => {
+                let _ = std::fs::remove_file(&self.path);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A request read from a client, waiting to be run
+pub struct Request {
+    request : String,
+    reply : oneshot::Sender<String>,
+}
+
+impl Request {
+    /// Run the command and send its reply to the client
+    pub fn run(self, state : &RefCell<State>) {
+        let (cmd, body) = self.request.split_once('\n').unwrap_or((&self.request, ""));
+        let reply = match run(state, cmd.trim(), body) {
+            Ok(output) => format!("ok\n{}", output),
+            Err(e) => format!("error: {}\n", e),
+        };
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// Listen for clients on the socket, returning the requests they send
+pub fn start(path : PathBuf) -> io::Result<(Socket, mpsc::UnboundedReceiver<Request>)> {
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let ino = std::fs::metadata(&path)?.ino();
+    info!("Listening for commands on {}", path.display());
+    let (send, recv) = mpsc::unbounded();
+    util::spawn("IPC socket", async move {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            util::spawn("IPC client", handle(stream, send.clone()));
+        }
+    });
+    Ok((Socket { path, ino }, recv))
+}
+
+async fn handle(mut stream : UnixStream, requests : mpsc::UnboundedSender<Request>) -> Result<(), Box<dyn Error>> {
+    let mut request = String::new();
+    (&mut stream).take(MAX_REQUEST).read_to_string(&mut request).await?;
+    let (reply, recv) = oneshot::channel();
+    if requests.unbounded_send(Request { request, reply }).is_err() {
+        return Ok(());
+    }
+    // The sender is dropped without a reply if the bar exits first
+    if let Ok(reply) = recv.await {
+        stream.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Run one command, returning its output
+fn run(state : &RefCell<State>, cmd : &str, body : &str) -> Result<String, Box<dyn Error>> {
+    let mut words = cmd.split_whitespace();
+    match words.next() {
+        Some("action") => {
+            let name = words.next().ok_or("usage: action <name>")?;
+            state.borrow_mut().run_action(name)?;
+            Ok(String::new())
+        }
+        Some("debug") => {
+            match words.next() {
+                None | Some("toggle") => debug::toggle(),
+                Some("on") => debug::set_enabled(true),
+                Some("off") => debug::set_enabled(false),
+                Some(_) => return Err("usage: debug [on|off|toggle]".into()),
+            }
+            state.borrow_mut().redraw_all();
+            Ok(String::new())
+        }
+        Some("item") => {
+            let name = words.next().ok_or("usage: item <name>, followed by the item's definition")?;
+            let value : toml::Value = toml::from_str(body)?;
+            state.borrow_mut().replace_item(name, value)?;
+            Ok(String::new())
+        }
+        Some("config") => {
+            match &state.borrow().runtime.config_path {
+                Some(path) => Ok(format!("{}\n", path.display())),
+                None => Err("no configuration file loaded".into()),
+            }
+        }
+        Some("metrics") => {
+            match words.next() {
+                None | Some("json") => Ok(format!("{}\n", metrics::to_json().pretty(2))),
+                Some("prometheus") => Ok(metrics::to_prometheus()),
+                Some(_) => Err("usage: metrics [json|prometheus]".into()),
+            }
+        }
+        Some("regions") => {
+            let state = state.borrow();
+            let bars : Vec<_> = state.bars.iter()
+                .map(|bar| bar.regions_json(&state.runtime))
+                .collect();
+            Ok(format!("{}\n", json::JsonValue::from(bars).pretty(2)))
+        }
+        Some("refresh") => {
+            let name = words.next().ok_or("usage: refresh <name>")?;
+            state.borrow().runtime.refresh_item(name)?;
+            Ok(String::new())
+        }
+        Some("reload") => {
+            state.borrow_mut().load_config(true)?;
+            Ok(String::new())
+        }
+        Some("reload-exec") => {
+            state.borrow_mut().reload_exec()?;
+            Ok(String::new())
+        }
+        Some("send") => {
+            let target = words.next().ok_or("usage: send <name>[.<key>] <message>")?;
+            let msg = words.collect::<Vec<_>>().join(" ");
+            let msg = if msg.is_empty() { body.trim_end_matches('\n') } else { &msg };
+            state.borrow().runtime.write_item(target, Value::Borrow(msg))?;
+            Ok(String::new())
+        }
+        Some("toggle") => {
+            let name = words.next().ok_or("usage: toggle <group>")?;
+            state.borrow().runtime.toggle_item(name)?;
+            Ok(String::new())
+        }
+        Some("timings") => {
+            match words.next() {
+                None => timing::toggle(),
+                Some(mode) => timing::set_mode(timing::Mode::from_str(mode).ok_or("unknown timing mode")?),
+            }
+            state.borrow_mut().redraw_all();
+            Ok(String::new())
+        }
+        Some(cmd) => Err(format!("unknown command '{}'", cmd).into()),
+        None => Err("empty request".into()),
+    }
+}
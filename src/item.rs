@@ -1,15 +1,30 @@
 //! Graphical rendering of an [Item]
+use crate::color;
 use crate::data::{Module,ModuleContext,ItemReference,IterationItem,Value};
+use crate::debug;
 use crate::event::EventSink;
-use crate::font::{render_font,render_font_item};
+use crate::font::{ellipsize,render_font,render_font_item,shape_font};
 use crate::icon;
-use crate::render::{Render,Align,Width};
+use crate::logging;
+use crate::menu;
+use crate::metrics;
+#[cfg(feature="dbus")]
+use crate::mpris;
+use crate::prompt;
+use crate::render::{Canvas,Render,Align,TextShadow,Width};
+use crate::screencopy;
 use crate::state::Runtime;
+use crate::timing;
+use crate::util::{self,Cell,toml_to_string};
+use crate::wlr;
 #[cfg(feature="dbus")]
 use crate::tray;
 use log::{debug,warn,error};
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::panic::{self,AssertUnwindSafe};
 use std::rc::Rc;
+use std::time::{Duration,Instant};
 use tiny_skia::{Color,Point,Transform};
 
 /// A visible item in a bar
@@ -18,14 +33,104 @@ pub struct Item {
     pub format : ItemFormat,
     pub data : Module,
     events : EventSink,
+    /// Set if rendering this item panicked; it is replaced by an error label
+    crashed : Cell<bool>,
+    /// Messages logged while drawing this item, and the data update sequence they are from
+    error : Cell<(u64, Option<Rc<str>>)>,
+    /// Display strings for raw values, used by the `map` filter
+    pub map : Option<Box<HashMap<Box<str>, Box<str>>>>,
+    /// The values last drawn, if the item is kept still while hovered
+    pub freeze : Option<Rc<Freeze>>,
 }
 
+/// The values drawn by an item with `freeze-on-hover`, replayed while it is hovered
+#[derive(Debug,Default)]
+pub struct Freeze {
+    /// Set while the pointer is over the item or its popup is open
+    pub active : Cell<bool>,
+    texts : Cell<HashMap<Box<str>, Vec<Value<'static>>>>,
+    lists : Cell<Vec<Vec<(bool, IterationItem)>>>,
+}
+
+/// The position in a [Freeze] while drawing its item
+#[derive(Debug)]
+pub struct FreezeCursor {
+    freeze : Rc<Freeze>,
+    texts : HashMap<Box<str>, usize>,
+    lists : usize,
+}
+
+impl FreezeCursor {
+    pub fn new(freeze : &Rc<Freeze>) -> Self {
+        if !freeze.active.get() {
+            // record the values from scratch
+            freeze.texts.take_in(|t| t.clear());
+            freeze.lists.take_in(|l| l.clear());
+        }
+        FreezeCursor { freeze : freeze.clone(), texts : HashMap::new(), lists : 0 }
+    }
+
+    /// The expansion of `fmt` that was drawn before, if frozen
+    pub fn replay_text(&mut self, fmt : &str) -> Option<Value<'static>> {
+        if !self.freeze.active.get() {
+            return None;
+        }
+        let n = self.texts.entry(fmt.into()).or_default();
+        let rv = self.freeze.texts.take_in(|t| t.get(fmt)?.get(*n).map(|v| v.as_ref().into_owned()));
+        *n += 1;
+        rv
+    }
+
+    pub fn record_text(&mut self, fmt : &str, value : &Value) {
+        if !self.freeze.active.get() {
+            self.freeze.texts.take_in(|t| t.entry(fmt.into()).or_default().push(value.as_ref().into_owned()));
+        }
+    }
+
+    /// The focus list that was drawn before, if frozen
+    pub fn replay_list(&mut self) -> Option<Vec<(bool, IterationItem)>> {
+        if !self.freeze.active.get() {
+            return None;
+        }
+        self.lists += 1;
+        self.freeze.lists.take_in(|l| l.get(self.lists - 1).cloned())
+    }
+
+    pub fn record_list(&mut self, list : &[(bool, IterationItem)]) {
+        if !self.freeze.active.get() {
+            self.freeze.lists.take_in(|l| l.push(list.to_vec()));
+        }
+    }
+}
+
+/// The font size used by `font-size = auto`, relative to the available height
+///
+/// This matches the default 16px font on the default 20px bar.
+const AUTO_FONT_SCALE : f32 = 0.8;
+
+/// How long the contents of a `width-stable = true` block must stay narrower before it shrinks
+const STABLE_SHRINK_TIME : Duration = Duration::from_secs(60);
+
 /// Formatting information for a visible bar item
 #[derive(Debug,Default)]
 pub struct ItemFormat {
     markup : bool,
     oneline: bool,
+    ellipsize : bool,
     cfg : Option<toml::Value>,
+    stable : Option<StableWidth>,
+    /// The widest the contents have been recently, for [StableWidth::Widest], and when they
+    /// became narrower than that
+    widest : Cell<(f32, Option<Instant>)>,
+}
+
+/// Width reserved by `width-stable` so that changing contents do not move other items
+#[derive(Debug)]
+enum StableWidth {
+    /// The widest value shown recently
+    Widest,
+    /// The width of this text in the block's font
+    Template(Box<str>),
 }
 
 impl ItemFormat {
@@ -33,6 +138,16 @@ impl ItemFormat {
         let mut rv = Self::default();
         rv.markup = config.get("markup").and_then(|v| v.as_bool()).unwrap_or(false);
         rv.oneline = config.get("oneline").and_then(|v| v.as_bool()).unwrap_or(false);
+        rv.ellipsize = config.get("ellipsize").and_then(|v| v.as_bool()).unwrap_or(false);
+        rv.stable = match config.get("width-stable") {
+            None | Some(toml::Value::Boolean(false)) => None,
+            Some(toml::Value::Boolean(true)) => Some(StableWidth::Widest),
+            Some(toml::Value::String(s)) => Some(StableWidth::Template(s.as_str().into())),
+            Some(v) => {
+                warn!("Invalid width-stable value {}, use true or a template string", v);
+                None
+            }
+        };
 
         rv.cfg = config.as_table()
             .map(|t| t.iter()
@@ -40,20 +155,27 @@ impl ItemFormat {
                     "align" |
                     "bg" |
                     "bg-alpha" |
+                    "bg-image" |
+                    "bg-image-mode" |
                     "border" |
                     "border-alpha" |
                     "border-color" |
                     "fg" |
                     "fg-alpha" |
                     "font" |
+                    "font-size" |
                     "halign" |
+                    "line-spacing" |
                     "margin" |
                     "max-width" |
+                    "min-height" |
                     "min-width" |
                     "padding" |
                     "text-outline" |
                     "text-outline-alpha" |
                     "text-outline-width" |
+                    "text-shadow" |
+                    "text-shadow-alpha" |
                     "valign" => true,
                     _ => false,
                 })
@@ -66,10 +188,10 @@ impl ItemFormat {
     }
 
     pub fn is_trivial(&self) -> bool {
-        self.cfg.is_none()
+        self.cfg.is_none() && self.stable.is_none()
     }
 
-    pub fn setup_ctx<'a, 'p : 'a, 'c>(&self, ctx : &'a mut Render<'p, 'c>) -> (Formatting, Render<'a, 'c>) {
+    pub fn setup_ctx<'a, 'p : 'a>(&self, ctx : &'a mut Render<'p>) -> (Formatting, Render<'a>) {
         let z = toml::Value::Integer(0);
         let config = self.cfg.as_ref().unwrap_or(&z);
         let fmt = Formatting::expand(config, ctx.runtime);
@@ -112,19 +234,46 @@ impl ItemFormat {
             let font = runtime.fonts.iter().find(|f| f.name == font);
             (font, size)
         });
+        let font_size = match get("font-size") {
+            Some(size) => {
+                let base = font_size.unwrap_or(ctx.font_size);
+                let size = size.trim();
+                let rv = if size == "auto" {
+                    let (t, _, b, _) = fmt.get_shrink().unwrap_or_default();
+                    let height = ctx.render_extents.1.y - ctx.render_extents.0.y - t - b;
+                    Some(height * AUTO_FONT_SCALE)
+                } else if let Some(pct) = size.strip_suffix('%') {
+                    pct.trim().parse::<f32>().ok().map(|pct| base * pct / 100.0)
+                } else {
+                    size.parse().ok()
+                };
+                match rv.filter(|&v| v > 0.0) {
+                    Some(v) => Some(v),
+                    None => {
+                        warn!("Invalid font-size '{}'", size);
+                        font_size
+                    }
+                }
+            }
+            None => font_size,
+        };
 
         let fg_rgba = Formatting::parse_rgba(get("fg"), get_f32("fg-alpha"));
         let stroke_rgba = Formatting::parse_rgba(get("text-outline"), get_f32("text-outline-alpha"));
         let stroke_size = get_f32("text-outline-width");
+        let line_spacing = get_f32("line-spacing").filter(|&v| v > 0.0);
+        let shadow = get("text-shadow").and_then(|v| Formatting::parse_shadow(&v, get_f32("text-shadow-alpha")));
 
         let render = Render {
             canvas : &mut *ctx.canvas,
             align : ctx.align.merge(&align),
             font : font.unwrap_or(&ctx.font),
             font_size : font_size.unwrap_or(ctx.font_size),
+            line_spacing : line_spacing.unwrap_or(ctx.line_spacing),
             font_color : fg_rgba.unwrap_or(ctx.font_color),
             text_stroke : stroke_rgba.or(ctx.text_stroke),
             text_stroke_size : stroke_size.or(ctx.text_stroke_size),
+            text_shadow : shadow.or(ctx.text_shadow),
             ..*ctx
         };
         (fmt, render)
@@ -135,10 +284,12 @@ impl ItemFormat {
 #[derive(Debug,Clone,Default,PartialEq)]
 pub struct Formatting {
     bg_rgba : Option<Color>,
+    bg_image : Option<(String, icon::ImageFit)>,
     border : Option<(f32, f32, f32, f32)>,
     border_rgba : Option<Color>,
     min_width : Option<Width>,
     max_width : Option<Width>,
+    min_height : Option<Width>,
     margin : Option<(f32, f32, f32, f32)>,
     padding : Option<(f32, f32, f32, f32)>,
 }
@@ -166,20 +317,31 @@ impl Formatting {
         };
         let min_width = get("min-width").and_then(Width::from_str);
         let max_width = get("max-width").and_then(Width::from_str);
+        let min_height = get("min-height").and_then(Width::from_str);
 
         let margin = get("margin").and_then(Formatting::parse_trbl);
         let border = get("border").and_then(Formatting::parse_trbl);
         let padding = get("padding").and_then(Formatting::parse_trbl);
 
         let bg_rgba = Formatting::parse_rgba(get("bg"), get_f32("bg-alpha"));
+        let bg_image = get("bg-image").filter(|v| !v.is_empty()).map(|path| {
+            let fit = get("bg-image-mode").map_or(Some(icon::ImageFit::Stretch), |v| icon::ImageFit::from_str(&v))
+                .unwrap_or_else(|| {
+                    warn!("Invalid bg-image-mode, using stretch");
+                    icon::ImageFit::Stretch
+                });
+            (path.into_owned(), fit)
+        });
         let border_rgba = Formatting::parse_rgba(get("border-color"), get_f32("border-alpha"));
 
         Self {
             bg_rgba,
+            bg_image,
             border,
             border_rgba,
             min_width,
             max_width,
+            min_height,
             margin,
             padding,
         }
@@ -207,76 +369,49 @@ impl Formatting {
         Some(rv)
     }
 
+    /// Parse a shadow like CSS text-shadow: "x y [blur] [color]"
+    fn parse_shadow(v : &str, alpha : Option<f32>) -> Option<TextShadow> {
+        let mut nums = Vec::new();
+        let mut rest = v.trim();
+        while nums.len() < 3 {
+            let (word, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            match word.trim_end_matches("px").parse::<f32>() {
+                Ok(n) => nums.push(n),
+                Err(_) => break,
+            }
+            rest = tail.trim_start();
+        }
+        // the color may contain spaces, as in "rgb(0 0 0 / 50%)"
+        let color = Some(rest).filter(|c| !c.is_empty());
+        if color.is_some_and(|c| color::parse(c).is_none()) {
+            warn!("Invalid text-shadow: '{}'", v);
+            return None;
+        }
+        if nums.len() < 2 {
+            if v != "none" {
+                warn!("Invalid text-shadow: '{}'", v);
+            }
+            return None;
+        }
+        Some(TextShadow {
+            color : Formatting::parse_rgba(Some(color.unwrap_or("black")), alpha)?,
+            offset : (nums[0], nums[1]),
+            blur : nums.get(2).copied().unwrap_or(0.0).max(0.0),
+        })
+    }
+
     pub fn parse_rgba(color : Option<impl AsRef<str>>, alpha : Option<f32>) -> Option<Color> {
         if color.is_none() && alpha.is_none() {
             return None;
         }
         let color = color.as_ref().map_or("black", |v| v.as_ref());
-        let (r,g,b,mut a);
-        let alpha_f = alpha.unwrap_or(1.0) * 65535.0;
-        a = f32::min(65535.0, f32::max(0.0, alpha_f)) as u64;
-        if color.starts_with('#') {
-            let v = u64::from_str_radix(&color[1..], 16);
-            match (v, color.len()) {
-                (Ok(v), 4) => {
-                    r = ((v >> 8) & 0xF) * 0x1111;
-                    g = ((v >> 4) & 0xF) * 0x1111;
-                    b = ((v >> 0) & 0xF) * 0x1111;
-                }
-                (Ok(v), 5) => {
-                    r = ((v >> 12) & 0xF) * 0x1111;
-                    g = ((v >> 8) & 0xF) * 0x1111;
-                    b = ((v >> 4) & 0xF) * 0x1111;
-                    a = ((v >> 0) & 0xF) * 0x1111;
-                }
-                (Ok(v), 7) => {
-                    r = ((v >> 16) & 0xFF) * 0x101;
-                    g = ((v >> 8) & 0xFF) * 0x101;
-                    b = ((v >> 0) & 0xFF) * 0x101;
-                }
-                (Ok(v), 9) => {
-                    r = ((v >> 24) & 0xFF) * 0x101;
-                    g = ((v >> 16) & 0xFF) * 0x101;
-                    b = ((v >> 8) & 0xFF) * 0x101;
-                    a = ((v >> 0) & 0xFF) * 0x101;
-                }
-                (Ok(v), 13) => {
-                    r = (v >> 32) & 0xFFFF;
-                    g = (v >> 16) & 0xFFFF;
-                    b = (v >> 0) & 0xFFFF;
-                }
-                (Ok(v), 17) => {
-                    r = (v >> 48) & 0xFFFF;
-                    g = (v >> 32) & 0xFFFF;
-                    b = (v >> 16) & 0xFFFF;
-                    a = (v >> 0) & 0xFFFF;
-                }
-                _ => {
-                    debug!("Could not parse color '{}'", color);
-                    r = 0; g = 0; b = 0;
-                }
-            }
-        } else {
-            match color {
-                "black" => { r = 0; g = 0; b = 0; }
-                "red" => { r = 0xFFFF; g = 0; b = 0; }
-                "yellow" => { r = 0xFFFF; g = 0xFFFF; b = 0; }
-                "green" => { r = 0; g = 0xFFFF; b = 0; }
-                "blue" => { r = 0; g = 0; b = 0xFFFF; }
-                "gray" => { r = 0x7FFF; g = 0x7FFF; b = 0x7FFF; }
-                "white" => { r = 0xFFFF; g = 0xFFFF; b = 0xFFFF; }
-                _ => {
-                    debug!("Unknown color '{}'", color);
-                    r = 0; g = 0; b = 0;
-                }
-            }
-        }
-        Color::from_rgba(
-            r as f32 / 65535.0,
-            g as f32 / 65535.0,
-            b as f32 / 65535.0,
-            a as f32 / 65535.0,
-        )
+        let (r, g, b, a) = color::parse(color).unwrap_or_else(|| {
+            debug!("Could not parse color '{}'", color);
+            (0.0, 0.0, 0.0, None)
+        });
+        // an alpha given in the color itself takes precedence
+        let a = a.unwrap_or_else(|| alpha.unwrap_or(1.0).clamp(0.0, 1.0));
+        Color::from_rgba(r, g, b, a)
     }
 
     fn get_shrink(&self) -> Option<(f32, f32, f32, f32)> {
@@ -300,12 +435,118 @@ impl Formatting {
     }
 }
 
+#[derive(Debug,Clone,Copy)]
+enum ColumnWidth {
+    Pixels(f32),
+    /// The width of the widest item
+    Equal,
+    /// The widest width seen so far, so the columns never shrink
+    Max,
+}
+
+/// Gives each item of a horizontal group the same width, for tabular layouts
+#[derive(Debug)]
+pub struct Columns {
+    width : ColumnWidth,
+    /// Position of an item that is narrower than its column (0 = left, 1 = right)
+    align : f32,
+    widest : Cell<f32>,
+}
+
+impl Columns {
+    pub fn from_toml(value : &toml::Value) -> Option<Self> {
+        let width = match value.get("column-width")? {
+            toml::Value::String(s) if s == "equal" => ColumnWidth::Equal,
+            toml::Value::String(s) if s == "max" => ColumnWidth::Max,
+            v => match util::toml_to_f64(Some(v)) {
+                Some(px) if px >= 0.0 => ColumnWidth::Pixels(px as f32),
+                _ => {
+                    error!("Invalid column-width: {}", v);
+                    return None;
+                }
+            }
+        };
+        let align = match value.get("column-align").and_then(|v| v.as_str()) {
+            None | Some("right") => 1.0,
+            Some("left") => 0.0,
+            Some("center") => 0.5,
+            Some(v) => Align::parse_hv(v.into()).unwrap_or_else(|| {
+                error!("Invalid column-align: {}", v);
+                1.0
+            }),
+        };
+        Some(Columns { width, align, widest : Cell::new(0.0) })
+    }
+
+    /// The width of every column, given the natural widths of the items
+    fn width(&self, widths : &[f32]) -> f32 {
+        let widest = widths.iter().copied().fold(0.0, f32::max);
+        match self.width {
+            ColumnWidth::Pixels(px) => px,
+            ColumnWidth::Equal => widest,
+            ColumnWidth::Max => {
+                let widest = widest.max(self.widest.get());
+                self.widest.set(widest);
+                widest
+            }
+        }
+    }
+}
+
+/// A marker drawn after items that logged warnings or errors while they were drawn
+#[derive(Debug)]
+pub struct ErrorBadge {
+    text : Box<str>,
+    color : Color,
+}
+
+impl ErrorBadge {
+    /// Parse the top-level `error-badge` setting, which is either a boolean or a table
+    pub fn from_toml(value : &toml::Value) -> Option<Self> {
+        let mut rv = ErrorBadge {
+            text : "⚠".into(),
+            color : Color::from_rgba8(255, 64, 64, 255),
+        };
+        if let Some(enabled) = value.as_bool() {
+            return enabled.then_some(rv);
+        }
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => {
+                error!("Ignoring invalid error-badge: {}", value);
+                return None;
+            }
+        };
+        if let Some(text) = table.get("text").and_then(|v| v.as_str()) {
+            rv.text = text.into();
+        }
+        if let Some(color) = table.get("color").and_then(|v| v.as_str()) {
+            match Formatting::parse_rgba(Some(color), None) {
+                Some(color) => rv.color = color,
+                None => error!("Ignoring invalid error-badge color: {}", color),
+            }
+        }
+        Some(rv)
+    }
+
+    fn render(&self, ctx : &mut Render) {
+        let color = ctx.font_color;
+        ctx.font_color = self.color;
+        render_font_item(ctx, &self.text, false);
+        ctx.font_color = color;
+    }
+}
+
 impl From<Module> for Item {
     fn from(data : Module) -> Self {
         Self {
             format : ItemFormat::default(),
             events : EventSink::default(),
-            data
+            data,
+            crashed : Cell::new(false),
+            error : Default::default(),
+            map : None,
+            freeze : None,
         }
     }
 }
@@ -316,6 +557,10 @@ impl Item {
             format : ItemFormat::default(),
             events : EventSink::default(),
             data : Module::parse_error(""),
+            crashed : Cell::new(false),
+            error : Default::default(),
+            map : None,
+            freeze : None,
         }
     }
 
@@ -331,6 +576,10 @@ impl Item {
                 left, center, right,
                 config : cfg,
             },
+            crashed : Cell::new(false),
+            error : Default::default(),
+            map : None,
+            freeze : None,
         }
     }
 
@@ -354,6 +603,10 @@ impl Item {
                 tooltip : None,
                 spacing : "".into(),
                 vertical: false,
+                visible : Cell::new(true),
+                transition : Duration::ZERO,
+                animation : Default::default(),
+                columns : None,
             }.into();
         }
 
@@ -365,10 +618,87 @@ impl Item {
             events : EventSink::from_toml(value),
             format : ItemFormat::from_toml(value),
             data,
+            crashed : Cell::new(false),
+            error : Default::default(),
+            map : value.get("map").and_then(|v| v.as_table()).map(|table| {
+                Box::new(table.iter().map(|(k, v)| {
+                    let v = toml_to_string(Some(v)).unwrap_or_default();
+                    (k.as_str().into(), v.into())
+                }).collect())
+            }),
+            freeze : value.get("freeze-on-hover").and_then(|v| v.as_bool())
+                .filter(|&v| v)
+                .map(|_| Default::default()),
+        }
+    }
+
+    /// Render the item, replacing it with an error label if it panics
+    pub fn render(self : &Rc<Self>, ctx : &mut Render) -> EventSink {
+        if let Some(freeze) = &self.freeze {
+            let rt = ctx.runtime;
+            let x0 = ctx.render_pos.x;
+            let mut rv = rt.with_freeze(freeze, || self.render_badged(ctx));
+            rv.add_freeze(x0, ctx.render_pos.x, freeze);
+            return rv;
+        }
+        self.render_badged(ctx)
+    }
+
+    fn render_badged(self : &Rc<Self>, ctx : &mut Render) -> EventSink {
+        if ctx.runtime.error_badge.is_some() {
+            return self.render_with_badge(ctx);
         }
+        self.render_checked(ctx)
     }
 
-    pub fn render(self : &Rc<Self>, parent_ctx : &mut Render) -> EventSink {
+    fn render_checked(self : &Rc<Self>, ctx : &mut Render) -> EventSink {
+        if !self.crashed.get() {
+            match panic::catch_unwind(AssertUnwindSafe(|| debug::outline(ctx, |ctx| self.render_unchecked(ctx)))) {
+                Ok(rv) => return rv,
+                Err(panic) => {
+                    error!("Rendering an item crashed, disabling it: {}", util::panic_message(&*panic));
+                    self.crashed.set(true);
+                }
+            }
+        }
+        render_font_item(ctx, "[crashed]", false);
+        EventSink::default()
+    }
+
+    /// Render the item, followed by the error badge if it logged any warnings or errors
+    fn render_with_badge(self : &Rc<Self>, ctx : &mut Render) -> EventSink {
+        let origin = ctx.render_pos;
+        let (rv, msgs) = logging::capture(|| self.render_checked(ctx));
+
+        // Errors are often only logged when the data is refreshed, so keep showing them until
+        // the next data update; this also keeps the measure and paint passes consistent.
+        let seq = ctx.runtime.data_update_seq();
+        let error = self.error.take_in(|(err_seq, error)| {
+            if !msgs.is_empty() {
+                *err_seq = seq;
+                *error = Some(msgs.join("\n").into());
+            } else if *err_seq != seq {
+                *error = None;
+            }
+            error.clone()
+        });
+        let (error, badge) = match (error, &ctx.runtime.error_badge) {
+            (Some(error), Some(badge)) => (error, badge),
+            _ => return rv,
+        };
+
+        let bottom = ctx.render_pos.y;
+        ctx.render_pos.y = origin.y;
+        badge.render(ctx);
+        ctx.render_pos.y = ctx.render_pos.y.max(bottom);
+
+        let mut sink = EventSink::default();
+        sink.add_hover(origin.x, ctx.render_pos.x, PopupDesc::Error(error));
+        sink.merge(rv);
+        sink
+    }
+
+    fn render_unchecked(self : &Rc<Self>, parent_ctx : &mut Render) -> EventSink {
         // skip rendering if we are outside the clip bounds
         if !parent_ctx.render_flex && parent_ctx.render_pos.x > parent_ctx.render_extents.1.x {
             return EventSink::default();
@@ -382,7 +712,7 @@ impl Item {
         }
 
         let (format, mut ctx) = self.format.setup_ctx(parent_ctx);
-        if format.is_boring() {
+        if format.is_boring() && self.format.stable.is_none() {
             self.render_inner(&mut ctx, &mut rv);
             let pos = ctx.render_pos;
             parent_ctx.render_pos = pos;
@@ -424,54 +754,77 @@ impl Item {
 
         ctx.render_pos = start_pos;
         ctx.render_extents = inner_clip;
-        self.render_inner(&mut ctx, &mut rv);
-
-        let mut end_pos = ctx.render_pos;
 
-        let child_render_width = end_pos.x - start_pos.x;
         let mut min_width = match format.min_width {
             None => 0.0,
             Some(Width::Pixels(n)) => n,
             Some(Width::Fraction(f)) => f * (outer_clip.1.x - outer_clip.0.x),
         };
+        match &self.format.stable {
+            Some(StableWidth::Widest) => {
+                let child_width = ctx.measure(|m| {
+                    self.render_inner(m, &mut EventSink::default());
+                    m.render_pos.x - start_pos.x
+                });
+                let widest = match self.format.widest.get() {
+                    (widest, _) if child_width >= widest => (child_width, None),
+                    // shrink once the contents have stayed narrower for a while
+                    (_, Some(since)) if since.elapsed() >= STABLE_SHRINK_TIME => (child_width, None),
+                    (widest, since) => (widest, Some(since.unwrap_or_else(Instant::now))),
+                };
+                self.format.widest.set(widest);
+                min_width = min_width.max(widest.0);
+            }
+            Some(StableWidth::Template(text)) => {
+                min_width = min_width.max(shape_font(&ctx, text, false).1.0);
+            }
+            None => {}
+        }
         if min_width > inner_clip.1.x - start_pos.x {
             // clamp the minimum to only the available region
             min_width = inner_clip.1.x - start_pos.x;
         }
 
-        let inner_x_offset;
-        if child_render_width < min_width {
-            // child is smaller than the box; align it
-            let expand = min_width - child_render_width;
-            match ctx.align.horiz {
-                Some(f) => {
-                    inner_x_offset = expand * f;
-                    let x0 = start_pos.x.floor() as usize;
-                    let x1 = end_pos.x.ceil() as usize;
-                    let ilen = x1 - x0;
-                    let wlen = min_width.ceil() as usize;
-                    if wlen > ilen {
-                        // Align by rotating the pixels of the inner clip region to the right.  The
-                        // right part of the clip region should just be blank pixels at this point,
-                        // which is what we want to put on the left.
-                        let rlen = (wlen - ilen) * 4;
-                        let stride = ctx.canvas.width() as usize * 4;
-                        let h = ctx.canvas.height() as usize;
-                        for y in 0..h {
-                            let x0 = y * stride + x0 * 4;
-                            let x2 = y * stride + (x0 + wlen) * 4;
-                            if let Some(buf) = ctx.canvas.data_mut().get_mut(x0..x2) {
-                                buf.rotate_right(rlen);
-                            }
-                        }
-                    }
+        if min_width > 0.0 && !ctx.measure_only {
+            if let Some(f) = ctx.align.horiz {
+                // measure the child first so that it can be painted at its aligned position
+                let child_width = ctx.measure(|m| {
+                    self.render_inner(m, &mut EventSink::default());
+                    m.render_pos.x - start_pos.x
+                });
+                if child_width < min_width {
+                    ctx.render_pos.x += (min_width - child_width) * f;
                 }
-                _ => { // defaults to left align
-                    inner_x_offset = 0.0;
+            }
+        }
+
+        let min_height = match format.min_height {
+            None => 0.0,
+            Some(Width::Pixels(n)) => n,
+            Some(Width::Fraction(f)) => f * (outer_clip.1.y - outer_clip.0.y),
+        };
+
+        // Without flex, text is already aligned within the whole clip height
+        if min_height > 0.0 && ctx.render_flex && !ctx.measure_only {
+            if let Some(f) = ctx.align.vert {
+                let child_height = ctx.measure(|m| {
+                    self.render_inner(m, &mut EventSink::default());
+                    m.render_pos.y - start_pos.y
+                });
+                if child_height < min_height {
+                    ctx.render_pos.y += (min_height - child_height) * f;
                 }
             }
-        } else {
-            inner_x_offset = 0.0;
+        }
+
+        self.render_inner(&mut ctx, &mut rv);
+
+        let mut end_pos = ctx.render_pos;
+        if end_pos.x < start_pos.x + min_width {
+            end_pos.x = start_pos.x + min_width;
+        }
+        if end_pos.y < start_pos.y + min_height {
+            end_pos.y = start_pos.y + min_height;
         }
 
         let shrink_r_width = shrink.map_or(0.0, |s| s.1);
@@ -482,9 +835,9 @@ impl Item {
         }
         let outer_pos = end_pos + Point { x: shrink_r_width, y: shrink_b_height };
 
-        rv.offset_clamp(inner_x_offset, start_pos.x, end_pos.x);
+        rv.offset_clamp(0.0, start_pos.x, end_pos.x);
 
-        if format.bg_rgba.is_some() || format.border.is_some() {
+        if !ctx.measure_only && (format.bg_rgba.is_some() || format.bg_image.is_some() || format.border.is_some()) {
             use tiny_skia::Rect;
             let mut bg_clip = (start_pos, end_pos);
             if let Some((t, r, b, l)) = format.padding {
@@ -494,6 +847,25 @@ impl Item {
                 bg_clip.1.y += b;
             }
 
+            if let Some((path, fit)) = &format.bg_image {
+                let mut corners = [bg_clip.0, bg_clip.1];
+                ctx.render_xform.map_points(&mut corners);
+                let x0 = corners[0].x.round() as i32;
+                let y0 = corners[0].y.round() as i32;
+                let width = corners[1].x.round() as i32 - x0;
+                let height = corners[1].y.round() as i32 - y0;
+                if width > 0 && height > 0 {
+                    if let Some(img) = icon::background(ctx.runtime, path, *fit, width as u32, height as u32) {
+                        let paint = tiny_skia::PixmapPaint {
+                            // painted underneath the contents, but above the background color
+                            blend_mode : tiny_skia::BlendMode::DestinationOver,
+                            ..Default::default()
+                        };
+                        ctx.canvas.draw_pixmap(x0, y0, img, &paint, Transform::identity());
+                    }
+                }
+            }
+
             if let Some(rgba) = format.bg_rgba {
                 if let Some(rect) = Rect::from_ltrb(bg_clip.0.x, bg_clip.0.y, bg_clip.1.x, bg_clip.1.y) {
                     let paint = tiny_skia::Paint {
@@ -503,7 +875,7 @@ impl Item {
                         blend_mode : tiny_skia::BlendMode::DestinationOver,
                         ..tiny_skia::Paint::default()
                     };
-                    ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
+                    ctx.canvas.fill_rect(rect, &paint, ctx.render_xform);
                 }
             }
 
@@ -515,28 +887,28 @@ impl Item {
                     ..tiny_skia::Paint::default()
                 };
 
-                bg_clip.0.y -= t;
-                if let Some(rect) = Rect::from_xywh(bg_clip.0.x, bg_clip.0.y, bg_clip.1.x - bg_clip.0.x, t) {
-                    // top edge, no corners
-                    ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
-                }
-
-                bg_clip.0.x -= l;
-                if let Some(rect) = Rect::from_xywh(bg_clip.0.x, bg_clip.0.y, l, bg_clip.1.y - bg_clip.0.y) {
-                    // left edge + top-left corner
-                    ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
-                }
-
-                if let Some(rect) = Rect::from_xywh(bg_clip.1.x, bg_clip.0.y, r, bg_clip.1.y - bg_clip.0.y) {
-                    // right edge + top-right corner
-                    ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
-                }
+                // Borders are snapped to whole pixels: thin rectangles at fractional positions
+                // would otherwise be blurred over two pixels (and can trip assertions in tiny-skia)
+                let xform = ctx.render_xform;
+                let canvas = &mut ctx.canvas;
+                let mut fill = |x0 : f32, y0 : f32, x1 : f32, y1 : f32| {
+                    let mut corners = [Point { x: x0, y: y0 }, Point { x: x1, y: y1 }];
+                    xform.map_points(&mut corners);
+                    let [p0, p1] = corners;
+                    if let Some(rect) = Rect::from_ltrb(p0.x.round(), p0.y.round(), p1.x.round(), p1.y.round()) {
+                        canvas.fill_rect(rect, &paint, Transform::identity());
+                    }
+                };
 
-                bg_clip.1.x += r;
-                if let Some(rect) = Rect::from_xywh(bg_clip.0.x, bg_clip.1.y, bg_clip.1.x - bg_clip.0.x, b) {
-                    // bottom edge + both corners
-                    ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
-                }
+                let (inner0, inner1) = bg_clip;
+                // top edge, no corners
+                fill(inner0.x, inner0.y - t, inner1.x, inner0.y);
+                // left edge + top-left corner
+                fill(inner0.x - l, inner0.y - t, inner0.x, inner1.y);
+                // right edge + top-right corner
+                fill(inner1.x, inner0.y - t, inner1.x + r, inner1.y);
+                // bottom edge + both corners
+                fill(inner0.x - l, inner1.y, inner1.x + r, inner1.y + b);
             }
         }
 
@@ -545,6 +917,25 @@ impl Item {
         rv
     }
 
+    /// Call the function with this item, or with the item it refers to if it is a reference
+    fn with_target<F : FnOnce(&Rc<Item>) -> R, R>(self : &Rc<Self>, rt : &Runtime, f : F) -> Option<R> {
+        match &self.data {
+            Module::ItemReference { value } => {
+                ItemReference::with(value, rt, |item| item.and_then(|item| item.with_target(rt, f)))
+            }
+            _ => Some(f(self)),
+        }
+    }
+
+    /// The background color this item would be drawn with
+    fn bg_color(self : &Rc<Self>, rt : &Runtime) -> Option<Color> {
+        self.with_target(rt, |item| {
+            item.format.cfg.as_ref()
+                .filter(|cfg| cfg.get("bg").is_some() || cfg.get("bg-alpha").is_some())
+                .and_then(|cfg| Formatting::expand(cfg, rt).bg_rgba)
+        }).flatten()
+    }
+
     pub fn render_clamped(self : &Rc<Self>, ctx : &mut Render, ev : &mut EventSink) {
         let x0 = ctx.render_pos.x;
         let mut rv = self.render(ctx);
@@ -566,6 +957,64 @@ impl Item {
         item_var.set(prev);
     }
 
+    /// Render the items of a group one after another
+    fn render_group(ctx : &mut Render, rv : &mut EventSink, items : &[Rc<Item>], spacing : f32, vertical : bool, columns : Option<&Columns>) {
+        let origin = ctx.render_pos;
+        let mut bounds = origin;
+        let columns = columns.filter(|_| !vertical);
+        let widths : Vec<f32> = match columns {
+            Some(_) => items.iter().map(|item| ctx.measure(|m| {
+                item.render(m);
+                m.render_pos.x - origin.x
+            })).collect(),
+            None => Vec::new(),
+        };
+        let column = columns.map(|c| c.width(&widths));
+        for (i, item) in items.iter().enumerate() {
+            item.with_target(ctx.runtime, |target| if let Module::Separator(sep) = &target.data {
+                let prev = i.checked_sub(1).and_then(|i| items[i].bg_color(ctx.runtime));
+                let next = items.get(i + 1).and_then(|i| i.bg_color(ctx.runtime));
+                sep.set_neighbors(prev, next);
+            });
+            match (columns, column) {
+                (Some(columns), Some(column)) => {
+                    let x0 = ctx.render_pos.x;
+                    let clip = (ctx.render_extents, ctx.render_flex);
+                    ctx.render_pos.x += ((column - widths[i]) * columns.align).max(0.0);
+                    if widths[i] > column {
+                        ctx.render_extents.1.x = ctx.render_extents.1.x.min(x0 + column);
+                        ctx.render_flex = false;
+                    }
+                    item.render_clamped(ctx, rv);
+                    (ctx.render_extents, ctx.render_flex) = clip;
+                    ctx.render_pos.x = x0 + column;
+                }
+                _ => item.render_clamped(ctx, rv),
+            }
+
+            if vertical {
+                if ctx.render_pos.x > bounds.x {
+                    bounds.x = ctx.render_pos.x;
+                }
+                ctx.render_pos.x = origin.x;
+                bounds.y = ctx.render_pos.y;
+                if spacing > 0.0 {
+                    ctx.render_pos.y = (ctx.render_pos.y + spacing).ceil();
+                }
+            } else {
+                bounds.x = ctx.render_pos.x;
+                if ctx.render_pos.y > bounds.y {
+                    bounds.y = ctx.render_pos.y;
+                }
+                ctx.render_pos.y = origin.y;
+                if spacing > 0.0 {
+                    ctx.render_pos.x = (ctx.render_pos.x + spacing).ceil();
+                }
+            }
+        }
+        ctx.render_pos = bounds;
+    }
+
     /// Render the block contents to the given context.
     ///
     /// Your item starts at the context's current point.  When you are done rendering, you should
@@ -582,11 +1031,27 @@ impl Item {
         match &self.data {
             Module::ItemReference { value } => {
                 ItemReference::with(value, &ctx.runtime, |item| match item {
+                    Some(item) if (timing::enabled() || metrics::items_enabled()) && !ctx.measure_only => {
+                        let begin = Instant::now();
+                        rv.merge(item.render(ctx));
+                        let time = begin.elapsed();
+                        let name = ctx.runtime.items.iter()
+                            .find(|(_, v)| Rc::ptr_eq(v, item))
+                            .map_or("?", |(k, _)| k);
+                        if timing::enabled() {
+                            timing::item(name, time);
+                        }
+                        metrics::item(name, time);
+                    }
                     Some(item) => rv.merge(item.render(ctx)),
                     None => {}
                 });
             }
-            Module::Group { condition, items, tooltip, spacing, vertical } => {
+            Module::Group { condition, items, tooltip, spacing, vertical, visible, animation, columns, .. } => {
+                let progress = animation.value(ctx.runtime);
+                if !visible.get() && progress.is_none() {
+                    return;
+                }
                 if let Some(cond) = condition {
                     if !cond.is_empty() {
                         match ctx.runtime.format(cond) {
@@ -598,33 +1063,25 @@ impl Item {
                         }
                     }
                 }
-                let origin = ctx.render_pos;
-                let mut bounds = origin;
                 let spacing = ctx.runtime.format(spacing).ok().and_then(|s| s.parse_f32()).unwrap_or(0.0);
-                for item in items {
-                    item.render_clamped(ctx, rv);
-
-                    if *vertical {
-                        if ctx.render_pos.x > bounds.x {
-                            bounds.x = ctx.render_pos.x;
-                        }
-                        ctx.render_pos.x = origin.x;
-                        bounds.y = ctx.render_pos.y;
-                        if spacing > 0.0 {
-                            ctx.render_pos.y = (ctx.render_pos.y + spacing).ceil();
-                        }
-                    } else {
-                        bounds.x = ctx.render_pos.x;
-                        if ctx.render_pos.y > bounds.y {
-                            bounds.y = ctx.render_pos.y;
-                        }
-                        ctx.render_pos.y = origin.y;
-                        if spacing > 0.0 {
-                            ctx.render_pos.x = (ctx.render_pos.x + spacing).ceil();
-                        }
+                match progress {
+                    Some(progress) => {
+                        // Reveal the part of the group that fits in the animated width
+                        let origin = ctx.render_pos;
+                        let width = ctx.measure(|m| {
+                            Self::render_group(m, &mut EventSink::default(), items, spacing, *vertical, columns.as_ref());
+                            m.render_pos.x - origin.x
+                        });
+                        let clip = (ctx.render_extents, ctx.render_flex);
+                        let clip_at = origin.x + (width * progress).round();
+                        ctx.render_extents.1.x = ctx.render_extents.1.x.min(clip_at);
+                        ctx.render_flex = false;
+                        Self::render_group(ctx, rv, items, spacing, *vertical, columns.as_ref());
+                        ctx.render_pos.x = ctx.render_pos.x.min(ctx.render_extents.1.x);
+                        (ctx.render_extents, ctx.render_flex) = clip;
                     }
+                    None => Self::render_group(ctx, rv, items, spacing, *vertical, columns.as_ref()),
                 }
-                ctx.render_pos = bounds;
                 if let Some(item) = tooltip {
                     rv.add_tooltip(PopupDesc::RenderItem {
                         item : item.clone(),
@@ -632,22 +1089,41 @@ impl Item {
                     });
                 }
             }
-            Module::FocusList { source, others, focused, spacing } => {
+            Module::FocusList { source, others, focused, empty, spacing, same_output } => {
                 let spacing = ctx.runtime.format(spacing).ok().and_then(|s| s.parse_f32()).unwrap_or(0.0);
                 let item_var = ctx.runtime.get_item_var();
                 let origin = ctx.render_pos;
                 let prev = item_var.replace(None);
-                source.read_focus_list(ctx.runtime, |focus, item| {
+                let bar_output = match same_output {
+                    true => ctx.runtime.format("{bar.output}").ok().map(|v| v.into_text().into_owned()),
+                    false => None,
+                };
+                ctx.runtime.read_focus_list(source, |focus, item| {
+                    if let Some(bar_output) = &bar_output {
+                        let other = item.read_in("output", ctx.runtime, |v| {
+                            let v = v.into_text();
+                            !v.is_empty() && v != *bar_output
+                        });
+                        if other {
+                            return;
+                        }
+                    }
                     item_var.set(Some(item.clone()));
                     let x0 = ctx.render_pos.x;
                     let mut ev = if focus {
                         focused.render(ctx)
+                    } else if !Rc::ptr_eq(empty, others) && item.read_in("empty", ctx.runtime, |v| v.as_bool()) {
+                        empty.render(ctx)
                     } else {
                         others.render(ctx)
                     };
                     let x1 = ctx.render_pos.x;
                     ev.offset_clamp(0.0, x0, x1);
                     ev.set_item(&item);
+                    if let Some(preview) = source.preview(&item, ctx.runtime) {
+                        // after the item's own tooltips, which take precedence
+                        ev.add_hover(x0, x1, PopupDesc::Preview(preview));
+                    }
                     rv.merge(ev);
                     ctx.render_pos.x += spacing;
                     ctx.render_pos.y = origin.y;
@@ -658,65 +1134,40 @@ impl Item {
             }
             Module::Bar { left, center, right, .. } => {
                 let clip = ctx.render_extents;
-                let xform = ctx.render_xform;
-                let width = clip.1.x - ctx.render_pos.x;
-                let height = (clip.1.y - ctx.render_pos.y).ceil();
-                let mut canvas_size = tiny_skia::Point { x: width, y: height };
-                let render_extents = (Point::zero(), canvas_size);
-                xform.map_points(std::slice::from_mut(&mut canvas_size));
-                let mut canvas = tiny_skia::Pixmap::new(canvas_size.x as u32, canvas_size.y as u32)
-                    .unwrap_or_else(|| tiny_skia::Pixmap::new(1,1).unwrap());
-                let mut canvas = canvas.as_mut();
+                let origin = ctx.render_pos;
+                let width = clip.1.x - origin.x;
 
                 let mut left_ev = left.render(ctx);
                 let left_size = ctx.render_pos.x.ceil();
+                let mut height = ctx.render_pos.y;
                 left_ev.offset_clamp(0.0, 0.0, left_size);
                 rv.merge(left_ev);
 
-                let mut group = Render {
-                    canvas : &mut canvas,
-                    cache : &ctx.cache,
-                    render_extents,
-                    render_xform: ctx.render_xform,
-                    render_pos: Point::zero(),
-                    render_flex : ctx.render_flex,
-
-                    font : ctx.font,
-                    font_size : ctx.font_size,
-                    font_color : ctx.font_color,
-                    text_stroke : ctx.text_stroke,
-                    text_stroke_size : ctx.text_stroke_size,
-
-                    align : ctx.align,
-                    err_name : "bar",
-                    runtime : ctx.runtime,
-                };
-
-                let mut right_ev = right.render(&mut group);
-                let right_width = group.render_pos.x.ceil();
+                let right_width = ctx.measure(|m| {
+                    m.render_pos = origin;
+                    right.render(m);
+                    m.render_pos.x - origin.x
+                }).ceil();
 
                 let right_offset = clip.1.x - right_width;
-                ctx.canvas.draw_pixmap(
-                    0, 0,
-                    group.canvas.as_ref(),
-                    &tiny_skia::PixmapPaint::default(),
-                    Transform::from_translate(right_offset * ctx.render_xform.sx, 0.0),
-                    None);
-
-                right_ev.offset_clamp(right_offset, right_offset, clip.1.x);
+                ctx.render_pos = Point { x: right_offset, y: origin.y };
+                let mut right_ev = right.render(ctx);
+                height = height.max(ctx.render_pos.y);
+                right_ev.offset_clamp(0.0, right_offset, clip.1.x);
                 rv.merge(right_ev);
 
-                group.canvas.fill(tiny_skia::Color::TRANSPARENT);
-                group.render_pos = Point::zero();
-
-                let mut cent_ev = center.render(&mut group);
-                let cent_size = group.render_pos.x.ceil();
+                let cent_size = ctx.measure(|m| {
+                    m.render_pos = origin;
+                    center.render(m);
+                    m.render_pos.x - origin.x
+                }).ceil();
 
                 let max_side = (width - cent_size) / 2.0;
                 let total_room = width - (left_size + right_width + cent_size);
                 let cent_offset;
                 if total_room < 0.0 {
                     // TODO maybe we should have cropped it?
+                    ctx.render_pos = Point { x: clip.1.x, y: height };
                     return;
                 } else if left_size > max_side {
                     // left side is too long to properly center; put it just to the right of that
@@ -728,16 +1179,13 @@ impl Item {
                     // Actually center the center module
                     cent_offset = max_side;
                 }
-                ctx.canvas.draw_pixmap(
-                    0, 0,
-                    group.canvas.as_ref(),
-                    &tiny_skia::PixmapPaint::default(),
-                    Transform::from_translate(cent_offset * ctx.render_xform.sx, 0.0),
-                    None);
-                cent_ev.offset_clamp(cent_offset, cent_offset, cent_offset + cent_size);
+                ctx.render_pos = Point { x: cent_offset, y: origin.y };
+                let mut cent_ev = center.render(ctx);
+                height = height.max(ctx.render_pos.y);
+                cent_ev.offset_clamp(0.0, cent_offset, cent_offset + cent_size);
                 rv.merge(cent_ev);
 
-                ctx.render_pos.x = clip.1.x;
+                ctx.render_pos = Point { x: clip.1.x, y: height };
             }
             Module::Icon { name, fallback, tooltip } => {
                 let markup = self.format.markup;
@@ -758,26 +1206,60 @@ impl Item {
                     });
                 }
             },
+            Module::Debug(d) => {
+                d.render(ctx);
+            }
+            Module::Custom(source) if source.entry_item().is_some() => {
+                let item = &source.entry_item().unwrap().0;
+                let mut entries = Vec::new();
+                source.read_focus_list(ctx.runtime, &mut |_, entry| entries.push(entry));
+                for entry in entries {
+                    item.render_clamped_item(ctx, rv, &IterationItem::Custom(entry));
+                }
+            }
+            Module::Pages(pages) => {
+                pages.render(ctx, rv);
+            }
+            Module::Separator(sep) => {
+                sep.render(ctx);
+            }
             Module::SwayTree(tree) => {
                 tree.render(ctx, rv);
             }
             #[cfg(feature="dbus")]
-            Module::Tray { passive, active, urgent } => {
-                tray::show(ctx, rv, [passive, active, urgent])
+            Module::Tray { passive, active, urgent, overrides } => {
+                tray::show(ctx, rv, [passive, active, urgent], overrides)
             }
 
             // All other modules are rendered as text
             _ => {
                 let markup = self.format.markup;
                 let oneline = self.format.oneline;
-                let mut text = self.data.read_to_owned(ctx.err_name, "text", &ctx.runtime).into_text();
+                let mut text = ctx.runtime.with_markup(markup, || ctx.runtime.read_text(ctx.err_name, &self.data)).into_text();
                 if oneline && text.contains('\n') {
                     text = text.replace('\n', " ").into();
                 }
+                if self.format.ellipsize && !markup {
+                    let room = ctx.render_extents.1.x - ctx.render_pos.x;
+                    text = ellipsize(ctx, text, room);
+                }
 
                 render_font_item(ctx, &text, markup);
 
                 match &self.data {
+                    Module::Clipboard { state } if state.popup => {
+                        rv.add_tooltip(PopupDesc::Clipboard(state.popup()));
+                    }
+                    Module::Menu(menu) => {
+                        rv.add_tooltip(PopupDesc::Menu(menu.popup()));
+                    }
+                    Module::Prompt(prompt) => {
+                        rv.add_tooltip(PopupDesc::Prompt(prompt.popup(ctx.runtime)));
+                    }
+                    #[cfg(feature="dbus")]
+                    Module::MediaPlayer2 { target, popup : true, .. } => {
+                        rv.add_tooltip(PopupDesc::MediaPlayer(mpris::MprisPopup::new(target)));
+                    }
                     Module::Formatted { tooltip : Some(item), .. } => {
                         rv.add_tooltip(PopupDesc::RenderItem {
                             item : item.clone(),
@@ -785,6 +1267,12 @@ impl Item {
                         });
                     }
                     Module::Formatted { tooltip : None, .. } => {}
+                    Module::Custom(source) if source.popup().is_some() => {
+                        rv.add_tooltip(PopupDesc::RenderItem {
+                            item : source.popup().unwrap().0.clone(),
+                            iter : ctx.runtime.copy_item_var(),
+                        });
+                    }
                     _ => {
                         let tt = self.data.read_to_owned(ctx.err_name, "tooltip", &ctx.runtime).into_text();
                         if !tt.is_empty() {
@@ -810,6 +1298,15 @@ pub enum PopupDesc {
         source : Rc<Item>,
         iter : Option<IterationItem>,
     },
+    Clipboard(wlr::ClipboardPopup),
+    /// Messages logged while drawing an item, shown by its error badge
+    Error(Rc<str>),
+    #[cfg(feature="dbus")]
+    MediaPlayer(mpris::MprisPopup),
+    Menu(menu::MenuPopup),
+    Prompt(prompt::PromptPopup),
+    /// The last screenshot of a workspace
+    Preview(screencopy::PreviewPopup),
     #[cfg(feature="dbus")]
     Tray(tray::TrayPopup),
 }
@@ -823,6 +1320,13 @@ impl PartialEq for PopupDesc {
             (PopupDesc::TextItem { source : a, iter : ai }, PopupDesc::TextItem { source : b, iter : bi }) => {
                 Rc::ptr_eq(a,b) && ai == bi
             }
+            (PopupDesc::Clipboard(a), PopupDesc::Clipboard(b)) => a == b,
+            (PopupDesc::Error(a), PopupDesc::Error(b)) => a == b,
+            #[cfg(feature="dbus")]
+            (PopupDesc::MediaPlayer(a), PopupDesc::MediaPlayer(b)) => a == b,
+            (PopupDesc::Menu(a), PopupDesc::Menu(b)) => a == b,
+            (PopupDesc::Prompt(a), PopupDesc::Prompt(b)) => a == b,
+            (PopupDesc::Preview(a), PopupDesc::Preview(b)) => a == b,
             #[cfg(feature="dbus")]
             (PopupDesc::Tray(a), PopupDesc::Tray(b)) => a == b,
             _ => false,
@@ -831,8 +1335,64 @@ impl PartialEq for PopupDesc {
 }
 
 impl PopupDesc {
-    pub fn render_popup(&mut self, runtime : &Runtime, target : &mut tiny_skia::PixmapMut, scale: i32) -> (i32, i32) {
-        target.fill(tiny_skia::Color::BLACK);
+    /// A short name for the kind of popup, for debugging output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PopupDesc::RenderItem { .. } => "item",
+            PopupDesc::TextItem { .. } => "tooltip",
+            PopupDesc::Clipboard(_) => "clipboard",
+            PopupDesc::Error(_) => "error",
+            #[cfg(feature="dbus")]
+            PopupDesc::MediaPlayer(_) => "mpris",
+            PopupDesc::Menu(_) => "menu",
+            PopupDesc::Prompt(_) => "prompt",
+            PopupDesc::Preview(_) => "preview",
+            #[cfg(feature="dbus")]
+            PopupDesc::Tray(_) => "tray",
+        }
+    }
+
+    /// The popup an item shows on hover, or the item itself if it has none
+    pub fn for_item(item : &Rc<Item>, runtime : &Runtime) -> Self {
+        let mut canvas = Canvas::new(1, 1);
+        let mut ctx = Render {
+            canvas : &mut canvas,
+            cache : &runtime.cache,
+            font : &runtime.fonts[0],
+            font_size : 16.0,
+            line_spacing : 1.0,
+            font_color : Color::WHITE,
+            align : Align::bar_default(),
+            render_extents : (Point::zero(), Point { x: 1.0, y: 1.0 }),
+            render_xform: Transform::identity(),
+            render_pos : Point::zero(),
+            render_flex : false,
+            measure_only : true,
+            err_name: "popup",
+            text_stroke : None,
+            text_stroke_size : None,
+            text_shadow : None,
+            runtime,
+        };
+        item.render(&mut ctx).take_hover()
+            .unwrap_or_else(|| PopupDesc::RenderItem { item : item.clone(), iter : None })
+    }
+
+    /// Get the size of this popup without drawing it
+    pub fn measure_popup(&mut self, runtime : &Runtime, scale: i32) -> (i32, i32) {
+        let mut canvas = Canvas::new(1, 1);
+        self.render_popup_with(runtime, &mut canvas, scale, (0.0, 0.0), true).0
+    }
+
+    /// Render the popup, with its content moved by the given offset (for padding and scrolling).
+    ///
+    /// Returns the size of the content and the regions (in content coordinates) that respond to
+    /// clicks, which are highlighted on hover.
+    pub fn render_popup(&mut self, runtime : &Runtime, target : &mut Canvas, scale: i32, offset : (f32, f32)) -> ((i32, i32), Vec<tiny_skia::Rect>) {
+        self.render_popup_with(runtime, target, scale, offset, false)
+    }
+
+    fn render_popup_with(&mut self, runtime : &Runtime, target : &mut Canvas, scale: i32, offset : (f32, f32), measure_only : bool) -> ((i32, i32), Vec<tiny_skia::Rect>) {
         let font = &runtime.fonts[0];
         let render_extents = (Point::zero(), Point { x: target.width() as f32, y: target.height() as f32 });
 
@@ -841,52 +1401,74 @@ impl PopupDesc {
             cache : &runtime.cache,
             font,
             font_size : 16.0,
+            line_spacing : 1.0,
             font_color : Color::WHITE,
             align : Align::bar_default(),
             render_extents,
-            render_xform: Transform::from_scale(scale as f32, scale as f32),
+            render_xform: Transform::from_scale(scale as f32, scale as f32).pre_translate(offset.0, offset.1),
             render_pos : tiny_skia::Point { x: 2.0, y: 2.0 },
             render_flex : true,
+            measure_only,
             err_name: "popup",
             text_stroke : None,
             text_stroke_size : None,
+            text_shadow : None,
             runtime,
         };
 
-        self.render(&mut ctx);
-        (ctx.render_pos.x as i32, ctx.render_pos.y as i32)
+        let hot = self.render(&mut ctx);
+        ((ctx.render_pos.x as i32, ctx.render_pos.y as i32), hot)
     }
 
-    fn render(&mut self, ctx : &mut Render) {
+    fn render(&mut self, ctx : &mut Render) -> Vec<tiny_skia::Rect> {
         match self {
             PopupDesc::RenderItem { item, iter } => {
                 let item_var = ctx.runtime.get_item_var();
                 item_var.set(iter.clone());
-                item.render(ctx);
+                let sink = item.render(ctx);
                 item_var.set(None);
+                let mut hot = Vec::new();
+                let height = ctx.render_pos.y;
+                sink.for_active_regions(|lo, hi| {
+                    hot.extend(tiny_skia::Rect::from_xywh(lo, 0.0, hi - lo, height));
+                });
+                hot
             }
             PopupDesc::TextItem { source, iter } => {
                 let item_var = ctx.runtime.get_item_var();
                 item_var.set(iter.clone());
-                let value = source.data.read_to_owned("tooltip", "tooltip", ctx.runtime).into_text();
+                let markup = source.format.markup;
+                let value = ctx.runtime.with_markup(markup, || source.data.read_to_owned("tooltip", "tooltip", ctx.runtime)).into_text();
                 item_var.set(None);
 
                 if value.is_empty() {
-                    return;
+                    return Vec::new();
                 }
 
-                let markup = source.format.markup;
-
                 let (width, height) = render_font(ctx, (2.0, 2.0), &value, markup);
                 ctx.render_pos.x = width + 4.0;
                 ctx.render_pos.y = height + 4.0;
+                Vec::new()
+            }
+            PopupDesc::Clipboard(clip) => clip.render(ctx),
+            PopupDesc::Error(msg) => {
+                let (width, height) = render_font(ctx, (2.0, 2.0), msg, false);
+                ctx.render_pos.x = width + 4.0;
+                ctx.render_pos.y = height + 4.0;
+                Vec::new()
             }
             #[cfg(feature="dbus")]
+            PopupDesc::MediaPlayer(player) => player.render(ctx),
+            PopupDesc::Menu(menu) => menu.render(ctx),
+            PopupDesc::Prompt(prompt) => prompt.render(ctx),
+            PopupDesc::Preview(preview) => preview.render(ctx),
+            #[cfg(feature="dbus")]
             PopupDesc::Tray(tray) => tray.render(ctx),
         }
     }
 
-    pub fn button(&mut self, x : f64, y : f64, button : u32, runtime : &mut Runtime) {
+    /// Handle a click in the popup; returns true if the popup should be closed
+    pub fn button(&mut self, x : f64, y : f64, button : u32, runtime : &mut Runtime) -> bool {
         match self {
             PopupDesc::RenderItem { item, iter } => {
                 if let Some(ii) = iter.as_ref() {
@@ -896,10 +1478,34 @@ impl PopupDesc {
                 } else {
                     item.events.button(x as f32, y as f32, button, runtime);
                 }
+                false
             }
-            PopupDesc::TextItem { .. } => { }
+            PopupDesc::TextItem { .. } => false,
+            PopupDesc::Error(_) => false,
+            PopupDesc::Clipboard(clip) => clip.button(x, y, button, runtime),
+            #[cfg(feature="dbus")]
+            PopupDesc::MediaPlayer(player) => player.button(x, y, button, runtime),
+            PopupDesc::Menu(menu) => menu.button(x, y, button, runtime),
+            PopupDesc::Prompt(_) => false,
+            PopupDesc::Preview(_) => false,
             #[cfg(feature="dbus")]
-            PopupDesc::Tray(tray) => tray.button(x, y, button, runtime),
+            PopupDesc::Tray(tray) => {
+                tray.button(x, y, button, runtime);
+                false
+            }
+        }
+    }
+
+    /// True if this popup has a text field that needs keyboard input
+    pub fn takes_text(&self) -> bool {
+        matches!(self, PopupDesc::Prompt(_))
+    }
+
+    /// Handle a key press in a popup that takes text; returns true if the popup should be closed
+    pub fn key(&mut self, keysym : u32, text : Option<&str>, runtime : &Runtime) -> bool {
+        match self {
+            PopupDesc::Prompt(prompt) => prompt.key(keysym, text, runtime),
+            _ => false,
         }
     }
 }
@@ -0,0 +1,188 @@
+//! Lock state of the Secret Service keyring and the keys loaded in the SSH agent
+use crate::data::{Periodic,Value};
+use crate::dbus::{DBus,typed};
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,toml_to_f64};
+use log::{debug,error};
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt,AsyncWriteExt};
+use tokio::net::UnixStream;
+use zbus::Connection;
+use zbus::zvariant::{ObjectPath,OwnedObjectPath,OwnedValue};
+
+const SECRETS_NAME : &str = "org.freedesktop.secrets";
+const SECRETS_PATH : &str = "/org/freedesktop/secrets";
+const SERVICE_IFACE : &str = "org.freedesktop.Secret.Service";
+const COLLECTION_IFACE : &str = "org.freedesktop.Secret.Collection";
+
+const SSH_AGENT_SUCCESS : u8 = 6;
+const SSH2_AGENTC_REQUEST_IDENTITIES : u8 = 11;
+const SSH2_AGENT_IDENTITIES_ANSWER : u8 = 12;
+const SSH2_AGENTC_REMOVE_ALL_IDENTITIES : u8 = 19;
+
+/// Send one request to the SSH agent and return the reply
+async fn agent_request(socket : &PathBuf, request : u8) -> io::Result<Vec<u8>> {
+    let run = async {
+        let mut stream = UnixStream::connect(socket).await?;
+        stream.write_all(&[0, 0, 0, 1, request]).await?;
+        let len = stream.read_u32().await? as usize;
+        if len == 0 || len > 256 * 1024 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad reply length"));
+        }
+        let mut reply = vec![0; len];
+        stream.read_exact(&mut reply).await?;
+        Ok(reply)
+    };
+    match tokio::time::timeout(Duration::from_secs(2), run).await {
+        Ok(rv) => rv,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+    }
+}
+
+/// The default keyring, or None if there is none
+async fn default_collection(zbus : &Connection) -> zbus::Result<Option<OwnedObjectPath>> {
+    let reply = zbus.call_method(Some(SECRETS_NAME), SECRETS_PATH, Some(SERVICE_IFACE), "ReadAlias", &("default",)).await?;
+    let path : OwnedObjectPath = reply.body()?;
+    Ok(Some(path).filter(|p| p.as_str() != "/"))
+}
+
+#[derive(Debug,Default)]
+struct State {
+    socket : Option<PathBuf>,
+    /// None if the agent could not be reached
+    keys : Cell<Option<u32>>,
+    /// None if there is no keyring
+    locked : Cell<Option<bool>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl State {
+    async fn query_keys(&self) -> io::Result<u32> {
+        let socket = self.socket.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "SSH_AUTH_SOCK is not set"))?;
+        let reply = agent_request(socket, SSH2_AGENTC_REQUEST_IDENTITIES).await?;
+        match reply.get(..5) {
+            Some(&[SSH2_AGENT_IDENTITIES_ANSWER, a, b, c, d]) => Ok(u32::from_be_bytes([a, b, c, d])),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply")),
+        }
+    }
+
+    async fn query_locked(&self) -> Result<Option<bool>, Box<dyn Error>> {
+        let zbus = DBus::get_session().connection().await;
+        let path = match default_collection(&zbus).await? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let reply = zbus.call_method(Some(SECRETS_NAME), path.as_str(), Some("org.freedesktop.DBus.Properties"), "Get", &(COLLECTION_IFACE, "Locked")).await?;
+        let value : OwnedValue = reply.body()?;
+        Ok(typed::<bool>(&value, "Locked"))
+    }
+
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let keys = match self.query_keys().await {
+            Ok(keys) => Some(keys),
+            Err(e) => {
+                debug!("Could not list SSH agent keys: {}", e);
+                None
+            }
+        };
+        let locked = match self.query_locked().await {
+            Ok(locked) => locked,
+            Err(e) => {
+                debug!("Could not read the keyring lock state: {}", e);
+                None
+            }
+        };
+        let old = (self.keys.replace(keys), self.locked.replace(locked));
+        if old != (keys, locked) {
+            self.interested.take().notify_data("keyring");
+        }
+        self.running.set(false);
+    }
+
+    async fn lock(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        let zbus = DBus::get_session().connection().await;
+        if let Some(path) = default_collection(&zbus).await? {
+            let objects = [ObjectPath::from(&path)];
+            zbus.call_method(Some(SECRETS_NAME), SECRETS_PATH, Some(SERVICE_IFACE), "Lock", &(&objects[..],)).await?;
+        }
+        self.refresh().await;
+        Ok(())
+    }
+
+    async fn forget_keys(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        let socket = self.socket.as_ref().ok_or("SSH_AUTH_SOCK is not set")?;
+        let reply = agent_request(socket, SSH2_AGENTC_REMOVE_ALL_IDENTITIES).await?;
+        if reply.first() != Some(&SSH_AGENT_SUCCESS) {
+            return Err("the agent refused to remove the keys".into());
+        }
+        self.refresh().await;
+        Ok(())
+    }
+}
+
+/// The "keyring" module
+#[derive(Debug)]
+pub struct Keyring {
+    poll : Periodic<Rc<State>>,
+}
+
+impl Keyring {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let socket = config.get("ssh-socket").and_then(|v| v.as_str()).map(PathBuf::from)
+            .or_else(|| std::env::var_os("SSH_AUTH_SOCK").map(PathBuf::from));
+        let state = State { socket, ..Default::default() };
+        Keyring {
+            poll : Periodic::new(toml_to_f64(config.get("poll")).unwrap_or(10.0), Rc::new(state)),
+        }
+    }
+}
+
+impl Source for Keyring {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.poll.read_refresh_async(rt, |state| state.clone().refresh());
+        let state = self.poll.data();
+        state.interested.take_in(|i| i.add(rt));
+        let locked = state.locked.get();
+        let keys = state.keys.get();
+        match key {
+            "" | "text" => match (locked, keys) {
+                (Some(true), _) => Value::Borrow("locked"),
+                (Some(false), _) => Value::Borrow("unlocked"),
+                // only an agent: it is as good as locked if it holds no keys
+                (None, Some(0)) => Value::Borrow("locked"),
+                (None, Some(_)) => Value::Borrow("unlocked"),
+                (None, None) => Value::Null,
+            },
+            "locked" => locked.map_or(Value::Null, Value::Bool),
+            "keys" => keys.map_or(Value::Null, |n| Value::Float(n as f64)),
+            _ => Value::Null,
+        }
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
+        let state = self.poll.data().clone();
+        match (key, value.as_str_fast()) {
+            ("", "" | "lock") => util::spawn("Keyring lock", state.lock()),
+            ("", "forget-keys") => util::spawn("SSH agent key removal", state.forget_keys()),
+            ("", "lock-all") => {
+                util::spawn("Keyring lock", state.clone().lock());
+                util::spawn("SSH agent key removal", state.forget_keys());
+            }
+            (key, value) => error!("Ignoring write of '{}' to {}.{}", value, name, key),
+        }
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.refresh();
+        true
+    }
+}
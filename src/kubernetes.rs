@@ -0,0 +1,203 @@
+//! The current kubectl context and the health of its cluster
+use crate::data::{Periodic,Value};
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,spawn_handle,toml_to_f64};
+use futures_util::future::RemoteHandle;
+use json::JsonValue;
+use log::debug;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Which pods to check
+#[derive(Debug,PartialEq)]
+enum Pods {
+    None,
+    Namespace,
+    All,
+}
+
+#[derive(Debug,Default,Clone,PartialEq)]
+struct Context {
+    name : String,
+    namespace : String,
+    cluster : String,
+    server : String,
+    user : String,
+}
+
+/// A pod that is not running as it should
+fn is_failing(pod : &JsonValue) -> bool {
+    let status = &pod["status"];
+    if matches!(status["phase"].as_str(), Some("Failed" | "Unknown")) {
+        return true;
+    }
+    status["initContainerStatuses"].members().chain(status["containerStatuses"].members()).any(|c| {
+        // such as CrashLoopBackOff or ImagePullBackOff
+        c["state"]["waiting"]["reason"].as_str().is_some_and(|r| !matches!(r, "ContainerCreating" | "PodInitializing"))
+    })
+}
+
+#[derive(Debug)]
+struct State {
+    kubectl : Box<str>,
+    check : bool,
+    pods : Pods,
+    context : Cell<Option<Context>>,
+    reachable : Cell<Option<bool>>,
+    failing : Cell<Option<u32>>,
+    total : Cell<Option<u32>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl State {
+    async fn kubectl(&self, args : &str) -> String {
+        let cmd = format!("{} {} 2>/dev/null", self.kubectl, args);
+        match util::shell_output(&cmd, &[]).await {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("Could not run {}: {}", cmd, e);
+                String::new()
+            }
+        }
+    }
+
+    async fn read_context(&self) -> Option<Context> {
+        let config = json::parse(&self.kubectl("config view --minify -o json").await).ok()?;
+        let name = config["current-context"].as_str()?;
+        let context = &config["contexts"][0]["context"];
+        Some(Context {
+            name : name.to_owned(),
+            namespace : context["namespace"].as_str().unwrap_or("default").to_owned(),
+            cluster : context["cluster"].as_str().unwrap_or_default().to_owned(),
+            server : config["clusters"][0]["cluster"]["server"].as_str().unwrap_or_default().to_owned(),
+            user : context["user"].as_str().unwrap_or_default().to_owned(),
+        })
+    }
+
+    /// Read the context again, after the kubeconfig file changes
+    async fn refresh_context(self : Rc<Self>) {
+        let context = self.read_context().await;
+        if self.context.take_in(|c| *c != context) {
+            self.context.set(context);
+            // the cluster's status is out of date
+            self.reachable.set(None);
+            self.failing.set(None);
+            self.total.set(None);
+            self.interested.take().notify_data("kubernetes");
+            self.refresh().await;
+        }
+    }
+
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let context = self.read_context().await;
+        let connected = context.is_some();
+        self.context.set(context);
+        if connected && self.check {
+            let ready = self.kubectl("get --raw /readyz --request-timeout=5s").await;
+            self.reachable.set(Some(ready.trim() == "ok"));
+        }
+        if connected && self.pods != Pods::None {
+            let args = match self.pods {
+                Pods::All => "get pods --all-namespaces -o json --request-timeout=10s",
+                _ => "get pods -o json --request-timeout=10s",
+            };
+            match json::parse(&self.kubectl(args).await) {
+                Ok(list) => {
+                    self.failing.set(Some(list["items"].members().filter(|p| is_failing(p)).count() as u32));
+                    self.total.set(Some(list["items"].len() as u32));
+                }
+                Err(_) => {
+                    self.failing.set(None);
+                    self.total.set(None);
+                }
+            }
+        }
+        self.interested.take().notify_data("kubernetes");
+        self.running.set(false);
+    }
+}
+
+/// The "kubernetes" module
+#[derive(Debug)]
+pub struct Kubernetes {
+    poll : Periodic<Rc<State>>,
+    watch : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Kubernetes {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let pods = match config.get("pods") {
+            Some(toml::Value::Boolean(true)) => Pods::Namespace,
+            Some(toml::Value::String(s)) if s == "all" => Pods::All,
+            Some(toml::Value::String(s)) if s == "namespace" => Pods::Namespace,
+            _ => Pods::None,
+        };
+        let state = State {
+            kubectl : config.get("kubectl").and_then(|v| v.as_str()).unwrap_or("kubectl").into(),
+            check : config.get("check").and_then(|v| v.as_bool()).unwrap_or(false),
+            pods,
+            context : Cell::default(),
+            reachable : Cell::default(),
+            failing : Cell::default(),
+            total : Cell::default(),
+            running : Cell::new(false),
+            interested : Cell::default(),
+        };
+        Kubernetes {
+            poll : Periodic::new(toml_to_f64(config.get("poll")).unwrap_or(60.0), Rc::new(state)),
+            watch : Cell::default(),
+        }
+    }
+}
+
+/// The file kubectl saves the current context in
+fn kubeconfig() -> Option<PathBuf> {
+    match std::env::var_os("KUBECONFIG") {
+        Some(paths) => std::env::split_paths(&paths).next(),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kube/config")),
+    }
+}
+
+impl Source for Kubernetes {
+    fn init(&self, _name : &str, _rt : &Runtime) {
+        if let Some(path) = kubeconfig() {
+            let state = self.poll.data().clone();
+            self.watch.set(Some(spawn_handle("kubeconfig watch", util::watch_file(path, move || {
+                util::spawn_noerr(state.clone().refresh_context());
+            }))));
+        }
+    }
+
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.poll.read_refresh_async(rt, |state| state.clone().refresh());
+        let state = self.poll.data();
+        state.interested.take_in(|i| i.add(rt));
+        let count = |n : Option<u32>| n.map_or(Value::Null, |n| Value::Float(n as f64));
+        match key {
+            "reachable" => state.reachable.get().map_or(Value::Null, Value::Bool),
+            "failing" => count(state.failing.get()),
+            "pods" => count(state.total.get()),
+            _ => state.context.take_in(|c| match c {
+                Some(c) => Value::Owned(match key {
+                    "" | "text" | "context" => c.name.clone(),
+                    "namespace" => c.namespace.clone(),
+                    "cluster" => c.cluster.clone(),
+                    "server" => c.server.clone(),
+                    "user" => c.user.clone(),
+                    _ => return Value::Null,
+                }),
+                None => Value::Null,
+            }),
+        }
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.refresh();
+        true
+    }
+}
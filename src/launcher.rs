@@ -0,0 +1,310 @@
+//! Application launchers read from desktop entries
+use crate::data::Value;
+use crate::registry::{Entry,Source,Template};
+use crate::state::Runtime;
+use crate::util::{self,Cell};
+use log::{error,info,warn};
+use std::cell::RefCell;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::{Path,PathBuf};
+use std::rc::Rc;
+use wayland_client::Main;
+use wayland_protocols::staging::xdg_activation::v1::client::xdg_activation_v1::XdgActivationV1;
+
+thread_local! {
+    /// The activation manager used for startup notification, once bound (None inside if the
+    /// compositor lacks it)
+    static ACTIVATION : RefCell<Option<Option<Main<XdgActivationV1>>>> = const { RefCell::new(None) };
+}
+
+/// One application, from its desktop entry
+#[derive(Debug)]
+pub struct Launcher {
+    id : Box<str>,
+    name : Box<str>,
+    comment : Box<str>,
+    icon : Box<str>,
+    exec : Vec<String>,
+    workdir : Option<PathBuf>,
+    terminal : bool,
+    startup_notify : bool,
+    terminal_cmd : Rc<str>,
+    scope : Option<bool>,
+}
+
+/// Expand the field codes in the arguments.  No files or URLs are passed, so those codes are
+/// removed; an argument that was only a removed code is dropped entirely.
+fn expand_field_codes(args : Vec<String>, name : &str, icon : &str, path : &Path) -> Vec<String> {
+    let mut rv = Vec::with_capacity(args.len());
+    for arg in args {
+        match &*arg {
+            "%f" | "%F" | "%u" | "%U" | "%d" | "%D" | "%n" | "%N" | "%v" | "%m" => continue,
+            "%i" => {
+                if !icon.is_empty() {
+                    rv.push("--icon".into());
+                    rv.push(icon.into());
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let mut out = String::with_capacity(arg.len());
+        let mut chars = arg.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('c') => out.push_str(name),
+                Some('k') => out.push_str(&path.to_string_lossy()),
+                _ => {}
+            }
+        }
+        rv.push(out);
+    }
+    rv
+}
+
+impl Launcher {
+    fn load(rt : &Runtime, id : &str, terminal_cmd : &Rc<str>, scope : Option<bool>) -> Option<Self> {
+        let id = id.strip_suffix(".desktop").unwrap_or(id);
+        let path = match rt.xdg.find_data_file(format!("applications/{}.desktop", id)) {
+            Some(path) => path,
+            None => {
+                warn!("Could not find a desktop entry for '{}'", id);
+                return None;
+            }
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Could not read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let mut in_entry = false;
+        let (mut name, mut comment, mut icon, mut exec, mut workdir) = ("", "", "", None, None);
+        let (mut terminal, mut startup_notify) = (false, false);
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                in_entry = line == "[Desktop Entry]";
+                continue;
+            }
+            if !in_entry {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "Name" => name = value,
+                "Comment" => comment = value,
+                "Icon" => icon = value,
+                "Exec" => exec = Some(value),
+                "Path" if !value.is_empty() => workdir = Some(PathBuf::from(value)),
+                "Terminal" => terminal = value == "true",
+                "StartupNotify" => startup_notify = value == "true",
+                _ => {}
+            }
+        }
+        let name = if name.is_empty() { id } else { name };
+        let exec = match exec.and_then(util::split_args) {
+            Some(args) if !args.is_empty() => expand_field_codes(args, name, icon, &path),
+            _ => {
+                warn!("Desktop entry {} has no valid Exec key", path.display());
+                return None;
+            }
+        };
+        Some(Launcher {
+            id : id.into(),
+            name : name.into(),
+            comment : comment.into(),
+            icon : icon.into(),
+            exec,
+            workdir,
+            terminal,
+            startup_notify,
+            terminal_cmd : terminal_cmd.clone(),
+            scope,
+        })
+    }
+
+    /// Start the application, passing it a startup notification token if the compositor supports
+    /// xdg-activation
+    pub fn launch(self : &Rc<Self>, rt : &Runtime) {
+        let manager = match &rt.wayland {
+            Some(wayland) if self.startup_notify => ACTIVATION.with(|cell| {
+                cell.borrow_mut().get_or_insert_with(|| {
+                    wayland.env.manager.instantiate_exact::<XdgActivationV1>(1).ok()
+                }).clone()
+            }),
+            _ => None,
+        };
+        let manager = match manager {
+            Some(manager) => manager,
+            None => return self.spawn(None, rt),
+        };
+        let token = manager.get_activation_token();
+        let app = self.clone();
+        let scope = self.scope.unwrap_or(rt.exec_scope);
+        token.quick_assign(move |token, event, _data| {
+            use wayland_protocols::staging::xdg_activation::v1::client::xdg_activation_token_v1::Event;
+            match event {
+                Event::Done { token : id } => {
+                    app.spawn_in(Some(&id), scope);
+                    token.destroy();
+                }
+                _ => {}
+            }
+        });
+        token.set_app_id(self.id.to_string());
+        token.commit();
+        // the requests are sent when the next frame flushes the connection
+        rt.request_draw();
+    }
+
+    fn spawn(&self, token : Option<&str>, rt : &Runtime) {
+        self.spawn_in(token, self.scope.unwrap_or(rt.exec_scope));
+    }
+
+    fn spawn_in(&self, token : Option<&str>, scope : bool) {
+        let mut args = self.exec.iter().map(String::as_str);
+        let mut cmd = if self.terminal {
+            let mut cmd = util::command(&self.terminal_cmd, scope);
+            cmd.arg("-e").args(args);
+            cmd
+        } else {
+            let mut cmd = util::command(args.next().unwrap_or(""), scope);
+            cmd.args(args);
+            cmd
+        };
+        if let Some(dir) = &self.workdir {
+            cmd.current_dir(dir);
+        }
+        if let Some(token) = token {
+            cmd.env("XDG_ACTIVATION_TOKEN", token);
+            cmd.env("DESKTOP_STARTUP_ID", token);
+        }
+        info!("Launching {} ({})", self.id, self.exec.join(" "));
+        match cmd.process_group(0).spawn() {
+            Ok(child) => drop(child),
+            Err(e) => error!("Could not launch {}: {}", self.id, e),
+        }
+    }
+
+}
+
+impl Entry for Launcher {
+    fn kind(&self) -> &'static str {
+        "launcher"
+    }
+
+    fn read<'a>(&'a self, key : &str, _rt : &Runtime) -> Value<'a> {
+        match key {
+            "id" => Value::Borrow(&self.id),
+            "name" | "text" | "" => Value::Borrow(&self.name),
+            "comment" => Value::Borrow(&self.comment),
+            "icon" => Value::Borrow(&self.icon),
+            "exec" => Value::Owned(self.exec.join(" ")),
+            _ => Value::Null,
+        }
+    }
+
+    fn write(self : Rc<Self>, key : &str, value : Value, rt : &Runtime) {
+        match (key, value.as_str_fast()) {
+            ("", "") | ("", "launch") => self.launch(rt),
+            (key, value) => error!("Ignoring write of '{}' to launcher {}.{}", value, self.id, key),
+        }
+    }
+}
+
+/// A list of applications shown as icons that start them when clicked
+#[derive(Debug)]
+pub struct Launchers {
+    apps : Vec<Box<str>>,
+    terminal : Rc<str>,
+    scope : Option<bool>,
+    item : Template,
+    entries : Cell<Option<Vec<Rc<Launcher>>>>,
+}
+
+impl Launchers {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let apps = match value.get("apps") {
+            Some(toml::Value::Array(list)) => list.iter().filter_map(|v| v.as_str()).map(Into::into).collect(),
+            Some(toml::Value::String(s)) => s.split_whitespace().map(Into::into).collect(),
+            _ => Vec::new(),
+        };
+        let terminal = value.get("terminal").and_then(|v| v.as_str()).map(String::from)
+            .or_else(|| std::env::var("TERMINAL").ok())
+            .unwrap_or_else(|| "xterm".into());
+        let item = match value.get("item") {
+            Some(item) => Template::from_toml(item),
+            None => {
+                let mut item = toml::value::Table::new();
+                item.insert("type".into(), "icon".into());
+                item.insert("name".into(), "{item.icon}".into());
+                item.insert("fallback".into(), "{item.name}".into());
+                item.insert("tooltip".into(), "{item.name}".into());
+                let mut click = toml::value::Table::new();
+                click.insert("send".into(), "item".into());
+                click.insert("format".into(), "launch".into());
+                item.insert("on-click".into(), toml::Value::Table(click));
+                Template::from_toml(&toml::Value::Table(item))
+            }
+        };
+        Launchers {
+            apps,
+            terminal : terminal.into(),
+            scope : value.get("scope").and_then(|v| v.as_bool()),
+            item,
+            entries : Cell::new(None),
+        }
+    }
+
+    /// The applications, read from their desktop entries on first use
+    fn entries(&self, rt : &Runtime) -> Vec<Rc<Launcher>> {
+        self.entries.take_in(|entries| {
+            entries.get_or_insert_with(|| {
+                self.apps.iter()
+                    .filter_map(|id| Launcher::load(rt, id, &self.terminal, self.scope))
+                    .map(Rc::new)
+                    .collect()
+            }).clone()
+        })
+    }
+
+}
+
+impl Source for Launchers {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        match key {
+            "count" => Value::Float(self.entries(rt).len() as f64),
+            _ => Value::Null,
+        }
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        let id = value.into_text();
+        let id = id.strip_suffix(".desktop").unwrap_or(&id);
+        match self.entries(rt).iter().find(|l| &*l.id == id) {
+            Some(launcher) if key.is_empty() => launcher.launch(rt),
+            _ => error!("Ignoring write of '{}' to {}.{}", id, name, key),
+        }
+    }
+
+    fn read_focus_list(&self, rt : &Runtime, f : &mut dyn FnMut(bool, Rc<dyn Entry>)) {
+        for launcher in self.entries(rt) {
+            f(false, launcher);
+        }
+    }
+
+    fn entry_item(&self) -> Option<&Template> {
+        Some(&self.item)
+    }
+}
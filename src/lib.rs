@@ -0,0 +1,145 @@
+//! A status bar for Wayland compositors that support the layer-shell protocol.
+//!
+//! The `rwaybar` binary only calls [run].  Other programs can embed parts of the bar:
+//!
+//! - [headless::render] draws the bars of a configuration to images, without a compositor
+//! - [register] adds a new item `type`, backed by a [Source] that supplies its [Value]s
+//! - [Runtime] is passed to sources; it expands format strings and schedules redraws through
+//!   [NotifierList]
+#![warn(missing_docs)]
+use std::error::Error;
+
+mod anim;
+mod astronomy;
+mod bar;
+mod cli;
+mod color;
+mod connectivity;
+mod data;
+#[cfg(feature="dbus")]
+mod dbus;
+mod debug;
+mod event;
+mod feed;
+mod filter;
+mod font;
+pub mod headless;
+mod history;
+mod hyprland;
+mod icon;
+#[cfg(feature="dbus")]
+mod idle;
+mod input;
+mod ipc;
+mod item;
+#[cfg(feature="dbus")]
+mod keyring;
+mod kubernetes;
+mod launcher;
+mod locale;
+mod logging;
+#[cfg(feature="lua")]
+mod lua;
+mod menu;
+mod metrics;
+mod mock;
+#[cfg(feature="dbus")]
+mod notifications;
+#[cfg(feature="dbus")]
+mod mpris;
+mod night_light;
+mod output_power;
+mod pages;
+#[cfg(feature="wasm")]
+mod plugin;
+#[cfg(feature="dbus")]
+mod power_profile;
+mod process;
+mod prompt;
+#[cfg(feature="pulse")]
+mod pulse;
+#[cfg(feature="pulse")]
+mod pulse_tokio;
+mod recorder;
+mod registry;
+mod render;
+mod sched;
+mod schedule;
+mod screencopy;
+mod separator;
+mod state;
+mod sway;
+mod ticker;
+mod timer;
+mod timing;
+mod todo;
+#[cfg(feature="dbus")]
+mod tray;
+#[cfg(feature="dbus")]
+mod udisks;
+mod updates;
+#[cfg(feature="dbus")]
+mod upower;
+mod util;
+mod wayland;
+mod wireguard;
+mod wlr;
+mod world_clock;
+#[cfg(feature="x11")]
+mod x11;
+
+use state::State;
+use wayland::WaylandClient;
+
+pub use data::Value;
+pub use registry::{Entry,Source,Template,register};
+pub use state::{NotifierList,Runtime};
+
+/// Run the bar using the command line arguments of the current process
+pub fn run() -> Result<(), Box<dyn Error>> {
+    let args = match cli::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args.install(),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::Args::usage());
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        print!("{}", cli::Args::usage());
+        return Ok(());
+    }
+    if args.version {
+        println!("rwaybar {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    logging::init(args.log_level.as_deref());
+    if let Some(mode) = args.timings {
+        timing::set_mode(mode);
+    }
+
+    // Avoid producing zombies.  We don't need exit status, and can detect end-of-file on pipes to
+    // handle any respawning required.
+    unsafe { libc::signal(libc::SIGCHLD, libc::SIG_IGN); }
+
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+
+    if let Some(opts) = &args.render_once {
+        return tokio::task::LocalSet::new().block_on(&rt, headless::render_once(opts.clone()));
+    }
+
+    let wayland = WaylandClient::new();
+    #[cfg(feature="x11")]
+    if let Err(e) = &wayland {
+        if x11::available() {
+            log::warn!("Could not connect to a wayland compositor ({}), using X11", e);
+            return tokio::task::LocalSet::new().block_on(&rt, x11::run()).map(|_| ());
+        }
+    }
+    let (client, wl_queue) = wayland?;
+
+    tokio::task::LocalSet::new().block_on(&rt, async move {
+        let state = State::new(client)?;
+        State::run(state, wl_queue).await
+    })
+}
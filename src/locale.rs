@@ -0,0 +1,142 @@
+//! Locale-specific day and month names and number formatting
+use log::{debug,warn};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ffi::{CStr,CString};
+use std::rc::Rc;
+
+#[derive(Debug)]
+struct Names {
+    /// Starting with Sunday
+    days : Vec<String>,
+    short_days : Vec<String>,
+    months : Vec<String>,
+    short_months : Vec<String>,
+    am : String,
+    pm : String,
+    decimal : String,
+    thousands : String,
+}
+
+thread_local! {
+    static CURRENT : RefCell<Option<Rc<Names>>> = Default::default();
+}
+
+impl Names {
+    /// Load the names for a locale; an empty name uses the locale from the environment
+    fn load(name : &str) -> Option<Self> {
+        let cname = CString::new(name).ok()?;
+        unsafe {
+            let loc = libc::newlocale(libc::LC_ALL_MASK, cname.as_ptr(), std::ptr::null_mut());
+            if loc.is_null() {
+                return None;
+            }
+            let get = |item : libc::nl_item| {
+                let s = libc::nl_langinfo_l(item, loc);
+                if s.is_null() {
+                    String::new()
+                } else {
+                    CStr::from_ptr(s).to_string_lossy().into_owned()
+                }
+            };
+            let list = |first : libc::nl_item, count : i32| (0..count).map(|i| get(first + i)).collect();
+            let rv = Names {
+                days : list(libc::DAY_1, 7),
+                short_days : list(libc::ABDAY_1, 7),
+                months : list(libc::MON_1, 12),
+                short_months : list(libc::ABMON_1, 12),
+                am : get(libc::AM_STR),
+                pm : get(libc::PM_STR),
+                decimal : get(libc::RADIXCHAR),
+                thousands : get(libc::THOUSEP),
+            };
+            libc::freelocale(loc);
+            Some(rv)
+        }
+    }
+}
+
+/// Select the locale used for names and numbers; an empty name uses the system locale
+pub fn set(name : &str) {
+    let names = Names::load(name);
+    match &names {
+        Some(names) => debug!("Using locale '{}': {:?}", name, names),
+        None => warn!("Could not load locale '{}'", name),
+    }
+    CURRENT.with(|c| *c.borrow_mut() = names.map(Rc::new));
+}
+
+fn current() -> Option<Rc<Names>> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Replace the name specifiers (`%A`, `%a`, `%B`, `%b`, `%h`, `%p`) in a chrono format string with
+/// the names from the current locale, so the rest can be formatted by chrono.
+pub fn localize_format<'a>(fmt : &'a str, date : &impl chrono::Datelike, hour : Option<u32>) -> Cow<'a, str> {
+    let names = match current() {
+        Some(names) if fmt.contains('%') => names,
+        _ => return fmt.into(),
+    };
+    let day = date.weekday().num_days_from_sunday() as usize;
+    let month = date.month0() as usize;
+    let pm = hour.map(|h| h >= 12);
+    let mut rv = String::with_capacity(fmt.len() + 16);
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            rv.push(c);
+            continue;
+        }
+        let name = match (chars.next(), pm) {
+            (Some('A'), _) => &names.days[day],
+            (Some('a'), _) => &names.short_days[day],
+            (Some('B'), _) => &names.months[month],
+            (Some('b'), _) | (Some('h'), _) => &names.short_months[month],
+            (Some('p'), Some(false)) => &names.am,
+            (Some('p'), Some(true)) => &names.pm,
+            (Some(c), _) => {
+                rv.push('%');
+                rv.push(c);
+                continue;
+            }
+            (None, _) => {
+                rv.push('%');
+                break;
+            }
+        };
+        rv.push_str(&name.replace('%', "%%"));
+    }
+    rv.into()
+}
+
+/// Format a number using the decimal and thousands separators of the current locale
+pub fn format_number(value : f64, decimals : Option<usize>) -> String {
+    let plain = match decimals {
+        Some(d) => format!("{:.*}", d, value),
+        None => format!("{}", value),
+    };
+    let (decimal, thousands) = match current() {
+        Some(names) => (names.decimal.clone(), names.thousands.clone()),
+        None => return plain,
+    };
+    let (int, frac) = match plain.split_once('.') {
+        Some((int, frac)) => (int, Some(frac)),
+        None => (&*plain, None),
+    };
+    let (sign, digits) = match int.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", int),
+    };
+    let mut rv = String::from(sign);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            rv.push_str(&thousands);
+        }
+        rv.push(c);
+    }
+    if let Some(frac) = frac {
+        rv.push_str(if decimal.is_empty() { "." } else { &decimal });
+        rv.push_str(frac);
+    }
+    rv
+}
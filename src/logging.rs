@@ -0,0 +1,206 @@
+//! Log output with per-module levels that can be set from the configuration
+use env_logger::filter::{Builder,Filter};
+use log::{Level,Log,Metadata,Record};
+use once_cell::sync::OnceCell;
+use std::cell::RefCell;
+use std::fs::{self,File,OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Used when neither the configuration nor RUST_LOG give a default level
+const DEFAULT_FILTER : &str = "warn,rwaybar::timing=info";
+
+struct LogFile {
+    path : PathBuf,
+    file : File,
+    size : u64,
+    max_size : u64,
+    keep : u32,
+}
+
+impl LogFile {
+    fn open(path : PathBuf, max_size : u64, keep : u32) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { path, file, size, max_size, keep })
+    }
+
+    fn rotated(&self, i : u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", i));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(i), self.rotated(i + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line : &str) {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                eprintln!("Could not rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+struct Inner {
+    filter : Filter,
+    file : Option<LogFile>,
+}
+
+struct Logger(Mutex<Inner>);
+
+static LOGGER : OnceCell<Logger> = OnceCell::new();
+
+/// The filter given on the command line, if any
+static CLI_FILTER : OnceCell<Option<String>> = OnceCell::new();
+
+/// Most messages kept by [capture]
+const MAX_CAPTURED : usize = 5;
+
+thread_local! {
+    /// Warnings and errors logged on this thread during [capture]
+    static CAPTURED : RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, returning any warnings or errors it logged on this thread.
+///
+/// Nested calls each see only the messages logged while they were the innermost capture.
+pub fn capture<R>(f : impl FnOnce() -> R) -> (R, Vec<String>) {
+    let prev = CAPTURED.with(|c| c.replace(Some(Vec::new())));
+    let rv = f();
+    let msgs = CAPTURED.with(|c| c.replace(prev)).unwrap_or_default();
+    (rv, msgs)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata : &Metadata) -> bool {
+        self.0.lock().is_ok_and(|i| i.filter.enabled(metadata))
+    }
+
+    fn log(&self, record : &Record) {
+        if record.level() <= Level::Warn {
+            CAPTURED.with(|c| match &mut *c.borrow_mut() {
+                Some(msgs) if msgs.len() < MAX_CAPTURED => msgs.push(record.args().to_string()),
+                _ => {}
+            });
+        }
+        let mut inner = match self.0.lock() {
+            Ok(inner) => inner,
+            Err(_) => return,
+        };
+        if !inner.filter.matches(record) {
+            return;
+        }
+        let line = format!("[{} {:<5} {}] {}\n",
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+            record.level(), record.target(), record.args());
+        let _ = std::io::stderr().write_all(line.as_bytes());
+        if let Some(file) = &mut inner.file {
+            file.write(&line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut inner) = self.0.lock() {
+            if let Some(file) = &mut inner.file {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+fn build_filter(config : &str) -> Filter {
+    let mut builder = Builder::new();
+    builder.parse(config);
+    // RUST_LOG always takes priority over the configuration, and the command line over both
+    if let Ok(env) = std::env::var("RUST_LOG") {
+        builder.parse(&env);
+    }
+    if let Some(Some(cli)) = CLI_FILTER.get() {
+        builder.parse(cli);
+    }
+    builder.build()
+}
+
+/// Install the logger, using RUST_LOG and the command line filter until the configuration is loaded
+pub fn init(cli_filter : Option<&str>) {
+    let _ = CLI_FILTER.set(cli_filter.map(String::from));
+    let filter = build_filter(DEFAULT_FILTER);
+    let logger = LOGGER.get_or_init(|| Logger(Mutex::new(Inner { filter, file : None })));
+    let max = logger.0.lock().map_or(log::LevelFilter::Warn, |i| i.filter.filter());
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max);
+    }
+}
+
+/// Apply the `[log]` table from the configuration
+pub fn configure(cfg : Option<&toml::Value>, xdg : &xdg::BaseDirectories) {
+    let logger = match LOGGER.get() {
+        Some(logger) => logger,
+        None => return,
+    };
+    let get = |key| cfg.and_then(|c| c.get(key));
+
+    let mut filter = String::from(DEFAULT_FILTER);
+    if let Some(level) = get("level").and_then(|v| v.as_str()) {
+        filter.push(',');
+        filter.push_str(level);
+    }
+    if let Some(modules) = get("modules").and_then(|v| v.as_table()) {
+        for (module, level) in modules {
+            match level.as_str() {
+                Some(level) => {
+                    filter += &format!(",rwaybar::{}={}", module.replace('-', "_"), level);
+                }
+                None => log::error!("Invalid log level for module {}: {}", module, level),
+            }
+        }
+    }
+    let filter = build_filter(&filter);
+
+    let file = get("file").and_then(|v| v.as_str()).and_then(|path| {
+        let path = match path.strip_prefix("~/") {
+            Some(rest) => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(rest)),
+            None if path.starts_with('/') => Some(path.into()),
+            None => xdg.place_cache_file(path).ok(),
+        }?;
+        let max_size = get("max-size").and_then(|v| v.as_integer()).filter(|&v| v > 0).unwrap_or(1 << 20) as u64;
+        let keep = get("keep").and_then(|v| v.as_integer()).filter(|&v| v >= 0).unwrap_or(3) as u32;
+        match LogFile::open(path.clone(), max_size, keep) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::error!("Could not open log file {}: {}", path.display(), e);
+                None
+            }
+        }
+    });
+
+    let max = filter.filter();
+    if let Ok(mut inner) = logger.0.lock() {
+        let reopen = match (&inner.file, &file) {
+            (Some(old), Some(new)) => old.path != new.path || old.max_size != new.max_size || old.keep != new.keep,
+            _ => true,
+        };
+        inner.filter = filter;
+        if reopen {
+            inner.file = file;
+        }
+    }
+    log::set_max_level(max);
+}
@@ -0,0 +1,118 @@
+//! Lua scripts for values and actions that are too complex for the expression language
+use crate::data::Value;
+use crate::registry::Source;
+use crate::state::Runtime;
+use log::{error,info};
+use mlua::Lua;
+use std::cell::RefCell;
+use std::error::Error;
+
+thread_local! {
+    static LUA : RefCell<Option<Lua>> = RefCell::new(None);
+}
+
+/// Load the script named in the `[lua]` table, replacing any previous one
+pub fn configure(cfg : Option<&toml::Value>, rt : &Runtime) {
+    let lua = cfg.and_then(|cfg| {
+        let file = match cfg.get("file").and_then(|v| v.as_str()) {
+            Some(file) => file,
+            None => {
+                error!("The lua table requires a 'file' key");
+                return None;
+            }
+        };
+        let path = match rt.find_config_file(file) {
+            Some(path) => path,
+            None => {
+                error!("Could not find the Lua script {}", file);
+                return None;
+            }
+        };
+        let lua = Lua::new();
+        let rv = std::fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|src| lua.load(&src).set_name(path.to_string_lossy()).exec().map_err(|e| e.to_string()));
+        match rv {
+            Ok(()) => {
+                info!("Loaded Lua script {}", path.display());
+                Some(lua)
+            }
+            Err(e) => {
+                error!("Could not load {}: {}", path.display(), e);
+                None
+            }
+        }
+    });
+    LUA.with(|l| *l.borrow_mut() = lua);
+}
+
+/// Call a global function of the script, returning its result as text (or None if it returned
+/// nil).
+///
+/// During the call, the script can use `rwaybar.get(name)` to read an item's value (for example,
+/// `rwaybar.get("pulse.volume")`), `rwaybar.format(text)` to expand a format string, and
+/// `rwaybar.set(name, value)` to send a value to an item.
+pub fn call<A : for<'lua> mlua::IntoLuaMulti<'lua>>(function : &str, args : A, rt : &Runtime) -> Result<Option<String>, Box<dyn Error>> {
+    LUA.with(|l| {
+        let l = l.borrow();
+        let lua = l.as_ref().ok_or("no Lua script is loaded; add a [lua] table")?;
+        let rv = lua.scope(|scope| {
+            let api = lua.create_table()?;
+            api.set("get", scope.create_function(|_, name : String| {
+                Ok(rt.format(&format!("{{{}}}", name)).ok().map(|v| v.into_text().into_owned()))
+            })?)?;
+            api.set("format", scope.create_function(|_, text : String| {
+                rt.format(&text).map(|v| v.into_text().into_owned()).map_err(mlua::Error::external)
+            })?)?;
+            api.set("set", scope.create_function(|_, (name, value) : (String, String)| {
+                rt.write_item(&name, Value::Owned(value)).map_err(mlua::Error::external)
+            })?)?;
+            // A call made while reading an item from another call replaces the table, which is
+            // only valid for that call; put back the caller's table when done.
+            let prev : mlua::Value = lua.globals().get("rwaybar")?;
+            lua.globals().set("rwaybar", api)?;
+            let rv = lua.globals().get::<_, mlua::Function>(function)
+                .and_then(|f| f.call::<_, mlua::Value>(args));
+            lua.globals().set("rwaybar", prev)?;
+            Ok(lua.coerce_string(rv?)?.map(|s| s.to_string_lossy().into_owned()))
+        });
+        Ok(rv?)
+    })
+}
+
+/// The "lua" module: the result of calling a Lua function
+#[derive(Debug)]
+pub struct LuaItem {
+    function : Box<str>,
+    args : Vec<Box<str>>,
+}
+
+impl LuaItem {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        LuaItem {
+            function : value.get("function").and_then(|v| v.as_str()).unwrap_or("").into(),
+            args : value.get("args").and_then(|v| v.as_array()).into_iter().flatten()
+                .filter_map(|v| v.as_str())
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+impl Source for LuaItem {
+    fn read<'a>(&'a self, name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        if key != "" && key != "text" {
+            return Value::Null;
+        }
+        let args : Vec<String> = self.args.iter()
+            .map(|arg| rt.format_or(arg, name).into_text().into_owned())
+            .collect();
+        match call(&self.function, args.into_iter().collect::<mlua::Variadic<_>>(), rt) {
+            Ok(Some(text)) => Value::Owned(text),
+            Ok(None) => Value::Null,
+            Err(e) => {
+                error!("{}: Lua function {} failed: {}", name, self.function, e);
+                Value::Null
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Menus of user-defined entries, shown in a popup
+use crate::data::Value;
+use crate::event::Action;
+use crate::font::render_font;
+use crate::icon;
+use crate::render::Render;
+use crate::state::Runtime;
+use log::error;
+use std::rc::Rc;
+
+/// A single line in the menu
+#[derive(Debug)]
+struct Entry {
+    label : Box<str>,
+    icon : Box<str>,
+    action : Action,
+    separator : bool,
+}
+
+impl Entry {
+    fn from_toml(value : &toml::Value) -> Self {
+        let separator = value.get("separator").and_then(|v| v.as_bool()).unwrap_or(false);
+        let label = value.get("label").and_then(|v| v.as_str()).unwrap_or("").into();
+        let icon = value.get("icon").and_then(|v| v.as_str()).unwrap_or("").into();
+        let action = if let Some(cmd) = value.get("command").and_then(|v| v.as_str()) {
+            Action::Exec { format : cmd.into(), opts : Default::default() }
+        } else if value.get("exec").is_some() || value.get("send").is_some() || value.get("write").is_some() {
+            Action::from_toml(value)
+        } else {
+            if !separator {
+                error!("Menu entry '{}' has no command", label);
+            }
+            Action::None
+        };
+        Entry { label, icon, action, separator }
+    }
+}
+
+#[derive(Debug)]
+pub struct Menu {
+    text : Box<str>,
+    entries : Rc<[Entry]>,
+}
+
+impl Menu {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let text = value.get("text").and_then(|v| v.as_str()).unwrap_or("").into();
+        let entries = value.get("entries")
+            .and_then(|v| v.as_array())
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(Entry::from_toml)
+            .collect();
+        Menu { text, entries }
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        match key {
+            "" | "text" => f(rt.format_or(&self.text, name)),
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn popup(&self) -> MenuPopup {
+        MenuPopup {
+            entries : self.entries.clone(),
+            rendered : Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct MenuPopup {
+    entries : Rc<[Entry]>,
+    /// The vertical extent of each clickable entry from the last render, and its index
+    rendered : Vec<(f32, f32, usize)>,
+}
+
+impl PartialEq for MenuPopup {
+    fn eq(&self, rhs : &Self) -> bool {
+        Rc::ptr_eq(&self.entries, &rhs.entries)
+    }
+}
+
+impl MenuPopup {
+    pub fn render(&mut self, ctx : &mut Render) -> Vec<tiny_skia::Rect> {
+        let width = ctx.render_extents.1.x;
+        let line_paint = tiny_skia::Paint {
+            shader: tiny_skia::Shader::SolidColor(tiny_skia::Color::WHITE),
+            ..Default::default()
+        };
+        let icon_size = ctx.font_size;
+        let text_x = if self.entries.iter().any(|e| !e.icon.is_empty()) {
+            2.0 + icon_size + 6.0
+        } else {
+            2.0
+        };
+
+        self.rendered.clear();
+        let mut xsize : f32 = 0.0;
+        let mut ypos = 2.0;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.separator {
+                if let Some(rect) = tiny_skia::Rect::from_xywh(5.0, ypos + 3.0, width - 10.0, 1.0) {
+                    if !ctx.measure_only {
+                        ctx.canvas.fill_rect(rect, &line_paint, ctx.render_xform);
+                    }
+                }
+                ypos += 7.0;
+                continue;
+            }
+            if !entry.icon.is_empty() {
+                let name = ctx.runtime.format_or(&entry.icon, "menu").into_text();
+                let saved = (ctx.render_pos, ctx.render_extents);
+                ctx.render_pos = tiny_skia::Point { x: 2.0, y: ypos };
+                ctx.render_extents.1 = tiny_skia::Point { x: 2.0 + icon_size, y: ypos + icon_size };
+                let _ = icon::render(ctx, &name);
+                (ctx.render_pos, ctx.render_extents) = saved;
+            }
+            let label = ctx.runtime.format_or(&entry.label, "menu").into_text();
+            let tsize = render_font(ctx, (text_x, ypos), &label, true);
+            let end = ypos + tsize.1.ceil().max(icon_size);
+            xsize = xsize.max(text_x + tsize.0);
+            self.rendered.push((ypos, end, i));
+            ypos = end + 5.0;
+        }
+        ctx.render_pos = tiny_skia::Point { x: xsize.ceil() + 2.0, y: ypos };
+        self.rendered.iter()
+            .filter_map(|&(min, max, _)| tiny_skia::Rect::from_ltrb(0.0, min - 2.0, width, max + 2.0))
+            .collect()
+    }
+
+    /// Activate the entry at the given position; returns true if one was found
+    pub fn button(&mut self, x : f64, y : f64, button : u32, runtime : &mut Runtime) -> bool {
+        let y = y as f32;
+        let _ = x;
+        if (5..9).contains(&button) {
+            // scrolling is not a selection
+            return false;
+        }
+        for &(min, max, i) in &self.rendered {
+            if y < min - 2.0 || y > max + 2.0 {
+                continue;
+            }
+            self.entries[i].action.invoke(runtime, button);
+            return true;
+        }
+        false
+    }
+}
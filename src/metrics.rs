@@ -0,0 +1,212 @@
+//! Counters describing the bar's own work, for the `metrics` control socket command and the
+//! optional Prometheus endpoint
+use crate::util;
+use futures_util::future::RemoteHandle;
+use json::JsonValue;
+use log::{error,info};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration,Instant};
+use tokio::io::{AsyncReadExt,AsyncWriteExt};
+use tokio::net::TcpListener;
+
+#[derive(Debug,Default,Clone,Copy)]
+struct Stat {
+    count : u64,
+    total : Duration,
+    max : Duration,
+}
+
+impl Stat {
+    fn add(&mut self, time : Duration) {
+        self.count += 1;
+        self.total += time;
+        self.max = self.max.max(time);
+    }
+
+    fn to_json(&self) -> JsonValue {
+        json::object! {
+            count : self.count,
+            total_ms : self.total.as_secs_f64() * 1000.0,
+            max_ms : self.max.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Metrics {
+    started : Instant,
+    /// True once per-item times are being collected
+    items_enabled : bool,
+    frames : Stat,
+    last_frame : Duration,
+    items : HashMap<Box<str>, Stat>,
+    dbus_sent : u64,
+    dbus_received : u64,
+    shm_bytes : u64,
+    /// The Prometheus listener and its address
+    listener : Option<(Box<str>, RemoteHandle<()>)>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started : Instant::now(),
+            items_enabled : false,
+            frames : Stat::default(),
+            last_frame : Duration::ZERO,
+            items : HashMap::new(),
+            dbus_sent : 0,
+            dbus_received : 0,
+            shm_bytes : 0,
+            listener : None,
+        }
+    }
+}
+
+thread_local! {
+    static METRICS : RefCell<Metrics> = Default::default();
+}
+
+/// True if the time taken by each item should be recorded
+pub fn items_enabled() -> bool {
+    METRICS.with(|m| m.borrow().items_enabled)
+}
+
+/// Record the time taken to render a named item
+pub fn item(name : &str, time : Duration) {
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        if let Some(stat) = m.items.get_mut(name) {
+            stat.add(time);
+        } else {
+            m.items.entry(name.into()).or_default().add(time);
+        }
+    });
+}
+
+/// Record the time taken to draw and rasterize a frame
+pub fn frame(time : Duration) {
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        m.frames.add(time);
+        m.last_frame = time;
+    });
+}
+
+#[cfg(feature="dbus")]
+pub fn dbus_sent() {
+    METRICS.with(|m| m.borrow_mut().dbus_sent += 1);
+}
+
+#[cfg(feature="dbus")]
+pub fn dbus_received() {
+    METRICS.with(|m| m.borrow_mut().dbus_received += 1);
+}
+
+/// Record a change in the size of the shared memory used for buffers
+pub fn shm_resized(old : usize, new : usize) {
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        m.shm_bytes = (m.shm_bytes + new as u64).saturating_sub(old as u64);
+    });
+}
+
+/// The current metrics as JSON.  Per-item times are collected from the first request on.
+pub fn to_json() -> JsonValue {
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        m.items_enabled = true;
+        let mut items = JsonValue::new_object();
+        for (name, stat) in &m.items {
+            items[&**name] = stat.to_json();
+        }
+        json::object! {
+            uptime_s : m.started.elapsed().as_secs_f64(),
+            frames : m.frames.to_json(),
+            last_frame_ms : m.last_frame.as_secs_f64() * 1000.0,
+            items : items,
+            dbus : json::object! {
+                sent : m.dbus_sent,
+                received : m.dbus_received,
+            },
+            shm_bytes : m.shm_bytes,
+        }
+    })
+}
+
+/// The current metrics in the Prometheus text format
+pub fn to_prometheus() -> String {
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        m.items_enabled = true;
+        let mut out = String::new();
+        let mut metric = |name : &str, kind : &str, help : &str, value : f64| {
+            let _ = write!(out, "# HELP rwaybar_{0} {1}\n# TYPE rwaybar_{0} {2}\nrwaybar_{0} {3}\n",
+                name, help, kind, value);
+        };
+        metric("uptime_seconds", "gauge", "Time since the bar started", m.started.elapsed().as_secs_f64());
+        metric("frames_total", "counter", "Frames drawn", m.frames.count as f64);
+        metric("frame_seconds_total", "counter", "Time spent drawing frames", m.frames.total.as_secs_f64());
+        metric("frame_seconds_max", "gauge", "Slowest frame", m.frames.max.as_secs_f64());
+        metric("last_frame_seconds", "gauge", "Time taken by the most recent frame", m.last_frame.as_secs_f64());
+        metric("dbus_sent_total", "counter", "D-Bus messages sent", m.dbus_sent as f64);
+        metric("dbus_received_total", "counter", "D-Bus messages received", m.dbus_received as f64);
+        metric("shm_bytes", "gauge", "Shared memory used for buffers", m.shm_bytes as f64);
+
+        let mut items : Vec<_> = m.items.iter().collect();
+        items.sort_by(|a, b| a.0.cmp(b.0));
+        out += "# HELP rwaybar_item_renders_total Times each item was drawn\n";
+        out += "# TYPE rwaybar_item_renders_total counter\n";
+        for (name, stat) in &items {
+            let _ = writeln!(out, "rwaybar_item_renders_total{{item=\"{}\"}} {}", escape(name), stat.count);
+        }
+        out += "# HELP rwaybar_item_seconds_total Time spent drawing each item\n";
+        out += "# TYPE rwaybar_item_seconds_total counter\n";
+        for (name, stat) in &items {
+            let _ = writeln!(out, "rwaybar_item_seconds_total{{item=\"{}\"}} {}", escape(name), stat.total.as_secs_f64());
+        }
+        out
+    })
+}
+
+fn escape(label : &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Apply the `[metrics]` table from the configuration, starting or stopping the Prometheus
+/// endpoint as needed
+pub fn configure(cfg : Option<&toml::Value>) {
+    let listen = cfg.and_then(|c| c.get("listen")).and_then(|v| v.as_str());
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.listener.as_ref().map(|l| &*l.0) == listen {
+            return;
+        }
+        m.listener = listen.map(|addr| {
+            let handle = util::spawn_handle("Metrics endpoint", serve(addr.to_owned()));
+            (addr.into(), handle)
+        });
+        if listen.is_some() {
+            m.items_enabled = true;
+        }
+    });
+}
+
+async fn serve(addr : String) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        // The request itself is ignored; every path returns the metrics
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).await;
+        let body = to_prometheus();
+        let reply = format!("HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(), body);
+        if let Err(e) = stream.write_all(reply.as_bytes()).await {
+            error!("Could not send metrics: {}", e);
+        }
+    }
+}
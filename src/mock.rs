@@ -0,0 +1,195 @@
+//! Scripted values for developing and demonstrating a configuration without real data sources
+use crate::data::Value;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,spawn_noerr,toml_to_f64};
+use log::{error,warn};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// One value (or set of keyed values) in the script
+#[derive(Debug)]
+struct Step {
+    values : Vec<(Box<str>, Value<'static>)>,
+    /// Seconds to show this step before the next one
+    delay : Option<f64>,
+}
+
+impl Step {
+    fn scalar(value : Value<'static>) -> Self {
+        Step { values : vec![("".into(), value)], delay : None }
+    }
+
+    fn from_toml(value : &toml::Value) -> Option<Self> {
+        let value = match value {
+            toml::Value::String(s) => Value::Owned(s.clone()),
+            toml::Value::Integer(i) => Value::Float(*i as f64),
+            toml::Value::Float(f) => Value::Float(*f),
+            toml::Value::Boolean(b) => Value::Bool(*b),
+            toml::Value::Table(table) => {
+                let mut step = Step { values : Vec::new(), delay : toml_to_f64(table.get("delay")) };
+                for (key, value) in table {
+                    match (&**key, Step::from_toml(value)) {
+                        ("delay", _) => {}
+                        (_, Some(Step { mut values, delay : None })) if values.len() == 1 => {
+                            step.values.push((key.as_str().into(), values.pop().unwrap().1));
+                        }
+                        _ => warn!("Ignoring invalid mock value for key {}", key),
+                    }
+                }
+                return Some(step);
+            }
+            _ => return None,
+        };
+        Some(Step::scalar(value))
+    }
+
+    fn from_json(value : &json::JsonValue) -> Option<Self> {
+        let value = match value {
+            json::JsonValue::String(_) | json::JsonValue::Short(_) => Value::Owned(value.as_str()?.to_owned()),
+            json::JsonValue::Number(_) => Value::Float(value.as_f64()?),
+            json::JsonValue::Boolean(b) => Value::Bool(*b),
+            json::JsonValue::Null => Value::Null,
+            json::JsonValue::Object(_) => {
+                let mut step = Step { values : Vec::new(), delay : value["delay"].as_f64() };
+                for (key, value) in value.entries() {
+                    match (key, Step::from_json(value)) {
+                        ("delay", _) => {}
+                        (_, Some(Step { mut values, delay : None })) if values.len() == 1 => {
+                            step.values.push((key.into(), values.pop().unwrap().1));
+                        }
+                        _ => warn!("Ignoring invalid mock value for key {}", key),
+                    }
+                }
+                return Some(step);
+            }
+            json::JsonValue::Array(_) => return None,
+        };
+        Some(Step::scalar(value))
+    }
+
+    fn get(&self, key : &str) -> Option<&Value<'static>> {
+        let find = |key : &str| self.values.iter().find(|(k, _)| &**k == key).map(|(_, v)| v);
+        match key {
+            // A scalar step is its own text; a table may set it using either name
+            "" | "text" | "value" => find(key).or_else(|| find("")).or_else(|| find("text")).or_else(|| find("value")),
+            key => find(key),
+        }
+    }
+}
+
+#[derive(Debug,Default)]
+struct MockInner {
+    steps : Cell<Vec<Step>>,
+    pos : Cell<usize>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+/// The "mock" module
+#[derive(Debug)]
+pub struct Mock {
+    inner : Rc<MockInner>,
+    replay : Option<Box<str>>,
+    interval : f64,
+    repeat : bool,
+}
+
+impl Mock {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let steps = config.get("values")
+            .and_then(|v| v.as_array())
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| {
+                let step = Step::from_toml(v);
+                if step.is_none() {
+                    error!("Invalid mock value: {}", v);
+                }
+                step
+            })
+            .collect();
+        Mock {
+            inner : Rc::new(MockInner { steps : Cell::new(steps), ..Default::default() }),
+            replay : config.get("replay").and_then(|v| v.as_str()).map(Into::into),
+            interval : toml_to_f64(config.get("interval")).filter(|&v| v > 0.0).unwrap_or(1.0),
+            repeat : config.get("loop").and_then(|v| v.as_bool()).unwrap_or(true),
+        }
+    }
+
+    fn load_replay(path : &str, rt : &Runtime) -> Option<Vec<Step>> {
+        let text = match rt.find_config_file(path).map(std::fs::read_to_string) {
+            Some(Ok(text)) => text,
+            Some(Err(e)) => {
+                error!("Could not read mock replay file {}: {}", path, e);
+                return None;
+            }
+            None => {
+                error!("Could not find mock replay file {}", path);
+                return None;
+            }
+        };
+        match json::parse(&text) {
+            Ok(json::JsonValue::Array(list)) => Some(list.iter().filter_map(Step::from_json).collect()),
+            Ok(_) => {
+                error!("Mock replay file {} must contain a JSON array", path);
+                None
+            }
+            Err(e) => {
+                error!("Could not parse mock replay file {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn start(&self, rt : &Runtime) {
+        if self.inner.running.replace(true) {
+            return;
+        }
+        if let Some(path) = &self.replay {
+            if let Some(steps) = Self::load_replay(path, rt) {
+                self.inner.steps.set(steps);
+            }
+        }
+        let weak = Rc::downgrade(&self.inner);
+        let interval = self.interval;
+        let repeat = self.repeat;
+        spawn_noerr(async move {
+            loop {
+                let delay = match weak.upgrade() {
+                    Some(inner) => inner.steps.take_in(|steps| {
+                        let pos = inner.pos.get();
+                        match steps.get(pos) {
+                            Some(step) if pos + 1 < steps.len() || repeat => step.delay.unwrap_or(interval),
+                            _ => 0.0,
+                        }
+                    }),
+                    None => return,
+                };
+                if delay <= 0.0 {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_secs_f64(delay)).await;
+                match weak.upgrade() {
+                    Some(inner) => {
+                        let len = inner.steps.take_in(|steps| steps.len());
+                        inner.pos.set((inner.pos.get() + 1) % len);
+                        inner.interested.take().notify_data("mock");
+                    }
+                    None => return,
+                }
+            }
+        });
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, _name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        self.start(rt);
+        self.inner.interested.take_in(|i| i.add(rt));
+        self.inner.steps.take_in(|steps| {
+            match steps.get(self.inner.pos.get()).and_then(|step| step.get(key)) {
+                Some(value) => f(value.as_ref()),
+                None => f(Value::Null),
+            }
+        })
+    }
+}
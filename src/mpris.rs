@@ -1,12 +1,22 @@
-use crate::dbus::DBus;
+use crate::dbus::{DBus,typed,unwrap_variant};
 use crate::data::{IterationItem,Value};
+use crate::font::{ellipsize,render_font};
+use crate::icon;
+use crate::render::Render;
 use crate::state::{Runtime,NotifierList};
+use crate::timer;
 use crate::util::{self,Cell};
+use futures_util::future::RemoteHandle;
 use once_cell::unsync::OnceCell;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::convert::TryInto;
 use std::error::Error;
+use std::fs::File;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration,Instant};
 use log::{debug,warn,error};
 use zbus::zvariant;
 use zvariant::Value as Variant;
@@ -155,12 +165,74 @@ struct Player {
     proxy : PlayerProxy<'static>,
     playing : Option<PlayState>,
     meta : Dict<'static, 'static>,
+    /// The playback position in seconds, and when it was read
+    position : Option<(f64, Instant)>,
+    /// When the position was last requested
+    position_asked : Option<Instant>,
+}
+
+impl Player {
+    /// The current playback position in seconds, assuming it has advanced normally since it was
+    /// read
+    fn position(&self) -> Option<f64> {
+        let (pos, at) = self.position?;
+        match self.playing {
+            Some(PlayState::Playing) => Some(pos + at.elapsed().as_secs_f64()),
+            _ => Some(pos),
+        }
+    }
+
+    /// The track length in seconds
+    fn length(&self) -> Option<f64> {
+        // the spec says this is an i64, but some players send a u64
+        match *self.meta_value("mpris:length")? {
+            Variant::I64(len) => Some(len as f64 / 1_000_000.0),
+            Variant::U64(len) => Some(len as f64 / 1_000_000.0),
+            _ => None,
+        }
+    }
+
+    fn meta_value(&self, key : &str) -> Option<&Variant<'_>> {
+        self.meta.get::<str,Variant>(key).ok().flatten().map(unwrap_variant)
+    }
+
+    fn meta_str(&self, key : &str) -> Option<&str> {
+        match self.meta_value(key)? {
+            Variant::Str(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    /// A metadata value as text, joining lists (like `xesam:artist`) with commas
+    fn meta_text(&self, key : &str) -> Option<String> {
+        match self.meta_value(key)? {
+            Variant::Str(s) => Some(s.as_str().to_owned()),
+            Variant::Array(a) => Some(a.get().iter()
+                .filter_map(|e| match unwrap_variant(e) {
+                    Variant::Str(s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join(", ")),
+            _ => None,
+        }
+    }
+}
+
+/// Prefer playing players, then paused, then any
+fn default_player(players : &[Player]) -> Option<&Player> {
+    players.iter().filter(|p| p.playing == Some(PlayState::Playing))
+        .chain(players.iter().filter(|p| p.playing == Some(PlayState::Paused)))
+        .chain(players.iter())
+        .next()
 }
 
 #[derive(Debug,Default)]
 struct MediaPlayer2 {
     players : Cell<Vec<Player>>,
     interested : Cell<NotifierList>,
+    /// Redraws an open popup while its seek bar is moving
+    popup_tick : Cell<Option<RemoteHandle<()>>>,
 }
 
 async fn initial_query(target : Rc<MediaPlayer2>, bus_name : BusName<'static>) -> Result<(), Box<dyn Error>> {
@@ -175,8 +247,10 @@ async fn initial_query(target : Rc<MediaPlayer2>, bus_name : BusName<'static>) -
         .await?
         .into_inner();
 
+    // The position is not included in property change signals, so it must be read each time
     let proxy = PlayerProxy::builder(&zbus)
         .destination(owner)?
+        .cache_properties(zbus::CacheProperties::No)
         .build().await?;
 
     let playing = PlayState::parse(&proxy.playback_status().await?);
@@ -188,6 +262,8 @@ async fn initial_query(target : Rc<MediaPlayer2>, bus_name : BusName<'static>) -
             proxy,
             playing,
             meta,
+            position : None,
+            position_asked : None,
         });
     });
 
@@ -275,18 +351,20 @@ impl MediaPlayer2 {
             for (&prop, value) in changed {
                 match prop {
                     "PlaybackStatus" => {
-                        if let Ok(status) = value.try_into() {
+                        if let Some(status) = typed::<&str>(value, "PlaybackStatus") {
                             player.playing = PlayState::parse(status);
                         }
                     }
                     "Metadata" => {
-                        if let Variant::Dict(meta) = &**value {
+                        if let Variant::Dict(meta) = unwrap_variant(value) {
                             player.meta = meta.clone();
                         }
                     }
                     _ => ()
                 }
             }
+            // the position may have jumped; read it again when it is next needed
+            player.position_asked = None;
             self.interested.take().notify_data("mpris:props");
         });
         Ok(())
@@ -311,12 +389,7 @@ pub fn read_in<F : FnOnce(Value) -> R, R>(_name : &str, target : &str, key : &st
                 player = players.iter().find(|p| &*p.name_tail == name);
             } else {
                 field = key;
-                // Prefer playing players, then paused, then any
-                //
-                player = players.iter().filter(|p| p.playing == Some(PlayState::Playing))
-                    .chain(players.iter().filter(|p| p.playing == Some(PlayState::Paused)))
-                    .chain(players.iter())
-                    .next();
+                player = default_player(players);
             }
 
             if field == "state" {
@@ -334,41 +407,25 @@ pub fn read_in<F : FnOnce(Value) -> R, R>(_name : &str, target : &str, key : &st
                         f(Value::Borrow(&player.name_tail))
                     }
                     "length" => {
-                        match player.meta.get::<_,u64>("mpris:length") {
-                            Ok(Some(len)) => f(Value::Float(*len as f64 / 1_000_000.0)),
-                            _ => f(Value::Null),
+                        match player.length() {
+                            Some(len) => f(Value::Float(len)),
+                            None => f(Value::Null),
                         }
                     }
                     _ if field.contains('.') => {
                         let real_field = field.replace('.', ":");
-                        let qf = player.meta.get::<str,str>(&field);
-                        let rf = player.meta.get::<str,str>(&real_field);
-
-                        match (qf, rf) {
-                            (Ok(Some(v)), _) |
-                            (_, Ok(Some(v))) => f(Value::Borrow(v)),
-                            _ => f(Value::Null),
+                        match player.meta_str(field).or_else(|| player.meta_str(&real_field)) {
+                            Some(v) => f(Value::Borrow(v)),
+                            None => f(Value::Null),
                         }
                     }
                     // See http://www.freedesktop.org/wiki/Specifications/mpris-spec/metadata for
                     // a list of valid names
                     _ => {
                         let xeasm = format!("xesam:{}", field);
-                        let value = player.meta.get::<str,Variant>(&xeasm).ok().flatten();
-                        match value {
-                            Some(Variant::Str(v)) => f(Value::Borrow(v.as_str())),
-                            Some(Variant::Array(a)) => {
-                                let mut tmp = String::new();
-                                for e in a.get().iter() {
-                                    if let Variant::Str(s) = e {
-                                        tmp.push_str(s);
-                                        tmp.push_str(", ");
-                                    }
-                                }
-                                tmp.pop(); tmp.pop();
-                                f(Value::Owned(tmp))
-                            }
-                            _ => f(Value::Null)
+                        match player.meta_text(&xeasm) {
+                            Some(v) => f(Value::Owned(v)),
+                            None => f(Value::Null),
                         }
                     }
                 }
@@ -405,11 +462,7 @@ pub fn write(_name : &str, target : &str, key : &str, command : Value, _rt : &Ru
             } else if !key.is_empty() {
                 player = players.iter().find(|p| &*p.name_tail == key);
             } else {
-                // Prefer playing players, then paused, then any
-                player = players.iter().filter(|p| p.playing == Some(PlayState::Playing))
-                    .chain(players.iter().filter(|p| p.playing == Some(PlayState::Paused)))
-                    .chain(players.iter())
-                    .next();
+                player = default_player(players);
             }
 
             let player = match player {
@@ -449,3 +502,320 @@ pub fn write(_name : &str, target : &str, key : &str, command : Value, _rt : &Ru
         })
     })
 }
+
+/// The index of the named player, or of the default player if the name is empty
+fn find_player(players : &[Player], target : &str) -> Option<usize> {
+    if target.is_empty() {
+        let player = default_player(players)?;
+        players.iter().position(|p| std::ptr::eq(p, player))
+    } else {
+        players.iter().position(|p| &*p.name_tail == target)
+    }
+}
+
+/// Convert a `file://` URL to a path, decoding any percent escapes
+fn file_url_path(url : &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let hex = path.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (path[i], hex) {
+            (b'%', Some(b)) => {
+                bytes.push(b);
+                i += 3;
+            }
+            (c, _) => {
+                bytes.push(c);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+thread_local! {
+    /// The most recently loaded album art
+    static ART : RefCell<Option<(String, Option<Arc<tiny_skia::Pixmap>>)>> = Default::default();
+}
+
+fn album_art(url : &str) -> Option<Arc<tiny_skia::Pixmap>> {
+    ART.with(|art| {
+        let mut art = art.borrow_mut();
+        match &*art {
+            Some((cached, img)) if cached == url => return img.clone(),
+            _ => {}
+        }
+        let img = file_url_path(url)
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| icon::OwnedImage::from_file(file, ART_SIZE as u32))
+            .map(|img| Arc::new(img.0));
+        if img.is_none() {
+            debug!("Could not load album art from {}", url);
+        }
+        *art = Some((url.into(), img.clone()));
+        img
+    })
+}
+
+const POPUP_WIDTH : f32 = 320.0;
+const ART_SIZE : f32 = 80.0;
+const BUTTON_SIZE : f32 = 28.0;
+
+/// A popup showing the current track with a seek bar and playback buttons
+#[derive(Debug,Clone)]
+pub struct MprisPopup {
+    target : Rc<str>,
+    /// The seek bar from the last render, and the track length
+    seek : Option<(tiny_skia::Rect, f64)>,
+    /// The buttons from the last render, and the command each one sends
+    buttons : Vec<(tiny_skia::Rect, &'static str)>,
+}
+
+impl PartialEq for MprisPopup {
+    fn eq(&self, rhs : &Self) -> bool {
+        self.target == rhs.target
+    }
+}
+
+impl MprisPopup {
+    pub fn new(target : &str) -> Self {
+        MprisPopup { target : target.into(), seek : None, buttons : Vec::new() }
+    }
+
+    pub fn render(&mut self, ctx : &mut Render) -> Vec<tiny_skia::Rect> {
+        self.seek = None;
+        self.buttons.clear();
+        DATA.with(|cell| {
+            let state = cell.get_or_init(MediaPlayer2::new);
+            state.interested.take_in(|i| i.add(ctx.runtime));
+            state.players.take_in(|players| {
+                match find_player(players, &self.target) {
+                    Some(i) => self.render_player(ctx, state, &players[i]),
+                    None => {
+                        let (w, h) = render_font(ctx, (2.0, 2.0), "No media player", false);
+                        ctx.render_pos = tiny_skia::Point { x: w + 4.0, y: h + 4.0 };
+                    }
+                }
+            })
+        });
+        self.buttons.iter().map(|&(rect, _)| rect).collect()
+    }
+
+    fn render_player(&mut self, ctx : &mut Render, state : &Rc<MediaPlayer2>, player : &Player) {
+        let white = tiny_skia::Paint {
+            shader: tiny_skia::Shader::SolidColor(ctx.font_color),
+            anti_alias: true,
+            ..Default::default()
+        };
+        let dim = tiny_skia::Paint {
+            shader: tiny_skia::Shader::SolidColor(tiny_skia::Color::from_rgba8(255, 255, 255, 64)),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let playing = player.playing == Some(PlayState::Playing);
+
+        // Keep the position fresh while the popup is shown
+        if !ctx.measure_only && player.position_asked.is_none_or(|t| now > t + Duration::from_secs(5)) {
+            query_position(state.clone(), player.proxy.clone(), ctx.runtime);
+        }
+        if !ctx.measure_only && playing {
+            let mut notify = NotifierList::active(ctx.runtime);
+            let sched = ctx.runtime.scheduler.clone();
+            state.popup_tick.set(Some(util::spawn_handle("MPRIS popup tick", async move {
+                sched.sleep_until(now + Duration::from_secs(1), Duration::from_millis(100)).await;
+                notify.notify_data("mpris:tick");
+                Ok(())
+            })));
+        }
+
+        let mut text_x = 2.0;
+        let mut ypos : f32 = 2.0;
+        if let Some(art) = player.meta_str("mpris:artUrl").and_then(album_art) {
+            let scale = f32::min(ART_SIZE / art.width() as f32, ART_SIZE / art.height() as f32);
+            if !ctx.measure_only {
+                let paint = tiny_skia::PixmapPaint {
+                    quality : tiny_skia::FilterQuality::Bilinear,
+                    ..Default::default()
+                };
+                let xform = ctx.render_xform.pre_translate(2.0, 2.0).pre_scale(scale, scale);
+                ctx.canvas.draw_pixmap(0, 0, art, &paint, xform);
+            }
+            text_x += ART_SIZE + 8.0;
+            ypos += ART_SIZE;
+        }
+
+        let mut text_y = 2.0;
+        let room = POPUP_WIDTH - text_x - 2.0;
+        let artist = player.meta_text("xesam:artist");
+        for line in [player.meta_str("xesam:title").map(Into::into), artist, player.meta_str("xesam:album").map(Into::into)] {
+            let line : String = match line {
+                Some(line) if !line.is_empty() => line,
+                _ => continue,
+            };
+            let line = ellipsize(ctx, line.into(), room);
+            let (_, h) = render_font(ctx, (text_x, text_y), &line, false);
+            text_y += h.ceil() + 2.0;
+        }
+        ypos = ypos.max(text_y) + 8.0;
+
+        if let Some(length) = player.length().filter(|&l| l > 0.0) {
+            let position = player.position().unwrap_or(0.0).clamp(0.0, length);
+            if let Some(bar) = tiny_skia::Rect::from_xywh(2.0, ypos, POPUP_WIDTH - 4.0, 6.0) {
+                if !ctx.measure_only {
+                    ctx.canvas.fill_rect(bar, &dim, ctx.render_xform);
+                    let done = (position / length) as f32 * bar.width();
+                    if let Some(rect) = tiny_skia::Rect::from_xywh(bar.left(), bar.top(), done, bar.height()) {
+                        ctx.canvas.fill_rect(rect, &white, ctx.render_xform);
+                    }
+                }
+                self.seek = Some((bar, length));
+            }
+            ypos += 10.0;
+            let pos_text = timer::format_time(position as u64);
+            let len_text = timer::format_time(length as u64);
+            let (_, h) = render_font(ctx, (2.0, ypos), &pos_text, false);
+            let len_w = ctx.measure(|ctx| render_font(ctx, (0.0, 0.0), &len_text, false).0);
+            render_font(ctx, (POPUP_WIDTH - 2.0 - len_w, ypos), &len_text, false);
+            ypos += h.ceil() + 4.0;
+        }
+
+        let play_pause = if playing { "Pause" } else { "Play" };
+        let mut x = (POPUP_WIDTH - BUTTON_SIZE * 3.0 - 16.0) / 2.0;
+        for cmd in ["Previous", play_pause, "Next"] {
+            let rect = match tiny_skia::Rect::from_xywh(x, ypos, BUTTON_SIZE, BUTTON_SIZE) {
+                Some(rect) => rect,
+                None => continue,
+            };
+            if !ctx.measure_only {
+                if let Some(path) = button_icon(cmd, rect) {
+                    ctx.canvas.fill_path(&path, &white, tiny_skia::FillRule::Winding, ctx.render_xform);
+                }
+            }
+            self.buttons.push((rect, if cmd == play_pause { "PlayPause" } else { cmd }));
+            x += BUTTON_SIZE + 8.0;
+        }
+        ypos += BUTTON_SIZE + 2.0;
+
+        ctx.render_pos = tiny_skia::Point { x: POPUP_WIDTH, y: ypos };
+    }
+
+    /// Handle a click on the seek bar or a button; the popup always stays open
+    pub fn button(&mut self, x : f64, y : f64, button : u32, runtime : &Runtime) -> bool {
+        let (x, y) = (x as f32, y as f32);
+        if button != 0 && button != 9 {
+            return false;
+        }
+        if let Some((bar, length)) = self.seek {
+            // allow a bit of slop around the thin bar
+            if x >= bar.left() && x <= bar.right() && y >= bar.top() - 4.0 && y <= bar.bottom() + 4.0 {
+                let to = ((x - bar.left()) / bar.width()) as f64 * length;
+                seek(&self.target, to);
+                runtime.request_draw();
+                return false;
+            }
+        }
+        for &(rect, cmd) in &self.buttons {
+            if x >= rect.left() && x < rect.right() && y >= rect.top() && y < rect.bottom() {
+                write("mpris", &self.target, "", Value::Borrow(cmd), runtime);
+            }
+        }
+        false
+    }
+}
+
+/// The shape drawn on a playback button
+fn button_icon(cmd : &str, rect : tiny_skia::Rect) -> Option<tiny_skia::Path> {
+    let (l, t, s) = (rect.left(), rect.top(), rect.width());
+    let mut pb = tiny_skia::PathBuilder::new();
+    let mut triangle = |x0 : f32, x1 : f32| {
+        pb.move_to(l + s * x0, t + s * 0.2);
+        pb.line_to(l + s * x1, t + s * 0.5);
+        pb.line_to(l + s * x0, t + s * 0.8);
+        pb.close();
+    };
+    match cmd {
+        "Play" => triangle(0.25, 0.8),
+        "Next" => triangle(0.2, 0.65),
+        "Previous" => triangle(0.8, 0.35),
+        _ => {}
+    }
+    let bars : &[f32] = match cmd {
+        "Pause" => &[0.25, 0.6],
+        "Next" => &[0.65],
+        "Previous" => &[0.2],
+        _ => &[],
+    };
+    for &bx in bars {
+        pb.push_rect(l + s * bx, t + s * 0.2, s * 0.15, s * 0.6);
+    }
+    pb.finish()
+}
+
+/// Read the position of the player, which is not sent with the other property changes
+fn query_position(state : Rc<MediaPlayer2>, proxy : PlayerProxy<'static>, rt : &Runtime) {
+    let dest = proxy.destination().to_owned();
+    state.players.take_in(|players| {
+        for p in players.iter_mut().filter(|p| *p.proxy.destination() == dest) {
+            p.position_asked = Some(Instant::now());
+        }
+    });
+    let mut notify = NotifierList::active(rt);
+    util::spawn("MPRIS position query", async move {
+        let pos = proxy.position().await?;
+        let at = Instant::now();
+        state.players.take_in(|players| {
+            for p in players.iter_mut().filter(|p| *p.proxy.destination() == dest) {
+                p.position = Some((pos as f64 / 1_000_000.0, at));
+            }
+        });
+        notify.notify_data("mpris:position");
+        Ok(())
+    });
+}
+
+/// Move the player to the given position in seconds
+fn seek(target : &str, to : f64) {
+    DATA.with(|cell| {
+        let state = cell.get_or_init(MediaPlayer2::new);
+        state.players.take_in(|players| {
+            let player = match find_player(players, target) {
+                Some(i) => &mut players[i],
+                None => return,
+            };
+            let micros = (to * 1_000_000.0) as i64;
+            let track = match player.meta_value("mpris:trackid") {
+                Some(Variant::ObjectPath(p)) => Some(p.to_owned()),
+                Some(Variant::Str(s)) => zvariant::ObjectPath::try_from(s.as_str()).ok().map(|p| p.into_owned()),
+                _ => None,
+            };
+            let dbus = DBus::get_session();
+            let msg = match track {
+                Some(track) => zbus::Message::method(
+                    None::<&str>,
+                    Some(player.proxy.destination().clone()),
+                    "/org/mpris/MediaPlayer2",
+                    Some("org.mpris.MediaPlayer2.Player"),
+                    "SetPosition",
+                    &(track, micros),
+                ),
+                None => {
+                    let from = (player.position().unwrap_or(0.0) * 1_000_000.0) as i64;
+                    zbus::Message::method(
+                        None::<&str>,
+                        Some(player.proxy.destination().clone()),
+                        "/org/mpris/MediaPlayer2",
+                        Some("org.mpris.MediaPlayer2.Player"),
+                        "Seek",
+                        &(micros - from),
+                    )
+                }
+            };
+            match msg {
+                Ok(msg) => dbus.send(msg),
+                Err(e) => warn!("Could not seek: {}", e),
+            }
+            player.position = Some((to, Instant::now()));
+        })
+    })
+}
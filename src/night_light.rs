@@ -0,0 +1,257 @@
+//! Night light (color temperature) control using wlr-gamma-control
+use crate::data::Value;
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,toml_to_f64};
+use log::error;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Seek,SeekFrom,Write};
+use std::os::unix::io::AsRawFd;
+use wayland_client::Main;
+use wayland_client::protocol::wl_output::WlOutput;
+use wayland_protocols::wlr::unstable::gamma_control::v1::client::zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1;
+use wayland_protocols::wlr::unstable::gamma_control::v1::client::zwlr_gamma_control_v1::ZwlrGammaControlV1;
+
+const MIN_TEMP : u32 = 1000;
+const MAX_TEMP : u32 = 10000;
+const NEUTRAL_TEMP : u32 = 6500;
+
+struct GammaOutput {
+    output : WlOutput,
+    control : ZwlrGammaControlV1,
+    size : Option<u32>,
+    /// The last table sent; it is kept open until it is replaced
+    table : Option<File>,
+}
+
+struct Gamma {
+    /// Kept attached so that it can create the per-output objects
+    manager : Option<Main<ZwlrGammaControlManagerV1>>,
+    outputs : Vec<GammaOutput>,
+    enabled : bool,
+    temperature : u32,
+    interested : NotifierList,
+}
+
+thread_local! {
+    static GAMMA : RefCell<Option<Gamma>> = const { RefCell::new(None) };
+}
+
+/// Approximate the white point of a black body at this temperature, relative to 6500K
+fn whitepoint(temp : u32) -> [f64; 3] {
+    fn rgb(temp : u32) -> [f64; 3] {
+        let t = temp as f64 / 100.0;
+        let r = if t <= 66.0 { 255.0 } else { 329.698727446 * (t - 60.0).powf(-0.1332047592) };
+        let g = if t <= 66.0 {
+            99.4708025861 * t.ln() - 161.1195681661
+        } else {
+            288.1221695283 * (t - 60.0).powf(-0.0755148492)
+        };
+        let b = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.5177312231 * (t - 10.0).ln() - 305.0447927307
+        };
+        [r, g, b].map(|v| (v / 255.0).clamp(0.0, 1.0))
+    }
+    let w = rgb(temp);
+    let n = rgb(NEUTRAL_TEMP);
+    [0, 1, 2].map(|i| (w[i] / n[i]).min(1.0))
+}
+
+/// Write a gamma table of the given size for the temperature to a new memfd
+fn gamma_table(size : u32, temp : u32) -> std::io::Result<File> {
+    let mut file = util::memfd(c"rwaybar-gamma")?;
+    let white = whitepoint(temp);
+    let mut buf = Vec::with_capacity(size as usize * 6);
+    for channel in white {
+        for i in 0..size {
+            let v = i as f64 / (size - 1).max(1) as f64 * channel;
+            buf.extend_from_slice(&((v * 65535.0).round() as u16).to_ne_bytes());
+        }
+    }
+    file.write_all(&buf)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+impl Gamma {
+    /// Send the current temperature to every output whose gamma size is known
+    fn apply(&mut self) {
+        for out in &mut self.outputs {
+            let size = match out.size {
+                Some(size) => size,
+                None => continue,
+            };
+            match gamma_table(size, self.temperature) {
+                Ok(table) => {
+                    out.control.set_gamma(table.as_raw_fd());
+                    out.table = Some(table);
+                }
+                Err(e) => error!("Could not create a gamma table: {}", e),
+            }
+        }
+    }
+
+    /// Take control of the gamma of any outputs not yet controlled
+    fn bind(&mut self, rt : &Runtime) {
+        let (manager, wayland) = match (&self.manager, &rt.wayland) {
+            (Some(manager), Some(wayland)) => (manager, wayland),
+            _ => return,
+        };
+        for output in wayland.env.get_all_outputs() {
+            if self.outputs.iter().any(|o| o.output == output) {
+                continue;
+            }
+            let control = manager.get_gamma_control(&output);
+            let id = control.as_ref().id();
+            control.quick_assign(move |control, event, _data| {
+                use wayland_protocols::wlr::unstable::gamma_control::v1::client::zwlr_gamma_control_v1::Event;
+                GAMMA.with(|cell| {
+                    let mut cell = cell.borrow_mut();
+                    let state = match cell.as_mut() {
+                        Some(state) => state,
+                        None => return,
+                    };
+                    match event {
+                        Event::GammaSize { size } => {
+                            if let Some(o) = state.outputs.iter_mut().find(|o| o.control.as_ref().id() == id) {
+                                o.size = Some(size);
+                            }
+                            if state.enabled {
+                                state.apply();
+                            }
+                        }
+                        Event::Failed => {
+                            // Another program (such as wlsunset or gammastep) controls the gamma,
+                            // or the output does not support it
+                            error!("Could not control the gamma of an output; is another night light program running?");
+                            state.outputs.retain(|o| o.control.as_ref().id() != id);
+                            control.destroy();
+                        }
+                        _ => {}
+                    }
+                });
+            });
+            self.outputs.push(GammaOutput { output, control : control.detach(), size : None, table : None });
+        }
+    }
+
+    fn set(&mut self, enabled : bool, temperature : u32, rt : &Runtime) {
+        let temperature = temperature.clamp(MIN_TEMP, MAX_TEMP);
+        if enabled == self.enabled && temperature == self.temperature {
+            return;
+        }
+        self.temperature = temperature;
+        if enabled {
+            self.enabled = true;
+            self.bind(rt);
+            self.apply();
+        } else if self.enabled {
+            // Destroying the controls restores the original gamma
+            self.enabled = false;
+            for out in self.outputs.drain(..) {
+                out.control.destroy();
+            }
+        }
+        self.interested.notify_data("night-light");
+        // the requests are sent when the next frame flushes the connection
+        rt.request_draw();
+    }
+}
+
+fn with_gamma<R>(rt : &Runtime, f : impl FnOnce(&mut Gamma) -> R) -> R {
+    GAMMA.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let state = cell.get_or_insert_with(|| {
+            let manager = rt.wayland.as_ref()
+                .and_then(|w| w.env.manager.instantiate_exact::<ZwlrGammaControlManagerV1>(1).ok());
+            if manager.is_none() {
+                error!("Night light not available, no zwlr_gamma_control_manager_v1 found");
+            }
+            Gamma {
+                manager,
+                outputs : Vec::new(),
+                enabled : false,
+                temperature : 4500,
+                interested : NotifierList::default(),
+            }
+        });
+        f(state)
+    })
+}
+
+/// A toggle for warmer screen colors, and the color temperature it uses
+#[derive(Debug)]
+pub struct NightLight {
+    temperature : u32,
+    enabled : bool,
+}
+
+impl NightLight {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        NightLight {
+            temperature : toml_to_f64(value.get("temperature")).map_or(4500, |t| t as u32),
+            enabled : value.get("enabled").and_then(|v| v.as_bool()).unwrap_or(false),
+        }
+    }
+}
+
+impl Source for NightLight {
+    fn init(&self, _name : &str, rt : &Runtime) {
+        with_gamma(rt, |state| {
+            let enabled = self.enabled || state.enabled;
+            state.set(enabled, self.temperature, rt);
+        });
+    }
+
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        with_gamma(rt, |state| {
+            state.interested.add(rt);
+            match key {
+                "" | "text" => {
+                    let temp = if state.enabled { state.temperature } else { NEUTRAL_TEMP };
+                    Value::Float(temp as f64)
+                }
+                "enabled" => Value::Bool(state.enabled),
+                "temperature" => Value::Float(state.temperature as f64),
+                _ => Value::Null,
+            }
+        })
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        with_gamma(rt, |state| {
+            let msg = value.as_str_fast();
+            match key {
+                "" | "enabled" => {
+                    let enabled = match msg {
+                        "on" => true,
+                        "off" => false,
+                        "" | "toggle" => !state.enabled,
+                        _ => {
+                            error!("Ignoring write of '{}' to {}.{}: use on, off, or toggle", msg, name, key);
+                            return;
+                        }
+                    };
+                    state.set(enabled, state.temperature, rt);
+                }
+                "temperature" => {
+                    let temp = match value.parse_f64() {
+                        Some(t) if msg.starts_with('+') || msg.starts_with('-') => state.temperature as f64 + t,
+                        Some(t) => t,
+                        None => {
+                            error!("Ignoring write of '{}' to {}.{}: not a number", msg, name, key);
+                            return;
+                        }
+                    };
+                    state.set(state.enabled, temp.max(0.0) as u32, rt);
+                }
+                _ => error!("Ignoring write to {}.{}", name, key),
+            }
+        })
+    }
+}
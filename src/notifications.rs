@@ -0,0 +1,307 @@
+//! Unread counts and do-not-disturb control for an external notification daemon
+use crate::data::Value;
+use crate::dbus::{DBus,SignalWatch,typed};
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,spawn_handle,toml_to_f64};
+use futures_util::future::RemoteHandle;
+use log::{debug,error};
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::{Rc,Weak};
+use std::time::Duration;
+use zbus::Connection;
+use zbus::zvariant::{OwnedValue,Value as Variant};
+
+const FDO_NAME : &str = "org.freedesktop.Notifications";
+const FDO_PATH : &str = "/org/freedesktop/Notifications";
+const DUNST_IFACE : &str = "org.dunstproject.cmd0";
+const MAKO_PATH : &str = "/fr/emersion/Mako";
+const MAKO_IFACE : &str = "fr.emersion.Mako";
+const SWAYNC_NAME : &str = "org.erikreider.swaync.cc";
+const SWAYNC_PATH : &str = "/org/erikreider/swaync/cc";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Daemon {
+    Dunst,
+    Mako,
+    Swaync,
+}
+
+impl Daemon {
+    fn from_str(name : &str) -> Option<Self> {
+        match name {
+            "dunst" => Some(Daemon::Dunst),
+            "mako" => Some(Daemon::Mako),
+            "swaync" | "SwayNotificationCenter" => Some(Daemon::Swaync),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Daemon::Dunst => "dunst",
+            Daemon::Mako => "mako",
+            Daemon::Swaync => "swaync",
+        }
+    }
+
+    /// Find out which daemon owns the notification service
+    async fn detect(zbus : &Connection) -> zbus::Result<Option<Self>> {
+        let reply = zbus.call_method(Some(FDO_NAME), FDO_PATH, Some(FDO_NAME), "GetServerInformation", &()).await?;
+        let (name, _vendor, _version, _spec) : (String, String, String, String) = reply.body()?;
+        Ok(Self::from_str(&name))
+    }
+}
+
+async fn dunst_get(zbus : &Connection, prop : &str) -> zbus::Result<OwnedValue> {
+    let reply = zbus.call_method(Some(FDO_NAME), FDO_PATH, Some("org.freedesktop.DBus.Properties"), "Get", &(DUNST_IFACE, prop)).await?;
+    reply.body()
+}
+
+/// The active mako modes; older versions only have a single mode
+async fn mako_modes(zbus : &Connection) -> zbus::Result<Vec<String>> {
+    match zbus.call_method(Some(FDO_NAME), MAKO_PATH, Some(MAKO_IFACE), "GetModes", &()).await {
+        Ok(reply) => reply.body(),
+        Err(_) => {
+            let reply = zbus.call_method(Some(FDO_NAME), MAKO_PATH, Some(MAKO_IFACE), "GetMode", &()).await?;
+            Ok(vec![reply.body()?])
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    daemon : Cell<Option<Daemon>>,
+    /// The mako mode that counts as do-not-disturb
+    mako_dnd : Box<str>,
+    count : Cell<Option<u32>>,
+    dnd : Cell<Option<bool>>,
+    watching : Cell<bool>,
+    signals : Cell<Vec<SignalWatch>>,
+    interested : Cell<NotifierList>,
+}
+
+impl Inner {
+    fn set(&self, count : Option<u32>, dnd : Option<bool>) {
+        let old = (self.count.replace(count), self.dnd.replace(dnd));
+        if old != (count, dnd) {
+            self.interested.take().notify_data("notifications");
+        }
+    }
+
+    async fn refresh(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        let zbus = DBus::get_session().connection().await;
+        let daemon = match self.daemon.get() {
+            Some(daemon) => daemon,
+            None => match Daemon::detect(&zbus).await? {
+                Some(daemon) => {
+                    debug!("Using {} for notification counts", daemon.name());
+                    self.daemon.set(Some(daemon));
+                    daemon
+                }
+                None => return Err("the notification daemon is not dunst, mako, or swaync".into()),
+            }
+        };
+        if !self.watching.replace(true) {
+            self.watch(daemon);
+        }
+
+        let (count, dnd) = match daemon {
+            Daemon::Dunst => {
+                let displayed = dunst_get(&zbus, "displayedLength").await?;
+                let waiting = dunst_get(&zbus, "waitingLength").await?;
+                let paused = dunst_get(&zbus, "paused").await?;
+                let count = typed::<u32>(&displayed, "displayedLength").unwrap_or(0)
+                    + typed::<u32>(&waiting, "waitingLength").unwrap_or(0);
+                (count, typed::<bool>(&paused, "paused").unwrap_or(false))
+            }
+            Daemon::Mako => {
+                let reply = zbus.call_method(Some(FDO_NAME), MAKO_PATH, Some(MAKO_IFACE), "ListNotifications", &()).await?;
+                let list : Vec<HashMap<String, OwnedValue>> = reply.body()?;
+                let modes = mako_modes(&zbus).await?;
+                (list.len() as u32, modes.iter().any(|m| **m == *self.mako_dnd))
+            }
+            Daemon::Swaync => {
+                let count = zbus.call_method(Some(SWAYNC_NAME), SWAYNC_PATH, Some(SWAYNC_NAME), "NotificationCount", &()).await?.body()?;
+                let dnd = zbus.call_method(Some(SWAYNC_NAME), SWAYNC_PATH, Some(SWAYNC_NAME), "GetDnd", &()).await?.body()?;
+                (count, dnd)
+            }
+        };
+        self.set(Some(count), Some(dnd));
+        Ok(())
+    }
+
+    /// Refresh as soon as the daemon announces a change, for daemons that do so
+    fn watch(self : &Rc<Self>, daemon : Daemon) {
+        let dbus = DBus::get_session();
+        match daemon {
+            Daemon::Dunst => {
+                let weak = Rc::downgrade(self);
+                dbus.add_property_change_watcher(move |hdr, iface, _changed, _inval| {
+                    let path = hdr.path().ok().flatten().map(|p| p.as_str());
+                    if iface == DUNST_IFACE && path == Some(FDO_PATH) {
+                        Self::refresh_weak(&weak);
+                    }
+                });
+            }
+            Daemon::Mako => {}
+            Daemon::Swaync => {
+                // swaync sends the new state along with the signal
+                let weak = Rc::downgrade(self);
+                let v1 = dbus.watch_signal("org.erikreider.swaync.cc.Subscribe", Some(SWAYNC_PATH), move |msg| {
+                    if let (Some(this), Ok((count, dnd, _visible))) = (weak.upgrade(), msg.body::<(u32, bool, bool)>()) {
+                        this.set(Some(count), Some(dnd));
+                    }
+                });
+                let weak = Rc::downgrade(self);
+                let v2 = dbus.watch_signal("org.erikreider.swaync.cc.SubscribeV2", Some(SWAYNC_PATH), move |msg| {
+                    if let (Some(this), Ok((count, dnd, _visible, _inhibited))) = (weak.upgrade(), msg.body::<(u32, bool, bool, bool)>()) {
+                        this.set(Some(count), Some(dnd));
+                    }
+                });
+                self.signals.set(vec![v1, v2]);
+            }
+        }
+    }
+
+    fn refresh_weak(weak : &Weak<Self>) {
+        if let Some(this) = weak.upgrade() {
+            util::spawn("Notification count", this.refresh());
+        }
+    }
+
+    async fn set_dnd(self : Rc<Self>, how : Option<bool>) -> Result<(), Box<dyn Error>> {
+        let zbus = DBus::get_session().connection().await;
+        let on = how.unwrap_or(!self.dnd.get().unwrap_or(false));
+        match self.daemon.get() {
+            Some(Daemon::Dunst) => {
+                zbus.call_method(Some(FDO_NAME), FDO_PATH, Some("org.freedesktop.DBus.Properties"), "Set",
+                    &(DUNST_IFACE, "paused", Variant::from(on))).await?;
+            }
+            Some(Daemon::Mako) => {
+                let mut modes = mako_modes(&zbus).await?;
+                modes.retain(|m| **m != *self.mako_dnd);
+                if on {
+                    modes.push(self.mako_dnd.to_string());
+                }
+                if zbus.call_method(Some(FDO_NAME), MAKO_PATH, Some(MAKO_IFACE), "SetModes", &modes).await.is_err() {
+                    let mode = if on { &*self.mako_dnd } else { "default" };
+                    zbus.call_method(Some(FDO_NAME), MAKO_PATH, Some(MAKO_IFACE), "SetMode", &mode).await?;
+                }
+            }
+            Some(Daemon::Swaync) => {
+                zbus.call_method(Some(SWAYNC_NAME), SWAYNC_PATH, Some(SWAYNC_NAME), "SetDnd", &on).await?;
+            }
+            None => return Err("no supported notification daemon is running".into()),
+        }
+        self.refresh().await
+    }
+
+    async fn call(self : Rc<Self>, what : &str) -> Result<(), Box<dyn Error>> {
+        let zbus = DBus::get_session().connection().await;
+        match (self.daemon.get(), what) {
+            (Some(Daemon::Dunst), "clear") => {
+                zbus.call_method(Some(FDO_NAME), FDO_PATH, Some(DUNST_IFACE), "NotificationCloseAll", &()).await?;
+            }
+            (Some(Daemon::Mako), "clear") => {
+                zbus.call_method(Some(FDO_NAME), MAKO_PATH, Some(MAKO_IFACE), "DismissAllNotifications", &()).await?;
+            }
+            (Some(Daemon::Swaync), "clear") => {
+                zbus.call_method(Some(SWAYNC_NAME), SWAYNC_PATH, Some(SWAYNC_NAME), "ClearAll", &()).await?;
+            }
+            (Some(Daemon::Swaync), "panel") => {
+                zbus.call_method(Some(SWAYNC_NAME), SWAYNC_PATH, Some(SWAYNC_NAME), "ToggleVisibility", &()).await?;
+            }
+            (Some(daemon), _) => return Err(format!("{} does not support '{}'", daemon.name(), what).into()),
+            (None, _) => return Err("no supported notification daemon is running".into()),
+        }
+        self.refresh().await
+    }
+}
+
+/// The "notifications" module
+#[derive(Debug)]
+pub struct Notifications {
+    inner : Rc<Inner>,
+    poll : Duration,
+    task : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Notifications {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let daemon = match value.get("daemon").and_then(|v| v.as_str()) {
+            None | Some("auto") => None,
+            Some(name) => {
+                let daemon = Daemon::from_str(name);
+                if daemon.is_none() {
+                    error!("Unknown notification daemon '{}', detecting it instead", name);
+                }
+                daemon
+            }
+        };
+        let poll = toml_to_f64(value.get("poll"))
+            .filter(|&p| p > 0.0)
+            .map_or(Duration::from_secs(5), Duration::from_secs_f64);
+        Notifications {
+            inner : Rc::new(Inner {
+                daemon : Cell::new(daemon),
+                mako_dnd : value.get("mako-dnd-mode").and_then(|v| v.as_str()).unwrap_or("do-not-disturb").into(),
+                count : Default::default(),
+                dnd : Default::default(),
+                watching : Default::default(),
+                signals : Default::default(),
+                interested : Default::default(),
+            }),
+            poll,
+            task : Default::default(),
+        }
+    }
+}
+
+impl Source for Notifications {
+    fn init(&self, name : &str, rt : &Runtime) {
+        let weak = Rc::downgrade(&self.inner);
+        let sched = rt.scheduler.clone();
+        let poll = self.poll;
+        let name = name.to_owned();
+        self.task.set(Some(spawn_handle("Notification count", async move {
+            loop {
+                let inner = match Weak::upgrade(&weak) {
+                    Some(inner) => inner,
+                    None => return Ok(()),
+                };
+                if let Err(e) = inner.clone().refresh().await {
+                    debug!("{}: could not query the notification daemon: {}", name, e);
+                    inner.set(None, None);
+                }
+                drop(inner);
+                sched.sleep(poll, poll / 10).await;
+            }
+        })));
+    }
+
+    fn read<'a>(&'a self, name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        let _ = name;
+        self.inner.interested.take_in(|i| i.add(rt));
+        match key {
+            "" | "text" | "count" => self.inner.count.get().map_or(Value::Null, |c| Value::Float(c as f64)),
+            "dnd" => self.inner.dnd.get().map_or(Value::Null, Value::Bool),
+            "daemon" => self.inner.daemon.get().map_or(Value::Null, |d| Value::Borrow(d.name())),
+            _ => Value::Null,
+        }
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        let _ = rt;
+        let inner = self.inner.clone();
+        match (key, value.as_str_fast()) {
+            ("dnd", "" | "toggle") => util::spawn("Notification DND", inner.set_dnd(None)),
+            ("dnd", "on") => util::spawn("Notification DND", inner.set_dnd(Some(true))),
+            ("dnd", "off") => util::spawn("Notification DND", inner.set_dnd(Some(false))),
+            ("", "clear") => util::spawn("Notification clear", inner.call("clear")),
+            ("", "panel") => util::spawn("Notification panel", inner.call("panel")),
+            (_, msg) => error!("Ignoring write of '{}' to {}.{}", msg, name, key),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Output power control (DPMS) using wlr-output-power-management
+use crate::data::Value;
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use log::{error,warn};
+use smithay_client_toolkit::output::with_output_info;
+use std::cell::RefCell;
+use wayland_client::Main;
+use wayland_client::protocol::wl_output::WlOutput;
+use wayland_protocols::wlr::unstable::output_power_management::v1::client::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1;
+use wayland_protocols::wlr::unstable::output_power_management::v1::client::zwlr_output_power_v1::{Mode,ZwlrOutputPowerV1};
+
+struct PowerOutput {
+    name : String,
+    output : WlOutput,
+    control : ZwlrOutputPowerV1,
+    on : Option<bool>,
+}
+
+struct Outputs {
+    /// Kept attached so that it can create the per-output objects
+    manager : Option<Main<ZwlrOutputPowerManagerV1>>,
+    outputs : Vec<PowerOutput>,
+    interested : NotifierList,
+}
+
+thread_local! {
+    static OUTPUTS : RefCell<Option<Outputs>> = const { RefCell::new(None) };
+}
+
+/// Run the function with the power state of every output, binding the power manager and any
+/// outputs that appeared since the last call
+fn with_outputs<R>(rt : &Runtime, f : impl FnOnce(&mut Outputs) -> R) -> R {
+    OUTPUTS.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let state = cell.get_or_insert_with(|| {
+            let manager = rt.wayland.as_ref()
+                .and_then(|w| w.env.manager.instantiate_exact::<ZwlrOutputPowerManagerV1>(1).ok());
+            if manager.is_none() {
+                error!("Output power control not available, no zwlr_output_power_manager_v1 found");
+            }
+            Outputs {
+                manager,
+                outputs : Vec::new(),
+                interested : NotifierList::default(),
+            }
+        });
+        if let (Some(manager), Some(wayland)) = (&state.manager, &rt.wayland) {
+            for output in wayland.env.get_all_outputs() {
+                if state.outputs.iter().any(|o| o.output == output) {
+                    continue;
+                }
+                let name = with_output_info(&output, |oi| (!oi.obsolete).then(|| oi.name.clone())).flatten();
+                if let Some(name) = name {
+                    let control = manager.get_output_power(&output);
+                    let id = control.as_ref().id();
+                    control.quick_assign(move |control, event, _data| {
+                        use wayland_protocols::wlr::unstable::output_power_management::v1::client::zwlr_output_power_v1::Event;
+                        OUTPUTS.with(|cell| {
+                            let mut cell = cell.borrow_mut();
+                            let state = match cell.as_mut() {
+                                Some(state) => state,
+                                None => return,
+                            };
+                            match event {
+                                Event::Mode { mode } => {
+                                    if let Some(o) = state.outputs.iter_mut().find(|o| o.control.as_ref().id() == id) {
+                                        o.on = Some(mode == Mode::On);
+                                    }
+                                }
+                                Event::Failed => {
+                                    state.outputs.retain(|o| o.control.as_ref().id() != id);
+                                    control.destroy();
+                                }
+                                _ => return,
+                            }
+                            state.interested.notify_data("output-power");
+                        });
+                    });
+                    state.outputs.push(PowerOutput { name, output, control : control.detach(), on : None });
+                }
+            }
+        }
+        f(state)
+    })
+}
+
+/// Turn the named output on or off; a mode of "toggle" (or "") switches to the other state
+pub fn set_power(rt : &Runtime, output : &str, mode : &str) {
+    with_outputs(rt, |state| {
+        let o = match state.outputs.iter().find(|o| o.name == output) {
+            Some(o) => o,
+            None => {
+                warn!("Cannot set power of unknown output '{}'", output);
+                return;
+            }
+        };
+        let on = match mode {
+            "on" => true,
+            "off" => false,
+            "" | "toggle" => o.on == Some(false),
+            _ => {
+                error!("Unknown output power mode '{}': use on, off, or toggle", mode);
+                return;
+            }
+        };
+        o.control.set_mode(if on { Mode::On } else { Mode::Off });
+    });
+    // the request is sent when the next frame flushes the connection
+    rt.request_draw();
+}
+
+/// The power state of one output, or of the bar's own output
+#[derive(Debug)]
+pub struct OutputPower {
+    output : Box<str>,
+}
+
+impl OutputPower {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        OutputPower {
+            output : value.get("output").and_then(|v| v.as_str()).unwrap_or("{bar.output}").into(),
+        }
+    }
+}
+
+impl Source for OutputPower {
+    fn read<'a>(&'a self, name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        let output = match key {
+            "" | "text" | "state" => rt.format_or(&self.output, name).into_text(),
+            key => key.into(),
+        };
+        with_outputs(rt, |state| {
+            state.interested.add(rt);
+            match state.outputs.iter().find(|o| o.name == *output).and_then(|o| o.on) {
+                Some(true) => Value::Borrow("on"),
+                Some(false) => Value::Borrow("off"),
+                None => Value::Null,
+            }
+        })
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        let output = match key {
+            "" | "text" | "state" => rt.format_or(&self.output, name).into_text(),
+            key => key.into(),
+        };
+        set_power(rt, &output, value.as_str_fast());
+    }
+}
@@ -0,0 +1,124 @@
+//! Several items that take turns being shown in the same place
+use crate::data::Value;
+use crate::event::EventSink;
+use crate::item::Item;
+use crate::render::Render;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,spawn_handle,toml_to_f64};
+use futures_util::future::RemoteHandle;
+use log::error;
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+
+/// The "pages" module
+#[derive(Debug)]
+pub struct Pages {
+    items : Vec<Rc<Item>>,
+    /// Seconds to show each page before moving to the next, or 0 to only change when written
+    interval : f64,
+    page : Cell<usize>,
+    /// When the current page was first shown
+    shown : Cell<Option<Instant>>,
+    timer : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Pages {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let items = value.get("items")
+            .and_then(|v| v.as_array())
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(Item::from_toml_ref)
+            .map(Rc::new)
+            .collect();
+        Pages {
+            items,
+            interval : toml_to_f64(value.get("interval")).filter(|&v| v > 0.0).unwrap_or(0.0),
+            page : Cell::new(0),
+            shown : Cell::new(None),
+            timer : Cell::new(None),
+        }
+    }
+
+    fn set_page(&self, page : usize) {
+        self.page.set(page);
+        self.shown.set(Some(Instant::now()));
+    }
+
+    /// The current page, after moving to the next one if its time is up
+    fn current(&self) -> usize {
+        let len = self.items.len();
+        if self.interval > 0.0 && len > 0 {
+            let now = Instant::now();
+            match self.shown.get() {
+                Some(shown) if now >= shown + Duration::from_secs_f64(self.interval) => {
+                    self.set_page((self.page.get() + 1) % len);
+                }
+                Some(_) => {}
+                None => self.shown.set(Some(now)),
+            }
+        }
+        self.page.get()
+    }
+
+    pub fn render(&self, ctx : &mut Render, rv : &mut EventSink) {
+        let len = self.items.len();
+        if len == 0 {
+            return;
+        }
+        let page = self.current() % len;
+        match self.shown.get() {
+            Some(shown) if self.interval > 0.0 && !ctx.measure_only => {
+                // Redraw when it is time for the next page
+                let period = Duration::from_secs_f64(self.interval);
+                let mut notify = NotifierList::active(ctx.runtime);
+                let sched = ctx.runtime.scheduler.clone();
+                self.timer.set(Some(spawn_handle("Page timer", async move {
+                    sched.sleep_until(shown + period, period / 20).await;
+                    notify.notify_data("pages");
+                    Ok(())
+                })));
+            }
+            _ => {}
+        }
+        rv.merge(self.items[page].render(ctx));
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        let page = self.current();
+        match key {
+            "page" => f(Value::Float(page as f64 + 1.0)),
+            "count" => f(Value::Float(self.items.len() as f64)),
+            _ => match self.items.get(page) {
+                Some(item) => item.data.read_in(name, key, rt, f),
+                None => f(Value::Null),
+            }
+        }
+    }
+
+    pub fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        let len = self.items.len();
+        if len == 0 {
+            return;
+        }
+        if !key.is_empty() && key != "page" {
+            error!("Ignoring write to {}.{}", name, key);
+            return;
+        }
+        let page = self.page.get();
+        let page = match &*value.into_text() {
+            "next" => (page + 1) % len,
+            "prev" | "previous" => (page + len - 1) % len,
+            v => match v.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= len => n - 1,
+                _ => {
+                    error!("Invalid page for {}: '{}' (use next, prev, or a number from 1 to {})", name, v, len);
+                    return;
+                }
+            }
+        };
+        self.set_page(page);
+        NotifierList::active(rt).notify_data("pages");
+    }
+}
@@ -0,0 +1,352 @@
+//! Custom modules loaded from WebAssembly plugins
+//!
+//! A plugin is a core wasm module exporting `memory` and `alloc(len) -> ptr`, and optionally:
+//!
+//! - `namespace() -> str`: the item name for the plugin's values (default: the file name)
+//! - `init(config : str)`: called once with the plugin's configuration table as JSON
+//! - `update() -> i32`: called every `poll` seconds; return nonzero if any value changed
+//! - `read(key : str) -> str`: the value of a key, or -1 if it has none ("" is the main value)
+//! - `click(msg : str)`: called for each message sent to the plugin's item
+//!
+//! Strings passed to the plugin are written to a buffer from `alloc`, which the plugin then owns,
+//! and passed as a (pointer, length) pair.  Strings returned by the plugin are packed into an i64
+//! as `(pointer << 32) | length`.  The plugin may import `rwaybar.log(level, ptr, len)` to log a
+//! message, with levels 1 (error) to 5 (trace).
+//!
+//! Each call into a plugin is stopped with an error if it runs for longer than [CALL_TIMEOUT], so
+//! that a plugin stuck in a loop does not hang the bar.  A plugin whose call fails this way (or
+//! traps) is left in an unknown state, so it is not called again and its values read as empty.
+use crate::data::{Module,Value};
+use crate::item::Item;
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,spawn_handle,toml_to_f64};
+use futures_util::future::RemoteHandle;
+use log::{debug,info,error};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::rc::{Rc,Weak};
+use std::time::Duration;
+use wasmtime::{Caller,Engine,EngineWeak,Instance,Linker,Memory,Store,TypedFunc};
+
+/// Longest time a single call into a plugin may run
+const CALL_TIMEOUT : Duration = Duration::from_millis(500);
+
+/// Interval between epoch increments, which is the precision of [CALL_TIMEOUT]
+const EPOCH_TICK : Duration = Duration::from_millis(10);
+
+struct Instantiated {
+    store : Store<()>,
+    memory : Memory,
+    alloc : TypedFunc<i32, i32>,
+    read : Option<TypedFunc<(i32, i32), i64>>,
+    update : Option<TypedFunc<(), i32>>,
+    click : Option<TypedFunc<(i32, i32), ()>>,
+}
+
+impl Instantiated {
+    fn new(engine : &Engine, path : &Path) -> Result<(Self, Instance), Box<dyn Error>> {
+        let module = wasmtime::Module::from_file(engine, path)?;
+        let mut linker = Linker::new(engine);
+        linker.func_wrap("rwaybar", "log", |mut caller : Caller<'_, ()>, level : i32, ptr : i32, len : i32| {
+            let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+                Some(memory) => memory,
+                None => return,
+            };
+            let data = memory.data(&caller);
+            let msg = data.get(ptr as u32 as usize..).and_then(|d| d.get(..len as u32 as usize))
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            let level = match level {
+                1 => log::Level::Error,
+                2 => log::Level::Warn,
+                3 => log::Level::Info,
+                4 => log::Level::Debug,
+                _ => log::Level::Trace,
+            };
+            log::log!(target: "rwaybar::plugin", level, "{}", msg);
+        })?;
+        let mut store = Store::new(engine, ());
+        // the module's start function runs during instantiation
+        store.set_epoch_deadline(call_ticks());
+        let instance = linker.instantiate(&mut store, &module)?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or("no exported memory")?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let read = instance.get_typed_func(&mut store, "read").ok();
+        let update = instance.get_typed_func(&mut store, "update").ok();
+        let click = instance.get_typed_func(&mut store, "click").ok();
+        Ok((Instantiated { store, memory, alloc, read, update, click }, instance))
+    }
+
+    /// The store, with a fresh deadline for the next call into the plugin
+    fn arm(&mut self) -> &mut Store<()> {
+        self.store.set_epoch_deadline(call_ticks());
+        &mut self.store
+    }
+
+    /// Copy a string into the plugin's memory
+    fn put(&mut self, s : &str) -> Result<(i32, i32), Box<dyn Error>> {
+        let len = s.len() as i32;
+        let alloc = self.alloc.clone();
+        let ptr = alloc.call(self.arm(), len)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, s.as_bytes())?;
+        Ok((ptr, len))
+    }
+
+    /// Read a string returned by the plugin
+    fn get(&self, packed : i64) -> Option<String> {
+        if packed < 0 {
+            return None;
+        }
+        let ptr = (packed >> 32) as u32 as usize;
+        let len = packed as u32 as usize;
+        let data = self.memory.data(&self.store);
+        data.get(ptr..).and_then(|d| d.get(..len)).map(|d| String::from_utf8_lossy(d).into_owned())
+    }
+}
+
+fn call_ticks() -> u64 {
+    (CALL_TIMEOUT.as_millis() / EPOCH_TICK.as_millis()) as u64
+}
+
+/// Advance the engine's epoch every [EPOCH_TICK] until the engine is dropped, which happens once
+/// all the plugins using it are unloaded
+fn start_epoch_timer(engine : EngineWeak) {
+    let rv = std::thread::Builder::new().name("plugin-timer".into()).spawn(move || {
+        loop {
+            std::thread::sleep(EPOCH_TICK);
+            match engine.upgrade() {
+                Some(engine) => engine.increment_epoch(),
+                None => return,
+            }
+        }
+    });
+    if let Err(e) = rv {
+        error!("Could not start the plugin timer: {}", e);
+    }
+}
+
+struct Inner {
+    instance : RefCell<Instantiated>,
+    interested : Cell<NotifierList>,
+    failed : Cell<bool>,
+}
+
+impl Inner {
+    /// Stop using a plugin after a call into it failed
+    fn fail(&self, name : &str, what : &str, e : &dyn fmt::Display) {
+        if !self.failed.replace(true) {
+            error!("Plugin {} failed {}, disabling it: {}", name, what, e);
+            self.interested.take().notify_data("plugin");
+        }
+    }
+}
+
+/// A plugin, used as the data of the item named by its namespace
+pub struct Plugin {
+    inner : Rc<Inner>,
+    poll : Option<Duration>,
+    timer : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Plugin").field("poll", &self.poll).finish()
+    }
+}
+
+impl Plugin {
+    /// Load a plugin, returning its namespace
+    fn load(engine : &Engine, path : &Path, config : &toml::value::Table) -> Result<(String, Self), Box<dyn Error>> {
+        let (mut inst, instance) = Instantiated::new(engine, path)?;
+
+        let namespace = match instance.get_typed_func::<(), i64>(&mut inst.store, "namespace") {
+            Ok(f) => {
+                let packed = f.call(inst.arm(), ())?;
+                inst.get(packed).ok_or("namespace() returned nothing")?
+            }
+            Err(_) => path.file_stem().and_then(|s| s.to_str()).ok_or("invalid file name")?.to_owned(),
+        };
+
+        if let Ok(init) = instance.get_typed_func::<(i32, i32), ()>(&mut inst.store, "init") {
+            let cfg = config.get(&namespace).map_or(json::JsonValue::new_object(), toml_to_json);
+            let (ptr, len) = inst.put(&cfg.dump())?;
+            init.call(inst.arm(), (ptr, len))?;
+        }
+
+        let poll = config.get(&namespace)
+            .and_then(|c| toml_to_f64(c.get("poll")))
+            .filter(|&p| p > 0.0)
+            .map(Duration::from_secs_f64)
+            .or(inst.update.is_some().then(|| Duration::from_secs(1)));
+
+        let inner = Rc::new(Inner {
+            instance : RefCell::new(inst),
+            interested : Default::default(),
+            failed : Cell::new(false),
+        });
+        Ok((namespace, Plugin { inner, poll, timer : Default::default() }))
+    }
+}
+
+fn toml_to_json(value : &toml::Value) -> json::JsonValue {
+    match value {
+        toml::Value::String(s) => s.as_str().into(),
+        toml::Value::Integer(i) => (*i).into(),
+        toml::Value::Float(f) => (*f).into(),
+        toml::Value::Boolean(b) => (*b).into(),
+        toml::Value::Datetime(d) => d.to_string().into(),
+        toml::Value::Array(a) => a.iter().map(toml_to_json).collect::<Vec<_>>().into(),
+        toml::Value::Table(t) => {
+            let mut obj = json::JsonValue::new_object();
+            for (k, v) in t {
+                obj[k.as_str()] = toml_to_json(v);
+            }
+            obj
+        }
+    }
+}
+
+impl Source for Plugin {
+    fn init(&self, name : &str, rt : &Runtime) {
+        let poll = match self.poll {
+            Some(poll) => poll,
+            None => return,
+        };
+        let weak = Rc::downgrade(&self.inner);
+        let sched = rt.scheduler.clone();
+        let name = name.to_owned();
+        self.timer.set(Some(spawn_handle("Plugin update", async move {
+            loop {
+                sched.sleep(poll, poll / 10).await;
+                let inner = match Weak::upgrade(&weak) {
+                    Some(inner) if !inner.failed.get() => inner,
+                    _ => return Ok(()),
+                };
+                let rv = {
+                    let mut inst = inner.instance.borrow_mut();
+                    let update = match &inst.update {
+                        Some(update) => update.clone(),
+                        None => return Ok(()),
+                    };
+                    update.call(inst.arm(), ())
+                };
+                match rv {
+                    Ok(0) => {}
+                    Ok(_) => inner.interested.take().notify_data("plugin"),
+                    Err(e) => {
+                        inner.fail(&name, "to update", &e);
+                        return Ok(());
+                    }
+                }
+            }
+        })));
+    }
+
+    fn read<'a>(&'a self, name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.inner.interested.take_in(|i| i.add(rt));
+        if self.inner.failed.get() {
+            return Value::Null;
+        }
+        let mut inst = self.inner.instance.borrow_mut();
+        let read = match &inst.read {
+            Some(read) => read.clone(),
+            None => return Value::Null,
+        };
+        let key = if key == "text" { "" } else { key };
+        let rv = inst.put(key).and_then(|args| Ok(read.call(inst.arm(), args)?));
+        match rv {
+            Ok(packed) => inst.get(packed).map_or(Value::Null, Value::Owned),
+            Err(e) => {
+                drop(inst);
+                self.inner.fail(name, &format!("to read '{}'", key), &e);
+                Value::Null
+            }
+        }
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        let _ = rt;
+        if self.inner.failed.get() {
+            return;
+        }
+        let mut inst = self.inner.instance.borrow_mut();
+        let click = match &inst.click {
+            Some(click) => click.clone(),
+            None => {
+                error!("Ignoring write to {}.{}", name, key);
+                return;
+            }
+        };
+        let rv = inst.put(&value.into_text()).and_then(|args| Ok(click.call(inst.arm(), args)?));
+        drop(inst);
+        match rv {
+            Ok(()) => self.inner.interested.take().notify_data("plugin"),
+            Err(e) => self.inner.fail(name, "to handle a click", &e),
+        }
+    }
+}
+
+/// Load the plugins in the directory named by the `[plugins]` table, returning the items they
+/// provide
+pub fn load(config : &toml::Value, rt : &Runtime) -> Vec<(String, Rc<Item>)> {
+    let table = match config.as_table() {
+        Some(table) => table,
+        None => {
+            error!("Ignoring invalid plugins table: {}", config);
+            return Vec::new();
+        }
+    };
+    let dir = match table.get("dir").and_then(|v| v.as_str()) {
+        Some(dir) => dir,
+        None => {
+            error!("The plugins table requires a 'dir' key");
+            return Vec::new();
+        }
+    };
+    let dir = match rt.find_config_file(dir) {
+        Some(dir) => dir,
+        None => {
+            error!("Could not find the plugin directory {}", dir);
+            return Vec::new();
+        }
+    };
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Could not read the plugin directory {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut wasm_config = wasmtime::Config::new();
+    wasm_config.epoch_interruption(true);
+    let engine = match Engine::new(&wasm_config) {
+        Ok(engine) => engine,
+        Err(e) => {
+            error!("Could not start the plugin engine: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut rv = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().map_or(true, |ext| ext != "wasm") {
+            continue;
+        }
+        match Plugin::load(&engine, &path, table) {
+            Ok((namespace, plugin)) => {
+                info!("Loaded plugin {} from {}", namespace, path.display());
+                rv.push((namespace, Rc::new(Item::from(Module::Custom(Box::new(plugin))))));
+            }
+            Err(e) => error!("Could not load plugin {}: {}", path.display(), e),
+        }
+    }
+    debug!("Loaded {} plugins", rv.len());
+    if !rv.is_empty() {
+        start_epoch_timer(engine.weak());
+    }
+    rv
+}
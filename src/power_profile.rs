@@ -0,0 +1,162 @@
+//! Power profile (power-profiles-daemon) status and control
+use crate::dbus::DBus;
+use crate::data::Value;
+use crate::state::{Runtime,NotifierList};
+use crate::util::{self,Cell};
+use once_cell::unsync::OnceCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::rc::Rc;
+use log::{debug,warn,error};
+use zbus::dbus_proxy;
+use zbus::zvariant::OwnedValue;
+
+#[dbus_proxy(
+    interface = "org.freedesktop.UPower.PowerProfiles",
+    default_service = "org.freedesktop.UPower.PowerProfiles",
+    default_path = "/org/freedesktop/UPower/PowerProfiles",
+)]
+trait PowerProfiles {
+    /// ActiveProfile property
+    #[dbus_proxy(property)]
+    fn active_profile(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn set_active_profile(&self, value: &str) -> zbus::Result<()>;
+
+    /// PerformanceDegraded property
+    #[dbus_proxy(property)]
+    fn performance_degraded(&self) -> zbus::Result<String>;
+
+    /// Profiles property
+    #[dbus_proxy(property)]
+    fn profiles(&self) -> zbus::Result<Vec<HashMap<String, OwnedValue>>>;
+}
+
+/// Older versions of the daemon only use this name
+const LEGACY_NAME : &str = "net.hadess.PowerProfiles";
+const LEGACY_PATH : &str = "/net/hadess/PowerProfiles";
+
+#[derive(Debug,Default)]
+struct PowerProfileData {
+    proxy : Cell<Option<PowerProfilesProxy<'static>>>,
+    active : Cell<String>,
+    degraded : Cell<String>,
+    profiles : Cell<Vec<String>>,
+    interested : Cell<NotifierList>,
+}
+
+thread_local! {
+    static DATA : OnceCell<Rc<PowerProfileData>> = Default::default();
+}
+
+impl PowerProfileData {
+    fn new() -> Rc<Self> {
+        let rv = Rc::new(PowerProfileData::default());
+
+        let this = rv.clone();
+        util::spawn("Power profile setup", async move {
+            let dbus = DBus::get_system();
+            let target = this.clone();
+            dbus.add_property_change_watcher(move |hdr, iface, _changed, _inval| {
+                let path = hdr.path().ok().flatten().map(|p| p.as_str());
+                if matches!(path, Some("/org/freedesktop/UPower/PowerProfiles") | Some(LEGACY_PATH))
+                    && (iface == "org.freedesktop.UPower.PowerProfiles" || iface == LEGACY_NAME)
+                {
+                    util::spawn("Power profile query", target.clone().query());
+                }
+            });
+
+            let zbus = dbus.connection().await;
+            let mut proxy = PowerProfilesProxy::builder(&zbus)
+                .cache_properties(zbus::CacheProperties::No)
+                .build().await?;
+            if let Err(e) = proxy.active_profile().await {
+                debug!("Trying legacy power-profiles-daemon name: {}", e);
+                proxy = PowerProfilesProxy::builder(&zbus)
+                    .destination(LEGACY_NAME)?
+                    .path(LEGACY_PATH)?
+                    .interface(LEGACY_NAME)?
+                    .cache_properties(zbus::CacheProperties::No)
+                    .build().await?;
+            }
+            this.proxy.set(Some(proxy));
+            this.query().await
+        });
+
+        rv
+    }
+
+    async fn query(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        let proxy = match self.proxy.take_in(|p| p.clone()) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let active = proxy.active_profile().await?;
+        let degraded = proxy.performance_degraded().await.unwrap_or_default();
+        let profiles = proxy.profiles().await?
+            .iter()
+            .filter_map(|p| p.get("Profile"))
+            .filter_map(|v| v.try_into().ok())
+            .map(|v : &str| v.to_owned())
+            .collect();
+        self.active.set(active);
+        self.degraded.set(degraded);
+        self.profiles.set(profiles);
+        self.interested.take().notify_data("power-profile");
+        Ok(())
+    }
+}
+
+pub fn read_in<F : FnOnce(Value) -> R, R>(_name : &str, key : &str, rt : &Runtime, f : F) -> R {
+    DATA.with(|cell| {
+        let state = cell.get_or_init(PowerProfileData::new);
+        state.interested.take_in(|i| i.add(rt));
+        match key {
+            "" | "text" | "profile" => state.active.take_in(|v| f(Value::Borrow(v))),
+            "degraded" => state.degraded.take_in(|v| f(Value::Borrow(v))),
+            "profiles" => state.profiles.take_in(|v| f(Value::Owned(v.join(" ")))),
+            _ => {
+                warn!("Unknown key '{}' in power-profile", key);
+                f(Value::Null)
+            }
+        }
+    })
+}
+
+pub fn write(name : &str, key : &str, value : Value, _rt : &Runtime) {
+    DATA.with(|cell| {
+        let state = cell.get_or_init(PowerProfileData::new);
+        let profiles = state.profiles.take_in(|v| v.clone());
+        let active = state.active.take_in(|v| v.clone());
+        let pos = profiles.iter().position(|p| *p == active);
+        let len = profiles.len();
+        let target = match (value.as_str_fast(), pos) {
+            ("next", Some(i)) | ("cycle", Some(i)) => profiles[(i + 1) % len].clone(),
+            ("prev", Some(i)) | ("previous", Some(i)) => profiles[(i + len - 1) % len].clone(),
+            ("next" | "cycle" | "prev" | "previous", None) => {
+                match profiles.first() {
+                    Some(p) => p.clone(),
+                    None => {
+                        warn!("No power profiles available");
+                        return;
+                    }
+                }
+            }
+            (p, _) if profiles.iter().any(|v| v == p) => p.to_owned(),
+            (p, _) => {
+                error!("Ignoring write of unknown profile '{}' to {}.{}", p, name, key);
+                return;
+            }
+        };
+        let proxy = match state.proxy.take_in(|p| p.clone()) {
+            Some(p) => p,
+            None => return,
+        };
+        let state = state.clone();
+        util::spawn("Power profile change", async move {
+            proxy.set_active_profile(&target).await?;
+            state.query().await
+        });
+    })
+}
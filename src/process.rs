@@ -0,0 +1,205 @@
+//! Process monitor (top N processes by CPU or memory)
+use crate::data::{IterationItem,Periodic,Value};
+use crate::state::Runtime;
+use crate::util::{Cell,toml_to_f64};
+use log::{debug,warn,error};
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+
+/// Information on a single process, taken during the last sample
+#[derive(Debug)]
+pub struct ProcessInfo {
+    pid : u32,
+    name : Box<str>,
+    cmdline : Box<str>,
+    cpu : f64,
+    rss : u64,
+}
+
+impl ProcessInfo {
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, key : &str, _rt : &Runtime, f : F) -> R {
+        match key {
+            "pid" => f(Value::Float(self.pid as f64)),
+            "" | "text" | "name" => f(Value::Borrow(&self.name)),
+            "cmdline" => f(Value::Borrow(&self.cmdline)),
+            "cpu" => f(Value::Float(self.cpu)),
+            "rss" => f(Value::Float(self.rss as f64)),
+            "rss-mb" => f(Value::Float(self.rss as f64 / 1_000_000.0)),
+            "rss-mib" => f(Value::Float(self.rss as f64 / 1048576.0)),
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn write(&self, key : &str, value : Value, _rt : &Runtime) {
+        let sig = match value.as_str_fast() {
+            "kill" => libc::SIGKILL,
+            "term" | "" => libc::SIGTERM,
+            "stop" => libc::SIGSTOP,
+            "cont" => libc::SIGCONT,
+            _ => {
+                error!("Ignoring write of '{}' to process.{} - use kill, term, stop, or cont", value, key);
+                return;
+            }
+        };
+        debug!("Sending signal {} to {} ({})", sig, self.pid, self.name);
+        if unsafe { libc::kill(self.pid as i32, sig) } != 0 {
+            warn!("Could not signal process {}: {}", self.pid, std::io::Error::last_os_error());
+        }
+    }
+}
+
+#[derive(Debug,Default)]
+struct Sample {
+    /// Total CPU time (in ticks) at the last sample
+    total : u64,
+    /// Total idle CPU time (in ticks) at the last sample
+    idle : u64,
+    /// Per-process CPU time (in ticks) at the last sample
+    times : HashMap<u32, u64>,
+    /// All processes, sorted by the configured key
+    list : Vec<Rc<ProcessInfo>>,
+    /// Percentage of all CPUs that were busy
+    busy : f64,
+}
+
+impl Sample {
+    fn refresh(&mut self, by_rss : bool) {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let mut total = 0;
+        let mut idle = 0;
+        let mut ncpu = 0;
+        for line in fs::read_to_string("/proc/stat").unwrap_or_default().lines() {
+            if line.starts_with("cpu ") {
+                let v : Vec<u64> = line.split_whitespace().skip(1).filter_map(|v| v.parse().ok()).collect();
+                total = v.iter().sum();
+                idle = v.get(3).copied().unwrap_or(0) + v.get(4).copied().unwrap_or(0);
+            } else if line.starts_with("cpu") {
+                ncpu += 1;
+            }
+        }
+        let d_total = total.saturating_sub(self.total);
+
+        let mut times = HashMap::with_capacity(self.times.len());
+        let mut list = Vec::with_capacity(self.list.len());
+        for entry in fs::read_dir("/proc").into_iter().flatten().filter_map(Result::ok) {
+            let pid = match entry.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            let path = entry.path();
+            let stat = match fs::read_to_string(path.join("stat")) {
+                Ok(s) => s,
+                Err(_) => continue, // the process exited
+            };
+            // The name is in parenthesis and may itself contain spaces or parenthesis
+            let (name, rest) = match (stat.find('('), stat.rfind(')')) {
+                (Some(s), Some(e)) if s < e => (&stat[s + 1..e], &stat[e + 1..]),
+                _ => continue,
+            };
+            let fields : Vec<&str> = rest.split_whitespace().collect();
+            // fields[0] is field 3 (state) in proc(5)
+            let utime : u64 = fields.get(11).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let stime : u64 = fields.get(12).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let rss : u64 = fields.get(21).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let time = utime + stime;
+            // Scale usage so that 100% is one full CPU, like top
+            let cpu = match self.times.get(&pid) {
+                Some(&prev) if d_total != 0 => {
+                    time.saturating_sub(prev) as f64 * 100.0 * ncpu.max(1) as f64 / d_total as f64
+                }
+                _ => 0.0,
+            };
+            times.insert(pid, time);
+            let cmdline = fs::read(path.join("cmdline")).unwrap_or_default();
+            let cmdline = String::from_utf8_lossy(&cmdline).trim_end_matches('\0').replace('\0', " ");
+            list.push(Rc::new(ProcessInfo {
+                pid,
+                name : name.into(),
+                cmdline : cmdline.into(),
+                cpu,
+                rss : rss * page_size,
+            }));
+        }
+        if by_rss {
+            list.sort_by(|a, b| b.rss.cmp(&a.rss));
+        } else {
+            list.sort_by(|a, b| b.cpu.partial_cmp(&a.cpu).unwrap_or(std::cmp::Ordering::Equal));
+        }
+
+        self.busy = if self.total != 0 && d_total != 0 {
+            100.0 - 100.0 * idle.saturating_sub(self.idle) as f64 / d_total as f64
+        } else {
+            0.0
+        };
+        self.total = total;
+        self.idle = idle;
+        self.times = times;
+        self.list = list;
+    }
+}
+
+/// The "processes" module
+#[derive(Debug)]
+pub struct Processes {
+    count : usize,
+    poll : Periodic<(bool, Cell<Sample>)>,
+}
+
+impl Processes {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let count = config.get("count").and_then(|v| v.as_integer()).unwrap_or(5);
+        let count = usize::try_from(count).unwrap_or_else(|_| {
+            error!("Invalid process count {}, showing none", count);
+            0
+        });
+        let by_rss = match config.get("sort").and_then(|v| v.as_str()) {
+            None | Some("cpu") => false,
+            Some("rss") | Some("memory") => true,
+            Some(x) => {
+                error!("Invalid process sort '{}', using cpu", x);
+                false
+            }
+        };
+        let poll = Periodic::new(
+            toml_to_f64(config.get("poll")).unwrap_or(5.0),
+            (by_rss, Cell::default()),
+        );
+        Processes { count, poll }
+    }
+
+    /// Read the process list again now, for the `refresh` action
+    pub fn force_refresh(&self) {
+        self.poll.refresh();
+    }
+
+    fn refresh(&self, rt : &Runtime) {
+        self.poll.read_refresh(rt, |(by_rss, sample)| {
+            sample.take_in(|s| s.refresh(*by_rss));
+            Some("processes")
+        });
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, _name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        self.refresh(rt);
+        self.poll.data().1.take_in(|s| {
+            match key {
+                "count" => f(Value::Float(s.list.len() as f64)),
+                "cpu" => f(Value::Float(s.busy)),
+                "" | "text" => match s.list.first() {
+                    Some(p) => f(Value::Borrow(&p.name)),
+                    None => f(Value::Null),
+                },
+                _ => f(Value::Null),
+            }
+        })
+    }
+
+    pub fn read_focus_list<F : FnMut(bool, IterationItem)>(&self, rt : &Runtime, mut f : F) {
+        self.refresh(rt);
+        let list : Vec<_> = self.poll.data().1.take_in(|s| s.list.iter().take(self.count).cloned().collect());
+        for item in list {
+            f(false, IterationItem::Process(item));
+        }
+    }
+}
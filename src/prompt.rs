@@ -0,0 +1,177 @@
+//! A popup with a line of text that the user can edit and submit
+use crate::data::Value;
+use crate::event::Action;
+use crate::font::render_font;
+use crate::render::Render;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,toml_to_f64};
+use log::error;
+use smithay_client_toolkit::seat::keyboard::keysyms;
+use std::rc::Rc;
+
+#[derive(Debug)]
+struct Shared {
+    prompt : Box<str>,
+    initial : Box<str>,
+    /// Minimum width of the entry field
+    width : f32,
+    submit : Action,
+    /// The last submitted text
+    value : Cell<String>,
+    interested : Cell<NotifierList>,
+}
+
+/// The "prompt" module
+#[derive(Debug)]
+pub struct Prompt {
+    text : Box<str>,
+    shared : Rc<Shared>,
+}
+
+impl Prompt {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let text = value.get("text").and_then(|v| v.as_str()).unwrap_or("").into();
+        let prompt = value.get("prompt").and_then(|v| v.as_str()).unwrap_or("").into();
+        let initial = value.get("initial").and_then(|v| v.as_str()).unwrap_or("").into();
+        let width = toml_to_f64(value.get("width")).unwrap_or(200.0) as f32;
+        let submit = match value.get("submit") {
+            Some(v) => Action::from_toml(v),
+            None => {
+                error!("Prompt '{}' has no submit action", text);
+                Action::None
+            }
+        };
+        Prompt {
+            text,
+            shared : Rc::new(Shared {
+                prompt,
+                initial,
+                width,
+                submit,
+                value : Default::default(),
+                interested : Default::default(),
+            }),
+        }
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        match key {
+            "" | "text" => f(rt.format_or(&self.text, name)),
+            "value" => {
+                self.shared.interested.take_in(|i| i.add(rt));
+                self.shared.value.take_in(|s| f(Value::Borrow(s)))
+            }
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
+        if key != "value" {
+            error!("Ignoring write to {}.{}", name, key);
+            return;
+        }
+        self.shared.value.set(value.into_text().into_owned());
+        self.shared.interested.take().notify_data("prompt");
+    }
+
+    pub fn popup(&self, rt : &Runtime) -> PromptPopup {
+        let edit = rt.format_or(&self.shared.initial, "prompt").into_text().into_owned();
+        PromptPopup {
+            shared : self.shared.clone(),
+            cursor : edit.len(),
+            edit,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct PromptPopup {
+    shared : Rc<Shared>,
+    edit : String,
+    /// Byte offset of the cursor in edit
+    cursor : usize,
+}
+
+impl PartialEq for PromptPopup {
+    fn eq(&self, rhs : &Self) -> bool {
+        Rc::ptr_eq(&self.shared, &rhs.shared)
+    }
+}
+
+impl PromptPopup {
+    pub fn render(&mut self, ctx : &mut Render) -> Vec<tiny_skia::Rect> {
+        let mut xsize : f32 = 0.0;
+        let mut ypos = 2.0;
+        let label = ctx.runtime.format_or(&self.shared.prompt, "prompt").into_text();
+        if !label.is_empty() {
+            let tsize = render_font(ctx, (2.0, ypos), &label, true);
+            xsize = xsize.max(2.0 + tsize.0);
+            ypos += tsize.1.ceil() + 4.0;
+        }
+
+        let (before, line) = ctx.measure(|ctx| {
+            let before = render_font(ctx, (0.0, 0.0), &self.edit[..self.cursor], false).0;
+            let line = render_font(ctx, (0.0, 0.0), "Ag", false).1;
+            (before, line.ceil())
+        });
+        let text_w = ctx.measure(|ctx| render_font(ctx, (0.0, 0.0), &self.edit, false).0);
+        let field_w = self.shared.width.max(text_w.ceil() + 10.0);
+        if !ctx.measure_only {
+            let field_paint = tiny_skia::Paint {
+                shader: tiny_skia::Shader::SolidColor(tiny_skia::Color::from_rgba8(255, 255, 255, 32)),
+                ..Default::default()
+            };
+            let cursor_paint = tiny_skia::Paint {
+                shader: tiny_skia::Shader::SolidColor(ctx.font_color),
+                ..Default::default()
+            };
+            if let Some(rect) = tiny_skia::Rect::from_xywh(2.0, ypos, field_w, line + 4.0) {
+                ctx.canvas.fill_rect(rect, &field_paint, ctx.render_xform);
+            }
+            if let Some(rect) = tiny_skia::Rect::from_xywh(5.0 + before, ypos + 2.0, 1.0, line) {
+                ctx.canvas.fill_rect(rect, &cursor_paint, ctx.render_xform);
+            }
+        }
+        render_font(ctx, (5.0, ypos + 2.0), &self.edit, false);
+        xsize = xsize.max(2.0 + field_w);
+        ypos += line + 4.0;
+
+        ctx.render_pos = tiny_skia::Point { x: xsize.ceil() + 2.0, y: ypos + 2.0 };
+        Vec::new()
+    }
+
+    /// Handle a key press, given the key and the text it types (if any).
+    ///
+    /// Returns true if the popup should be closed.
+    pub fn key(&mut self, keysym : u32, text : Option<&str>, runtime : &Runtime) -> bool {
+        let prev_char = self.edit[..self.cursor].chars().next_back().map_or(0, char::len_utf8);
+        let next_char = self.edit[self.cursor..].chars().next().map_or(0, char::len_utf8);
+        match keysym {
+            keysyms::XKB_KEY_Return | keysyms::XKB_KEY_KP_Enter => {
+                self.shared.value.set(self.edit.clone());
+                self.shared.interested.take().notify_data("prompt");
+                self.shared.submit.invoke(runtime, 0);
+                return true;
+            }
+            keysyms::XKB_KEY_BackSpace => {
+                self.cursor -= prev_char;
+                self.edit.drain(self.cursor..self.cursor + prev_char);
+            }
+            keysyms::XKB_KEY_Delete | keysyms::XKB_KEY_KP_Delete => {
+                self.edit.drain(self.cursor..self.cursor + next_char);
+            }
+            keysyms::XKB_KEY_Left | keysyms::XKB_KEY_KP_Left => self.cursor -= prev_char,
+            keysyms::XKB_KEY_Right | keysyms::XKB_KEY_KP_Right => self.cursor += next_char,
+            keysyms::XKB_KEY_Home | keysyms::XKB_KEY_KP_Home => self.cursor = 0,
+            keysyms::XKB_KEY_End | keysyms::XKB_KEY_KP_End => self.cursor = self.edit.len(),
+            _ => {
+                // Control characters (from ctrl+key, tab, etc) are not inserted
+                if let Some(text) = text.filter(|t| !t.chars().any(char::is_control)) {
+                    self.edit.insert_str(self.cursor, text);
+                    self.cursor += text.len();
+                }
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,216 @@
+//! Screenshots and screen recordings
+#[cfg(feature="dbus")]
+use crate::dbus::DBus;
+use crate::data::Value;
+use crate::event::Action;
+use crate::state::{NotifierList,Runtime};
+use crate::timer;
+use crate::util::{Cell,read_lines,spawn_handle};
+use futures_util::future::RemoteHandle;
+use log::{error,info};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command,Stdio};
+use std::rc::Rc;
+use std::time::Instant;
+
+#[derive(Debug)]
+struct Recording {
+    pid : libc::pid_t,
+    started : Instant,
+    /// True once the recorder has been asked to stop, until it exits
+    stopping : bool,
+    /// Set when the recorder's output ends, which marks its exit
+    exited : Rc<Cell<bool>>,
+    _output : RemoteHandle<()>,
+}
+
+/// The "recorder" module
+#[derive(Debug)]
+pub struct Recorder {
+    text : Box<str>,
+    /// Command used for screenshots, or None to use the desktop portal
+    screenshot : Option<Action>,
+    #[cfg(feature="dbus")]
+    interactive : bool,
+    record : Box<str>,
+    on_stop : Action,
+    recording : Cell<Option<Recording>>,
+    interested : Cell<NotifierList>,
+    tick : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Recorder {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let screenshot = match value.get("screenshot") {
+            Some(v) => Some(Action::from_toml(v)),
+            None if cfg!(feature="dbus") => None,
+            None => Some(Action::Exec { format : "grim".into(), opts : Default::default() }),
+        };
+        let record = value.get("record").and_then(|v| v.as_str())
+            .unwrap_or("wf-recorder -f \"$HOME/Videos/recording-$(date +%Y%m%d-%H%M%S).mp4\"")
+            .into();
+        Recorder {
+            text : value.get("text").and_then(|v| v.as_str()).unwrap_or("").into(),
+            screenshot,
+            #[cfg(feature="dbus")]
+            interactive : value.get("interactive").and_then(|v| v.as_bool()).unwrap_or(false),
+            record,
+            on_stop : value.get("on-stop").map_or(Action::None, Action::from_toml),
+            recording : Cell::new(None),
+            interested : Default::default(),
+            tick : Cell::new(None),
+        }
+    }
+
+    /// Forget about the recorder if it has exited, running on-stop if so
+    fn check_exit(&self, rt : &Runtime) {
+        let exited = self.recording.take_in(|rec| {
+            if !rec.as_ref().is_some_and(|rec| rec.exited.get()) {
+                return false;
+            }
+            info!("Screen recorder exited");
+            *rec = None;
+            true
+        });
+        if exited {
+            self.tick.set(None);
+            self.interested.take().notify_data("recorder");
+            self.on_stop.invoke(rt, 0);
+        }
+    }
+
+    fn start(&self, rt : &Runtime) {
+        if self.recording.take_in(|rec| rec.is_some()) {
+            return;
+        }
+        let cmd = match rt.format(&self.record) {
+            Ok(cmd) => cmd.into_text().into_owned(),
+            Err(e) => {
+                error!("Error expanding format for recorder: {}", e);
+                return;
+            }
+        };
+        info!("Executing '{}'", cmd);
+        // Use a new process group so that stopping it reaches the recorder and not only the shell
+        let child = Command::new("/bin/sh").arg("-c").arg(&cmd[..])
+            .stdout(Stdio::piped())
+            .process_group(0)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => return error!("Could not execute {}: {}", cmd, e),
+        };
+        // children are not waited for, so the end of the output marks the exit
+        let stdout = child.stdout.take().unwrap();
+        let fd = stdout.as_raw_fd();
+        unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+        let exited = Rc::new(Cell::new(false));
+        let done = exited.clone();
+        let output = spawn_handle("Screen recorder", async move {
+            let rv = read_lines(fd, &cmd, |line| info!("{}: {}", cmd, line)).await;
+            drop(stdout);
+            done.set(true);
+            Ok(rv?)
+        });
+        self.recording.set(Some(Recording {
+            pid : child.id() as libc::pid_t,
+            started : Instant::now(),
+            stopping : false,
+            exited,
+            _output : output,
+        }));
+        self.interested.take().notify_data("recorder");
+    }
+
+    fn stop(&self) {
+        let sent = self.recording.take_in(|rec| match rec {
+            Some(rec) if !rec.stopping => {
+                // SIGINT lets recorders such as wf-recorder finish writing the file
+                unsafe { libc::kill(-rec.pid, libc::SIGINT); }
+                rec.stopping = true;
+                true
+            }
+            _ => false,
+        });
+        if sent {
+            self.interested.take().notify_data("recorder");
+        }
+    }
+
+    fn screenshot(&self, rt : &Runtime) {
+        match &self.screenshot {
+            Some(action) => action.invoke(rt, 0),
+            #[cfg(feature="dbus")]
+            None => {
+                let mut options = std::collections::HashMap::new();
+                options.insert("interactive", zbus::zvariant::Value::from(self.interactive));
+                match zbus::Message::method(
+                    None::<&str>,
+                    Some("org.freedesktop.portal.Desktop"),
+                    "/org/freedesktop/portal/desktop",
+                    Some("org.freedesktop.portal.Screenshot"),
+                    "Screenshot",
+                    &("", options),
+                ) {
+                    Ok(msg) => DBus::get_session().send(msg),
+                    Err(e) => error!("Could not request a screenshot: {}", e),
+                }
+            }
+            #[cfg(not(feature="dbus"))]
+            None => {}
+        }
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        self.check_exit(rt);
+        self.interested.take_in(|i| i.add(rt));
+        let (state, elapsed) = self.recording.take_in(|rec| match rec {
+            Some(rec) if rec.stopping => ("stopping", Some(rec.started.elapsed())),
+            Some(rec) => ("recording", Some(rec.started.elapsed())),
+            None => ("idle", None),
+        });
+        if let Some(elapsed) = elapsed {
+            // Update the time and notice when the recorder exits
+            timer::schedule_tick(&self.tick, Some(timer::until_next_second(elapsed, false)), rt);
+        }
+        match key {
+            "" | "text" => f(rt.format_or(&self.text, name)),
+            "state" => f(Value::Borrow(state)),
+            "recording" => f(Value::Bool(elapsed.is_some())),
+            "elapsed" | "time" => {
+                let elapsed = match elapsed {
+                    Some(elapsed) => elapsed,
+                    None => return f(Value::Null),
+                };
+                if key == "time" {
+                    f(Value::Owned(timer::format_time(elapsed.as_secs())))
+                } else {
+                    f(Value::Float(elapsed.as_secs() as f64))
+                }
+            }
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        if !key.is_empty() {
+            error!("Ignoring write to {}.{}", name, key);
+            return;
+        }
+        self.check_exit(rt);
+        match &*value.into_text() {
+            "screenshot" => self.screenshot(rt),
+            "start" => self.start(rt),
+            "stop" => self.stop(),
+            "record" | "toggle" => {
+                if self.recording.take_in(|rec| rec.is_some()) {
+                    self.stop();
+                } else {
+                    self.start(rt);
+                }
+            }
+            v => error!("Unknown command for {}: '{}' (use screenshot, start, stop, or toggle)", name, v),
+        }
+    }
+}
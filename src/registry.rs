@@ -0,0 +1,214 @@
+//! Registry of item types, keyed by the value of their `type` key
+//!
+//! Types that need special handling while rendering are still parsed directly in
+//! [Module::from_toml_in]; everything else is created through this registry.  Programs using
+//! rwaybar as a library can add data sources by implementing [Source] and calling [register]
+//! before the configuration is loaded, without needing to add a variant to [Module].
+use crate::astronomy;
+use crate::color;
+use crate::connectivity;
+use crate::data::{Module,Value};
+use crate::debug;
+use crate::feed;
+use crate::item::Item;
+#[cfg(feature="dbus")]
+use crate::idle;
+use crate::input;
+#[cfg(feature="dbus")]
+use crate::keyring;
+use crate::kubernetes;
+use crate::launcher;
+#[cfg(feature="lua")]
+use crate::lua;
+use crate::menu;
+use crate::mock;
+#[cfg(feature="dbus")]
+use crate::notifications;
+use crate::night_light;
+use crate::output_power;
+use crate::pages;
+use crate::prompt;
+use crate::recorder;
+use crate::schedule;
+use crate::separator;
+use crate::state::Runtime;
+use crate::ticker;
+use crate::timer;
+use crate::todo;
+#[cfg(feature="dbus")]
+use crate::udisks;
+use crate::wireguard;
+use crate::world_clock;
+use log::error;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Creates a [Module] from its configuration
+pub trait Constructor {
+    fn from_toml(&self, value : &toml::Value) -> Module;
+}
+
+impl<F : Fn(&toml::Value) -> Module> Constructor for F {
+    fn from_toml(&self, value : &toml::Value) -> Module {
+        self(value)
+    }
+}
+
+/// A data source for an item type added with [register]; it is shown as text like most items,
+/// unless it provides an [Source::entry_item]
+pub trait Source : fmt::Debug {
+    /// One-time setup, called when the item is created or replaced
+    fn init(&self, name : &str, rt : &Runtime) {
+        let _ = (name, rt);
+    }
+
+    /// Read a value; the key is "" or "text" for the item's main value.
+    ///
+    /// Sources that change over time should register interest using
+    /// [crate::state::NotifierList::add] and notify it when they do.
+    fn read<'a>(&'a self, name : &str, key : &str, rt : &Runtime) -> Value<'a>;
+
+    /// Fetch the data again now, for the `refresh` action; returns false if not supported
+    fn refresh(&self, name : &str, rt : &Runtime) -> bool {
+        let _ = (name, rt);
+        false
+    }
+
+    /// Handle a write or send to the item
+    fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        let _ = (value, rt);
+        error!("Ignoring write to {}.{}", name, key);
+    }
+
+    /// List the entries of this source, for a `focus-list` using it as its `source`.
+    ///
+    /// The flag marks the focused entry.
+    fn read_focus_list(&self, rt : &Runtime, f : &mut dyn FnMut(bool, Rc<dyn Entry>)) {
+        let _ = (rt, f);
+    }
+
+    /// The item drawn once for each entry of the focus list, instead of showing the text
+    fn entry_item(&self) -> Option<&Template> {
+        None
+    }
+
+    /// The item shown in a popup when hovering over this one
+    fn popup(&self) -> Option<&Template> {
+        None
+    }
+}
+
+/// One entry in the focus list of a [Source], available as `item` while it is shown
+pub trait Entry : fmt::Debug {
+    /// A short name for the kind of entry, for debugging output
+    fn kind(&self) -> &'static str;
+
+    /// Read the value of `item.<key>`
+    fn read<'a>(&'a self, key : &str, rt : &Runtime) -> Value<'a>;
+
+    /// Handle a write or send to `item.<key>`
+    fn write(self : Rc<Self>, key : &str, value : Value, rt : &Runtime) {
+        let _ = (value, rt);
+        error!("Ignoring write to {} item.{}", self.kind(), key);
+    }
+}
+
+/// An item defined by a source, for [Source::entry_item] or [Source::popup]
+#[derive(Debug,Clone)]
+pub struct Template(pub(crate) Rc<Item>);
+
+impl Template {
+    /// Parse the item, which may be any item definition valid in the configuration
+    pub fn from_toml(value : &toml::Value) -> Self {
+        Template(Rc::new(Item::from_toml_ref(value)))
+    }
+}
+
+thread_local! {
+    static REGISTRY : RefCell<HashMap<Box<str>, Rc<dyn Constructor>>> = RefCell::new(builtin());
+}
+
+fn builtin() -> HashMap<Box<str>, Rc<dyn Constructor>> {
+    let mut map : HashMap<Box<str>, Rc<dyn Constructor>> = HashMap::new();
+    let mut add = |kind : &str, ctor : fn(&toml::Value) -> Module| {
+        map.insert(kind.into(), Rc::new(ctor));
+    };
+    add("astronomy", |v| match astronomy::Astronomy::from_toml(v) {
+        Ok(a) => Module::Custom(Box::new(a)),
+        Err(e) => Module::parse_error(e),
+    });
+    add("color-scale", |v| Module::Custom(Box::new(color::ColorScale::from_toml(v))));
+    add("connectivity", |v| match connectivity::Connectivity::from_toml(v) {
+        Ok(c) => Module::Custom(Box::new(c)),
+        Err(e) => Module::parse_error(e),
+    });
+    add("debug", |v| Module::Debug(debug::DebugItem::from_toml(v)));
+    add("feed", |v| match feed::Feeds::from_toml(v) {
+        Ok(f) => Module::Custom(Box::new(f)),
+        Err(e) => Module::parse_error(e),
+    });
+    #[cfg(feature="dbus")]
+    add("idle", |v| Module::Custom(Box::new(idle::Idle::from_toml(v))));
+    add("input-devices", |v| match input::InputDevices::from_toml(v) {
+        Ok(i) => Module::Custom(Box::new(i)),
+        Err(e) => Module::parse_error(e),
+    });
+    #[cfg(feature="dbus")]
+    add("keyring", |v| Module::Custom(Box::new(keyring::Keyring::from_toml(v))));
+    add("kubernetes", |v| Module::Custom(Box::new(kubernetes::Kubernetes::from_toml(v))));
+    #[cfg(feature="lua")]
+    add("lua", |v| Module::Custom(Box::new(lua::LuaItem::from_toml(v))));
+    add("menu", |v| Module::Menu(menu::Menu::from_toml(v)));
+    add("mock", |v| Module::Mock(mock::Mock::from_toml(v)));
+    #[cfg(feature="dbus")]
+    add("notifications", |v| Module::Custom(Box::new(notifications::Notifications::from_toml(v))));
+    add("night-light", |v| Module::Custom(Box::new(night_light::NightLight::from_toml(v))));
+    add("output-power", |v| Module::Custom(Box::new(output_power::OutputPower::from_toml(v))));
+    add("launchers", |v| Module::Custom(Box::new(launcher::Launchers::from_toml(v))));
+    add("pages", |v| Module::Pages(pages::Pages::from_toml(v)));
+    add("prompt", |v| Module::Prompt(prompt::Prompt::from_toml(v)));
+    add("recorder", |v| Module::Recorder(recorder::Recorder::from_toml(v)));
+    #[cfg(feature="dbus")]
+    add("removable-media", |v| Module::Custom(Box::new(udisks::RemovableMedia::from_toml(v))));
+    add("schedule", |v| Module::Custom(Box::new(schedule::Schedule::from_toml(v))));
+    add("separator", |v| Module::Separator(separator::Separator::from_toml(v)));
+    add("stopwatch", |v| Module::Timer(timer::Timer::from_toml(v)));
+    add("ticker", |v| match ticker::Ticker::from_toml(v) {
+        Ok(t) => Module::Custom(Box::new(t)),
+        Err(e) => Module::parse_error(e),
+    });
+    add("timer", |v| Module::Timer(timer::Timer::from_toml(v)));
+    add("timers", |v| Module::Timers(timer::Timers::from_toml(v)));
+    add("todo", |v| match todo::Todo::from_toml(v) {
+        Ok(t) => Module::Custom(Box::new(t)),
+        Err(e) => Module::parse_error(e),
+    });
+    add("wireguard", |v| match wireguard::WireGuard::from_toml(v) {
+        Ok(w) => Module::Custom(Box::new(w)),
+        Err(e) => Module::parse_error(e),
+    });
+    add("world-clock", |v| Module::Custom(Box::new(world_clock::WorldClock::from_toml(v))));
+    map
+}
+
+/// Add or replace the data source for items with the given `type`
+///
+/// The constructor is called with the item's configuration each time the configuration is
+/// loaded; an error is shown in place of the item.
+pub fn register<F>(kind : &str, ctor : F)
+    where F : Fn(&toml::Value) -> Result<Box<dyn Source>, String> + 'static
+{
+    let ctor = move |value : &toml::Value| match ctor(value) {
+        Ok(source) => Module::Custom(source),
+        Err(e) => Module::parse_error(e),
+    };
+    REGISTRY.with(|r| r.borrow_mut().insert(kind.into(), Rc::new(ctor)));
+}
+
+/// Create an item of a registered type, or None if the type is not registered
+pub fn create(kind : &str, value : &toml::Value) -> Option<Module> {
+    let ctor = REGISTRY.with(|r| r.borrow().get(kind).cloned())?;
+    Some(ctor.from_toml(value))
+}
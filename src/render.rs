@@ -1,4 +1,6 @@
-use crate::font::{FontMapped,RenderKey,TextImage};
+//! The render context used by items, and rasterizing recorded frames into buffers
+use crate::font::{FontMapped,RenderKey,ShapeKey,ShapedText,TextImage};
+use crate::metrics;
 use crate::state::Runtime;
 use crate::wayland::{Globals,Surface};
 use log::error;
@@ -6,11 +8,14 @@ use tiny_skia::PixmapMut;
 use std::borrow::Cow;
 use std::convert::TryInto;
 use std::io;
+use std::sync::{Arc,Mutex,mpsc};
 use std::time;
 use smithay_client_toolkit::environment::Environment;
-use smithay_client_toolkit::shm::AutoMemPool;
+use smithay_client_toolkit::shm::{Format,MemPool};
 use wayland_client::Attached;
+use wayland_client::protocol::wl_buffer::WlBuffer;
 use wayland_client::protocol::wl_pointer::WlPointer;
+use wayland_client::protocol::wl_shm::WlShm;
 use wayland_client::protocol::wl_surface::WlSurface;
 
 #[derive(Copy,Clone,Debug,Eq,PartialEq,Hash,Ord,PartialOrd)]
@@ -25,7 +30,6 @@ impl Default for UID {
 }
 
 pub struct Renderer {
-    shm : AutoMemPool,
     pub cursor : Cursor,
     has_be_rgba: bool,
 }
@@ -33,7 +37,6 @@ pub struct Renderer {
 impl Renderer {
     pub fn new(env : &Environment<Globals>) -> io::Result<Self> {
         use smithay_client_toolkit::shm::ShmHandling;
-        let shm = env.create_auto_pool()?;
 
         let mut cursor_scale = 1;
 
@@ -51,28 +54,26 @@ impl Renderer {
 
         Ok(Renderer {
             cursor,
-            shm,
             has_be_rgba,
         })
     }
 
-    pub fn render_be_rgba(&mut self, surface: &Surface) -> (&mut [u8], impl FnOnce(&mut [u8])) {
+    pub fn render_be_rgba<'s>(&self, surface: &'s mut Surface) -> (&'s mut [u8], impl FnOnce(&mut [u8])) {
         let width = surface.pixel_width();
         let height = surface.pixel_height();
-        let target = &surface.wl;
 
         let stride = width * 4;
         let has_be_rgba = self.has_be_rgba;
         let fmt = if has_be_rgba {
-            smithay_client_toolkit::shm::Format::Abgr8888
+            Format::Abgr8888
         } else {
             // wayland always supports this format, so we convert to it as a fallback
-            smithay_client_toolkit::shm::Format::Argb8888
+            Format::Argb8888
         };
-        let (canvas, wl_buf) = self.shm.buffer(width, height, stride, fmt).expect("OOM");
+        let (canvas, wl_buf) = surface.buffers.buffer(width, height, stride, fmt).expect("OOM");
 
-        target.attach(Some(&wl_buf), 0, 0);
-        target.damage_buffer(0, 0, width, height);
+        surface.wl.attach(Some(&wl_buf), 0, 0);
+        surface.wl.damage_buffer(0, 0, width, height);
 
         (canvas, move |buf| {
             if !has_be_rgba {
@@ -85,6 +86,73 @@ impl Renderer {
     }
 }
 
+/// Shared memory buffers for a single surface.
+///
+/// A buffer is reused as soon as the compositor releases it, and a new one is only allocated if
+/// all existing buffers are still in use.  Extra idle buffers are freed, as are all the buffers
+/// when the surface is destroyed.
+#[derive(Debug)]
+pub struct BufferPool {
+    shm : Attached<WlShm>,
+    pools : Vec<MemPool>,
+    /// Size of the pools, as last reported to the metrics
+    size : usize,
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        metrics::shm_resized(self.size, 0);
+    }
+}
+
+impl BufferPool {
+    pub fn new(shm : Attached<WlShm>) -> Self {
+        BufferPool { shm, pools : Vec::new(), size : 0 }
+    }
+
+    /// Update the metrics with the total size of the shared memory pools
+    fn report_size(&mut self) {
+        let size = self.pools.iter_mut().map(|p| p.mmap().len()).sum();
+        metrics::shm_resized(self.size, size);
+        self.size = size;
+    }
+
+    fn buffer(&mut self, width : i32, height : i32, stride : i32, fmt : Format) -> io::Result<(&mut [u8], WlBuffer)> {
+        let size = (stride * height) as usize;
+        // also catches changes made by an earlier call that failed
+        self.report_size();
+        match self.pools.iter().position(|p| !p.is_used()) {
+            Some(i) => self.pools.swap(0, i),
+            None => {
+                self.pools.push(MemPool::new(self.shm.clone(), |_| ())?);
+                let last = self.pools.len() - 1;
+                self.pools.swap(0, last);
+            }
+        }
+        // Keep at most one spare buffer in addition to the one we are about to use
+        let mut first = true;
+        let mut spare = 0;
+        self.pools.retain(|p| {
+            if std::mem::take(&mut first) || p.is_used() {
+                return true;
+            }
+            spare += 1;
+            spare <= 1
+        });
+
+        // Pools can't shrink, so replace one that is much larger than needed (for example,
+        // after a large popup was closed)
+        if self.pools[0].mmap().len() > size * 2 {
+            self.pools[0] = MemPool::new(self.shm.clone(), |_| ())?;
+        }
+        self.pools[0].resize(size)?;
+        self.report_size();
+        let pool = &mut self.pools[0];
+        let buf = pool.buffer(0, width, height, stride, fmt);
+        Ok((&mut pool.mmap()[..size], buf))
+    }
+}
+
 pub struct Cursor {
     cursor_surf : Attached<WlSurface>,
     spot : (i32, i32),
@@ -122,6 +190,8 @@ impl Cursor {
 #[derive(Debug)]
 pub struct RenderCache {
     pub text: std::cell::RefCell<std::collections::HashMap<RenderKey, TextImage>>,
+    /// Glyph layouts, reused by both the measure and paint passes
+    pub shaped: std::cell::RefCell<std::collections::HashMap<ShapeKey, ShapedText>>,
     last_expire: time::Instant,
 }
 
@@ -129,6 +199,7 @@ impl RenderCache {
     pub fn new() -> Self {
         Self {
             text: Default::default(),
+            shaped: Default::default(),
             last_expire: time::Instant::now(),
         }
     }
@@ -143,14 +214,172 @@ impl RenderCache {
                 v.last_used > min
             });
             log::debug!("Cache pruned from {} to {} entries", had, self.text.get_mut().len());
+            self.shaped.get_mut().retain(|_k,v| {
+                v.last_used > min
+            });
         }
         self.last_expire = as_of;
     }
 }
 
+/// A drawing operation recorded by a [Canvas]
+#[derive(Debug)]
+enum DrawOp {
+    FillRect(tiny_skia::Rect, tiny_skia::Paint<'static>, tiny_skia::Transform),
+    FillPath(tiny_skia::Path, tiny_skia::Paint<'static>, tiny_skia::FillRule, tiny_skia::Transform),
+    StrokePath(tiny_skia::Path, tiny_skia::Paint<'static>, tiny_skia::Stroke, tiny_skia::Transform),
+    DrawPixmap(i32, i32, Arc<tiny_skia::Pixmap>, tiny_skia::PixmapPaint, tiny_skia::Transform),
+}
+
+/// A list of drawing operations for one surface.
+///
+/// Items are rendered on the main thread (they need access to the [Runtime]), but they only
+/// record what they want to draw here.  The actual rasterization is done by [rasterize], which
+/// can run on another thread.
+#[derive(Debug)]
+pub struct Canvas {
+    width : u32,
+    height : u32,
+    ops : Vec<DrawOp>,
+}
+
+impl Canvas {
+    pub fn new(width : u32, height : u32) -> Self {
+        Canvas { width, height, ops : Vec::new() }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn fill_rect(&mut self, rect : tiny_skia::Rect, paint : &tiny_skia::Paint<'static>, xform : tiny_skia::Transform) {
+        self.ops.push(DrawOp::FillRect(rect, paint.clone(), xform));
+    }
+
+    pub fn fill_path(&mut self, path : &tiny_skia::Path, paint : &tiny_skia::Paint<'static>, rule : tiny_skia::FillRule, xform : tiny_skia::Transform) {
+        self.ops.push(DrawOp::FillPath(path.clone(), paint.clone(), rule, xform));
+    }
+
+    pub fn stroke_path(&mut self, path : &tiny_skia::Path, paint : &tiny_skia::Paint<'static>, stroke : &tiny_skia::Stroke, xform : tiny_skia::Transform) {
+        self.ops.push(DrawOp::StrokePath(path.clone(), paint.clone(), stroke.clone(), xform));
+    }
+
+    pub fn draw_pixmap(&mut self, x : i32, y : i32, pixmap : Arc<tiny_skia::Pixmap>, paint : &tiny_skia::PixmapPaint, xform : tiny_skia::Transform) {
+        self.ops.push(DrawOp::DrawPixmap(x, y, pixmap, *paint, xform));
+    }
+
+    /// Draw all recorded operations to the target
+    pub fn replay(&self, target : &mut PixmapMut) {
+        for op in &self.ops {
+            match op {
+                DrawOp::FillRect(rect, paint, xform) => {
+                    target.fill_rect(*rect, paint, *xform, None);
+                }
+                DrawOp::FillPath(path, paint, rule, xform) => {
+                    target.fill_path(path, paint, *rule, *xform, None);
+                }
+                DrawOp::StrokePath(path, paint, stroke, xform) => {
+                    target.stroke_path(path, paint, stroke, *xform, None);
+                }
+                DrawOp::DrawPixmap(x, y, pixmap, paint, xform) => {
+                    target.draw_pixmap(*x, *y, pixmap.as_ref().as_ref(), paint, *xform, None);
+                }
+            }
+        }
+    }
+
+    fn into_pixmap(self) -> Option<tiny_skia::Pixmap> {
+        let mut pixmap = tiny_skia::Pixmap::new(self.width, self.height)?;
+        self.replay(&mut pixmap.as_mut());
+        Some(pixmap)
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    static WORKERS : once_cell::unsync::OnceCell<mpsc::Sender<Job>> = Default::default();
+}
+
+/// Start the render worker threads, which live as long as the program
+fn start_workers() -> mpsc::Sender<Job> {
+    let (send, recv) = mpsc::channel::<Job>();
+    let recv = Arc::new(Mutex::new(recv));
+    // the calling thread renders one of the canvases itself
+    let count = std::thread::available_parallelism().map_or(1, |n| n.get().saturating_sub(1)).clamp(1, 8);
+    for i in 0..count {
+        let recv = recv.clone();
+        let rv = std::thread::Builder::new().name(format!("render-{}", i)).spawn(move || loop {
+            let job = match recv.lock() {
+                Ok(recv) => recv.recv(),
+                Err(_) => return,
+            };
+            match job {
+                // a panic drops the job's result sender, which is reported by the caller
+                Ok(job) => { let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)); }
+                Err(_) => return,
+            }
+        });
+        if let Err(e) = rv {
+            error!("Could not start a render thread: {}", e);
+        }
+    }
+    send
+}
+
+/// Rasterize a set of canvases, spreading them over a pool of worker threads if there is more
+/// than one.
+///
+/// Each bar's rendering is independent of the others, so multi-monitor setups can render all
+/// their bars in parallel.  The calling thread renders the first canvas while it waits.
+pub fn rasterize<K>(jobs : Vec<(K, Canvas)>) -> Vec<(K, Option<tiny_skia::Pixmap>)> {
+    let mut jobs = jobs.into_iter();
+    let (first_key, first) = match jobs.next() {
+        Some(job) => job,
+        None => return Vec::new(),
+    };
+    let pending : Vec<_> = jobs.map(|(k, canvas)| {
+        let (send, recv) = mpsc::channel();
+        let job : Job = Box::new(move || { let _ = send.send(canvas.into_pixmap()); });
+        WORKERS.with(|w| {
+            // if every worker has exited, render here instead
+            if let Err(mpsc::SendError(job)) = w.get_or_init(start_workers).send(job) {
+                job();
+            }
+        });
+        (k, recv)
+    }).collect();
+
+    let mut rv = Vec::with_capacity(pending.len() + 1);
+    rv.push((first_key, first.into_pixmap()));
+    rv.extend(pending.into_iter().map(|(k, recv)| (k, recv.recv().unwrap_or_else(|_| {
+        error!("Render thread panicked");
+        None
+    }))));
+    rv
+}
+
+/// A drop shadow drawn beneath text
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct TextShadow {
+    pub color : tiny_skia::Color,
+    /// Offset of the shadow from the text, in logical pixels
+    pub offset : (f32, f32),
+    /// Blur radius in logical pixels; zero for a sharp shadow
+    pub blur : f32,
+}
+
 /// State available to an [Item][crate::item::Item] render function
-pub struct Render<'a, 'c> {
-    pub canvas : &'a mut PixmapMut<'c>,
+///
+/// Rendering is done in two phases when needed: a measure phase (with `measure_only` set) which
+/// computes the size of an item without touching the canvas, and a paint phase which draws it at
+/// its final position.
+pub struct Render<'a> {
+    pub canvas : &'a mut Canvas,
 
     pub cache: &'a RenderCache,
 
@@ -158,18 +387,53 @@ pub struct Render<'a, 'c> {
     pub render_extents : (tiny_skia::Point, tiny_skia::Point),
     pub render_pos : tiny_skia::Point,
     pub render_flex : bool,
+    /// If true, only advance render_pos; do not draw anything to the canvas
+    pub measure_only : bool,
 
     pub font : &'a FontMapped,
     pub font_size : f32,
+    /// Distance between lines of text, relative to the font's line height
+    pub line_spacing : f32,
     pub font_color : tiny_skia::Color,
     pub text_stroke : Option<tiny_skia::Color>,
     pub text_stroke_size : Option<f32>,
+    pub text_shadow : Option<TextShadow>,
 
     pub align : Align,
     pub err_name : &'a str,
     pub runtime : &'a Runtime,
 }
 
+impl<'a> Render<'a> {
+    /// Run a measure pass using a copy of this context.
+    ///
+    /// Nothing is drawn; the closure should use the final render position of the context it is
+    /// passed to determine the size of what it rendered.
+    pub fn measure<R>(&self, f : impl FnOnce(&mut Render) -> R) -> R {
+        let mut canvas = Canvas::new(1, 1);
+        let mut ctx = Render {
+            canvas : &mut canvas,
+            cache : self.cache,
+            render_xform : self.render_xform,
+            render_extents : self.render_extents,
+            render_pos : self.render_pos,
+            render_flex : self.render_flex,
+            measure_only : true,
+            font : self.font,
+            font_size : self.font_size,
+            line_spacing : self.line_spacing,
+            font_color : self.font_color,
+            text_stroke : self.text_stroke,
+            text_stroke_size : self.text_stroke_size,
+            text_shadow : self.text_shadow,
+            align : self.align,
+            err_name : self.err_name,
+            runtime : self.runtime,
+        };
+        f(&mut ctx)
+    }
+}
+
 #[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Width {
     /// Some fraction (0.0-1.0) of the total width
@@ -0,0 +1,183 @@
+//! Shared timer for periodic item updates
+use crate::util::{Cell,spawn_noerr};
+use futures_util::future::{poll_fn,select};
+use std::convert::Infallible;
+use std::rc::Rc;
+use std::task;
+use std::time::{Duration,Instant};
+use tokio::sync::Notify;
+
+/// A single pending wakeup
+struct Entry {
+    id : u64,
+    /// Earliest time this entry is willing to be woken
+    earliest : Instant,
+    /// Latest time this entry is willing to be woken
+    latest : Instant,
+    fired : Rc<Cell<bool>>,
+    waker : Option<task::Waker>,
+}
+
+struct SchedulerInner {
+    entries : Cell<Vec<Entry>>,
+    next_id : Cell<u64>,
+    changed : Notify,
+    running : Cell<bool>,
+    /// True once [Scheduler::drive] has taken over from the task started by the first sleep
+    driven : Cell<bool>,
+    /// Multiplier applied to the period of every call to [Scheduler::sleep]
+    stretch : Cell<f64>,
+}
+
+impl Default for SchedulerInner {
+    fn default() -> Self {
+        SchedulerInner {
+            entries : Default::default(),
+            next_id : Default::default(),
+            changed : Default::default(),
+            running : Default::default(),
+            driven : Default::default(),
+            stretch : Cell::new(1.0),
+        }
+    }
+}
+
+/// Tracks a distinct wake deadline for every periodic item.
+///
+/// Each deadline has some slack, which allows items whose deadlines are close to each other to be
+/// woken together; this results in one redraw instead of several in a row.  When a deadline
+/// fires, only the items whose deadlines have been reached are woken.
+#[derive(Clone,Default)]
+pub struct Scheduler(Rc<SchedulerInner>);
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, fmt : &mut std::fmt::Formatter) -> std::fmt::Result {
+        let count = self.0.entries.take_in(|e| e.len());
+        write!(fmt, "Scheduler({} pending)", count)
+    }
+}
+
+impl Scheduler {
+    /// Sleep until some time in the range `[deadline, deadline + slack]`
+    pub async fn sleep_until(&self, deadline : Instant, slack : Duration) {
+        let inner = &self.0;
+        let id = inner.next_id.get();
+        inner.next_id.set(id + 1);
+        let fired = Rc::new(Cell::new(false));
+        inner.entries.take_in(|e| e.push(Entry {
+            id,
+            earliest : deadline,
+            latest : deadline + slack,
+            fired : fired.clone(),
+            waker : None,
+        }));
+        if !inner.running.replace(true) {
+            spawn_noerr(Self::run(self.0.clone()));
+        }
+        inner.changed.notify_one();
+
+        // Remove our entry if we are cancelled before firing
+        struct Guard<'a>(&'a SchedulerInner, u64);
+        impl<'a> Drop for Guard<'a> {
+            fn drop(&mut self) {
+                let id = self.1;
+                self.0.entries.take_in(|e| e.retain(|e| e.id != id));
+            }
+        }
+        let _guard = Guard(inner, id);
+
+        poll_fn(|ctx| {
+            if fired.get() {
+                return task::Poll::Ready(());
+            }
+            inner.entries.take_in(|e| {
+                for e in e.iter_mut().filter(|e| e.id == id) {
+                    e.waker = Some(ctx.waker().clone());
+                }
+            });
+            task::Poll::Pending
+        }).await
+    }
+
+    /// Sleep for the given period, waking at most `slack` later than requested.
+    ///
+    /// Both values are scaled by the current stretch factor.
+    pub async fn sleep(&self, period : Duration, slack : Duration) {
+        let stretch = self.0.stretch.get();
+        self.sleep_until(Instant::now() + period.mul_f64(stretch), slack.mul_f64(stretch)).await
+    }
+
+    /// Sleep like [Scheduler::sleep], adding a random delay of up to `jitter` to the period.
+    ///
+    /// This keeps items that were started together with the same period (such as several feeds
+    /// fetched over the network) from always waking at the same instant.
+    pub async fn sleep_jittered(&self, period : Duration, jitter : Duration, slack : Duration) {
+        self.sleep(period + jitter.mul_f64(random_fraction()), slack).await
+    }
+
+    /// Make all future periodic sleeps longer (or shorter) by the given factor
+    pub fn set_stretch(&self, factor : f64) {
+        self.0.stretch.set(factor);
+    }
+
+    /// Wake the sleeping items from the caller's event loop instead of a separate task.
+    ///
+    /// Until this is called, the first sleep starts a task that does the same.
+    pub async fn drive(&self) -> Infallible {
+        let inner = &self.0;
+        inner.running.set(true);
+        inner.driven.set(true);
+        // Stop the task, if one was started
+        inner.changed.notify_waiters();
+        loop {
+            Self::step(inner).await;
+        }
+    }
+
+    async fn run(inner : Rc<SchedulerInner>) {
+        while !inner.driven.get() {
+            Self::step(&inner).await;
+        }
+    }
+
+    /// Wait for the next deadline, then wake the items that have reached theirs
+    async fn step(inner : &SchedulerInner) {
+        let next = inner.entries.take_in(|e| e.iter().map(|e| e.latest).min());
+        match next {
+            None => inner.changed.notified().await,
+            Some(next) => {
+                let sleep = tokio::time::sleep_until(next.into());
+                let changed = inner.changed.notified();
+                futures_util::pin_mut!(sleep, changed);
+                select(sleep, changed).await;
+            }
+        }
+
+        // Wake everything that has reached its earliest deadline, not just the one that
+        // caused us to wake up.
+        let now = Instant::now();
+        let fired : Vec<_> = inner.entries.take_in(|entries| {
+            let mut fired = Vec::new();
+            entries.retain_mut(|e| {
+                if e.earliest <= now {
+                    e.fired.set(true);
+                    fired.extend(e.waker.take());
+                    false
+                } else {
+                    true
+                }
+            });
+            fired
+        });
+        for waker in fired {
+            waker.wake();
+        }
+    }
+}
+
+/// A random number in `[0, 1)`, taken from the per-process random keys of the standard hasher
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher,Hasher};
+    let bits = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,446 @@
+//! Named events at the same time each day (or on some days of the week)
+use crate::data::Value;
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::timer::schedule_tick;
+use crate::util::{self,Cell,spawn_handle,toml_to_f64};
+use chrono::{DateTime,Datelike,Duration as Span,Local,NaiveDate,NaiveDateTime,NaiveTime,TimeZone,Timelike,Utc};
+use chrono_tz::Tz;
+use futures_util::future::RemoteHandle;
+use log::{debug,error,info};
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+
+const EVERY_DAY : u8 = 0x7f;
+static DAY_NAMES : [&str; 7] = ["mo", "tu", "we", "th", "fr", "sa", "su"];
+
+#[derive(Debug,Clone)]
+struct Event {
+    name : Box<str>,
+    time : NaiveTime,
+    /// Days of the week it happens on; bit 0 is Monday
+    days : u8,
+    /// Set for an event that happens only once
+    date : Option<NaiveDate>,
+    /// The first and last dates of a repeating event, if it has them
+    first : Option<NaiveDate>,
+    last : Option<NaiveDate>,
+    /// Dates a repeating event is skipped on
+    except : Vec<NaiveDate>,
+    /// None for the local time zone
+    zone : Option<Tz>,
+}
+
+impl Event {
+    /// When the event happens on the given date (in its own time zone), if it does
+    fn on(&self, date : NaiveDate) -> Option<DateTime<Utc>> {
+        match self.date {
+            Some(d) if d != date => return None,
+            Some(_) => {}
+            None if self.days & (1 << date.weekday().num_days_from_monday()) == 0 => return None,
+            None if self.first.is_some_and(|d| date < d) || self.last.is_some_and(|d| date > d) => return None,
+            None if self.except.contains(&date) => return None,
+            None => {}
+        }
+        let t = date.and_time(self.time);
+        match self.zone {
+            None => Local.from_local_datetime(&t).earliest().map(|t| t.with_timezone(&Utc)),
+            Some(tz) => tz.from_local_datetime(&t).earliest().map(|t| t.with_timezone(&Utc)),
+        }
+    }
+}
+
+/// Parse a day of the week, such as "mon" or "Monday", as its bit
+fn day_bit(name : &str) -> Option<u8> {
+    let name = name.trim().to_lowercase();
+    DAY_NAMES.iter().position(|d| name.starts_with(d)).map(|i| 1 << i)
+}
+
+fn event_from_toml(value : &toml::Value) -> Option<Event> {
+    let name = value.get("name").and_then(|v| v.as_str()).unwrap_or("");
+    let time = value.get("time").and_then(|v| v.as_str())?;
+    let time = match NaiveTime::parse_from_str(time, "%H:%M").or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S")) {
+        Ok(time) => time,
+        Err(_) => {
+            error!("Invalid time '{}' for '{}' (expected HH:MM)", time, name);
+            return None;
+        }
+    };
+    let days = match value.get("days") {
+        None => EVERY_DAY,
+        Some(toml::Value::String(s)) => match &**s {
+            "daily" => EVERY_DAY,
+            "weekdays" => 0x1f,
+            "weekends" => 0x60,
+            day => day_bit(day).unwrap_or_else(|| {
+                error!("Invalid day '{}' for '{}'", day, name);
+                0
+            }),
+        },
+        Some(toml::Value::Array(a)) => a.iter().filter_map(|v| v.as_str()).filter_map(day_bit).fold(0, |a, b| a | b),
+        Some(_) => 0,
+    };
+    Some(Event { name : name.into(), time, days, date : None, first : None, last : None, except : Vec::new(), zone : None })
+}
+
+/// Undo the escaping of an iCalendar text value
+fn unescape(value : &str) -> String {
+    let mut rv = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => rv.push(' '),
+                Some(c) => rv.push(c),
+                None => {}
+            },
+            c => rv.push(c),
+        }
+    }
+    rv
+}
+
+/// Parse the parameters and value of a DTSTART line; all-day events are skipped
+fn parse_start<'a>(params : impl Iterator<Item=&'a str>, value : &str) -> Option<(NaiveDateTime, Option<Tz>)> {
+    let mut zone = None;
+    for param in params {
+        match param.split_once('=') {
+            Some(("VALUE", "DATE")) => return None,
+            Some(("TZID", tz)) => match tz.trim_matches('"').parse::<Tz>() {
+                Ok(tz) => zone = Some(tz),
+                Err(e) => info!("Unknown time zone in calendar: {}", e),
+            },
+            _ => {}
+        }
+    }
+    let (value, utc) = match value.strip_suffix('Z') {
+        Some(v) => (v, true),
+        None => (value, false),
+    };
+    let t = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+    Some((t, if utc { Some(Tz::UTC) } else { zone }))
+}
+
+/// Read the events of an iCalendar file.  Only daily and weekly repeats are supported.
+fn parse_ical(text : &str) -> Vec<Event> {
+    // Long lines are folded by starting the continuation with a space or tab
+    let mut lines : Vec<String> = Vec::new();
+    for line in text.lines().map(|l| l.trim_end_matches('\r')) {
+        match (line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    let mut events = Vec::new();
+    let mut in_event = false;
+    // components such as VALARM inside the event
+    let mut nested = 0;
+    let (mut summary, mut start, mut rule) = (None, None, None);
+    let mut except = Vec::new();
+    for line in &lines {
+        let (head, value) = match line.split_once(':') {
+            Some(split) => split,
+            None => continue,
+        };
+        let mut params = head.split(';');
+        match (params.next().unwrap_or(""), value) {
+            ("BEGIN", "VEVENT") => {
+                in_event = true;
+                summary = None;
+                start = None;
+                rule = None;
+                except.clear();
+            }
+            ("BEGIN", _) if in_event => nested += 1,
+            ("END", "VEVENT") => {
+                in_event = false;
+                if let Some((start, zone)) = start.take() {
+                    let name = summary.take().unwrap_or_default();
+                    events.extend(ical_event(name, start, zone, rule.take(), &except));
+                }
+            }
+            ("END", _) if in_event => nested -= 1,
+            _ if !in_event || nested > 0 => {}
+            ("SUMMARY", value) => summary = Some(unescape(value)),
+            ("DTSTART", value) => start = parse_start(params, value),
+            ("RRULE", value) => rule = Some(value.to_owned()),
+            ("EXDATE", value) => except.extend(value.split(',').map(str::to_owned)),
+            _ => {}
+        }
+    }
+    events
+}
+
+/// The date and time (if it has one) of an UNTIL or EXDATE value, in the event's time zone
+fn local_date(value : &str, zone : Option<Tz>) -> Option<(NaiveDate, Option<NaiveTime>)> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Some((date, None));
+    }
+    let t = match value.strip_suffix('Z') {
+        Some(value) => {
+            let t = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+            match zone {
+                None => Local.from_utc_datetime(&t).naive_local(),
+                Some(tz) => tz.from_utc_datetime(&t).naive_local(),
+            }
+        }
+        None => NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?,
+    };
+    Some((t.date(), Some(t.time())))
+}
+
+/// The date of the `count`th day (from 1) on or after `start` that is one of `days`
+fn nth_day(start : NaiveDate, days : u8, count : u32) -> Option<NaiveDate> {
+    let per_week = days.count_ones();
+    if count == 0 || per_week == 0 {
+        return None;
+    }
+    // Any 7 days in a row hold per_week of the days, so skip to the last week
+    let weeks = (count - 1) / per_week;
+    let mut left = count - weeks * per_week;
+    let mut date = start.checked_add_signed(Span::weeks(weeks.into()))?;
+    loop {
+        if days & (1 << date.weekday().num_days_from_monday()) != 0 {
+            left -= 1;
+            if left == 0 {
+                return Some(date);
+            }
+        }
+        date = date.succ_opt()?;
+    }
+}
+
+fn ical_event(name : String, start : NaiveDateTime, zone : Option<Tz>, rule : Option<String>, except : &[String]) -> Option<Event> {
+    let mut event = Event {
+        name : name.into(),
+        time : start.time(),
+        days : EVERY_DAY,
+        date : None,
+        first : Some(start.date()),
+        last : None,
+        except : except.iter()
+            .filter_map(|v| local_date(v, zone))
+            .map(|(date, _)| date)
+            .collect(),
+        zone,
+    };
+    let rule = match rule {
+        Some(rule) => rule,
+        None => {
+            event.date = Some(start.date());
+            return Some(event);
+        }
+    };
+    let part = |key : &str| rule.split(';').find_map(|p| p.strip_prefix(key)?.strip_prefix('='));
+    if part("INTERVAL").is_some_and(|i| i != "1") {
+        debug!("Skipping '{}': repeats with an interval", event.name);
+        return None;
+    }
+    match part("FREQ") {
+        Some("DAILY") => {}
+        Some("WEEKLY") => {
+            event.days = match part("BYDAY") {
+                Some(days) => days.split(',').filter_map(day_bit).fold(0, |a, b| a | b),
+                None => 1 << start.weekday().num_days_from_monday(),
+            };
+        }
+        _ => {
+            debug!("Skipping '{}': unsupported repeat {}", event.name, rule);
+            return None;
+        }
+    }
+    if let Some(until) = part("UNTIL") {
+        let (date, time) = match local_date(until, zone) {
+            Some(until) => until,
+            None => {
+                debug!("Skipping '{}': invalid end {}", event.name, until);
+                return None;
+            }
+        };
+        // The end is inclusive, but an end time before the event's time excludes that day
+        event.last = match time {
+            Some(time) if time < event.time => date.pred_opt(),
+            _ => Some(date),
+        };
+    }
+    if let Some(count) = part("COUNT") {
+        let last = match count.parse() {
+            Ok(count) => nth_day(start.date(), event.days, count),
+            Err(_) => {
+                debug!("Skipping '{}': invalid count {}", event.name, count);
+                return None;
+            }
+        };
+        let last = last?;
+        event.last = Some(event.last.map_or(last, |d| d.min(last)));
+    }
+    Some(event)
+}
+
+#[derive(Debug)]
+struct IcalFile {
+    path : PathBuf,
+    events : Cell<Vec<Event>>,
+    interested : Cell<NotifierList>,
+}
+
+impl IcalFile {
+    fn load(&self) {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => self.events.set(parse_ical(&text)),
+            Err(e) => {
+                error!("Could not read {}: {}", self.path.display(), e);
+                self.events.set(Vec::new());
+            }
+        }
+    }
+}
+
+/// The "schedule" module
+#[derive(Debug)]
+pub struct Schedule {
+    events : Vec<Event>,
+    ical : Option<Rc<IcalFile>>,
+    format : Box<str>,
+    /// How long an event stays current after it starts
+    active : Span,
+    tick : Cell<Option<RemoteHandle<()>>>,
+    watch : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Schedule {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let events = config.get("events").and_then(|v| v.as_array()).map_or(&[][..], |a| &a[..])
+            .iter().filter_map(event_from_toml).collect();
+        let ical = config.get("ical").and_then(|v| v.as_str()).map(|path| {
+            let file = IcalFile {
+                path : path.into(),
+                events : Cell::default(),
+                interested : Cell::default(),
+            };
+            file.load();
+            Rc::new(file)
+        });
+        Schedule {
+            events,
+            ical,
+            format : config.get("format").and_then(|v| v.as_str()).unwrap_or("%H:%M").into(),
+            active : Span::seconds((toml_to_f64(config.get("active")).unwrap_or(10.0) * 60.0) as i64),
+            tick : Cell::default(),
+            watch : Cell::default(),
+        }
+    }
+
+    /// Every occurrence from two days ago to a week from now, in order
+    fn occurrences(&self, now : DateTime<Utc>) -> Vec<(DateTime<Utc>, Box<str>)> {
+        let today = now.with_timezone(&Local).naive_local().date();
+        let ical = self.ical.as_ref().map_or_else(Vec::new, |f| f.events.take_in(|e| e.clone()));
+        let mut rv = Vec::new();
+        for event in self.events.iter().chain(&ical) {
+            match event.date {
+                Some(date) => rv.extend(event.on(date).map(|t| (t, event.name.clone()))),
+                None => rv.extend((-2..=8).filter_map(|d| event.on(today + Span::days(d))).map(|t| (t, event.name.clone()))),
+            }
+        }
+        rv.sort();
+        rv
+    }
+}
+
+impl Source for Schedule {
+    fn init(&self, _name : &str, _rt : &Runtime) {
+        if let Some(file) = &self.ical {
+            let f = file.clone();
+            self.watch.set(Some(spawn_handle("Schedule file watch", util::watch_file(file.path.clone(), move || {
+                f.load();
+                f.interested.take().notify_data("schedule");
+            }))));
+        }
+    }
+
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        if let Some(file) = &self.ical {
+            file.interested.take_in(|i| i.add(rt));
+        }
+        let now = Utc::now();
+        let all = self.occurrences(now);
+        let split = all.partition_point(|(t, _)| *t <= now);
+        let previous = split.checked_sub(1).map(|i| &all[i]);
+        let next = all.get(split);
+        let current = previous.filter(|(t, _)| now - *t < self.active);
+
+        // Redraw when the countdown's minute changes, or the current event ends
+        let mut wake = now + Span::seconds(60 - now.second() as i64);
+        if let Some((t, _)) = current {
+            wake = wake.min(*t + self.active);
+        }
+        schedule_tick(&self.tick, (wake - now).to_std().ok().map(|d| d + Duration::from_millis(1)), rt);
+
+        let time = |t : &DateTime<Utc>| Value::Owned(t.with_timezone(&Local).format(&self.format).to_string());
+        let left = next.map(|(t, _)| (*t - now).num_seconds().max(0) as u64);
+        match key {
+            "" | "text" => match (next, left) {
+                (Some((_, name)), Some(secs)) => Value::Owned(format!("{} in {}", name, format_countdown(secs))),
+                _ => Value::Null,
+            },
+            "next" => next.map_or(Value::Null, |(_, name)| Value::Owned(name.to_string())),
+            "next-time" => next.map_or(Value::Null, |(t, _)| time(t)),
+            "countdown" => left.map_or(Value::Null, |s| Value::Owned(format_countdown(s))),
+            "seconds" => left.map_or(Value::Null, |s| Value::Float(s as f64)),
+            "previous" => previous.map_or(Value::Null, |(_, name)| Value::Owned(name.to_string())),
+            "previous-time" => previous.map_or(Value::Null, |(t, _)| time(t)),
+            "current" => current.map_or(Value::Null, |(_, name)| Value::Owned(name.to_string())),
+            "today" => {
+                let today = now.with_timezone(&Local).date();
+                let lines : Vec<_> = all.iter()
+                    .filter(|(t, _)| t.with_timezone(&Local).date() == today)
+                    .map(|(t, name)| format!("{} {}", t.with_timezone(&Local).format(&self.format), name))
+                    .collect();
+                Value::Owned(lines.join("\n"))
+            }
+            _ => Value::Null,
+        }
+    }
+}
+
+/// Minutes left, rounded up, as `H:MM`
+fn format_countdown(secs : u64) -> String {
+    let minutes = secs.div_ceil(60);
+    format!("{}:{:02}", minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(lines : &str) -> Event {
+        let text = format!("BEGIN:VEVENT\nSUMMARY:test\nDTSTART:20250106T090000Z\n{}\nEND:VEVENT\n", lines);
+        parse_ical(&text).pop().unwrap()
+    }
+
+    fn dates(event : &Event) -> Vec<u32> {
+        (1..=31).filter(|&d| event.on(NaiveDate::from_ymd_opt(2025, 1, d).unwrap()).is_some()).collect()
+    }
+
+    #[test]
+    fn until() {
+        assert_eq!(dates(&event("RRULE:FREQ=DAILY;UNTIL=20250109T090000Z")), [6, 7, 8, 9]);
+        assert_eq!(dates(&event("RRULE:FREQ=DAILY;UNTIL=20250109T080000Z")), [6, 7, 8]);
+        assert_eq!(dates(&event("RRULE:FREQ=WEEKLY;UNTIL=20250120")), [6, 13, 20]);
+    }
+
+    #[test]
+    fn count() {
+        assert_eq!(dates(&event("RRULE:FREQ=DAILY;COUNT=3")), [6, 7, 8]);
+        assert_eq!(dates(&event("RRULE:FREQ=WEEKLY;BYDAY=MO,WE;COUNT=5")), [6, 8, 13, 15, 20]);
+        assert_eq!(dates(&event("RRULE:FREQ=WEEKLY;BYDAY=MO,WE;COUNT=3;UNTIL=20250107")), [6]);
+    }
+
+    #[test]
+    fn exdate() {
+        let event = event("RRULE:FREQ=DAILY;COUNT=5\nEXDATE:20250107T090000Z,20250109T090000Z\nEXDATE;VALUE=DATE:20250110");
+        assert_eq!(dates(&event), [6, 8]);
+    }
+}
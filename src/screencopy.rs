@@ -0,0 +1,219 @@
+//! Workspace previews captured with wlr-screencopy
+use crate::font::render_font;
+use crate::render::Render;
+use crate::state::{NotifierList,Runtime};
+use crate::util;
+use log::{debug,error};
+use smithay_client_toolkit::output::with_output_info;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::sync::Arc;
+use tiny_skia::{Pixmap,PixmapPaint,Transform};
+use wayland_client::{Attached,Main};
+use wayland_client::protocol::wl_buffer::WlBuffer;
+use wayland_client::protocol::wl_shm::{Format,WlShm};
+use wayland_client::protocol::wl_shm_pool::WlShmPool;
+use wayland_protocols::wlr::unstable::screencopy::v1::client::zwlr_screencopy_frame_v1::Flags;
+use wayland_protocols::wlr::unstable::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
+
+/// Width of the stored previews, in pixels
+const THUMB_WIDTH : f32 = 320.0;
+
+#[derive(Default)]
+struct Previews {
+    /// The screencopy manager, once bound (None inside if the compositor lacks it)
+    manager : Option<Option<Main<ZwlrScreencopyManagerV1>>>,
+    images : HashMap<Box<str>, Arc<Pixmap>>,
+    interested : NotifierList,
+}
+
+thread_local! {
+    static PREVIEWS : RefCell<Previews> = Default::default();
+}
+
+/// The shared memory buffer a frame is copied into
+struct ShmTarget {
+    file : File,
+    pool : Main<WlShmPool>,
+    buffer : Main<WlBuffer>,
+    format : Format,
+    size : (u32, u32),
+    stride : u32,
+}
+
+impl ShmTarget {
+    fn new(shm : &Attached<WlShm>, format : Format, width : u32, height : u32, stride : u32) -> std::io::Result<Self> {
+        let len = stride * height;
+        let file = util::memfd(c"rwaybar-screencopy")?;
+        file.set_len(len as u64)?;
+        let pool = shm.create_pool(file.as_raw_fd(), len as i32);
+        let buffer = pool.create_buffer(0, width as i32, height as i32, stride as i32, format);
+        buffer.quick_assign(|_, _, _| {});
+        Ok(ShmTarget { file, pool, buffer, format, size : (width, height), stride })
+    }
+
+    /// Read the copied frame and scale it down to a preview
+    fn thumbnail(&self, y_invert : bool) -> Result<Pixmap, Box<dyn Error>> {
+        let bgr = match self.format {
+            Format::Argb8888 | Format::Xrgb8888 => true,
+            Format::Abgr8888 | Format::Xbgr8888 => false,
+            format => return Err(format!("unsupported format {:?}", format).into()),
+        };
+        let (width, height) = self.size;
+        let mut data = vec![0; (self.stride * height) as usize];
+        self.file.read_exact_at(&mut data, 0)?;
+
+        let mut full = Pixmap::new(width, height).ok_or("invalid frame size")?;
+        for (y, row) in full.data_mut().chunks_exact_mut(width as usize * 4).enumerate() {
+            let src_y = if y_invert { height as usize - 1 - y } else { y };
+            let src = &data[src_y * self.stride as usize..][..width as usize * 4];
+            for (d, s) in row.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+                if bgr {
+                    d[..3].copy_from_slice(&[s[2], s[1], s[0]]);
+                } else {
+                    d[..3].copy_from_slice(&s[..3]);
+                }
+                // the screen is opaque, whatever the alpha channel says
+                d[3] = 255;
+            }
+        }
+
+        let scale = (THUMB_WIDTH / width as f32).min(1.0);
+        let tw = ((width as f32 * scale).round() as u32).max(1);
+        let th = ((height as f32 * scale).round() as u32).max(1);
+        let mut thumb = Pixmap::new(tw, th).ok_or("invalid preview size")?;
+        let paint = PixmapPaint {
+            quality : tiny_skia::FilterQuality::Bilinear,
+            ..Default::default()
+        };
+        thumb.draw_pixmap(0, 0, full.as_ref(), &paint, Transform::from_scale(scale, scale), None);
+        Ok(thumb)
+    }
+
+    fn destroy(self) {
+        self.buffer.destroy();
+        self.pool.destroy();
+    }
+}
+
+/// Capture the named output and store it as the preview for `key`
+pub fn capture(rt : &Runtime, output_name : &str, key : &str) {
+    let wayland = match &rt.wayland {
+        Some(wayland) => wayland,
+        None => return,
+    };
+    let output = wayland.env.get_all_outputs().into_iter().find(|output| {
+        with_output_info(output, |oi| !oi.obsolete && oi.name == output_name).unwrap_or(false)
+    });
+    let output = match output {
+        Some(output) => output,
+        None => return,
+    };
+    let manager = PREVIEWS.with(|cell| {
+        cell.borrow_mut().manager.get_or_insert_with(|| {
+            let manager = wayland.env.manager.instantiate_range::<ZwlrScreencopyManagerV1>(1, 2).ok();
+            if manager.is_none() {
+                error!("Workspace previews not available, no zwlr_screencopy_manager_v1 found");
+            }
+            manager
+        }).clone()
+    });
+    let manager = match manager {
+        Some(manager) => manager,
+        None => return,
+    };
+
+    let shm : Attached<WlShm> = wayland.env.require_global();
+    let key : Box<str> = key.into();
+    let mut target = None;
+    let mut y_invert = false;
+    let frame = manager.capture_output(0, &output);
+    frame.quick_assign(move |frame, event, _data| {
+        use wayland_protocols::wlr::unstable::screencopy::v1::client::zwlr_screencopy_frame_v1::Event;
+        match event {
+            Event::Buffer { format, width, height, stride } if target.is_none() => {
+                match ShmTarget::new(&shm, format, width, height, stride) {
+                    Ok(t) => {
+                        frame.copy(&t.buffer);
+                        target = Some(t);
+                    }
+                    Err(e) => {
+                        error!("Could not create a buffer for a workspace preview: {}", e);
+                        frame.destroy();
+                    }
+                }
+            }
+            Event::Flags { flags } => {
+                y_invert = flags.contains(Flags::YInvert);
+            }
+            Event::Ready { .. } => {
+                if let Some(t) = target.take() {
+                    match t.thumbnail(y_invert) {
+                        Ok(image) => PREVIEWS.with(|cell| {
+                            let mut previews = cell.borrow_mut();
+                            previews.images.insert(key.clone(), Arc::new(image));
+                            previews.interested.notify_data("preview");
+                        }),
+                        Err(e) => error!("Could not read a workspace preview: {}", e),
+                    }
+                    t.destroy();
+                }
+                frame.destroy();
+            }
+            Event::Failed => {
+                debug!("Capturing a preview of {} failed", key);
+                if let Some(t) = target.take() {
+                    t.destroy();
+                }
+                frame.destroy();
+            }
+            _ => {}
+        }
+    });
+    // the request is sent when the next frame flushes the connection
+    rt.request_draw();
+}
+
+/// A popup showing the stored preview of a workspace
+#[derive(Debug,Clone,PartialEq)]
+pub struct PreviewPopup {
+    key : Rc<str>,
+}
+
+impl PreviewPopup {
+    pub fn new(key : &str) -> Self {
+        PreviewPopup { key : key.into() }
+    }
+
+    pub fn render(&mut self, ctx : &mut Render) -> Vec<tiny_skia::Rect> {
+        let image = PREVIEWS.with(|cell| {
+            let mut previews = cell.borrow_mut();
+            previews.interested.add(ctx.runtime);
+            previews.images.get(&*self.key).cloned()
+        });
+        match image {
+            Some(image) => {
+                let (w, h) = (image.width() as f32, image.height() as f32);
+                if !ctx.measure_only {
+                    let paint = PixmapPaint {
+                        quality : tiny_skia::FilterQuality::Bilinear,
+                        ..Default::default()
+                    };
+                    let xform = ctx.render_xform.pre_translate(2.0, 2.0);
+                    ctx.canvas.draw_pixmap(0, 0, image, &paint, xform);
+                }
+                ctx.render_pos = tiny_skia::Point { x: w + 4.0, y: h + 4.0 };
+            }
+            None => {
+                let (w, h) = render_font(ctx, (2.0, 2.0), &format!("No preview of {} yet", self.key), false);
+                ctx.render_pos = tiny_skia::Point { x: w + 4.0, y: h + 4.0 };
+            }
+        }
+        Vec::new()
+    }
+}
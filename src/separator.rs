@@ -0,0 +1,158 @@
+//! Powerline-style transitions between the backgrounds of adjacent items
+use crate::data::Value;
+use crate::item::Formatting;
+use crate::render::Render;
+use crate::state::Runtime;
+use crate::util::Cell;
+use log::error;
+use tiny_skia::{Color,FillRule,Paint,PathBuilder,Rect,Shader};
+
+/// Bezier control point distance for approximating a quarter ellipse
+const KAPPA : f32 = 0.5523;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Shape {
+    ArrowRight,
+    ArrowLeft,
+    SlantRight,
+    SlantLeft,
+    RoundRight,
+    RoundLeft,
+}
+
+impl Shape {
+    fn from_str(v : &str) -> Option<Self> {
+        match v {
+            "arrow" | "arrow-right" => Some(Shape::ArrowRight),
+            "arrow-left" => Some(Shape::ArrowLeft),
+            "slant" | "slant-right" => Some(Shape::SlantRight),
+            "slant-left" => Some(Shape::SlantLeft),
+            "round" | "round-right" => Some(Shape::RoundRight),
+            "round-left" => Some(Shape::RoundLeft),
+            _ => None,
+        }
+    }
+
+    /// The region covered by the "from" color; the rest of the box uses the "to" color
+    fn path(self, x : f32, y : f32, w : f32, h : f32) -> Option<tiny_skia::Path> {
+        let mut pb = PathBuilder::new();
+        let (r, b, mid) = (x + w, y + h, y + h / 2.0);
+        match self {
+            Shape::ArrowRight => {
+                pb.move_to(x, y);
+                pb.line_to(r, mid);
+                pb.line_to(x, b);
+            }
+            Shape::ArrowLeft => {
+                pb.move_to(x, y);
+                pb.line_to(r, y);
+                pb.line_to(x, mid);
+                pb.line_to(r, b);
+                pb.line_to(x, b);
+            }
+            Shape::SlantRight => {
+                pb.move_to(x, y);
+                pb.line_to(r, y);
+                pb.line_to(x, b);
+            }
+            Shape::SlantLeft => {
+                pb.move_to(x, y);
+                pb.line_to(r, b);
+                pb.line_to(x, b);
+            }
+            Shape::RoundRight => {
+                pb.move_to(x, y);
+                pb.cubic_to(x + w * KAPPA, y, r, mid - h / 2.0 * KAPPA, r, mid);
+                pb.cubic_to(r, mid + h / 2.0 * KAPPA, x + w * KAPPA, b, x, b);
+            }
+            Shape::RoundLeft => {
+                pb.move_to(x, y);
+                pb.line_to(r, y);
+                pb.cubic_to(r - w * KAPPA, y, x, mid - h / 2.0 * KAPPA, x, mid);
+                pb.cubic_to(x, mid + h / 2.0 * KAPPA, r - w * KAPPA, b, r, b);
+                pb.line_to(x, b);
+            }
+        }
+        pb.close();
+        pb.finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Separator {
+    shape : Shape,
+    width : Box<str>,
+    from : Box<str>,
+    to : Box<str>,
+    /// Background colors of the previous and next items in the enclosing group
+    neighbors : Cell<(Option<Color>, Option<Color>)>,
+}
+
+impl Separator {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let shape = value.get("shape").and_then(|v| v.as_str()).unwrap_or("arrow");
+        let shape = Shape::from_str(shape).unwrap_or_else(|| {
+            error!("Invalid separator shape '{}', using arrow", shape);
+            Shape::ArrowRight
+        });
+        let width = value.get("width").and_then(|v| v.as_str()).unwrap_or("").into();
+        let from = value.get("from").and_then(|v| v.as_str()).unwrap_or("").into();
+        let to = value.get("to").and_then(|v| v.as_str()).unwrap_or("").into();
+        Separator { shape, width, from, to, neighbors : Cell::default() }
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        match key {
+            "width" => f(rt.format_or(&self.width, name)),
+            "from" => f(rt.format_or(&self.from, name)),
+            "to" => f(rt.format_or(&self.to, name)),
+            _ => f(Value::Null),
+        }
+    }
+
+    /// Record the background colors of the items on either side of this one
+    pub fn set_neighbors(&self, prev : Option<Color>, next : Option<Color>) {
+        self.neighbors.set((prev, next));
+    }
+
+    fn color(&self, fmt : &str, neighbor : Option<Color>, rt : &Runtime) -> Option<Color> {
+        if fmt.is_empty() {
+            return neighbor;
+        }
+        let color = rt.format_or(fmt, "separator").into_text();
+        if color.is_empty() {
+            return neighbor;
+        }
+        Formatting::parse_rgba(Some(color), None)
+    }
+
+    pub fn render(&self, ctx : &mut Render) {
+        let rt = ctx.runtime;
+        let height = ctx.render_extents.1.y - ctx.render_pos.y;
+        if height <= 0.0 {
+            return;
+        }
+        let width = rt.format_or(&self.width, ctx.err_name).parse_f32()
+            .unwrap_or((height / 2.0).ceil());
+        let (prev, next) = self.neighbors.get();
+        let from = self.color(&self.from, prev, rt);
+        let to = self.color(&self.to, next, rt);
+        let (x, y) = (ctx.render_pos.x, ctx.render_pos.y);
+
+        if !ctx.measure_only {
+            let paint = |color| Paint {
+                shader : Shader::SolidColor(color),
+                anti_alias : true,
+                ..Default::default()
+            };
+            if let (Some(to), Some(rect)) = (to, Rect::from_xywh(x, y, width, height)) {
+                ctx.canvas.fill_rect(rect, &paint(to), ctx.render_xform);
+            }
+            if let (Some(from), Some(path)) = (from, self.shape.path(x, y, width, height)) {
+                ctx.canvas.fill_path(&path, &paint(from), FillRule::Winding, ctx.render_xform);
+            }
+        }
+        ctx.render_pos.x += width;
+        ctx.render_pos.y += height;
+    }
+}
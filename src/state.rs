@@ -1,23 +1,43 @@
+//! Global state: the loaded configuration, items, and bars
 use log::{debug,info,warn,error};
-use futures_util::future::poll_fn;
+use futures_channel::mpsc;
+use futures_util::{pin_mut,select,StreamExt};
+use futures_util::future::{poll_fn,FutureExt};
+use futures_util::future::RemoteHandle;
 use smithay_client_toolkit::output::with_output_info;
 use smithay_client_toolkit::output::OutputInfo;
 use smithay_client_toolkit::output::OutputStatusListener;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap,HashSet};
+use std::convert::Infallible;
 use std::error::Error;
-use std::time::Instant;
+use std::path::PathBuf;
+use std::time::{Duration,Instant};
 use std::rc::{self,Rc};
 use std::task;
+use tokio::signal::unix::{signal,SignalKind};
+use tokio::sync::Notify;
 use wayland_client::protocol::wl_output::WlOutput;
 
 use crate::bar::Bar;
+use crate::cli;
 use crate::data::{Module,IterationItem,Value};
+use crate::event::Action;
+use crate::filter;
 use crate::font::FontMapped;
+use crate::history;
+use crate::hyprland;
+use crate::ipc;
 use crate::item::*;
-use crate::render::{Renderer,RenderCache};
-use crate::util::{Cell,spawn,spawn_noerr};
-use crate::wayland::WaylandClient;
+use crate::locale;
+use crate::logging;
+use crate::metrics;
+use crate::render::{self,Renderer,RenderCache};
+use crate::sched::Scheduler;
+use crate::sway;
+use crate::timing;
+use crate::util::{self,Cell,spawn_handle,spawn_noerr,toml_to_f64};
+use crate::wayland::{self,WaylandClient};
 
 #[derive(Debug,Clone)]
 struct Notifier {
@@ -41,6 +61,7 @@ struct NotifierInner {
 impl Notifier {
     pub fn notify_data(&self, who : &str) {
         debug!("{} triggered refresh", who);
+        timing::trigger(who);
         self.inner.state.set(NotifyState::NewData);
         self.inner.waker.take().map(|w| w.wake());
     }
@@ -53,15 +74,16 @@ impl Notifier {
     }
 }
 
+/// The bars to redraw when a data source changes
 #[derive(Debug,Default,Clone)]
 pub struct NotifierList(Option<Notifier>);
 
 impl NotifierList {
-    pub fn active(rt : &Runtime) -> Self {
+    pub(crate) fn active(rt : &Runtime) -> Self {
         NotifierList(Some(Notifier { inner : rt.notify.inner.clone() }))
     }
 
-    pub fn data_update_seq(&self) -> u64 {
+    pub(crate) fn data_update_seq(&self) -> u64 {
         self.0.as_ref().map(|n| n.inner.data_update_seq.get()).unwrap_or_default()
     }
 
@@ -72,6 +94,7 @@ impl NotifierList {
         self.0 = Some(Notifier { inner : rt.notify.inner.clone() });
     }
 
+    /// Take the bars of `other` if this list has none
     pub fn merge(&mut self, other: &Self) {
         if self.0.is_none() {
             self.clone_from(other);
@@ -85,20 +108,388 @@ impl NotifierList {
     }
 }
 
+/// Find the configuration file when none was given on the command line.
+///
+/// Each XDG configuration directory (`$XDG_CONFIG_HOME`, then `$XDG_CONFIG_DIRS`) is checked for
+/// `rwaybar/rwaybar.toml` and then `rwaybar.toml`, and finally the current directory is checked.
+fn find_config(xdg : &xdg::BaseDirectories) -> Option<PathBuf> {
+    let mut dirs = vec![xdg.get_config_home()];
+    dirs.extend(xdg.get_config_dirs());
+    dirs.into_iter()
+        .flat_map(|dir| [dir.join("rwaybar/rwaybar.toml"), dir.join("rwaybar.toml")])
+        .chain(Some(PathBuf::from("rwaybar.toml")))
+        .find(|path| path.is_file())
+}
+
+/// Icons may use other icons in their text, up to this depth
+const MAX_ICON_DEPTH : u32 = 8;
+
+/// An entry in the `[icons]` table
+#[derive(Debug)]
+pub(crate) struct Icon {
+    text : Box<str>,
+    /// The font used for the text, in items that use markup
+    font : Option<Box<str>>,
+}
+
 /// Common state available during rendering operations
 pub struct Runtime {
-    pub xdg : xdg::BaseDirectories,
-    pub fonts : Vec<FontMapped>,
-    pub items : HashMap<String, Rc<Item>>,
-    pub cache: RenderCache,
-    pub wayland : WaylandClient,
+    pub(crate) xdg : xdg::BaseDirectories,
+    /// The configuration file that was most recently loaded
+    pub(crate) config_path : Option<PathBuf>,
+    pub(crate) fonts : Vec<FontMapped>,
+    pub(crate) items : HashMap<String, Rc<Item>>,
+    pub(crate) cache: RenderCache,
+    /// Absent when drawing without a compositor connection
+    pub(crate) wayland : Option<WaylandClient>,
+    pub(crate) scheduler : Scheduler,
+    /// Marker drawn on items that logged errors, if enabled
+    pub(crate) error_badge : Option<ErrorBadge>,
+    /// The modifier keys held down, as reported to a focused bar (see [crate::event::MOD_SHIFT])
+    pub(crate) modifiers : u32,
+    /// Named actions from the `[actions]` table, run by the `action` control socket command
+    pub(crate) actions : HashMap<String, Action>,
+    /// Each `{icon:NAME}` from the `[icons]` table
+    pub(crate) icons : HashMap<Box<str>, Icon>,
+    /// Run commands started by actions in their own systemd scope, from `exec-scope`
+    pub(crate) exec_scope : bool,
     item_var : Rc<Item>,
+    /// True while expanding the text of an item that uses markup
+    markup : Cell<bool>,
+    /// The recorded values of the `freeze-on-hover` item being drawn
+    freeze : Cell<Option<FreezeCursor>>,
     notify : Notifier,
     read_depth : Cell<u8>,
+    popup_toggle : Cell<bool>,
+    item_popup : Cell<Option<String>>,
+    max_fps : Option<f64>,
+    battery_multiplier : f64,
+    on_battery : Cell<bool>,
+    history : Cell<HashMap<Box<str>, history::History>>,
 }
 
 impl Runtime {
-    pub fn get_recursion_handle(&self) -> Option<impl Sized + '_> {
+    pub(crate) fn new(wayland : Option<WaylandClient>) -> Result<Self, Box<dyn Error>> {
+        let notify_inner = Rc::new(NotifierInner {
+            waker : Cell::new(None),
+            state : Cell::new(NotifyState::NewData),
+            data_update_seq : Cell::new(1),
+        });
+        Ok(Runtime {
+            xdg : xdg::BaseDirectories::new()?,
+            config_path : None,
+            fonts : Vec::new(),
+            cache: RenderCache::new(),
+            items : Default::default(),
+            item_var : Rc::new(Module::new_current_item().into()),
+            markup : Cell::new(false),
+            freeze : Cell::new(None),
+            notify : Notifier { inner : notify_inner },
+            read_depth : Cell::new(0),
+            popup_toggle : Cell::new(false),
+            item_popup : Cell::new(None),
+            max_fps : None,
+            battery_multiplier : 1.0,
+            on_battery : Cell::new(false),
+            history : Default::default(),
+            scheduler : Scheduler::default(),
+            error_badge : None,
+            modifiers : 0,
+            actions : HashMap::new(),
+            icons : HashMap::new(),
+            exec_scope : false,
+            wayland,
+        })
+    }
+
+    /// Find a file named in the configuration.  Relative paths are looked up next to the
+    /// configuration file first, then in the XDG configuration directories.
+    pub(crate) fn find_config_file(&self, path : &str) -> Option<PathBuf> {
+        if path.starts_with('/') {
+            return Some(PathBuf::from(path));
+        }
+        self.config_path.as_ref()
+            .and_then(|cfg| cfg.parent())
+            .map(|dir| dir.join(path))
+            .filter(|file| file.exists())
+            .or_else(|| self.xdg.find_config_file(path))
+    }
+
+    /// The wayland connection.  Only the output backends that don't use wayland lack one, and
+    /// they never create anything that needs it.
+    pub(crate) fn wayland(&self) -> &WaylandClient {
+        self.wayland.as_ref().expect("No wayland connection")
+    }
+
+    pub(crate) fn wayland_mut(&mut self) -> &mut WaylandClient {
+        self.wayland.as_mut().expect("No wayland connection")
+    }
+
+    /// Load the items, fonts, and global settings from the configuration file, returning the
+    /// configuration of each bar.
+    pub(crate) fn load_config(&mut self) -> Result<Vec<toml::Value>, Box<dyn Error>> {
+        let config_path = match &cli::args().config {
+            Some(path) => path.clone(),
+            None => find_config(&self.xdg)
+                .ok_or("Could not find configuration: create ~/.config/rwaybar/rwaybar.toml")?,
+        };
+        self.load_config_from(config_path)
+    }
+
+    /// Like [Runtime::load_config], but read the given file instead of the one named on the
+    /// command line or found in the XDG configuration directories.
+    pub(crate) fn load_config_from(&mut self, config_path : PathBuf) -> Result<Vec<toml::Value>, Box<dyn Error>> {
+        let mut bar_config = Vec::new();
+        let mut font_list = Vec::new();
+        let mut popup_config = None;
+        let mut log_config = None;
+        let mut metrics_config = None;
+        let mut locale_name = String::new();
+        let mut max_fps = None;
+        let mut battery_multiplier = 1.0;
+        let mut error_badge = None;
+        let mut actions = HashMap::new();
+        let mut icons = HashMap::new();
+        let mut exec_scope = false;
+        let mut plugin_config = None;
+        let mut lua_config = None;
+
+        info!("Loading configuration from {}", config_path.display());
+        self.config_path = Some(config_path.clone());
+
+        let cfg = std::fs::read_to_string(&config_path)
+            .map_err(|e| format!("Could not read {}: {}", config_path.display(), e))?;
+        let config : toml::Value = toml::from_str(&cfg)?;
+
+        let cfg = config.as_table().unwrap();
+
+        let new_items = cfg.iter().filter_map(|(key, value)| {
+            match key.as_str() {
+                "bar" => {
+                    if let Some(bars) = value.as_array() {
+                        bar_config.extend(bars.iter().cloned());
+                    } else {
+                        bar_config.push(value.clone());
+                    }
+                    None
+                }
+                "actions" => {
+                    match value.as_table() {
+                        Some(table) => {
+                            actions = table.iter()
+                                .map(|(name, action)| (name.clone(), Action::from_toml(action)))
+                                .collect();
+                        }
+                        None => error!("Ignoring invalid actions table: {}", value),
+                    }
+                    None
+                }
+                "error-badge" => {
+                    error_badge = ErrorBadge::from_toml(value);
+                    None
+                }
+                "exec-scope" => {
+                    exec_scope = value.as_bool().unwrap_or_else(|| {
+                        error!("Ignoring invalid exec-scope: {}", value);
+                        false
+                    });
+                    None
+                }
+                "fonts" => {
+                    if let Some(list) = value.as_table() {
+                        font_list = list.iter().collect();
+                    }
+                    None
+                }
+                "icons" => {
+                    match value.as_table() {
+                        Some(table) => {
+                            for (name, icon) in table {
+                                let (text, font) = match icon {
+                                    toml::Value::String(text) => (Some(text.as_str()), None),
+                                    _ => (icon.get("text").and_then(|v| v.as_str()), icon.get("font").and_then(|v| v.as_str())),
+                                };
+                                let text = match text {
+                                    Some(text) => text,
+                                    None => {
+                                        error!("Ignoring icon '{}' without text: {}", name, icon);
+                                        continue;
+                                    }
+                                };
+                                icons.insert(name.as_str().into(), Icon { text : text.into(), font : font.map(Into::into) });
+                            }
+                        }
+                        None => error!("Ignoring invalid icons table: {}", value),
+                    }
+                    None
+                }
+                "plugins" => {
+                    plugin_config = Some(value.clone());
+                    None
+                }
+                "popup" => {
+                    popup_config = Some(value.clone());
+                    None
+                }
+                "log" => {
+                    log_config = Some(value.clone());
+                    None
+                }
+                "lua" => {
+                    lua_config = Some(value.clone());
+                    None
+                }
+                "metrics" => {
+                    metrics_config = Some(value.clone());
+                    None
+                }
+                "locale" => {
+                    locale_name = value.as_str().unwrap_or_else(|| {
+                        error!("Ignoring invalid locale: {}", value);
+                        ""
+                    }).to_owned();
+                    None
+                }
+                "max-fps" => {
+                    max_fps = toml_to_f64(Some(value)).filter(|&v| v > 0.0);
+                    if max_fps.is_none() {
+                        error!("Ignoring invalid max-fps: {}", value);
+                    }
+                    None
+                }
+                "on-battery-multiplier" => {
+                    battery_multiplier = toml_to_f64(Some(value)).filter(|&v| v > 0.0).unwrap_or_else(|| {
+                        error!("Ignoring invalid on-battery-multiplier: {}", value);
+                        1.0
+                    });
+                    None
+                }
+                _ => {
+                    let key = key.to_owned();
+                    let value = Rc::new(Item::from_item_list(&key, value));
+                    Some((key, value))
+                }
+            }
+        }).collect();
+
+        logging::configure(log_config.as_ref(), &self.xdg);
+        metrics::configure(metrics_config.as_ref());
+        locale::set(&locale_name);
+        #[cfg(feature="lua")]
+        crate::lua::configure(lua_config.as_ref(), self);
+        #[cfg(not(feature="lua"))]
+        if lua_config.is_some() {
+            error!("Lua scripts require rwaybar to be built with the lua feature");
+        }
+
+        if bar_config.is_empty() {
+            Err("At least one [[bar]] section is required")?;
+        }
+
+        let selected = &cli::args().bars;
+        if !selected.is_empty() {
+            let mut index = 0;
+            bar_config.retain(|cfg| {
+                let id = cfg.get("id").and_then(|v| v.as_str());
+                let keep = selected.iter().any(|s| Some(&**s) == id || s.parse() == Ok(index));
+                index += 1;
+                keep
+            });
+            if bar_config.is_empty() {
+                Err("No [[bar]] section matches the --bar option")?;
+            }
+        }
+
+        // the global [popup] table applies to any bar without its own
+        if let Some(popup) = popup_config {
+            for bar in &mut bar_config {
+                if let Some(table) = bar.as_table_mut() {
+                    table.entry("popup").or_insert_with(|| popup.clone());
+                }
+            }
+        }
+
+        let mut fonts = Vec::with_capacity(font_list.len());
+        for (name, path) in font_list {
+            match FontMapped::new(name.clone(), path.as_str().unwrap_or("").to_owned().into()) {
+                Ok(font) => fonts.push(font),
+                Err(e) => {
+                    error!("Could not load font '{name}' from {path}: {e}");
+                }
+            }
+        }
+
+        if fonts.is_empty() {
+            Err("At least one valid font is required in the [fonts] section")?;
+        }
+
+        debug!("Loading configuration");
+
+        let mut old_items = std::mem::replace(&mut self.items, new_items);
+        self.fonts = fonts;
+        self.max_fps = max_fps;
+        self.battery_multiplier = battery_multiplier;
+        self.error_badge = error_badge;
+        self.actions = actions;
+        self.icons = icons;
+        self.exec_scope = exec_scope;
+        self.apply_power_settings();
+
+        self.items.insert("item".into(), self.item_var.clone());
+
+        #[cfg(feature="wasm")]
+        if let Some(cfg) = &plugin_config {
+            for (name, item) in crate::plugin::load(cfg, self) {
+                if self.items.contains_key(&name) {
+                    error!("Plugin namespace '{}' is already used by an item", name);
+                } else {
+                    self.items.insert(name, item);
+                }
+            }
+        }
+        #[cfg(not(feature="wasm"))]
+        if plugin_config.is_some() {
+            error!("Plugins require rwaybar to be built with the wasm feature");
+        }
+
+        for (k,v) in &self.items {
+            if let Some(item) = old_items.remove(k) {
+                v.data.init(k, self, Some(&item.data));
+            } else {
+                v.data.init(k, self, None);
+            }
+        }
+        self.notify.inner.state.set(NotifyState::NewData);
+        Ok(bar_config)
+    }
+
+    /// Wait until something requests a redraw
+    pub(crate) fn wait_for_draw(&self) -> impl std::future::Future<Output=()> {
+        let inner = self.notify.inner.clone();
+        poll_fn(move |ctx| {
+            inner.waker.set(Some(ctx.waker().clone()));
+            match inner.state.get() {
+                NotifyState::Idle => task::Poll::Pending,
+                _ => task::Poll::Ready(()),
+            }
+        })
+    }
+
+    /// Start a new frame.  Returns true if any data changed since the last frame, in which case
+    /// every bar needs to be redrawn.
+    pub(crate) fn begin_frame(&self) -> bool {
+        match self.notify.inner.state.replace(NotifyState::Idle) {
+            NotifyState::Idle => return false,
+            NotifyState::DrawOnly => return false,
+            NotifyState::NewData => {}
+        }
+
+        let seq = self.notify.inner.data_update_seq.get();
+        self.notify.inner.data_update_seq.set(seq + 1);
+        true
+    }
+
+    pub(crate) fn get_recursion_handle(&self) -> Option<impl Sized + '_> {
         let depth = self.read_depth.get();
         if depth > 80 {
             None
@@ -114,10 +505,39 @@ impl Runtime {
         }
     }
 
+    /// Expand a format string such as `{clock} {item.text}`
     pub fn format<'a>(&'a self, fmt : &'a str) -> Result<Value<'a>, strfmt::FmtError> {
         if !fmt.contains("{") {
             return Ok(Value::Borrow(fmt));
         }
+        // Expansions done while expanding this one are not recorded
+        let mut cursor = match self.freeze.take() {
+            Some(cursor) => cursor,
+            None => return self.format_live(fmt),
+        };
+        let rv = match cursor.replay_text(fmt) {
+            Some(value) => Ok(value),
+            None => {
+                let rv = self.format_live(fmt);
+                if let Ok(value) = &rv {
+                    cursor.record_text(fmt, value);
+                }
+                rv
+            }
+        };
+        self.freeze.set(Some(cursor));
+        rv
+    }
+
+    fn format_live<'a>(&'a self, fmt : &'a str) -> Result<Value<'a>, strfmt::FmtError> {
+        if fmt.contains("{icon:") {
+            return self.format(&self.expand_icons(fmt)?).map(Value::into_owned);
+        }
+        if fmt.contains('|') {
+            if let Some(expanded) = filter::expand_filters(self, fmt)? {
+                return self.format(&expanded).map(Value::into_owned);
+            }
+        }
         if fmt.starts_with("{") && fmt.ends_with("}") && !fmt[1..fmt.len() - 1].contains(&['{', ':'] as &[char]) {
             let q = &fmt[1..fmt.len() - 1];
             let (name, key) = match q.find('.') {
@@ -125,7 +545,7 @@ impl Runtime {
                 None => (&q[..], ""),
             };
             if let Some(item) = self.items.get(name) {
-                return Ok(item.data.read_to_owned(name, key, self));
+                return Ok(self.read_item(item, name, key, |v| v.into_owned()));
             } else {
                 return Err(strfmt::FmtError::KeyError(name.to_string()));
             }
@@ -137,7 +557,7 @@ impl Runtime {
             };
             match self.items.get(name) {
                 Some(item) => {
-                    item.data.read_in(name, key, self, |s| match s {
+                    self.read_item(item, name, key, |s| match s {
                         Value::Borrow(s) => q.str(s),
                         Value::Owned(s) => q.str(&s),
                         Value::Float(f) => q.f64(f),
@@ -151,6 +571,71 @@ impl Runtime {
         }).map(Value::Owned)
     }
 
+    /// Replace each `{icon:NAME}` with its text from the `[icons]` table
+    fn expand_icons(&self, fmt : &str) -> Result<String, strfmt::FmtError> {
+        let mut rv = String::with_capacity(fmt.len());
+        self.expand_icons_in(fmt, fmt, 0, &mut rv)?;
+        Ok(rv)
+    }
+
+    fn expand_icons_in(&self, fmt : &str, text : &str, depth : u32, rv : &mut String) -> Result<(), strfmt::FmtError> {
+        if depth > MAX_ICON_DEPTH {
+            return Err(strfmt::FmtError::Invalid(format!("Icons nested too deeply in '{}'", fmt)));
+        }
+        let mut rest = text;
+        while let Some(p) = rest.find("{icon:") {
+            rv.push_str(&rest[..p]);
+            let tail = &rest[p + 6..];
+            let end = tail.find('}').ok_or_else(|| strfmt::FmtError::Invalid(format!("Unclosed icon in '{}'", fmt)))?;
+            let name = &tail[..end];
+            let icon = self.icons.get(name).ok_or_else(|| strfmt::FmtError::KeyError(format!("icon:{}", name)))?;
+            // The font only applies to the icon's own text, which needs markup to mark
+            match &icon.font {
+                Some(font) if self.markup.get() => {
+                    rv.push_str("<span font='");
+                    rv.push_str(font);
+                    rv.push_str("'>");
+                    self.expand_icons_in(fmt, &icon.text, depth + 1, rv)?;
+                    rv.push_str("</span>");
+                }
+                _ => self.expand_icons_in(fmt, &icon.text, depth + 1, rv)?,
+            }
+            rest = &tail[end + 1..];
+        }
+        rv.push_str(rest);
+        Ok(())
+    }
+
+    /// Expand text for an item that does (or does not) use markup
+    pub(crate) fn with_markup<R>(&self, markup : bool, f : impl FnOnce() -> R) -> R {
+        let prev = self.markup.replace(markup);
+        let rv = f();
+        self.markup.set(prev);
+        rv
+    }
+
+    /// Read a key from an item, handling the history suffixes (such as `.delta` or `.avg5`) that
+    /// can be added to any numeric key
+    fn read_item<F : FnOnce(Value) -> R, R>(&self, item : &Item, name : &str, key : &str, f : F) -> R {
+        let (base, suffix) = key.rsplit_once('.').unwrap_or(("", key));
+        let op = match history::Op::parse(suffix) {
+            Some(op) => op,
+            None => return item.data.read_in(name, key, self, f),
+        };
+        let value = item.data.read_to_owned(name, base, self).parse_f64();
+        let seq = self.notify.inner.data_update_seq.get();
+        let path = format!("{}.{}", name, base);
+        let result = self.history.take_in(|h| {
+            let entry = h.entry(path.into()).or_default();
+            if let Some(value) = value {
+                entry.record(seq, value);
+            }
+            entry.eval(op)
+        });
+        f(result.map_or(Value::Null, Value::Float))
+    }
+
+    /// Expand a format string, logging any error with the given context and returning Null
     pub fn format_or<'a>(&'a self, fmt : &'a str, context : &str) -> Value<'a> {
         match self.format(fmt) {
             Ok(v) => v,
@@ -161,11 +646,156 @@ impl Runtime {
         }
     }
 
-    pub fn copy_item_var(&self) -> Option<IterationItem> {
+    /// A counter that increases on each frame drawn because data changed
+    pub(crate) fn data_update_seq(&self) -> u64 {
+        self.notify.inner.data_update_seq.get()
+    }
+
+    /// Redraw without marking any data as changed
+    pub fn request_draw(&self) {
+        self.notify.notify_draw_only();
+    }
+
+    /// Show or hide the named group, as done by the `toggle-item` action
+    pub fn toggle_item(&self, name : &str) -> Result<(), String> {
+        match self.items.get(name).map(|item| &item.data) {
+            Some(Module::Group { visible, transition, animation, .. }) => {
+                let show = !visible.get();
+                visible.set(show);
+                // the animation runs over the fraction of the group's width that is shown
+                let (from, to) = if show { (0.0, 1.0) } else { (1.0, 0.0) };
+                animation.start(from, to, *transition, self);
+                NotifierList::active(self).notify_data("toggle-item");
+                Ok(())
+            }
+            Some(_) => Err(format!("Item '{}' is not a group", name)),
+            None => Err(format!("Could not find item '{}'", name)),
+        }
+    }
+
+    /// Draw an item with `freeze-on-hover`, recording the values it shows, or showing the recorded
+    /// values instead while it is frozen
+    pub(crate) fn with_freeze<R>(&self, freeze : &Rc<Freeze>, f : impl FnOnce() -> R) -> R {
+        let prev = self.freeze.replace(Some(FreezeCursor::new(freeze)));
+        let rv = f();
+        self.freeze.set(prev);
+        rv
+    }
+
+    /// Read the text of a module being drawn, or the one recorded for the frozen item being drawn
+    pub(crate) fn read_text(&self, name : &str, source : &Module) -> Value<'static> {
+        // Not a valid format string, so this does not collide with a recorded expansion
+        const KEY : &str = "{}text";
+        // Reads done while reading this one are not recorded
+        let mut cursor = match self.freeze.take() {
+            Some(cursor) => cursor,
+            None => return source.read_to_owned(name, "text", self),
+        };
+        let rv = cursor.replay_text(KEY).unwrap_or_else(|| {
+            let value = source.read_to_owned(name, "text", self);
+            cursor.record_text(KEY, &value);
+            value
+        });
+        self.freeze.set(Some(cursor));
+        rv
+    }
+
+    /// Read the entries of a focus list, or the ones recorded for the frozen item being drawn
+    pub(crate) fn read_focus_list<F : FnMut(bool, IterationItem)>(&self, source : &Module, mut f : F) {
+        let list = match self.freeze.take_in(|c| c.as_mut().map(|c| c.replay_list())) {
+            None => return source.read_focus_list(self, f),
+            Some(Some(list)) => list,
+            Some(None) => {
+                let mut list = Vec::new();
+                source.read_focus_list(self, |focus, item| list.push((focus, item)));
+                self.freeze.take_in_some(|c| c.record_list(&list));
+                list
+            }
+        };
+        for (focus, item) in list {
+            f(focus, item);
+        }
+    }
+
+    /// Read the named item again now, as done by the `refresh` action
+    pub fn refresh_item(&self, name : &str) -> Result<(), String> {
+        match self.items.get(name) {
+            Some(item) if item.data.refresh(name, self) => Ok(()),
+            Some(_) => Err(format!("Item '{}' cannot be refreshed", name)),
+            None => Err(format!("Could not find item '{}'", name)),
+        }
+    }
+
+    /// Write a value to `NAME` or `NAME.KEY`, as done by the `send` action
+    pub fn write_item(&self, target : &str, value : Value) -> Result<(), String> {
+        let (name, key) = match target.find('.') {
+            Some(p) => (&target[..p], &target[p + 1..]),
+            None => (target, ""),
+        };
+        match self.items.get(name) {
+            Some(item) => {
+                item.data.write(name, key, value, self);
+                Ok(())
+            }
+            None => Err(format!("Could not find variable {}", target)),
+        }
+    }
+
+    /// Redraw every bar on the next frame, for a running [animation](crate::anim::Animation)
+    pub(crate) fn request_animation_frame(&self) {
+        self.notify.inner.state.set(NotifyState::NewData);
+        if let Some(w) = self.notify.inner.waker.take() { w.wake() }
+    }
+
+    /// Ask the bar handling the current click to open or close a pinned popup
+    pub(crate) fn request_popup_toggle(&self) {
+        self.popup_toggle.set(true);
+    }
+
+    pub(crate) fn take_popup_toggle(&self) -> bool {
+        self.popup_toggle.take()
+    }
+
+    /// Ask for the popup of the named item to be opened in the middle of the bar
+    pub(crate) fn request_item_popup(&self, name : &str) {
+        self.item_popup.set(Some(name.into()));
+    }
+
+    pub(crate) fn take_item_popup(&self) -> Option<String> {
+        self.item_popup.take()
+    }
+
+    /// True if we are running on battery and should save power where possible (for example, by
+    /// skipping animations)
+    pub(crate) fn low_power(&self) -> bool {
+        self.on_battery.get() && self.battery_multiplier > 1.0
+    }
+
+    #[cfg(feature="dbus")]
+    pub(crate) fn set_on_battery(&self, on_battery : bool) {
+        if self.on_battery.replace(on_battery) != on_battery {
+            info!("Switching to {} power settings", if on_battery { "battery" } else { "AC" });
+        }
+        self.apply_power_settings();
+    }
+
+    fn apply_power_settings(&self) {
+        let stretch = if self.on_battery.get() { self.battery_multiplier } else { 1.0 };
+        self.scheduler.set_stretch(stretch);
+    }
+
+    /// The minimum time between frames, if frames are limited
+    pub(crate) fn frame_interval(&self) -> Option<Duration> {
+        let fps = self.max_fps?;
+        let stretch = if self.on_battery.get() { self.battery_multiplier } else { 1.0 };
+        Some(Duration::from_secs_f64(stretch / fps))
+    }
+
+    pub(crate) fn copy_item_var(&self) -> Option<IterationItem> {
         self.get_item_var().take_in_some(|v| v.clone())
     }
 
-    pub fn get_item_var(&self) -> &Cell<Option<IterationItem>> {
+    pub(crate) fn get_item_var(&self) -> &Cell<Option<IterationItem>> {
         match &*self.item_var {
             &Item { data : Module::Item { ref value }, .. } => value,
             _ => {
@@ -175,6 +805,9 @@ impl Runtime {
     }
 }
 
+/// Set for a bar started by `reload-exec` to the pid of the bar it replaces
+const REPLACE_ENV : &str = "RWAYBAR_REPLACE";
+
 /// The singleton global state object
 pub struct State {
     pub bars : Vec<Bar>,
@@ -182,18 +815,24 @@ pub struct State {
     pub renderer : Renderer,
     pub runtime : Runtime,
     this : rc::Weak<RefCell<State>>,
+    focused_output : Option<String>,
+    focus_watched : bool,
+    /// Outputs currently showing a fullscreen window
+    fullscreen_outputs : HashSet<String>,
+    fullscreen_watched : bool,
+    battery_watched : bool,
+    config_watch : Option<(PathBuf, RemoteHandle<()>)>,
+    /// Notified when the configuration file changes, to reload it from the main loop
+    config_changed : Rc<Notify>,
+    ipc_socket : Option<ipc::Socket>,
+    /// The bar being upgraded by this one, which is stopped once this one has drawn a frame
+    replaces : Option<libc::pid_t>,
     #[allow(unused)] // need to hold this handle for the callback to remain alive
     output_status_listener : OutputStatusListener,
 }
 
 impl State {
     pub fn new(mut wayland : WaylandClient) -> Result<Rc<RefCell<Self>>, Box<dyn Error>> {
-        let notify_inner = Rc::new(NotifierInner {
-            waker : Cell::new(None),
-            state : Cell::new(NotifyState::NewData),
-            data_update_seq : Cell::new(1),
-        });
-
         let output_status_listener = wayland.add_output_listener(move |output, _oi, mut data| {
             let state : &mut State = data.get().unwrap();
             let state = state.this.upgrade().unwrap();
@@ -202,6 +841,11 @@ impl State {
                 with_output_info(&output, |oi| {
                     state.output_ready(&output, oi);
                 });
+                // Adding or removing an output can change which one is primary
+                state.move_bars("primary");
+                if state.focused_output.is_none() {
+                    state.move_bars("focused");
+                }
             });
         });
 
@@ -209,30 +853,35 @@ impl State {
             bars : Vec::new(),
             bar_config : Vec::new(),
             renderer: Renderer::new(&wayland.env)?,
-            runtime : Runtime {
-                xdg : xdg::BaseDirectories::new()?,
-                fonts : Vec::new(),
-                cache: RenderCache::new(),
-                items : Default::default(),
-                item_var : Rc::new(Module::new_current_item().into()),
-                notify : Notifier { inner : notify_inner.clone() },
-                read_depth : Cell::new(0),
-                wayland,
-            },
+            runtime : Runtime::new(Some(wayland))?,
             this : rc::Weak::new(),
+            focused_output : None,
+            focus_watched : false,
+            fullscreen_outputs : HashSet::new(),
+            fullscreen_watched : false,
+            battery_watched : false,
+            config_watch : None,
+            config_changed : Default::default(),
+            ipc_socket : None,
+            replaces : None,
             output_status_listener,
         };
 
         state.load_config(false)?;
 
-        let sync_cb = state.runtime.wayland.wl_display.sync();
+        if let Some(pid) = std::env::var_os(REPLACE_ENV) {
+            std::env::remove_var(REPLACE_ENV);
+            state.replaces = pid.to_str().and_then(|pid| pid.parse().ok());
+        }
+
+        let sync_cb = state.runtime.wayland().wl_display.sync();
         sync_cb.quick_assign(move |_sync, _event, mut data| {
             let state : &mut State = data.get().unwrap();
             if !state.bars.is_empty() {
                 return;
             }
             error!("No bars matched this outptut configuration.  Available outputs:");
-            for output in state.runtime.wayland.env.get_all_outputs() {
+            for output in state.runtime.wayland().env.get_all_outputs() {
                 with_output_info(&output, |oi| {
                     error!(" name='{}' description='{}' make='{}' model='{}'",
                         oi.name, oi.description, oi.make, oi.model);
@@ -242,118 +891,143 @@ impl State {
 
         let rv = Rc::new(RefCell::new(state));
         rv.borrow_mut().this = Rc::downgrade(&rv);
+        rv.borrow_mut().watch_focused_output();
+        rv.borrow_mut().watch_fullscreen();
+        rv.borrow_mut().watch_battery();
+        rv.borrow_mut().watch_config();
 
-        let state = rv.clone();
-        spawn_noerr(async move {
-            loop {
-                poll_fn(|ctx| {
-                    notify_inner.waker.set(Some(ctx.waker().clone()));
-                    match notify_inner.state.get() {
-                        NotifyState::Idle => task::Poll::Pending,
-                        _ => task::Poll::Ready(()),
-                    }
-                }).await;
+        Ok(rv)
+    }
+
+    /// Draw the bars whenever something requests it, limited by max-fps
+    async fn draw_loop(state : Rc<RefCell<Self>>) -> Infallible {
+        loop {
+            let wait = state.borrow().runtime.wait_for_draw();
+            wait.await;
+            let begin = Instant::now();
+            let interval = {
                 let mut state = state.borrow_mut();
                 state.draw_now();
+                state.runtime.frame_interval()
+            };
+            if let Some(interval) = interval {
+                tokio::time::sleep_until((begin + interval).into()).await;
             }
-        });
-
-        let state = rv.clone();
-        spawn("Config reload", async move {
-            let mut hups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
-            while let Some(()) = hups.recv().await {
-                match state.borrow_mut().load_config(true) {
-                    Ok(()) => (),
-                    Err(e) => error!("Config reload failed: {}", e),
-                }
-            }
-            Ok(())
-        });
-
-        Ok(rv)
+        }
     }
 
-    fn load_config(&mut self, reload : bool) -> Result<(), Box<dyn Error>> {
-        let mut bar_config = Vec::new();
-        let mut font_list = Vec::new();
-
-        let config_path = self.runtime.xdg.find_config_file("rwaybar.toml")
-            .ok_or("Could not find configuration: create ~/.config/rwaybar.toml")?;
-
-        let cfg = std::fs::read_to_string(config_path)?;
-        let config : toml::Value = toml::from_str(&cfg)?;
-
-        let cfg = config.as_table().unwrap();
+    /// Run the bar until it is asked to exit with SIGINT or SIGTERM.
+    ///
+    /// Everything that must happen in order (compositor events, drawing, item timers, IPC
+    /// commands, and the config file changes or signals that reload or exit) is handled here, one
+    /// event at a time; data sources run as separate tasks and only request redraws.
+    pub async fn run(state : Rc<RefCell<Self>>, wl_queue : wayland_client::EventQueue) -> Result<(), Box<dyn Error>> {
+        let mut hup = signal(SignalKind::hangup())?;
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+        let mut term = signal(SignalKind::terminate())?;
+        let mut int = signal(SignalKind::interrupt())?;
+        let mut ipc = state.borrow_mut().start_ipc().fuse();
+        let config_changed = state.borrow().config_changed.clone();
+        let wayland = wayland::run_queue(wl_queue, state.clone()).fuse();
+        let draw = Self::draw_loop(state.clone()).fuse();
+        let timers = state.borrow().runtime.scheduler.clone();
+        let timers = timers.drive().fuse();
+        pin_mut!(wayland, draw, timers);
 
-        let new_items = cfg.iter().filter_map(|(key, value)| {
-            match key.as_str() {
-                "bar" => {
-                    if let Some(bars) = value.as_array() {
-                        bar_config.extend(bars.iter().cloned());
-                    } else {
-                        bar_config.push(value.clone());
+        loop {
+            select! {
+                rv = wayland => match rv? {},
+                never = draw => match never {},
+                never = timers => match never {},
+                request = ipc.select_next_some() => request.run(&state),
+                _ = config_changed.notified().fuse() => {
+                    info!("Configuration file changed, reloading");
+                    if let Err(e) = state.borrow_mut().load_config(true) {
+                        error!("Config reload failed: {}", e);
                     }
-                    None
                 }
-                "fonts" => {
-                    if let Some(list) = value.as_table() {
-                        font_list = list.iter().collect();
+                _ = hup.recv().fuse() => {
+                    if let Err(e) = state.borrow_mut().load_config(true) {
+                        error!("Config reload failed: {}", e);
                     }
-                    None
                 }
-                _ => {
-                    let key = key.to_owned();
-                    let value = Rc::new(Item::from_item_list(&key, value));
-                    Some((key, value))
+                _ = usr1.recv().fuse() => {
+                    timing::toggle();
+                    state.borrow_mut().redraw_all();
                 }
+                _ = term.recv().fuse() => break,
+                _ = int.recv().fuse() => break,
             }
-        }).collect();
-
-        if bar_config.is_empty() {
-            Err("At least one [[bar]] section is required")?;
         }
 
-        let mut fonts = Vec::with_capacity(font_list.len());
-        for (name, path) in font_list {
-            match FontMapped::new(name.clone(), path.as_str().unwrap_or("").to_owned().into()) {
-                Ok(font) => fonts.push(font),
-                Err(e) => {
-                    error!("Could not load font '{name}' from {path}: {e}");
-                }
+        info!("Exiting");
+        state.borrow_mut().shutdown();
+        #[cfg(feature="dbus")]
+        let _ = tokio::time::timeout(Duration::from_millis(500), crate::tray::release_names()).await;
+        Ok(())
+    }
+
+    /// Create the control socket, returning the requests sent to it
+    fn start_ipc(&mut self) -> mpsc::UnboundedReceiver<ipc::Request> {
+        let path = match ipc::socket_path(&self.runtime.xdg) {
+            Some(path) => path,
+            None => {
+                warn!("Could not create the IPC socket: no runtime directory");
+                return mpsc::unbounded().1;
+            }
+        };
+        match ipc::start(path.clone()) {
+            Ok((socket, requests)) => {
+                self.ipc_socket = Some(socket);
+                requests
+            }
+            Err(e) => {
+                error!("Could not create the IPC socket {}: {}", path.display(), e);
+                mpsc::unbounded().1
             }
         }
+    }
 
-        if fonts.is_empty() {
-            Err("At least one valid font is required in the [fonts] section")?;
+    /// Remove the bars and stop watching for changes, before exiting
+    fn shutdown(&mut self) {
+        for bar in &mut self.bars {
+            bar.popup = None;
         }
+        self.bars.clear();
+        self.runtime.wayland_mut().flush_now();
+        self.config_watch = None;
+        self.ipc_socket = None;
+    }
 
-        debug!("Loading configuration");
-
-        let mut old_items = std::mem::replace(&mut self.runtime.items, new_items);
-        self.bar_config = bar_config;
-        self.runtime.fonts = fonts;
+    /// Start a new copy of the bar from the current executable, which stops this one once it has
+    /// drawn its first frame.  If the new copy fails to start, this one keeps running.
+    pub fn reload_exec(&mut self) -> Result<(), Box<dyn Error>> {
+        let exe = std::env::current_exe()?;
+        let child = std::process::Command::new(&exe)
+            .args(std::env::args_os().skip(1))
+            .env(REPLACE_ENV, std::process::id().to_string())
+            .spawn()?;
+        info!("Started {} (pid {}) to replace this bar", exe.display(), child.id());
+        Ok(())
+    }
 
-        self.runtime.items.insert("item".into(), self.runtime.item_var.clone());
-
-        for (k,v) in &self.runtime.items {
-            if let Some(item) = old_items.remove(k) {
-                v.data.init(k, &self.runtime, Some(&item.data));
-            } else {
-                v.data.init(k, &self.runtime, None);
-            }
-        }
-        self.runtime.notify.inner.state.set(NotifyState::NewData);
+    pub fn load_config(&mut self, reload : bool) -> Result<(), Box<dyn Error>> {
+        self.bar_config = self.runtime.load_config()?;
 
         self.bars.clear();
-        for output in self.runtime.wayland.env.get_all_outputs() {
+        for output in self.runtime.wayland().env.get_all_outputs() {
             with_output_info(&output, |oi| {
                 self.output_ready(&output, oi);
             });
         }
         if reload {
+            self.watch_focused_output();
+            self.watch_fullscreen();
+            self.watch_battery();
+            self.watch_config();
             if self.bars.is_empty() {
                 error!("No bars matched this outptut configuration.  Available outputs:");
-                for output in self.runtime.wayland.env.get_all_outputs() {
+                for output in self.runtime.wayland().env.get_all_outputs() {
                     with_output_info(&output, |oi| {
                         error!(" name='{}' description='{}' make='{}' model='{}'",
                             oi.name, oi.description, oi.make, oi.model);
@@ -369,20 +1043,64 @@ impl State {
     }
 
     pub fn request_draw(&mut self) {
-        self.runtime.notify.notify_draw_only();
+        self.runtime.request_draw();
+    }
+
+    /// Redraw every bar, even if no data changed
+    pub fn redraw_all(&mut self) {
+        for bar in &mut self.bars {
+            bar.dirty = true;
+        }
+        self.request_draw();
+    }
+
+    /// Replace (or add) the definition of a single item without reloading the rest of the
+    /// configuration
+    pub fn replace_item(&mut self, name : &str, value : toml::Value) -> Result<(), Box<dyn Error>> {
+        match name {
+            "actions" | "bar" | "error-badge" | "fonts" | "item" | "locale" | "log" | "lua" | "max-fps" | "metrics" | "on-battery-multiplier" | "plugins" | "popup" => {
+                Err(format!("'{}' is not an item", name))?;
+            }
+            _ => {}
+        }
+        let item = Rc::new(Item::from_item_list(name, &value));
+        if let Module::ParseError { msg } = &item.data {
+            Err(msg.to_string())?;
+        }
+        let old = self.runtime.items.insert(name.into(), item.clone());
+        item.data.init(name, &self.runtime, old.as_ref().map(|i| &i.data));
+        info!("Replaced item {}", name);
+        self.runtime.notify.notify_data("ipc");
+        Ok(())
+    }
+
+    /// Run one of the named actions from the `[actions]` table
+    pub fn run_action(&mut self, name : &str) -> Result<(), Box<dyn Error>> {
+        let action = self.runtime.actions.get(name)
+            .ok_or_else(|| format!("no action named '{}'", name))?
+            .clone();
+        action.invoke(&self.runtime, 0);
+        if self.runtime.take_popup_toggle() {
+            warn!("toggle-popup needs a click to find its item; use popup:NAME in {}", name);
+        }
+        if let Some(item) = self.runtime.take_item_popup() {
+            // Only the focused output is used, as a popup anywhere else would be easy to miss
+            let focused = self.focused_output.as_deref()
+                .ok_or("the focused output is not known, so the popup is not shown")?;
+            let bar = self.bars.iter_mut().find(|bar| &*bar.name == focused)
+                .ok_or_else(|| format!("no bar on the focused output {} to show the popup on", focused))?;
+            bar.toggle_item_popup(&item, &self.runtime)?;
+        }
+        self.runtime.request_draw();
+        Ok(())
     }
 
     fn set_data(&mut self) {
         // Propagate new_data notifications to all bar dirty fields
-        match self.runtime.notify.inner.state.replace(NotifyState::Idle) {
-            NotifyState::Idle => return,
-            NotifyState::DrawOnly => return,
-            NotifyState::NewData => {}
+        if !self.runtime.begin_frame() {
+            return;
         }
 
-        let seq = self.runtime.notify.inner.data_update_seq.get();
-        self.runtime.notify.inner.data_update_seq.set(seq + 1);
-
         for bar in &mut self.bars {
             bar.dirty = true;
         }
@@ -392,75 +1110,334 @@ impl State {
         self.set_data();
 
         let begin = Instant::now();
+        timing::begin_frame();
+        let mut frames = Vec::new();
+        for (i, bar) in self.bars.iter_mut().enumerate() {
+            bar.place(&self.runtime);
+            if let Some(canvas) = bar.record(&mut self.runtime) {
+                frames.push((i, canvas));
+            }
+        }
+        let recorded = Instant::now();
+        let drawn = !frames.is_empty();
+        for (i, pixmap) in render::rasterize(frames) {
+            self.bars[i].present(&self.renderer, pixmap);
+        }
+        if drawn {
+            metrics::frame(begin.elapsed());
+            if let Some(pid) = self.replaces.take() {
+                info!("Stopping the bar being replaced (pid {})", pid);
+                unsafe { libc::kill(pid, libc::SIGTERM); }
+            }
+        }
+        if drawn && timing::enabled() {
+            timing::frame(recorded - begin, recorded.elapsed());
+        }
         for bar in &mut self.bars {
-            bar.render_with(&mut self.runtime, &mut self.renderer);
+            bar.render_popup(&mut self.runtime, &self.renderer);
         }
         self.runtime.cache.prune(begin);
-        self.runtime.wayland.flush();
+        self.runtime.wayland_mut().flush();
         let render_time = begin.elapsed().as_nanos();
         log::debug!("Frame took {}.{:06} ms", render_time / 1_000_000, render_time % 1_000_000);
     }
 
-    pub fn output_ready(&mut self, output : &WlOutput, data : &OutputInfo) {
-        if data.obsolete {
-            return;
+    /// Check if this is the primary output: the one at the origin of the layout
+    fn is_primary(&self, data : &OutputInfo) -> bool {
+        if data.location == (0, 0) {
+            return true;
         }
-        info!("Output name='{}' description='{}' make='{}' model='{}'",
-            data.name, data.description, data.make, data.model);
-        for (i, cfg) in self.bar_config.iter().enumerate() {
-            if let Some(name) = cfg.get("name").and_then(|v| v.as_str()) {
-                if name != &data.name {
-                    continue;
+        let mut first = None;
+        let mut origin = false;
+        for output in self.runtime.wayland().env.get_all_outputs() {
+            with_output_info(&output, |oi| {
+                if oi.obsolete {
+                    return;
+                }
+                if first.is_none() {
+                    first = Some(oi.name.clone());
+                }
+                origin |= oi.location == (0, 0);
+            });
+        }
+        !origin && first.as_deref() == Some(&*data.name)
+    }
+
+    fn bar_matches(&self, cfg : &toml::Value, data : &OutputInfo) -> bool {
+        match cfg.get("output").and_then(|v| v.as_str()) {
+            None => {}
+            Some("primary") => {
+                if !self.is_primary(data) {
+                    return false;
                 }
             }
-            if let Some(make) = cfg.get("make").and_then(|v| v.as_str()) {
-                match regex::Regex::new(make) {
-                    Ok(re) => {
-                        if !re.is_match(&data.make) {
-                            continue;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Ignoring invalid regex in bar.make: {}", e);
-                    }
+            Some("focused") => {
+                match &self.focused_output {
+                    Some(name) if *name != data.name => return false,
+                    Some(_) => {}
+                    // Until we know which output has focus, use the primary one
+                    None if !self.is_primary(data) => return false,
+                    None => {}
                 }
             }
-            if let Some(model) = cfg.get("model").and_then(|v| v.as_str()) {
-                match regex::Regex::new(model) {
-                    Ok(re) => {
-                        if !re.is_match(&data.model) {
-                            continue;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Ignoring invalid regex in bar.model: {}", e);
+            Some(output) => {
+                error!("Ignoring invalid bar.output '{}': use primary or focused", output);
+            }
+        }
+        if let Some(name) = cfg.get("name").and_then(|v| v.as_str()) {
+            if name != data.name {
+                return false;
+            }
+        }
+        if let Some(make) = cfg.get("make").and_then(|v| v.as_str()) {
+            match regex::Regex::new(make) {
+                Ok(re) => {
+                    if !re.is_match(&data.make) {
+                        return false;
                     }
                 }
+                Err(e) => {
+                    error!("Ignoring invalid regex in bar.make: {}", e);
+                }
             }
-            if let Some(description) = cfg.get("description").and_then(|v| v.as_str()) {
-                match regex::Regex::new(description) {
-                    Ok(re) => {
-                        if !re.is_match(&data.description) {
-                            continue;
-                        }
+        }
+        if let Some(model) = cfg.get("model").and_then(|v| v.as_str()) {
+            match regex::Regex::new(model) {
+                Ok(re) => {
+                    if !re.is_match(&data.model) {
+                        return false;
                     }
-                    Err(e) => {
-                        error!("Ignoring invalid regex in bar.description: {}", e);
+                }
+                Err(e) => {
+                    error!("Ignoring invalid regex in bar.model: {}", e);
+                }
+            }
+        }
+        if let Some(description) = cfg.get("description").and_then(|v| v.as_str()) {
+            match regex::Regex::new(description) {
+                Ok(re) => {
+                    if !re.is_match(&data.description) {
+                        return false;
                     }
                 }
+                Err(e) => {
+                    error!("Ignoring invalid regex in bar.description: {}", e);
+                }
+            }
+        }
+        true
+    }
+
+    fn add_bar(&mut self, i : usize, output : &WlOutput, data : &OutputInfo) {
+        let mut cfg = self.bar_config[i].clone();
+        if let Some(table) = cfg.as_table_mut() {
+            table.insert("name".into(), data.name.clone().into());
+        }
+
+        let mut bar = Bar::new(self.runtime.wayland(), output, data, cfg, i);
+        let fullscreen = self.fullscreen_outputs.contains(&data.name);
+        match fullscreen_mode(&self.bar_config[i]) {
+            Some("overlap") => bar.set_overlap(fullscreen, false, &self.runtime),
+            Some("hide") => bar.set_hidden(fullscreen, false, &self.runtime),
+            _ => {}
+        }
+        self.bars.retain(|bar| {
+            bar.cfg_index != i || *bar.name != data.name
+        });
+        self.bars.push(bar);
+        self.runtime.wayland_mut().flush();
+    }
+
+    pub fn output_ready(&mut self, output : &WlOutput, data : &OutputInfo) {
+        if data.obsolete {
+            return;
+        }
+        info!("Output name='{}' description='{}' make='{}' model='{}'",
+            data.name, data.description, data.make, data.model);
+        for i in 0..self.bar_config.len() {
+            if self.bar_matches(&self.bar_config[i], data) {
+                self.add_bar(i, output, data);
+            }
+        }
+    }
+
+    /// Start tracking the focused output if any bar is configured to follow it
+    fn watch_battery(&mut self) {
+        if self.battery_watched || self.runtime.battery_multiplier == 1.0 {
+            return;
+        }
+        #[cfg(feature="dbus")]
+        {
+            self.battery_watched = true;
+            let this = self.this.clone();
+            crate::upower::watch_on_battery(move |on_battery| {
+                if let Some(state) = this.upgrade() {
+                    let state = state.borrow();
+                    state.runtime.set_on_battery(on_battery);
+                    state.runtime.request_draw();
+                }
+            });
+        }
+        #[cfg(not(feature="dbus"))]
+        warn!("on-battery-multiplier requires dbus support");
+    }
+
+    /// Reload the configuration when the file it was loaded from changes
+    fn watch_config(&mut self) {
+        let path = match &self.runtime.config_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        if matches!(&self.config_watch, Some((watched, _)) if *watched == path) {
+            return;
+        }
+        let changed = self.config_changed.clone();
+        let handle = spawn_handle("Config watch", util::watch_file(path.clone(), move || {
+            changed.notify_one();
+        }));
+        self.config_watch = Some((path, handle));
+    }
+
+    fn watch_focused_output(&mut self) {
+        if self.focus_watched {
+            return;
+        }
+        let follow = self.bar_config.iter()
+            .any(|cfg| cfg.get("output").and_then(|v| v.as_str()) == Some("focused"));
+        // Named actions may open popups on the focused output
+        if !follow && self.runtime.actions.is_empty() {
+            return;
+        }
+        self.focus_watched = true;
+        let this = self.this.clone();
+        let on_focus = move |name : &str| {
+            let name = name.to_owned();
+            let state = match this.upgrade() {
+                Some(state) => state,
+                None => return,
+            };
+            spawn_noerr(async move {
+                state.borrow_mut().set_focused_output(name);
+            });
+        };
+        if hyprland::is_running() {
+            hyprland::watch_focused_output(on_focus);
+        } else {
+            sway::watch_focused_output(on_focus);
+        }
+    }
+
+    /// Start tracking fullscreen windows if any bar is configured to react to them
+    fn watch_fullscreen(&mut self) {
+        let mut used = false;
+        for cfg in &self.bar_config {
+            match cfg.get("fullscreen").map(|v| v.as_str()) {
+                None | Some(Some("show")) => {}
+                Some(Some("hide" | "overlap")) => used = true,
+                Some(_) => error!("Ignoring invalid bar.fullscreen: use show, hide, or overlap"),
             }
-            let mut cfg = cfg.clone();
-            if let Some(table) = cfg.as_table_mut() {
-                table.insert("name".into(), data.name.clone().into());
+        }
+        if self.fullscreen_watched || !used {
+            return;
+        }
+        self.fullscreen_watched = true;
+        let this = self.this.clone();
+        let on_change = move |outputs : HashSet<String>| {
+            let state = match this.upgrade() {
+                Some(state) => state,
+                None => return,
+            };
+            spawn_noerr(async move {
+                state.borrow_mut().set_fullscreen_outputs(outputs);
+            });
+        };
+        if hyprland::is_running() {
+            hyprland::watch_fullscreen(on_change);
+        } else if sway::is_running() {
+            sway::watch_fullscreen(on_change);
+        } else if !crate::wlr::watch_fullscreen(self.runtime.wayland(), on_change) {
+            warn!("bar.fullscreen requires sway, hyprland, or wlr-foreign-toplevel-management");
+        }
+    }
+
+    /// Hide or move the exclusive zone of bars on outputs that gained or lost a fullscreen window
+    fn set_fullscreen_outputs(&mut self, outputs : HashSet<String>) {
+        if self.fullscreen_outputs == outputs {
+            return;
+        }
+        debug!("Outputs with fullscreen windows: {:?}", outputs);
+        self.fullscreen_outputs = outputs;
+        for bar in &mut self.bars {
+            let fullscreen = self.fullscreen_outputs.contains(&*bar.name);
+            match fullscreen_mode(&self.bar_config[bar.cfg_index]) {
+                Some("overlap") => bar.set_overlap(fullscreen, true, &self.runtime),
+                Some("hide") => bar.set_hidden(fullscreen, true, &self.runtime),
+                _ => {}
             }
+        }
+        self.runtime.wayland_mut().flush();
+    }
 
-            let bar = Bar::new(&self.runtime.wayland, &output, &data, cfg, i);
-            self.bars.retain(|bar| {
-                bar.cfg_index != i || &*bar.name != data.name
+    /// Move any bars that follow the focused output
+    fn set_focused_output(&mut self, name : String) {
+        if self.focused_output.as_ref() == Some(&name) {
+            return;
+        }
+        debug!("Focused output is now {}", name);
+        self.focused_output = Some(name);
+        self.move_bars("focused");
+        self.runtime.notify.notify_data("focus");
+    }
+
+    /// Move the bars configured with the given `output` (primary or focused) to the outputs they
+    /// match now, if that changed
+    fn move_bars(&mut self, mode : &str) {
+        let follow : Vec<usize> = self.bar_config.iter().enumerate()
+            .filter(|(_, cfg)| cfg.get("output").and_then(|v| v.as_str()) == Some(mode))
+            .map(|(i, _)| i)
+            .collect();
+        if follow.is_empty() {
+            return;
+        }
+        let mut wanted = Vec::new();
+        for output in self.runtime.wayland().env.get_all_outputs() {
+            with_output_info(&output, |oi| {
+                if oi.obsolete {
+                    return;
+                }
+                for &i in &follow {
+                    if self.bar_matches(&self.bar_config[i], oi) {
+                        wanted.push((i, oi.name.clone()));
+                    }
+                }
+            });
+        }
+        let mut current : Vec<_> = self.bars.iter()
+            .filter(|bar| follow.contains(&bar.cfg_index))
+            .map(|bar| (bar.cfg_index, String::from(&*bar.name)))
+            .collect();
+        wanted.sort();
+        current.sort();
+        if wanted == current {
+            return;
+        }
+        self.bars.retain(|bar| !follow.contains(&bar.cfg_index));
+        for output in self.runtime.wayland().env.get_all_outputs() {
+            with_output_info(&output, |oi| {
+                if oi.obsolete {
+                    return;
+                }
+                for &i in &follow {
+                    if self.bar_matches(&self.bar_config[i], oi) {
+                        self.add_bar(i, &output, oi);
+                    }
+                }
             });
-            self.bars.push(bar);
-            self.runtime.wayland.flush();
         }
     }
+}
 
+/// The bar.fullscreen setting: show, hide, or overlap
+fn fullscreen_mode(cfg : &toml::Value) -> Option<&str> {
+    cfg.get("fullscreen").and_then(|v| v.as_str())
 }
@@ -3,13 +3,17 @@ use crate::event::EventSink;
 use crate::item::Item;
 use crate::data::{IterationItem,Value};
 use crate::render::Render;
+use crate::screencopy::{self,PreviewPopup};
 use crate::state::Runtime;
 use crate::state::NotifierList;
-use crate::util::{Cell,spawn_noerr};
+use crate::util::{Cell,read_lines,spawn_noerr};
 use log::{warn,error};
 use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::convert::TryInto;
+use std::os::unix::io::AsRawFd;
+use std::process::Stdio;
 use std::rc::Rc;
 use tokio::net::UnixStream;
 use tokio::sync::Notify;
@@ -32,22 +36,46 @@ struct SwaySocket {
     listeners : Vec<(u32, Box<dyn FnMut(&[u8]) -> ListenerResult>)>,
 }
 
+/// Find the IPC socket of the running window manager.  Sway and i3 use the same protocol, so
+/// either one works; if neither variable is set, ask i3 directly.
+async fn socket_path() -> Option<std::ffi::OsString> {
+    if let Some(path) = std::env::var_os("SWAYSOCK").or_else(|| std::env::var_os("I3SOCK")) {
+        return Some(path);
+    }
+    // children are not waited for, so the end of the output marks the exit
+    let mut child = std::process::Command::new("i3")
+        .arg("--get-socketpath")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn().ok()?;
+    let stdout = child.stdout.take()?;
+    let fd = stdout.as_raw_fd();
+    unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+    let mut path = String::new();
+    if let Err(e) = read_lines(fd, "i3 --get-socketpath", |line| path.push_str(line.trim())).await {
+        warn!("Error reading output of i3 --get-socketpath: {}", e);
+    }
+    drop(stdout);
+    (!path.is_empty()).then(|| path.into())
+}
+
 impl SwaySocket {
     fn init() -> Self {
         let write_notify = Rc::new(Notify::new());
         let notify = write_notify.clone();
 
         spawn_noerr(async move {
-            let (mut rh, mut wh) = match match std::env::var_os("SWAYSOCK") {
+            let (mut rh, mut wh) = match match socket_path().await {
                 Some(path) => UnixStream::connect(path).await,
                 None => {
-                    error!("Could not connect to sway: no SWAYSOCK defined");
+                    error!("Could not connect to sway or i3: neither SWAYSOCK nor I3SOCK is defined, and i3 did not report a socket");
                     return;
                 }
             } {
                 Ok(sock) => sock.into_split(),
                 Err(e) => {
-                    error!("Could not connect to sway: {}", e);
+                    error!("Could not connect to sway or i3: {}", e);
                     return;
                 }
             };
@@ -66,7 +94,7 @@ impl SwaySocket {
                         match tokio::io::AsyncWriteExt::write_all(&mut wh, &wbuf).await {
                             Ok(()) => wbuf.clear(),
                             Err(e) => {
-                                error!("Error writing to SWAYSOCK: {}", e);
+                                error!("Error writing to the IPC socket: {}", e);
                                 return;
                             }
                         }
@@ -80,11 +108,11 @@ impl SwaySocket {
             'read : loop {
                 match tokio::io::AsyncReadExt::read_buf(&mut rh, &mut rbuf).await {
                     Ok(0) => {
-                        error!("End of file when reading from SWAYSOCK");
+                        error!("End of file when reading from the IPC socket");
                         return;
                     }
                     Err(e) => {
-                        error!("Error reading from SWAYSOCK: {}", e);
+                        error!("Error reading from the IPC socket: {}", e);
                         return;
                     }
                     Ok(_) => {}
@@ -196,6 +224,98 @@ impl SwaySocket {
     }
 }
 
+pub fn is_running() -> bool {
+    std::env::var_os("SWAYSOCK").or_else(|| std::env::var_os("I3SOCK")).is_some()
+}
+
+/// Call the function with the name of the focused output, and again whenever it changes
+pub fn watch_focused_output(f : impl FnMut(&str) + 'static) {
+    let f = Rc::new(RefCell::new(f));
+    let cb = f.clone();
+    SwaySocket::subscribe("workspace", 0x80000000, Box::new(move |buf| {
+        match std::str::from_utf8(buf).map(json::parse) {
+            Ok(Ok(msg)) if msg["change"].as_str() == Some("focus") => {
+                if let Some(output) = msg["current"]["output"].as_str() {
+                    (*cb.borrow_mut())(output);
+                }
+            }
+            Ok(Ok(_)) => {}
+            _ => warn!("Ignoring invalid workspace change message")
+        }
+        ListenerResult {
+            remove_callback : false,
+            consumed : false,
+        }
+    }));
+    SwaySocket::send(1, b"", move |buf| {
+        match std::str::from_utf8(buf).map(json::parse) {
+            Ok(Ok(msg)) => {
+                for workspace in msg.members() {
+                    if workspace["focused"].as_bool() == Some(true) {
+                        if let Some(output) = workspace["output"].as_str() {
+                            (*f.borrow_mut())(output);
+                        }
+                    }
+                }
+            }
+            _ => warn!("Ignoring invalid get_workspaces reply")
+        }
+    });
+}
+
+/// Check if any window in this node or its children is fullscreen
+fn has_fullscreen(node : &json::JsonValue) -> bool {
+    node["fullscreen_mode"].as_u32().unwrap_or(0) != 0 ||
+        node["nodes"].members().chain(node["floating_nodes"].members()).any(has_fullscreen)
+}
+
+/// Call the function with the names of the outputs showing a fullscreen window, and again after
+/// any window or workspace change
+pub fn watch_fullscreen(f : impl FnMut(HashSet<String>) + 'static) {
+    let f : Rc<RefCell<dyn FnMut(HashSet<String>)>> = Rc::new(RefCell::new(f));
+    fn refresh(f : Rc<RefCell<dyn FnMut(HashSet<String>)>>) {
+        SwaySocket::send(4, b"", move |buf| {
+            let tree = match std::str::from_utf8(buf).map(json::parse) {
+                Ok(Ok(tree)) => tree,
+                _ => {
+                    warn!("Ignoring invalid get_tree reply");
+                    return;
+                }
+            };
+            let mut outputs = HashSet::new();
+            for output in tree["nodes"].members() {
+                let name = match output["name"].as_str() {
+                    Some(name) if !name.starts_with("__") => name,
+                    _ => continue,
+                };
+                let current = output["current_workspace"].as_str();
+                if output["nodes"].members()
+                    .filter(|ws| ws["name"].as_str() == current)
+                    .any(has_fullscreen)
+                {
+                    outputs.insert(name.to_owned());
+                }
+            }
+            (*f.borrow_mut())(outputs);
+        });
+    }
+    for (name, id) in [("window", 0x80000003), ("workspace", 0x80000000)] {
+        let f = f.clone();
+        SwaySocket::subscribe(name, id, Box::new(move |_| {
+            // get_tree can't be sent from inside the callback
+            let f = f.clone();
+            spawn_noerr(async move {
+                refresh(f);
+            });
+            ListenerResult {
+                remove_callback : false,
+                consumed : false,
+            }
+        }));
+    }
+    refresh(f);
+}
+
 #[derive(Debug,Default)]
 pub struct Mode {
     value : Rc<ModeInner>,
@@ -268,19 +388,201 @@ impl Mode {
     }
 }
 
+/// The icon for an app_id: from its desktop entry if there is one, otherwise the app_id itself
+fn app_icon<F : FnOnce(Value) -> R,R>(appid : &str, rt : &Runtime, f : F) -> R {
+    match crate::icon::desktop_icon(&rt.xdg, appid) {
+        Some(icon) => f(Value::Borrow(&icon)),
+        None if appid.starts_with("org.kde.") => f(Value::Borrow(&appid[8..])),
+        None => f(Value::Borrow(appid)),
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct Window {
+    value : Rc<WindowInner>,
+}
+
+#[derive(Debug,Default)]
+struct WindowInner {
+    /// The con_id of the focused window, if any
+    id : Cell<Option<u32>>,
+    title : Cell<String>,
+    appid : Cell<String>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl WindowInner {
+    fn set_focus(&self, node : &json::JsonValue) {
+        self.id.set(node["id"].as_u32());
+        self.title.set(node["name"].as_str().unwrap_or("").to_owned());
+        self.appid.set(node["app_id"].as_str()
+            .or_else(|| node["window_properties"]["class"].as_str())
+            .unwrap_or("").to_owned());
+    }
+
+    fn clear(&self) {
+        self.id.set(None);
+        self.title.set(String::new());
+        self.appid.set(String::new());
+    }
+
+    /// Find the focused window in a reply to get_tree
+    fn find_focus(node : &json::JsonValue) -> Option<&json::JsonValue> {
+        if node["focused"].as_bool() == Some(true) {
+            return Some(node);
+        }
+        node["nodes"].members()
+            .chain(node["floating_nodes"].members())
+            .find_map(Self::find_focus)
+    }
+}
+
+impl Window {
+    pub fn from_toml(_config : &toml::Value) -> Self {
+        Window::default()
+    }
+
+    fn interest(&self, rt : &Runtime) {
+        self.value.interested.take_in(|i| i.add(rt));
+        if self.value.running.replace(true) {
+            return;
+        }
+
+        let weak = Rc::downgrade(&self.value);
+        SwaySocket::subscribe("window", 0x80000003, Box::new(move |buf| {
+            let remove_callback;
+            if let Some(wi) = weak.upgrade() {
+                match std::str::from_utf8(buf).map(json::parse) {
+                    Ok(Ok(msg)) => {
+                        let con = &msg["container"];
+                        let is_current = con["id"].as_u32().is_some() && con["id"].as_u32() == wi.id.get();
+                        match msg["change"].as_str() {
+                            Some("focus") => wi.set_focus(con),
+                            Some("title") if is_current => wi.set_focus(con),
+                            Some("close") if is_current => wi.clear(),
+                            _ => {}
+                        }
+                        wi.interested.take().notify_data("sway:window");
+                    }
+                    _ => warn!("Ignoring invalid window change message")
+                }
+                remove_callback = false;
+            } else {
+                remove_callback = true;
+            }
+            ListenerResult {
+                remove_callback,
+                consumed : false,
+            }
+        }));
+
+        let weak = Rc::downgrade(&self.value);
+        SwaySocket::subscribe("workspace", 0x80000000, Box::new(move |buf| {
+            let remove_callback;
+            if let Some(wi) = weak.upgrade() {
+                match std::str::from_utf8(buf).map(json::parse) {
+                    Ok(Ok(msg)) => {
+                        // switching to an empty workspace does not produce a window event
+                        let wks = &msg["current"];
+                        if msg["change"].as_str() == Some("focus") && wks["nodes"].is_empty() && wks["floating_nodes"].is_empty() {
+                            wi.clear();
+                            wi.interested.take().notify_data("sway:window");
+                        }
+                    }
+                    _ => warn!("Ignoring invalid workspace change message")
+                }
+                remove_callback = false;
+            } else {
+                remove_callback = true;
+            }
+            ListenerResult {
+                remove_callback,
+                consumed : false,
+            }
+        }));
+
+        let value = self.value.clone();
+        SwaySocket::send(4, b"", move |buf| {
+            match std::str::from_utf8(buf).map(json::parse) {
+                Ok(Ok(msg)) => {
+                    match WindowInner::find_focus(&msg) {
+                        Some(node) if node["type"].as_str() != Some("workspace") => value.set_focus(node),
+                        _ => value.clear(),
+                    }
+                    value.interested.take().notify_data("sway:window");
+                }
+                _ => warn!("Ignoring invalid get_tree reply")
+            }
+        });
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, _name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        self.interest(rt);
+        match key {
+            "" | "text" | "title" => self.value.title.take_in(|t| f(Value::Borrow(t))),
+            "appid" => self.value.appid.take_in(|a| f(Value::Borrow(a))),
+            "icon" => self.value.appid.take_in(|a| if a.is_empty() { f(Value::Null) } else { app_icon(a, rt, f) }),
+            "id" => f(self.value.id.get().map_or(Value::Null, |id| Value::Float(id as f64))),
+            "tooltip" => f(Value::Null),
+            _ => {
+                warn!("Unknown key in sway-window");
+                f(Value::Null)
+            }
+        }
+    }
+
+    pub fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
+        match (key, self.value.id.get()) {
+            ("", Some(id)) => SwaySocket::send(0, format!("[con_id={}] {}", id, value).as_bytes(), |_| ()),
+            ("", None) => {}
+            _ => error!("Ignoring write to {}.{}", name, key),
+        }
+    }
+}
+
 #[derive(Debug,Clone)]
 pub struct WorkspaceData {
     name : String,
     output : String,
     repr : String,
+    /// False for a persistent workspace that sway has not created
+    exists : bool,
+    /// Display the name without its numeric prefix
+    strip : bool,
 }
 
 impl WorkspaceData {
+    fn new(name : String, output : String, repr : String, strip : bool) -> Self {
+        WorkspaceData { name, output, repr, exists : true, strip }
+    }
+
+    /// The name with any numeric prefix (such as the "1:" in "1:web") removed
+    fn short_name(&self) -> &str {
+        let rest = self.name.trim_start_matches(|c : char| c.is_ascii_digit());
+        if rest.len() == self.name.len() {
+            return &self.name;
+        }
+        match rest.strip_prefix(':').unwrap_or(rest) {
+            "" => &self.name,
+            short => short,
+        }
+    }
+
     pub fn read_in<F : FnOnce(Value) -> R,R>(&self, key : &str, _rt : &Runtime, f : F) -> R {
         match key {
+            "text" | "" if self.strip => {
+                f(Value::Borrow(self.short_name()))
+            }
             "name" | "text" | "" => {
                 f(Value::Borrow(&self.name))
             }
+            "short" => {
+                f(Value::Borrow(self.short_name()))
+            }
+            "empty" => {
+                f(Value::Bool(!self.exists))
+            }
             "output" | "tooltip" => {
                 f(Value::Borrow(&self.output))
             }
@@ -332,12 +634,21 @@ struct WorkspacesData {
     focus : Cell<String>,
     list : Cell<Vec<Rc<WorkspaceData>>>,
     running : Cell<bool>,
+    strip : bool,
+    /// Capture a preview of each workspace when it is shown
+    preview : bool,
+    /// Workspaces that were just shown, and their outputs, waiting to be captured
+    capture : Cell<Vec<(String, String)>>,
     interested : Cell<NotifierList>,
 }
 
 #[derive(Debug)]
 pub struct Workspace {
     output : Option<Box<str>>,
+    /// Workspaces that are always shown, and the outputs they are shown on (empty for all)
+    persistent : Vec<(Box<str>, Vec<Box<str>>)>,
+    /// Items for persistent workspaces that do not exist, kept so they compare equal between renders
+    placeholders : Cell<Vec<Rc<WorkspaceData>>>,
     value : Rc<WorkspacesData>,
 }
 
@@ -347,6 +658,9 @@ impl WorkspacesData {
             Some("focus") => {
                 if let Some(name) = msg["current"]["name"].as_str() {
                     self.focus.set(name.to_owned());
+                    if let (true, Some(output)) = (self.preview, msg["current"]["output"].as_str()) {
+                        self.capture.take_in(|c| c.push((output.to_owned(), name.to_owned())));
+                    }
                     if let Some(repr) = msg["current"]["representation"].as_str() {
                         self.list.take_in(|list| {
                             for wks in list {
@@ -362,14 +676,15 @@ impl WorkspacesData {
                 }
             }
             Some("init") => {
-                let new = WorkspaceData {
-                    name : match msg["current"]["name"].as_str() {
+                let new = WorkspaceData::new(
+                    match msg["current"]["name"].as_str() {
                         Some(n) => n.to_owned(),
                         None => return,
                     },
-                    output : msg["current"]["output"].as_str().unwrap_or("").to_owned(),
-                    repr : msg["current"]["representation"].as_str().unwrap_or("").to_owned(),
-                };
+                    msg["current"]["output"].as_str().unwrap_or("").to_owned(),
+                    msg["current"]["representation"].as_str().unwrap_or("").to_owned(),
+                    self.strip,
+                );
                 // Note: sway will sometimes send duplicate "init" messages
                 self.list.take_in(|list| {
                     for wks in &mut *list {
@@ -428,12 +743,57 @@ impl WorkspacesData {
 impl Workspace {
     pub fn from_toml(config : &toml::Value) -> Self {
         let output = config.get("output").and_then(|v| v.as_str()).map(Into::into);
+        let strip = config.get("strip-numbers").and_then(|v| v.as_bool()).unwrap_or(false);
+        let preview = config.get("preview").and_then(|v| v.as_bool()).unwrap_or(false);
+        let mut persistent = Vec::new();
+        match config.get("persistent") {
+            None => {}
+            Some(toml::Value::Array(names)) => {
+                for name in names {
+                    match crate::util::toml_to_string(Some(name)) {
+                        Some(name) => persistent.push((name.into(), Vec::new())),
+                        None => error!("Invalid persistent workspace name: {}", name),
+                    }
+                }
+            }
+            Some(toml::Value::Table(names)) => {
+                for (name, outputs) in names {
+                    let outputs = match outputs {
+                        toml::Value::String(o) => vec![o.as_str().into()],
+                        toml::Value::Array(list) => list.iter().filter_map(|o| o.as_str()).map(Into::into).collect(),
+                        _ => {
+                            error!("Invalid output list for persistent workspace {}", name);
+                            continue;
+                        }
+                    };
+                    persistent.push((name.as_str().into(), outputs));
+                }
+            }
+            Some(v) => error!("Invalid persistent workspace list: {}", v),
+        }
         Workspace {
             output,
-            value : Default::default(),
+            persistent,
+            placeholders : Default::default(),
+            value : Rc::new(WorkspacesData { strip, preview, ..Default::default() }),
         }
     }
 
+    /// Get the item for a persistent workspace that does not exist
+    fn placeholder(&self, name : &str, output : &str) -> Rc<WorkspaceData> {
+        self.placeholders.take_in(|list| {
+            if let Some(wks) = list.iter().find(|wks| wks.name == name && wks.output == output) {
+                return wks.clone();
+            }
+            let wks = Rc::new(WorkspaceData {
+                exists : false,
+                ..WorkspaceData::new(name.into(), output.into(), String::new(), self.value.strip)
+            });
+            list.push(wks.clone());
+            wks
+        })
+    }
+
     fn interest(&self, rt : &Runtime) {
         self.value.interested.take_in(|i| i.add(rt));
         if self.value.running.replace(true) {
@@ -467,14 +827,18 @@ impl Workspace {
                 Ok(Ok(msg)) => {
                     let mut list = Vec::new();
                     for workspace in msg.members() {
-                        let new = WorkspaceData {
-                            name : workspace["name"].as_str().unwrap_or("").to_owned(),
-                            output : workspace["output"].as_str().unwrap_or("").to_owned(),
-                            repr : workspace["representation"].as_str().unwrap_or("").to_owned(),
-                        };
+                        let new = WorkspaceData::new(
+                            workspace["name"].as_str().unwrap_or("").to_owned(),
+                            workspace["output"].as_str().unwrap_or("").to_owned(),
+                            workspace["representation"].as_str().unwrap_or("").to_owned(),
+                            value.strip,
+                        );
                         if workspace["focused"].as_bool() == Some(true) {
                             value.focus.set(new.name.clone());
                         }
+                        if value.preview && workspace["visible"].as_bool() == Some(true) {
+                            value.capture.take_in(|c| c.push((new.output.clone(), new.name.clone())));
+                        }
                         list.push(Rc::new(new));
                     }
                     list.sort_by(sway_sort_fn);
@@ -498,21 +862,50 @@ impl Workspace {
         }
     }
 
+    /// The preview popup for a workspace, if previews are enabled
+    pub fn preview(&self, data : &WorkspaceData) -> Option<PreviewPopup> {
+        (self.value.preview && data.exists).then(|| PreviewPopup::new(&data.name))
+    }
+
     pub fn read_focus_list<F : FnMut(bool, IterationItem)>(&self, rt : &Runtime, mut f : F) {
         self.interest(rt);
+        for (output, name) in self.value.capture.take() {
+            screencopy::capture(rt, &output, &name);
+        }
         let output = self.output.as_ref()
             .map(|v| rt.format_or(&v, "sway-workspace").into_text())
             .unwrap_or_default();
         let focus = self.value.focus.take_in(|f| f.clone());
-        self.value.list.take_in(|list| {
-            for item in &*list {
-                let focus = item.name == focus;
-                if !output.is_empty() && item.output != output {
+        let mut items = self.value.list.take_in(|list| {
+            list.iter()
+                .filter(|item| output.is_empty() || item.output == output)
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+        if !self.persistent.is_empty() {
+            let existing = self.value.list.take_in(|list| {
+                list.iter().map(|item| item.name.clone()).collect::<Vec<_>>()
+            });
+            for (name, outputs) in &self.persistent {
+                if existing.iter().any(|e| **e == **name) {
+                    continue;
+                }
+                if !output.is_empty() && !outputs.is_empty() && !outputs.iter().any(|o| **o == *output) {
                     continue;
                 }
-                f(focus, IterationItem::SwayWorkspace(item.clone()));
+                let on = if output.is_empty() {
+                    outputs.first().map_or("", |o| &**o)
+                } else {
+                    &*output
+                };
+                items.push(self.placeholder(name, on));
             }
-        });
+            items.sort_by(sway_sort_fn);
+        }
+        for item in items {
+            let focus = item.name == focus;
+            f(focus, IterationItem::SwayWorkspace(item));
+        }
     }
 
     pub fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
@@ -542,6 +935,7 @@ enum NodeType {
     Window {
         title : Cell<Box<str>>,
         appid : Box<str>, // or Class if null
+        max_title : Option<usize>,
     },
 }
 
@@ -549,12 +943,14 @@ enum NodeType {
 pub struct Node {
     id : u32,
     focus : bool,
+    urgent : bool,
+    depth : u32,
     marks : String, // "" or "1" or "mark-name, another-mark"
     contents : NodeType,
 }
 
 impl Node {
-    fn parse(value : &mut json::JsonValue) -> Node {
+    fn parse(value : &mut json::JsonValue, depth : u32, max_title : Option<usize>) -> Node {
         let mut marks = String::new();
         for (i, mark) in value["marks"].members().enumerate() {
             if i != 0 {
@@ -575,17 +971,22 @@ impl Node {
                         appid : value["app_id"].take_string()
                             .or_else(|| value["window_properties"]["class"].take_string())
                             .unwrap_or_default().into(),
+                        max_title,
                     };
                 }
             };
             break NodeType::Container {
                 layout,
-                children : value["nodes"].members_mut().map(Node::parse).map(Rc::new).collect(),
+                children : value["nodes"].members_mut()
+                    .map(|v| Rc::new(Node::parse(v, depth + 1, max_title)))
+                    .collect(),
             };
         };
         Node {
             id : value["id"].as_u32().unwrap_or(!0),
             focus : value["focused"].as_bool().unwrap_or(false),
+            urgent : value["urgent"].as_bool().unwrap_or(false),
+            depth,
             marks,
             contents,
         }
@@ -606,14 +1007,21 @@ impl Node {
                 }
             }
             NodeType::Window { .. } => {
-                if let Some(item) = &items.window {
+                let state_item = if self.urgent {
+                    &items.urgent_window
+                } else if self.focus {
+                    &items.focused_window
+                } else {
+                    &None
+                };
+                if let Some(item) = state_item.as_ref().or(items.window.as_ref()) {
                     item.render_clamped_item(ctx, ev, &ii);
                 }
             }
         }
     }
 
-    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, key : &str, _rt : &Runtime, f : F) -> R {
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, key : &str, rt : &Runtime, f : F) -> R {
         match (key, &self.contents) {
             ("id", _) => {
                 f(Value::Float(self.id as f64))
@@ -624,17 +1032,31 @@ impl Node {
             ("focus", _) => {
                 f(Value::Bool(self.focus))
             }
+            ("urgent", _) => {
+                f(Value::Bool(self.urgent))
+            }
+            ("depth", _) => {
+                f(Value::Float(self.depth as f64))
+            }
             ("appid", NodeType::Window { appid, .. }) => {
                 f(Value::Borrow(appid))
             }
             ("icon", NodeType::Window { appid, .. }) => {
-                if appid.starts_with("org.kde.") {
-                    f(Value::Borrow(&appid[8..]))
-                } else {
-                    f(Value::Borrow(appid))
-                }
+                app_icon(appid, rt, f)
+            }
+            ("title", NodeType::Window { title, max_title, .. }) => {
+                f(Value::Owned(title.take_in(|t| {
+                    match max_title {
+                        Some(max) if t.chars().count() > *max => {
+                            let mut s : String = t.chars().take(max - 1).collect();
+                            s.push('…');
+                            s
+                        }
+                        _ => String::from(&**t),
+                    }
+                })))
             }
-            ("title", NodeType::Window { title, .. }) => {
+            ("full-title", NodeType::Window { title, .. }) => {
                 f(Value::Owned(title.take_in(|t| String::from(&**t))))
             }
             ("layout", NodeType::Container { layout, ..}) => {
@@ -681,17 +1103,19 @@ struct WorkspaceNode {
 }
 
 impl WorkspaceNode {
-    fn parse_tree(mut value : json::JsonValue) -> Vec<WorkspaceNode> {
+    fn parse_tree(mut value : json::JsonValue, max_title : Option<usize>) -> Vec<WorkspaceNode> {
         let mut rv = Vec::new();
         for output in value["nodes"].members_mut() {
             let output_name = output["name"].as_str().unwrap_or_default().to_owned();
             for workspace in output["nodes"].members_mut() {
-                let repr = Rc::new(Node::parse(workspace));
+                let repr = Rc::new(Node::parse(workspace, 0, max_title));
                 rv.push(WorkspaceNode {
                     output : output_name.clone(),
                     name : workspace["name"].take_string().unwrap_or_default(),
                     repr,
-                    floating : workspace["floating_nodes"].members_mut().map(Node::parse).map(Rc::new).collect(),
+                    floating : workspace["floating_nodes"].members_mut()
+                        .map(|v| Rc::new(Node::parse(v, 1, max_title)))
+                        .collect(),
                 });
             }
         }
@@ -712,6 +1136,8 @@ struct TreeItems {
     pre_workspace : Option<Rc<Item>>,
     pre_node : Option<Rc<Item>>,
     window : Option<Rc<Item>>,
+    focused_window : Option<Rc<Item>>,
+    urgent_window : Option<Rc<Item>>,
     post_node : Option<Rc<Item>>,
     pre_floats : Option<Rc<Item>>,
     pre_float : Option<Rc<Item>>,
@@ -724,6 +1150,7 @@ struct TreeInner {
     workspaces : Cell<Option<Vec<WorkspaceNode>>>,
     running : Cell<bool>,
     interested : Cell<NotifierList>,
+    max_title : Option<usize>,
 }
 
 impl TreeInner {
@@ -731,7 +1158,7 @@ impl TreeInner {
         SwaySocket::send(4, b"", move |buf| {
             match std::str::from_utf8(buf).map(|buf| json::parse(buf)) {
                 Ok(Ok(msg)) => {
-                    value.workspaces.set(Some(WorkspaceNode::parse_tree(msg)));
+                    value.workspaces.set(Some(WorkspaceNode::parse_tree(msg, value.max_title)));
                     value.interested.take().notify_data("sway:tree");
                 }
                 _ => warn!("Ignoring invalid get_binding_state reply")
@@ -760,11 +1187,43 @@ impl TreeInner {
 
 impl Tree {
     pub fn from_toml(config : &toml::Value) -> Self {
+        let nesting = config.get("nesting").and_then(|v| v.as_bool()).unwrap_or(false);
+        let icons = config.get("icons").and_then(|v| v.as_bool()).unwrap_or(false);
+        let window = config.get("window").cloned().or_else(|| icons.then(Self::default_window));
+        // The per-state blocks are applied on top of the window block, so they only need to
+        // contain the keys (often just the colors) that differ
+        let state_window = |key : &str| -> Option<Rc<Item>> {
+            let state = config.get(key)?;
+            let item = match (window.as_ref().and_then(|w| w.as_table()), state.as_table()) {
+                (Some(base), Some(overrides)) => {
+                    let mut merged = base.clone();
+                    for (k, v) in overrides {
+                        merged.insert(k.clone(), v.clone());
+                    }
+                    Item::from_toml_ref(&toml::Value::Table(merged))
+                }
+                _ => Item::from_toml_ref(state),
+            };
+            Some(Rc::new(item))
+        };
+        let node_item = |key : &str, format : &str| {
+            match config.get(key) {
+                Some(v) => Some(Rc::new(Item::from_toml_ref(v))),
+                None if nesting => {
+                    let mut item = toml::value::Table::new();
+                    item.insert("format".into(), format.into());
+                    Some(Rc::new(Item::from_toml_ref(&toml::Value::Table(item))))
+                }
+                None => None,
+            }
+        };
         let items = TreeItems {
             pre_workspace : config.get("pre-workspace").map(Item::from_toml_ref).map(Rc::new),
-            pre_node : config.get("pre-node").map(Item::from_toml_ref).map(Rc::new),
-            window : config.get("window").map(Item::from_toml_ref).map(Rc::new),
-            post_node : config.get("post-node").map(Item::from_toml_ref).map(Rc::new),
+            pre_node : node_item("pre-node", "{item.layout}["),
+            focused_window : state_window("focused-window"),
+            urgent_window : state_window("urgent-window"),
+            window : window.as_ref().map(Item::from_toml_ref).map(Rc::new),
+            post_node : node_item("post-node", "]"),
             pre_floats : config.get("pre-floats").map(Item::from_toml_ref).map(Rc::new),
             pre_float : config.get("pre-float").map(Item::from_toml_ref).map(Rc::new),
             post_float : config.get("post-float").map(Item::from_toml_ref).map(Rc::new),
@@ -772,14 +1231,29 @@ impl Tree {
         };
         let output = config.get("output").and_then(|v| v.as_str()).map(Into::into);
         let workspace = config.get("workspace").and_then(|v| v.as_str()).map(Into::into);
+        let max_title = config.get("max-title").and_then(|v| v.as_integer()).map(|v| v.max(1) as usize);
         Tree {
-            value : Default::default(),
+            value : Rc::new(TreeInner { max_title, ..Default::default() }),
             items : Box::new(items),
             output,
             workspace,
         }
     }
 
+    /// The window block used for `icons = true` if none is configured
+    fn default_window() -> toml::Value {
+        let mut item = toml::value::Table::new();
+        item.insert("type".into(), "icon".into());
+        item.insert("name".into(), "{item.icon}".into());
+        item.insert("fallback".into(), "{item.title}".into());
+        item.insert("tooltip".into(), "{item.title}".into());
+        let mut click = toml::value::Table::new();
+        click.insert("send".into(), "item".into());
+        click.insert("format".into(), "focus".into());
+        item.insert("on-click".into(), toml::Value::Table(click));
+        toml::Value::Table(item)
+    }
+
     fn interest(&self, rt : &Runtime) {
         self.value.interested.take_in(|i| i.add(rt));
         if self.value.running.replace(true) {
@@ -846,11 +1320,12 @@ impl Tree {
                 if !name_filter.is_empty() && workspace.name != name_filter {
                     continue;
                 }
-                let ii = IterationItem::SwayWorkspace(Rc::new(WorkspaceData {
-                    name : workspace.name.clone(),
-                    output : workspace.output.clone(),
-                    repr : String::new(), // TODO
-                }));
+                let ii = IterationItem::SwayWorkspace(Rc::new(WorkspaceData::new(
+                    workspace.name.clone(),
+                    workspace.output.clone(),
+                    String::new(), // TODO
+                    false,
+                )));
                 if let Some(item) = &items.pre_workspace {
                     item.render_clamped_item(ctx, ev, &ii);
                 }
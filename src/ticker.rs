@@ -0,0 +1,336 @@
+//! Stock and cryptocurrency prices from HTTP APIs
+use crate::data::{Periodic,Value};
+use crate::registry::{Entry,Source};
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,toml_to_f64};
+use json::JsonValue;
+use log::{debug,warn};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash,Hasher};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+
+/// Where prices come from
+#[derive(Debug)]
+enum Provider {
+    /// CoinGecko's free API; all symbols are read in one request
+    CoinGecko,
+    /// Yahoo Finance charts, one request per symbol
+    Yahoo,
+    /// Any JSON API, one request per symbol
+    Custom {
+        url : Box<str>,
+        price : Box<str>,
+        change : Option<Box<str>>,
+        previous : Option<Box<str>>,
+    },
+}
+
+/// A number, which some APIs send as a string
+fn number(v : &JsonValue) -> Option<f64> {
+    v.as_f64().or_else(|| v.as_str().and_then(|s| s.trim().parse().ok()))
+}
+
+/// Follow a path like `data.quotes.0.price`
+fn lookup<'a>(mut v : &'a JsonValue, path : &str) -> &'a JsonValue {
+    for part in path.split('.').filter(|p| !p.is_empty()) {
+        v = match (v, part.parse::<usize>()) {
+            (JsonValue::Array(a), Ok(i)) => a.get(i).unwrap_or(&JsonValue::Null),
+            _ => &v[part],
+        };
+    }
+    v
+}
+
+fn percent(price : Option<f64>, previous : Option<f64>) -> Option<f64> {
+    match (price, previous) {
+        (Some(p), Some(prev)) if prev != 0.0 => Some((p - prev) / prev * 100.0),
+        _ => None,
+    }
+}
+
+impl Provider {
+    async fn fetch(&self, prices : &Prices) -> Result<(), String> {
+        match self {
+            Provider::CoinGecko => {
+                let ids : Vec<_> = prices.quotes.iter().map(|q| &*q.symbol).collect();
+                let url = format!("https://api.coingecko.com/api/v3/simple/price?ids={}&vs_currencies={}&include_24hr_change=true",
+                    ids.join(","), prices.currency);
+                let json = prices.get(&url).await?;
+                let change = format!("{}_24h_change", prices.currency);
+                let mut found = false;
+                for quote in &prices.quotes {
+                    let data = &json[&*quote.symbol];
+                    let price = number(&data[&*prices.currency]);
+                    found |= price.is_some();
+                    quote.set(price, number(&data[&*change]), &prices.currency);
+                }
+                match found {
+                    true => Ok(()),
+                    false => Err(format!("no prices in the reply from {}", url)),
+                }
+            }
+            Provider::Yahoo => {
+                let mut rv = Ok(());
+                for quote in &prices.quotes {
+                    let url = format!("https://query1.finance.yahoo.com/v8/finance/chart/{}?range=1d&interval=1d", quote.symbol);
+                    match prices.get(&url).await {
+                        Ok(json) => {
+                            let meta = &json["chart"]["result"][0]["meta"];
+                            let price = number(&meta["regularMarketPrice"]);
+                            let previous = number(&meta["chartPreviousClose"]).or_else(|| number(&meta["previousClose"]));
+                            quote.set(price, percent(price, previous), meta["currency"].as_str().unwrap_or(&prices.currency));
+                        }
+                        Err(e) => rv = Err(e),
+                    }
+                }
+                rv
+            }
+            Provider::Custom { url, price, change, previous } => {
+                let mut rv = Ok(());
+                for quote in &prices.quotes {
+                    let url = url.replace("{symbol}", &quote.symbol);
+                    match prices.get(&url).await {
+                        Ok(json) => {
+                            let p = number(lookup(&json, price));
+                            let c = match (change, previous) {
+                                (Some(path), _) => number(lookup(&json, path)),
+                                (None, Some(path)) => percent(p, number(lookup(&json, path))),
+                                (None, None) => None,
+                            };
+                            quote.set(p, c, &prices.currency);
+                        }
+                        Err(e) => rv = Err(e),
+                    }
+                }
+                rv
+            }
+        }
+    }
+}
+
+/// The price of one symbol
+#[derive(Debug)]
+pub struct Quote {
+    symbol : Box<str>,
+    price : Cell<Option<f64>>,
+    /// Percent change since the previous close (or over 24 hours)
+    change : Cell<Option<f64>>,
+    currency : Cell<Option<String>>,
+}
+
+impl Quote {
+    fn set(&self, price : Option<f64>, change : Option<f64>, currency : &str) {
+        self.price.set(price);
+        self.change.set(change);
+        self.currency.set(Some(currency.to_owned()));
+    }
+
+    /// "bitcoin 64123.45 +1.23%"
+    fn text(&self) -> String {
+        match (self.price.get(), self.change.get()) {
+            (Some(p), Some(c)) => format!("{} {:.2} {:+.2}%", self.symbol, p, c),
+            (Some(p), None) => format!("{} {:.2}", self.symbol, p),
+            (None, _) => format!("{} ?", self.symbol),
+        }
+    }
+
+}
+
+impl Entry for Quote {
+    fn kind(&self) -> &'static str {
+        "quote"
+    }
+
+    fn read<'a>(&'a self, key : &str, _rt : &Runtime) -> Value<'a> {
+        match key {
+            "" | "text" => Value::Owned(self.text()),
+            "symbol" => Value::Borrow(&self.symbol),
+            "price" => self.price.get().map_or(Value::Null, Value::Float),
+            "change" => self.change.get().map_or(Value::Null, Value::Float),
+            "up" => self.change.get().map_or(Value::Null, |c| Value::Bool(c >= 0.0)),
+            "currency" => self.currency.take_in(|c| c.clone()).map_or(Value::Null, Value::Owned),
+            _ => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Prices {
+    provider : Provider,
+    currency : Box<str>,
+    fetch : Box<str>,
+    period : Duration,
+    quotes : Vec<Rc<Quote>>,
+    /// Time of the last successful read
+    updated : Cell<Option<Instant>>,
+    failures : Cell<u32>,
+    /// Skip polls until this time after a failure
+    retry_at : Cell<Option<Instant>>,
+    error : Cell<Option<String>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+/// Replies are cached in the runtime directory, so that reloading the configuration or running
+/// several bars does not use up an API's request limit
+fn cache_path(url : &str) -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    Some(PathBuf::from(dir).join(format!("rwaybar-ticker-{:016x}.json", hasher.finish())))
+}
+
+impl Prices {
+    /// Read and parse a URL, using the cached reply if it is recent enough
+    async fn get(&self, url : &str) -> Result<JsonValue, String> {
+        let cache = cache_path(url);
+        if let Some(path) = &cache {
+            let fresh = fs::metadata(path).and_then(|m| m.modified()).ok()
+                .and_then(|m| m.elapsed().ok())
+                .is_some_and(|age| age < self.period);
+            if let Some(json) = fresh.then(|| fs::read_to_string(path).ok()).flatten().and_then(|t| json::parse(&t).ok()) {
+                debug!("Using cached reply for {}", url);
+                return Ok(json);
+            }
+        }
+        let text = util::shell_output(&self.fetch, &[url]).await
+            .map_err(|e| format!("could not run {}: {}", self.fetch, e))?;
+        let json = json::parse(&text).map_err(|e| format!("bad reply from {}: {}", url, e))?;
+        if let Some(path) = &cache {
+            if let Err(e) = fs::write(path, &text) {
+                debug!("Could not cache {}: {}", url, e);
+            }
+        }
+        Ok(json)
+    }
+
+    async fn refresh(self : Rc<Self>) {
+        let now = Instant::now();
+        if self.retry_at.get().is_some_and(|t| now < t) {
+            return;
+        }
+        if self.running.replace(true) {
+            return;
+        }
+        match self.provider.fetch(&self).await {
+            Ok(()) => {
+                self.failures.set(0);
+                self.retry_at.set(None);
+                self.error.set(None);
+                self.updated.set(Some(Instant::now()));
+            }
+            Err(e) => {
+                // Back off exponentially, up to an hour, so a rate limit can recover
+                let failures = self.failures.get() + 1;
+                self.failures.set(failures);
+                let delay = (self.period * 2u32.pow(failures.min(6))).min(Duration::from_secs(3600)).max(self.period);
+                warn!("Could not read prices, retrying in {}s: {}", delay.as_secs(), e);
+                self.retry_at.set(Some(now + delay));
+                self.error.set(Some(e));
+            }
+        }
+        self.interested.take().notify_data("ticker");
+        self.running.set(false);
+    }
+}
+
+/// The "ticker" module
+#[derive(Debug)]
+pub struct Ticker {
+    poll : Periodic<Rc<Prices>>,
+}
+
+impl Ticker {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let str_key = |key : &str| config.get(key).and_then(|v| v.as_str());
+        let provider = match (str_key("provider"), str_key("url")) {
+            (Some("coingecko"), _) | (None, None) => Provider::CoinGecko,
+            (Some("yahoo"), _) => Provider::Yahoo,
+            (Some("custom"), Some(url)) | (None, Some(url)) => Provider::Custom {
+                url : url.into(),
+                price : str_key("price-path").ok_or("A custom ticker needs a 'price-path'")?.into(),
+                change : str_key("change-path").map(Into::into),
+                previous : str_key("previous-path").map(Into::into),
+            },
+            (Some("custom"), None) => return Err("A custom ticker needs a 'url'".into()),
+            (Some(p), _) => return Err(format!("Unknown ticker provider '{}'", p)),
+        };
+        let symbols : Vec<_> = match config.get("symbols") {
+            Some(toml::Value::String(s)) => vec![s.as_str()],
+            Some(toml::Value::Array(a)) => a.iter().filter_map(|v| v.as_str()).collect(),
+            _ => return Err("A ticker needs a list of 'symbols'".into()),
+        };
+        // Free API tiers allow a few requests per minute at most
+        let period = toml_to_f64(config.get("poll")).unwrap_or(300.0).max(60.0);
+        let prices = Prices {
+            provider,
+            currency : str_key("currency").unwrap_or("usd").into(),
+            fetch : str_key("fetch-command").unwrap_or("curl -sfL --max-time 30 -A Mozilla/5.0").into(),
+            period : Duration::from_secs_f64(period),
+            quotes : symbols.into_iter().map(|symbol| Rc::new(Quote {
+                symbol : symbol.into(),
+                price : Cell::new(None),
+                change : Cell::new(None),
+                currency : Cell::new(None),
+            })).collect(),
+            updated : Cell::new(None),
+            failures : Cell::new(0),
+            retry_at : Cell::new(None),
+            error : Cell::new(None),
+            running : Cell::new(false),
+            interested : Cell::default(),
+        };
+        Ok(Ticker {
+            poll : Periodic::new(period, Rc::new(prices)),
+        })
+    }
+
+    fn prices(&self, rt : &Runtime) -> &Prices {
+        self.poll.read_refresh_async(rt, |prices| prices.clone().refresh());
+        let prices = self.poll.data();
+        prices.interested.take_in(|i| i.add(rt));
+        prices
+    }
+}
+
+impl Source for Ticker {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        let prices = self.prices(rt);
+        match key {
+            "" | "text" => {
+                let text : Vec<_> = prices.quotes.iter().map(|q| q.text()).collect();
+                Value::Owned(text.join("  "))
+            }
+            "error" => prices.error.take_in(|e| e.clone()).map_or(Value::Null, Value::Owned),
+            "age" => prices.updated.get().map_or(Value::Null, |t| Value::Float(t.elapsed().as_secs_f64())),
+            "stale" => Value::Bool(prices.retry_at.get().is_some()),
+            _ => {
+                // "bitcoin" or "bitcoin.change"
+                let (symbol, field) = match prices.quotes.iter().any(|q| &*q.symbol == key) {
+                    true => (key, "price"),
+                    false => key.rsplit_once('.').unwrap_or((key, "price")),
+                };
+                match prices.quotes.iter().find(|q| &*q.symbol == symbol) {
+                    Some(quote) => quote.read(field, rt),
+                    None => Value::Null,
+                }
+            }
+        }
+    }
+
+    /// Read now, even if waiting after a failure
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.data().retry_at.set(None);
+        self.poll.refresh();
+        true
+    }
+
+    fn read_focus_list(&self, rt : &Runtime, f : &mut dyn FnMut(bool, Rc<dyn Entry>)) {
+        for quote in &self.prices(rt).quotes {
+            f(false, quote.clone());
+        }
+    }
+}
@@ -0,0 +1,483 @@
+//! Countdown timers and stopwatches
+//!
+//! The "timer" module is a single countdown, optionally cycling through several phases (for
+//! example, a pomodoro timer); the "stopwatch" module is the same without a duration.  The
+//! "timers" module holds any number of named timers that are created while the bar is running.
+use crate::data::{IterationItem,Value};
+use crate::event::Action;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,spawn_handle};
+use futures_util::future::RemoteHandle;
+use log::error;
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+
+/// Parse a duration such as `90`, `45s`, `25m`, or `1h30m`; a plain number is in seconds
+pub fn parse_duration(value : &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let split = rest.find(|c : char| !c.is_ascii_digit() && c != '.')?;
+        let unit = rest[split..].find(|c : char| c.is_ascii_digit()).map_or(rest.len(), |p| p + split);
+        let n = rest[..split].parse::<f64>().ok()?;
+        total += n * match rest[split..unit].trim() {
+            "s" | "sec" => 1.0,
+            "m" | "min" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        rest = &rest[unit..];
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+fn toml_to_duration(value : Option<&toml::Value>) -> Option<Duration> {
+    match value? {
+        toml::Value::Integer(i) => Duration::try_from_secs_f64(*i as f64).ok(),
+        toml::Value::Float(f) => Duration::try_from_secs_f64(*f).ok(),
+        toml::Value::String(s) => parse_duration(s),
+        _ => None,
+    }
+}
+
+/// Format a number of seconds as `M:SS` or `H:MM:SS`
+pub fn format_time(secs : u64) -> String {
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Redraw after the given delay, replacing any earlier request
+pub fn schedule_tick(tick : &Cell<Option<RemoteHandle<()>>>, delay : Option<Duration>, rt : &Runtime) {
+    let delay = match delay {
+        Some(delay) => delay,
+        None => {
+            tick.set(None);
+            return;
+        }
+    };
+    let wake = Instant::now() + delay;
+    let mut notify = NotifierList::active(rt);
+    let sched = rt.scheduler.clone();
+    tick.set(Some(spawn_handle("Timer tick", async move {
+        sched.sleep_until(wake, Duration::ZERO).await;
+        notify.notify_data("timer");
+        Ok(())
+    })));
+}
+
+/// Time until the whole number of seconds in `t` next changes, as it counts down or up
+pub fn until_next_second(t : Duration, counting_down : bool) -> Duration {
+    let frac = Duration::from_nanos(t.subsec_nanos() as u64);
+    match (counting_down, frac.is_zero()) {
+        (_, true) => Duration::from_secs(1),
+        (true, false) => frac,
+        (false, false) => Duration::from_secs(1) - frac,
+    }
+}
+
+/// Measures running time, which can be paused and resumed
+#[derive(Debug,Default)]
+struct Watch {
+    /// When the watch was last started, if it is running
+    started : Cell<Option<Instant>>,
+    /// Time accumulated before the last start
+    before : Cell<Duration>,
+}
+
+impl Watch {
+    fn elapsed(&self, now : Instant) -> Duration {
+        self.before.get() + self.started.get().map_or(Duration::ZERO, |s| now.saturating_duration_since(s))
+    }
+
+    fn running(&self) -> bool {
+        self.started.get().is_some()
+    }
+
+    fn start(&self, now : Instant) {
+        if self.started.get().is_none() {
+            self.started.set(Some(now));
+        }
+    }
+
+    fn pause(&self, now : Instant) {
+        self.before.set(self.elapsed(now));
+        self.started.set(None);
+    }
+
+    fn toggle(&self, now : Instant) {
+        if self.running() {
+            self.pause(now);
+        } else {
+            self.start(now);
+        }
+    }
+
+    fn reset(&self) {
+        self.started.set(None);
+        self.before.set(Duration::ZERO);
+    }
+
+    fn state(&self, now : Instant) -> &'static str {
+        if self.running() {
+            "running"
+        } else if self.elapsed(now).is_zero() {
+            "stopped"
+        } else {
+            "paused"
+        }
+    }
+
+    /// Handle one of the messages shared by all kinds of timer; returns false if it is unknown
+    fn write(&self, msg : &str, now : Instant) -> bool {
+        match msg {
+            "start" => self.start(now),
+            "pause" | "stop" => self.pause(now),
+            "toggle" => self.toggle(now),
+            "reset" => self.reset(),
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Phase {
+    name : Box<str>,
+    duration : Cell<Duration>,
+}
+
+/// The "timer" and "stopwatch" modules
+#[derive(Debug)]
+pub struct Timer {
+    text : Box<str>,
+    /// An empty list makes this a stopwatch
+    phases : Vec<Phase>,
+    auto_start : bool,
+    on_end : Action,
+    phase : Cell<usize>,
+    watch : Watch,
+    tick : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Timer {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        let mut phases : Vec<Phase> = value.get("phases")
+            .and_then(|v| v.as_array())
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| {
+                let name = v.get("name").and_then(|v| v.as_str()).unwrap_or("").into();
+                match toml_to_duration(v.get("duration")) {
+                    Some(d) => Some(Phase { name, duration : Cell::new(d) }),
+                    None => {
+                        error!("Timer phase '{}' needs a valid duration", name);
+                        None
+                    }
+                }
+            })
+            .collect();
+        if phases.is_empty() && value.get("type").and_then(|v| v.as_str()) != Some("stopwatch") {
+            let duration = toml_to_duration(value.get("duration")).unwrap_or_else(|| {
+                error!("Timer needs a valid duration or list of phases");
+                Duration::ZERO
+            });
+            phases.push(Phase { name : "".into(), duration : Cell::new(duration) });
+        }
+        Timer {
+            text : value.get("text").and_then(|v| v.as_str()).unwrap_or("").into(),
+            phases,
+            auto_start : value.get("auto-start").and_then(|v| v.as_bool()).unwrap_or(false),
+            on_end : value.get("on-end").map_or(Action::None, Action::from_toml),
+            phase : Cell::new(0),
+            watch : Watch::default(),
+            tick : Cell::new(None),
+        }
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.phases.get(self.phase.get()).map(|p| p.duration.get())
+    }
+
+    fn next_phase(&self) -> usize {
+        (self.phase.get() + 1) % self.phases.len().max(1)
+    }
+
+    /// Switch to the given phase, keeping the timer running if it was
+    fn set_phase(&self, phase : usize, now : Instant) {
+        let running = self.watch.running();
+        self.phase.set(phase);
+        self.watch.reset();
+        if running {
+            self.watch.start(now);
+        }
+    }
+
+    /// Time spent in the current phase, finishing the phase first if its time is up
+    fn update(&self, rt : &Runtime) -> Duration {
+        let now = Instant::now();
+        let elapsed = self.watch.elapsed(now);
+        match self.duration() {
+            Some(duration) if self.watch.running() && elapsed >= duration => {
+                // Run the hook while the phase that ended is still current.  The timer is stopped
+                // first, because the hook may read or write this item.
+                self.watch.pause(now);
+                self.on_end.invoke(rt, 0);
+                self.set_phase(self.next_phase(), now);
+                if self.auto_start {
+                    self.watch.start(now);
+                }
+                Duration::ZERO
+            }
+            Some(duration) => elapsed.min(duration),
+            None => elapsed,
+        }
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        let elapsed = self.update(rt);
+        let left = self.duration().map(|d| d - elapsed);
+        let delay = match left {
+            Some(left) => until_next_second(left, true),
+            None => until_next_second(elapsed, false),
+        };
+        schedule_tick(&self.tick, Some(delay).filter(|_| self.watch.running()), rt);
+        match key {
+            "" | "text" if !self.text.is_empty() => f(rt.format_or(&self.text, name)),
+            "" | "text" | "time" => match left {
+                // Round up, so that 0:00 is only shown when the time is up
+                Some(left) => f(Value::Owned(format_time(left.as_secs_f64().ceil() as u64))),
+                None => f(Value::Owned(format_time(elapsed.as_secs()))),
+            }
+            "remaining" => f(left.map_or(Value::Null, |left| Value::Float(left.as_secs_f64().ceil()))),
+            "elapsed" => f(Value::Float(elapsed.as_secs_f64().floor())),
+            "duration" => f(self.duration().map_or(Value::Null, |d| Value::Float(d.as_secs_f64()))),
+            "progress" => match self.duration() {
+                Some(d) if !d.is_zero() => f(Value::Float(elapsed.as_secs_f64() / d.as_secs_f64())),
+                _ => f(Value::Null),
+            }
+            "phase" => f(self.phases.get(self.phase.get()).map_or(Value::Null, |p| Value::Borrow(&p.name))),
+            "phase-number" => f(Value::Float(self.phase.get() as f64 + 1.0)),
+            "next-phase" => f(self.phases.get(self.next_phase()).map_or(Value::Null, |p| Value::Borrow(&p.name))),
+            "state" => f(Value::Borrow(self.watch.state(Instant::now()))),
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        self.update(rt);
+        let now = Instant::now();
+        let value = value.into_text();
+        match (key, &*value) {
+            ("", msg) if self.watch.write(msg, now) => {}
+            ("", "reset-all") => {
+                self.watch.reset();
+                self.phase.set(0);
+            }
+            ("", "skip") | ("", "next") if !self.phases.is_empty() => self.set_phase(self.next_phase(), now),
+            ("duration", v) if !self.phases.is_empty() => match parse_duration(v) {
+                Some(d) => {
+                    self.phases[self.phase.get()].duration.set(d);
+                    self.set_phase(self.phase.get(), now);
+                }
+                None => {
+                    error!("Invalid duration for {}: '{}'", name, v);
+                    return;
+                }
+            }
+            (_, v) => {
+                error!("Ignoring write of '{}' to {}.{}", v, name, key);
+                return;
+            }
+        }
+        NotifierList::active(rt).notify_data("timer");
+    }
+}
+
+/// One of the timers in a "timers" module
+#[derive(Debug)]
+pub struct TimerEntry {
+    name : Box<str>,
+    /// None for a stopwatch
+    duration : Option<Duration>,
+    watch : Watch,
+    removed : Cell<bool>,
+}
+
+impl TimerEntry {
+    fn left(&self, now : Instant) -> Option<Duration> {
+        self.duration.map(|d| d.saturating_sub(self.watch.elapsed(now)))
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, key : &str, _rt : &Runtime, f : F) -> R {
+        let now = Instant::now();
+        let elapsed = self.watch.elapsed(now);
+        match key {
+            "" | "text" | "name" => f(Value::Borrow(&self.name)),
+            "time" => match self.left(now) {
+                Some(left) => f(Value::Owned(format_time(left.as_secs_f64().ceil() as u64))),
+                None => f(Value::Owned(format_time(elapsed.as_secs()))),
+            }
+            "remaining" => f(self.left(now).map_or(Value::Null, |left| Value::Float(left.as_secs_f64().ceil()))),
+            "elapsed" => f(Value::Float(elapsed.as_secs_f64().floor())),
+            "duration" => f(self.duration.map_or(Value::Null, |d| Value::Float(d.as_secs_f64()))),
+            "kind" => f(Value::Borrow(if self.duration.is_some() { "timer" } else { "stopwatch" })),
+            "state" => f(Value::Borrow(self.watch.state(now))),
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn write(&self, key : &str, value : Value, rt : &Runtime) {
+        let msg = value.as_str_fast();
+        match msg {
+            "remove" => self.removed.set(true),
+            msg if self.watch.write(msg, Instant::now()) => {}
+            _ => {
+                error!("Ignoring write of '{}' to timer.{} - use start, pause, toggle, reset, or remove", msg, key);
+                return;
+            }
+        }
+        NotifierList::active(rt).notify_data("timer");
+    }
+}
+
+/// The "timers" module
+#[derive(Debug)]
+pub struct Timers {
+    list : Cell<Vec<Rc<TimerEntry>>>,
+    on_end : Action,
+    /// The name of the timer that finished most recently
+    ended : Cell<Box<str>>,
+    tick : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Timers {
+    pub fn from_toml(value : &toml::Value) -> Self {
+        Timers {
+            list : Default::default(),
+            on_end : value.get("on-end").map_or(Action::None, Action::from_toml),
+            ended : Cell::new("".into()),
+            tick : Cell::new(None),
+        }
+    }
+
+    /// Remove finished and removed timers, running the hook for each finished one, and return the
+    /// remaining list
+    fn update(&self, rt : &Runtime) -> Vec<Rc<TimerEntry>> {
+        let now = Instant::now();
+        let mut finished = Vec::new();
+        let list = self.list.take_in(|list| {
+            list.retain(|t| {
+                if t.removed.get() {
+                    return false;
+                }
+                if t.watch.running() && t.left(now).is_some_and(|left| left.is_zero()) {
+                    finished.push(t.name.clone());
+                    return false;
+                }
+                true
+            });
+            list.clone()
+        });
+        for name in finished {
+            self.ended.set(name);
+            self.on_end.invoke(rt, 0);
+        }
+
+        let delay = list.iter()
+            .filter(|t| t.watch.running())
+            .map(|t| match t.left(now) {
+                Some(left) => until_next_second(left, true),
+                None => until_next_second(t.watch.elapsed(now), false),
+            })
+            .min();
+        schedule_tick(&self.tick, delay, rt);
+        list
+    }
+
+    /// The running countdown that will finish first
+    fn next(list : &[Rc<TimerEntry>]) -> Option<&Rc<TimerEntry>> {
+        let now = Instant::now();
+        list.iter()
+            .filter(|t| t.watch.running())
+            .filter_map(|t| Some((t.left(now)?, t)))
+            .min_by_key(|(left, _)| *left)
+            .map(|(_, t)| t)
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, _name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        let list = self.update(rt);
+        match key {
+            "" | "text" | "count" => f(Value::Float(list.len() as f64)),
+            "running" => f(Value::Float(list.iter().filter(|t| t.watch.running()).count() as f64)),
+            "next" => f(Timers::next(&list).map_or(Value::Null, |t| Value::Borrow(&t.name))),
+            "next-time" => match Timers::next(&list) {
+                Some(t) => t.read_in("time", rt, f),
+                None => f(Value::Null),
+            }
+            "ended" => self.ended.take_in(|name| f(Value::Borrow(name))),
+            _ => f(Value::Null),
+        }
+    }
+
+    pub fn read_focus_list<F : FnMut(bool, IterationItem)>(&self, rt : &Runtime, mut f : F) {
+        let list = self.update(rt);
+        for timer in list {
+            f(timer.watch.running(), IterationItem::Timer(timer));
+        }
+    }
+
+    pub fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        self.update(rt);
+        let now = Instant::now();
+        let value = value.into_text();
+        let mut words = value.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let timer = words.next();
+        let arg = words.collect::<Vec<_>>().join(" ");
+        let find = |timer : &str| self.list.take_in(|list| list.iter().find(|t| &*t.name == timer).cloned());
+        let add = |duration| {
+            let entry = Rc::new(TimerEntry {
+                name : timer.unwrap_or("").into(),
+                duration,
+                watch : Watch::default(),
+                removed : Cell::new(false),
+            });
+            entry.watch.start(now);
+            self.list.take_in(|list| {
+                list.retain(|t| t.name != entry.name);
+                list.push(entry);
+            });
+        };
+        match (key, cmd, timer) {
+            ("", "start", Some(_)) if !arg.is_empty() => match parse_duration(&arg) {
+                Some(d) => add(Some(d)),
+                None => {
+                    error!("Invalid duration for {}: '{}'", name, arg);
+                    return;
+                }
+            }
+            ("", "stopwatch", Some(_)) => add(None),
+            ("", "clear", None) => self.list.set(Vec::new()),
+            ("", msg, Some(timer)) => match find(timer) {
+                Some(t) => t.write("", Value::Borrow(msg), rt),
+                None => {
+                    error!("No timer named '{}' in {}", timer, name);
+                    return;
+                }
+            }
+            _ => {
+                error!("Ignoring write of '{}' to {}.{}", value, name, key);
+                return;
+            }
+        }
+        NotifierList::active(rt).notify_data("timer");
+    }
+}
@@ -0,0 +1,211 @@
+//! Optional render timing statistics, for finding slow modules
+use crate::font::render_font;
+use crate::render::Render;
+use log::info;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration,Instant};
+
+/// How often to log a summary
+const REPORT_PERIOD : Duration = Duration::from_secs(10);
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Mode {
+    Off,
+    /// Log a summary periodically
+    Log,
+    /// Log a summary and also show the most recent frame's timings on the bar
+    Overlay,
+}
+
+impl Mode {
+    pub fn from_str(v : &str) -> Option<Self> {
+        match v {
+            "off" => Some(Mode::Off),
+            "" | "log" => Some(Mode::Log),
+            "overlay" => Some(Mode::Overlay),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug,Default,Clone,Copy)]
+struct Stat {
+    count : u32,
+    total : Duration,
+    max : Duration,
+}
+
+impl Stat {
+    fn add(&mut self, time : Duration) {
+        self.count += 1;
+        self.total += time;
+        self.max = self.max.max(time);
+    }
+}
+
+impl std::fmt::Display for Stat {
+    fn fmt(&self, fmt : &mut std::fmt::Formatter) -> std::fmt::Result {
+        let avg = self.total.checked_div(self.count).unwrap_or_default();
+        write!(fmt, "{}x avg {:.2}ms max {:.2}ms", self.count,
+            avg.as_secs_f64() * 1000.0, self.max.as_secs_f64() * 1000.0)
+    }
+}
+
+#[derive(Debug)]
+struct Timings {
+    mode : Mode,
+    since : Instant,
+    record : Stat,
+    raster : Stat,
+    items : HashMap<Box<str>, Stat>,
+    triggers : HashMap<Box<str>, u32>,
+    /// The frame time and slowest item of the most recent frame, for the overlay
+    last_frame : Duration,
+    slowest : Option<(Box<str>, Duration)>,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Timings {
+            mode : Mode::Off,
+            since : Instant::now(),
+            record : Stat::default(),
+            raster : Stat::default(),
+            items : HashMap::new(),
+            triggers : HashMap::new(),
+            last_frame : Duration::ZERO,
+            slowest : None,
+        }
+    }
+}
+
+thread_local! {
+    static TIMINGS : RefCell<Timings> = Default::default();
+}
+
+pub fn set_mode(mode : Mode) {
+    TIMINGS.with(|t| {
+        let mut t = t.borrow_mut();
+        *t = Timings::default();
+        t.mode = mode;
+    });
+    info!("Render timings: {:?}", mode);
+}
+
+pub fn mode() -> Mode {
+    TIMINGS.with(|t| t.borrow().mode)
+}
+
+/// Cycle through off, log, and overlay modes
+pub fn toggle() {
+    set_mode(match mode() {
+        Mode::Off => Mode::Log,
+        Mode::Log => Mode::Overlay,
+        Mode::Overlay => Mode::Off,
+    });
+}
+
+pub fn enabled() -> bool {
+    mode() != Mode::Off
+}
+
+/// Record the time taken to render a named item
+pub fn item(name : &str, time : Duration) {
+    TIMINGS.with(|t| {
+        let mut t = t.borrow_mut();
+        if let Some(stat) = t.items.get_mut(name) {
+            stat.add(time);
+        } else {
+            t.items.entry(name.into()).or_default().add(time);
+        }
+        if t.slowest.as_ref().is_none_or(|s| s.1 < time) {
+            t.slowest = Some((name.into(), time));
+        }
+    });
+}
+
+/// Record an item whose data change caused a redraw
+pub fn trigger(who : &str) {
+    TIMINGS.with(|t| {
+        let mut t = t.borrow_mut();
+        if t.mode == Mode::Off {
+            return;
+        }
+        if let Some(count) = t.triggers.get_mut(who) {
+            *count += 1;
+        } else {
+            t.triggers.insert(who.into(), 1);
+        }
+    });
+}
+
+/// Record the time taken to draw and rasterize a frame, and log a summary if one is due
+pub fn frame(record : Duration, raster : Duration) {
+    TIMINGS.with(|t| {
+        let mut t = t.borrow_mut();
+        t.record.add(record);
+        t.raster.add(raster);
+        t.last_frame = record + raster;
+        if t.since.elapsed() >= REPORT_PERIOD {
+            report(&t);
+            let mode = t.mode;
+            *t = Timings::default();
+            t.mode = mode;
+        }
+    });
+}
+
+fn report(t : &Timings) {
+    info!("Timings for the last {:.1}s: record {}, rasterize {}",
+        t.since.elapsed().as_secs_f64(), t.record, t.raster);
+    let mut items : Vec<_> = t.items.iter().collect();
+    items.sort_by(|a, b| b.1.total.cmp(&a.1.total));
+    for (name, stat) in items.iter().take(10) {
+        info!("  item {}: {}", name, stat);
+    }
+    let mut triggers : Vec<_> = t.triggers.iter().collect();
+    triggers.sort_by(|a, b| b.1.cmp(a.1));
+    for (who, count) in triggers.iter().take(10) {
+        info!("  {} triggered {} redraws", who, count);
+    }
+}
+
+/// Start timing a frame; the slowest item is tracked per frame
+pub fn begin_frame() {
+    TIMINGS.with(|t| t.borrow_mut().slowest = None);
+}
+
+/// Draw the timing overlay at the right edge of the bar, if enabled
+pub fn draw_overlay(ctx : &mut Render) {
+    let text = TIMINGS.with(|t| {
+        let t = t.borrow();
+        if t.mode != Mode::Overlay {
+            return None;
+        }
+        let mut text = format!("{:.1}ms", t.last_frame.as_secs_f64() * 1000.0);
+        if let Some((name, time)) = &t.slowest {
+            text += &format!(" {} {:.1}ms", name, time.as_secs_f64() * 1000.0);
+        }
+        Some(text)
+    });
+    let text = match text {
+        Some(text) => text,
+        None => return,
+    };
+    let font_size = ctx.font_size;
+    ctx.font_size = 12.0;
+    ctx.font_color = tiny_skia::Color::WHITE;
+    let size = ctx.measure(|m| render_font(m, (0.0, 0.0), &text, false));
+    let x = (ctx.render_extents.1.x - size.0 - 2.0).max(0.0);
+    if let Some(rect) = tiny_skia::Rect::from_xywh(x - 2.0, 0.0, size.0 + 4.0, size.1) {
+        let paint = tiny_skia::Paint {
+            shader : tiny_skia::Shader::SolidColor(tiny_skia::Color::from_rgba8(0, 0, 0, 192)),
+            ..Default::default()
+        };
+        ctx.canvas.fill_rect(rect, &paint, ctx.render_xform);
+    }
+    ctx.render_pos = tiny_skia::Point { x, y: 0.0 };
+    render_font(ctx, (x, 0.0), &text, false);
+    ctx.font_size = font_size;
+}
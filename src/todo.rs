@@ -0,0 +1,228 @@
+//! Pending tasks from a todo.txt file or taskwarrior
+use crate::data::{Periodic,Value};
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,spawn_handle,toml_to_f64};
+use chrono::{Local,NaiveDate};
+use futures_util::future::RemoteHandle;
+use log::{error,warn};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+#[derive(Debug)]
+enum Backend {
+    TodoTxt(PathBuf),
+    /// The taskwarrior command
+    Taskwarrior(Box<str>),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum TaskId {
+    /// Line number and text in todo.txt, to check that the file has not changed
+    Line(usize, String),
+    Uuid(String),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct Task {
+    summary : String,
+    due : Option<NaiveDate>,
+    id : TaskId,
+}
+
+/// Parse a pending todo.txt task, such as `(A) 2024-01-01 Call mom +family due:2024-01-05`,
+/// returning it with its priority
+fn parse_todo_txt(n : usize, line : &str) -> Option<(Option<char>, Task)> {
+    let line = line.trim_end();
+    if line.trim().is_empty() || line.starts_with("x ") {
+        return None;
+    }
+    let mut rest = line;
+    let mut priority = None;
+    if let [b'(', p @ b'A'..=b'Z', b')', b' ', ..] = rest.as_bytes() {
+        priority = Some(*p as char);
+        rest = &rest[4..];
+    }
+    // the creation date
+    if rest.get(..10).is_some_and(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").is_ok()) && rest[10..].starts_with(' ') {
+        rest = &rest[11..];
+    }
+    let due = rest.split_whitespace()
+        .find_map(|w| w.strip_prefix("due:"))
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    Some((priority, Task {
+        summary : rest.trim().to_owned(),
+        due,
+        id : TaskId::Line(n, line.to_owned()),
+    }))
+}
+
+#[derive(Debug)]
+struct State {
+    backend : Backend,
+    /// The most important first
+    tasks : Cell<Vec<Task>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl State {
+    async fn load(&self) -> Result<Vec<Task>, Box<dyn Error>> {
+        match &self.backend {
+            Backend::TodoTxt(path) => {
+                let text = fs::read_to_string(path)?;
+                let mut tasks : Vec<_> = text.lines().enumerate().filter_map(|(n, line)| parse_todo_txt(n, line)).collect();
+                // by priority, then by due date; a task without either comes last
+                tasks.sort_by_key(|(priority, task)| (priority.is_none(), *priority, task.due.is_none(), task.due));
+                Ok(tasks.into_iter().map(|(_, task)| task).collect())
+            }
+            Backend::Taskwarrior(cmd) => {
+                let text = util::shell_output(&format!("{} status:pending export", cmd), &[]).await?;
+                let json = json::parse(&text)?;
+                let mut tasks : Vec<_> = json.members().map(|t| {
+                    let due = t["due"].as_str().and_then(|d| NaiveDate::parse_from_str(d.get(..8)?, "%Y%m%d").ok());
+                    let task = Task {
+                        summary : t["description"].as_str().unwrap_or_default().to_owned(),
+                        due,
+                        id : TaskId::Uuid(t["uuid"].as_str().unwrap_or_default().to_owned()),
+                    };
+                    (t["urgency"].as_f64().unwrap_or(0.0), task)
+                }).collect();
+                tasks.sort_by(|a, b| b.0.total_cmp(&a.0));
+                Ok(tasks.into_iter().map(|(_, task)| task).collect())
+            }
+        }
+    }
+
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let tasks = match self.load().await {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                warn!("Could not read tasks: {}", e);
+                Vec::new()
+            }
+        };
+        if self.tasks.take_in(|t| *t != tasks) {
+            self.tasks.set(tasks);
+            self.interested.take().notify_data("todo");
+        }
+        self.running.set(false);
+    }
+
+    /// Mark the most important task as done
+    async fn done(self : Rc<Self>) -> Result<(), Box<dyn Error>> {
+        let top = match self.tasks.take_in(|t| t.first().cloned()) {
+            Some(task) => task,
+            None => return Ok(()),
+        };
+        match (&self.backend, &top.id) {
+            (Backend::TodoTxt(path), TaskId::Line(n, expected)) => {
+                let text = fs::read_to_string(path)?;
+                let mut lines : Vec<_> = text.lines().map(String::from).collect();
+                match lines.get_mut(*n) {
+                    Some(line) if line.trim_end() == expected => {
+                        // the priority is dropped, as todo.txt asks
+                        let task = match line.as_bytes() {
+                            [b'(', b'A'..=b'Z', b')', b' ', ..] => &line[4..],
+                            _ => &line[..],
+                        };
+                        *line = format!("x {} {}", Local::today().format("%Y-%m-%d"), task);
+                    }
+                    _ => return Err(format!("{} has changed; not marking '{}' as done", path.display(), top.summary).into()),
+                }
+                let mut text = lines.join("\n");
+                text.push('\n');
+                fs::write(path, text)?;
+            }
+            (Backend::Taskwarrior(cmd), TaskId::Uuid(uuid)) => {
+                util::shell_output(&format!("{} rc.confirmation=off", cmd), &[uuid, "done"]).await?;
+            }
+            _ => {}
+        }
+        self.refresh().await;
+        Ok(())
+    }
+}
+
+/// The "todo" module
+#[derive(Debug)]
+pub struct Todo {
+    poll : Periodic<Rc<State>>,
+    watch : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Todo {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let (backend, period) = match (config.get("file").and_then(|v| v.as_str()), config.get("taskwarrior")) {
+            // the file is watched instead of polled
+            (Some(path), None) => (Backend::TodoTxt(path.into()), 0.0),
+            (None, Some(cmd)) => {
+                let cmd = match cmd {
+                    toml::Value::String(cmd) => cmd.as_str(),
+                    _ => "task",
+                };
+                (Backend::Taskwarrior(cmd.into()), toml_to_f64(config.get("poll")).unwrap_or(60.0))
+            }
+            (Some(_), Some(_)) => return Err("Only one of 'file' and 'taskwarrior' may be given".into()),
+            (None, None) => return Err("A todo item needs a 'file' or 'taskwarrior'".into()),
+        };
+        let state = State {
+            backend,
+            tasks : Cell::default(),
+            running : Cell::new(false),
+            interested : Cell::default(),
+        };
+        Ok(Todo {
+            poll : Periodic::new(period, Rc::new(state)),
+            watch : Cell::default(),
+        })
+    }
+}
+
+impl Source for Todo {
+    fn init(&self, _name : &str, _rt : &Runtime) {
+        let state = self.poll.data().clone();
+        if let Backend::TodoTxt(path) = &state.backend {
+            let path = path.clone();
+            self.watch.set(Some(spawn_handle("todo.txt watch", util::watch_file(path, move || {
+                util::spawn_noerr(state.clone().refresh());
+            }))));
+        }
+    }
+
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.poll.read_refresh_async(rt, |state| state.clone().refresh());
+        let state = self.poll.data();
+        state.interested.take_in(|i| i.add(rt));
+        state.tasks.take_in(|tasks| match key {
+            "" | "text" | "pending" => Value::Float(tasks.len() as f64),
+            "top" => tasks.first().map_or(Value::Null, |t| Value::Owned(t.summary.clone())),
+            "due" => {
+                let today = Local::today().naive_local();
+                Value::Float(tasks.iter().filter(|t| t.due.is_some_and(|d| d <= today)).count() as f64)
+            }
+            "list" => {
+                let lines : Vec<_> = tasks.iter().map(|t| &*t.summary).collect();
+                Value::Owned(lines.join("\n"))
+            }
+            _ => Value::Null,
+        })
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
+        match (key, value.as_str_fast()) {
+            ("", "" | "done") => util::spawn("Marking a task done", self.poll.data().clone().done()),
+            (key, value) => error!("Ignoring write of '{}' to {}.{}", value, name, key),
+        }
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        util::spawn_noerr(self.poll.data().clone().refresh());
+        true
+    }
+}
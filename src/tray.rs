@@ -1,5 +1,5 @@
 use crate::data::{IterationItem,Value};
-use crate::dbus::DBus;
+use crate::dbus::{DBus,typed,unwrap_variant};
 use crate::event::EventSink;
 use crate::font::render_font;
 use crate::item::{Item,PopupDesc};
@@ -16,7 +16,7 @@ use std::rc::Rc;
 use std::time::Instant;
 use std::mem::ManuallyDrop;
 use std::time::{SystemTime,Duration,UNIX_EPOCH};
-use log::{debug,warn};
+use log::{debug,warn,error};
 use zbus::fdo::DBusProxy;
 use zbus::dbus_proxy;
 use zbus::fdo;
@@ -66,6 +66,58 @@ trait DBusMenu {
         -> fdo::Result<()>;
 }
 
+#[dbus_proxy(interface="org.freedesktop.StatusNotifierWatcher", default_path="/StatusNotifierWatcher")]
+// Note: override interface and destination to org.kde.StatusNotifierWatcher if needed
+trait StatusNotifierWatcher {
+    #[dbus_proxy(property)]
+    fn registered_status_notifier_items(&self) -> zbus::Result<Vec<String>>;
+}
+
+/// The `ToolTip` property of an item: (icon-name, icon-pixmap, title, text)
+///
+/// See https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierItem/#org.freedesktop.statusnotifieritem.tooltip
+struct ToolTip {
+    title : String,
+    text : String,
+}
+
+impl TryFrom<&Variant<'_>> for ToolTip {
+    type Error = zvariant::Error;
+    fn try_from(value : &Variant<'_>) -> zvariant::Result<Self> {
+        let fields = match value {
+            Variant::Structure(s) => s.fields(),
+            _ => return Err(zvariant::Error::IncorrectType),
+        };
+        let field = |i : usize| fields.get(i)
+            .and_then(|v| typed::<String>(v, "tooltip field"))
+            .ok_or(zvariant::Error::IncorrectType);
+        Ok(ToolTip { title : field(2)?, text : field(3)? })
+    }
+}
+
+/// One node of a dbusmenu layout: (id, properties, children)
+struct MenuNode<'a> {
+    id : i32,
+    props : &'a zvariant::Dict<'a, 'a>,
+    children : &'a [Variant<'a>],
+}
+
+impl<'a> TryFrom<&'a Variant<'a>> for MenuNode<'a> {
+    type Error = zvariant::Error;
+    fn try_from(value : &'a Variant<'a>) -> zvariant::Result<Self> {
+        let fields = match unwrap_variant(value) {
+            Variant::Structure(s) => s.fields(),
+            _ => return Err(zvariant::Error::IncorrectType),
+        };
+        match (fields.first().map(unwrap_variant), fields.get(1).map(unwrap_variant), fields.get(2).map(unwrap_variant)) {
+            (Some(&Variant::I32(id)), Some(Variant::Dict(props)), Some(Variant::Array(children))) => {
+                Ok(MenuNode { id, props, children : children.get() })
+            }
+            _ => Err(zvariant::Error::IncorrectType),
+        }
+    }
+}
+
 #[dbus_proxy(interface="org.freedesktop.StatusNotifierItem")]
 // Note: override interface to org.kde.StatusNotifierItem if needed
 trait StatusNotifierItem {
@@ -145,6 +197,8 @@ pub struct TrayItem {
     title : Cell<Option<Rc<str>>>,
     icon : Cell<Box<str>>,
     icon_path : Cell<Box<str>>,
+    /// The item only supports showing its menu, not being activated
+    is_menu : Cell<bool>,
     status : Cell<Box<str>>,
     tooltip : Cell<Option<Rc<str>>>,
     inspection: Cell<Option<RemoteHandle<()>>>,
@@ -265,6 +319,21 @@ macro_rules! build_snw {
                                 reg_db.push((service, $is_kde));
                                 reg_db.sort();
                                 reg_db.dedup();
+                            } else if !path.is_empty() {
+                                // libappindicator registers a well-known name that it owns; use the
+                                // unique name so that signals from the item can be matched to it
+                                let service = format!("{}/StatusNotifierItem", hdr.sender()?.unwrap());
+                                dbus.send(zbus::Message::signal(
+                                    None::<&str>,
+                                    None::<&str>,
+                                    "/StatusNotifierWatcher",
+                                    $name,
+                                    "StatusNotifierItemRegistered",
+                                    &service,
+                                )?);
+                                reg_db.push((service, $is_kde));
+                                reg_db.sort();
+                                reg_db.dedup();
                             } else {
                                 warn!("Unknown RegisterStatusNotifierItem from {:?}: {}", hdr.sender(), path);
                             }
@@ -302,6 +371,32 @@ macro_rules! build_snw {
 build_snw!(snw_kde, "org.kde.StatusNotifierWatcher", true);
 build_snw!(snw_fdo, "org.freedesktop.StatusNotifierWatcher", false);
 
+/// Give up the host and watcher names, so that another tray (or a replacement bar) can take them
+/// without waiting for our connection to close
+pub async fn release_names() {
+    if DATA.with(|cell| cell.get().is_none()) {
+        return;
+    }
+    let dbus = DBus::get_session();
+    let zbus = dbus.connection().await;
+    let dbif = match DBusProxy::builder(&zbus).cache_properties(zbus::CacheProperties::No).build().await {
+        Ok(dbif) => dbif,
+        Err(e) => {
+            warn!("Could not release tray names: {}", e);
+            return;
+        }
+    };
+    for who in ["kde", "freedesktop"] {
+        let host = format!("org.{}.StatusNotifierHost-{}", who, std::process::id());
+        let watcher = format!("org.{}.StatusNotifierWatcher", who);
+        for name in [host, watcher] {
+            if let Ok(name) = (&*name).try_into() {
+                let _ = dbif.release_name(name).await;
+            }
+        }
+    }
+}
+
 async fn init_snw(is_kde : bool) -> Result<(), Box<dyn Error>> {
     let who = if is_kde { "kde" } else { "freedesktop" };
     let snw_path = if is_kde { "org.kde.StatusNotifierWatcher" } else { "org.freedesktop.StatusNotifierWatcher" };
@@ -406,17 +501,13 @@ async fn init_snw(is_kde : bool) -> Result<(), Box<dyn Error>> {
     )?).await?;
 
     // Note: this must be well-ordered after the above RequestName
-    match zbus.call_method(
-        Some(snw_path),
-        "/StatusNotifierWatcher",
-        Some("org.freedesktop.DBus.Properties"),
-        "Get",
-        &(snw_path, "RegisteredStatusNotifierItems"),
-    ).await?.body()? {
-        Variant::Array(items) => for item in items.get() {
-            do_add_item(is_kde, item.try_into()?);
-        }
-        _ => {}
+    let snw = StatusNotifierWatcherProxy::builder(&zbus)
+        .destination(snw_path)?
+        .interface(snw_path)?
+        .cache_properties(zbus::CacheProperties::No)
+        .build().await?;
+    for item in snw.registered_status_notifier_items().await? {
+        do_add_item(is_kde, item);
     }
 
     Ok(())
@@ -469,6 +560,7 @@ fn do_add_item(is_kde : bool, item : String) {
                 title : Default::default(),
                 icon : Default::default(),
                 icon_path : Default::default(),
+                is_menu : Default::default(),
                 status : Default::default(),
                 tooltip : Default::default(),
                 rule : rule.into(),
@@ -535,11 +627,12 @@ impl TrayItem {
         for (key, value) in props {
             let value = &**value;
             match &**key {
-                "Id" => { drop(value.try_into().map(|v: String| self.id.set(v.into()))); }
-                "Title" => { drop(value.try_into().map(|v : String| self.title.set(Some(v.into())))); }
-                "IconName" => { drop(value.try_into().map(|v : String| self.icon.set(v.into()))); }
-                "IconThemePath" => { drop(value.try_into().map(|v : String| self.icon_path.set(v.into()))); }
-                "Status" => drop(value.try_into().map(|v : String| {
+                "Id" => if let Some(v) = typed::<String>(value, "Id") { self.id.set(v.into()); }
+                "Title" => if let Some(v) = typed::<String>(value, "Title") { self.title.set(Some(v.into())); }
+                "IconName" => if let Some(v) = typed::<String>(value, "IconName") { self.icon.set(v.into()); }
+                "IconThemePath" => if let Some(v) = typed::<String>(value, "IconThemePath") { self.icon_path.set(v.into()); }
+                "ItemIsMenu" => if let Some(v) = typed::<bool>(value, "ItemIsMenu") { self.is_menu.set(v); }
+                "Status" => if let Some(v) = typed::<String>(value, "Status") {
                     self.status.set(v.into());
                     // item status is queried without setting self.interested, so updates need to
                     // notify anyone who iterated the tray and not just those who query this item
@@ -548,17 +641,13 @@ impl TrayItem {
                         let tray = tray.as_ref().unwrap();
                         tray.interested.take().notify_data("tray:item-status");
                     });
-                })),
-                "ToolTip" => match value {
-                    Variant::Structure(v) => {
-                        // value is (icon-name, icon-bitmap, title, text)
-                        // see https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierItem/#org.freedesktop.statusnotifieritem.tooltip
-                        v.fields().get(3).and_then(|v| v.try_into().ok())
-                            .map(|v: String| self.tooltip.set(Some(v.into())));
-                    }
-                    _ => ()
                 }
-                "Menu" => { drop(value.try_into().map(|v: zvariant::ObjectPath| {
+                "ToolTip" => if let Some(tt) = typed::<ToolTip>(value, "ToolTip") {
+                    // the text is often empty, with only a title set
+                    let text = if tt.text.is_empty() { tt.title } else { tt.text };
+                    self.tooltip.set(Some(text.into()));
+                }
+                "Menu" => if let Some(v) = typed::<zvariant::ObjectPath>(value, "Menu") {
                     self.menu.take_in(|menu| {
                         let old_path = menu.as_ref().and_then(|menu| {
                             menu.menu_path.take_in(|mp| mp.clone())
@@ -578,7 +667,7 @@ impl TrayItem {
                             }));
                         }
                     });
-                })); }
+                }
                 _ => ()
             }
         }
@@ -627,51 +716,38 @@ impl TrayPopupMenu {
     fn add_items<'a, I>(items : &mut Vec<MenuItem>, iter : I, depth : u32)
         where I : Iterator<Item=&'a Variant<'a>>
     {
-        let mut item = MenuItem::default();
         for entry in iter {
-            let entry = match entry {
-                Variant::Value(v) => &v,
-                v => v,
+            let node = match typed::<MenuNode>(entry, "menu item") {
+                Some(node) => node,
+                None => continue,
             };
-            let fields = match entry {
-                Variant::Structure(s) => s.fields(),
-                _ => continue,
-            };
-            if let Some(id) = fields.get(0).and_then(|v| v.try_into().ok()) {
-                item.id = id;
-            }
-            if let Some(props) = fields.get(1).and_then(|v| v.downcast_ref::<zvariant::Dict>()) {
-                if let Ok(Some(label)) = props.get::<_, str>("label") {
-                    let mut text = String::with_capacity(label.len());
-                    let mut esc = false;
-                    for c in label.chars() {
-                        if c == '_' && !esc {
-                            esc = true;
-                            continue;
-                        }
-                        esc = false;
-                        text.push(c);
+            let mut item = MenuItem { id : node.id, depth, ..Default::default() };
+            if let Ok(Some(label)) = node.props.get::<_, str>("label") {
+                let mut text = String::with_capacity(label.len());
+                let mut esc = false;
+                for c in label.chars() {
+                    if c == '_' && !esc {
+                        esc = true;
+                        continue;
                     }
-                    item.label = text.into();
+                    esc = false;
+                    text.push(c);
                 }
-                item.visible = props.get("visible").ok().flatten().copied().unwrap_or(true);
-                item.enabled = props.get("enabled").ok().flatten().copied().unwrap_or(true);
-                if !item.visible {
-                    // Note: this is needed to correctly hide the sub-menu
-                    continue;
-                }
-                match props.get::<_, str>("type") {
-                    Ok(Some("separator")) => item.is_sep = true,
-                    Ok(Some(v)) => debug!("Unknown menu item type: {}", v),
-                    _ => ()
-                }
-                item.depth = depth;
-                items.push(item);
-                item = MenuItem::default();
+                item.label = text;
             }
-            if let Some(Variant::Array(submenu)) = fields.get(2) {
-                Self::add_items(items, submenu.into_iter(), depth + 1);
+            item.visible = node.props.get("visible").ok().flatten().copied().unwrap_or(true);
+            item.enabled = node.props.get("enabled").ok().flatten().copied().unwrap_or(true);
+            if !item.visible {
+                // Note: this is needed to correctly hide the sub-menu
+                continue;
             }
+            match node.props.get::<_, str>("type") {
+                Ok(Some("separator")) => item.is_sep = true,
+                Ok(Some(v)) => debug!("Unknown menu item type: {}", v),
+                _ => ()
+            }
+            items.push(item);
+            Self::add_items(items, node.children.iter(), depth + 1);
         }
     }
 
@@ -790,7 +866,7 @@ impl Drop for TrayPopupMenu {
 
 
 impl TrayPopup {
-    pub fn render(&mut self, ctx : &mut Render) {
+    pub fn render(&mut self, ctx : &mut Render) -> Vec<tiny_skia::Rect> {
         let width = ctx.render_extents.1.x;
         let rendered_ids = &mut self.rendered_ids;
         rendered_ids.clear();
@@ -827,7 +903,9 @@ impl TrayPopup {
         self.menu.items.take_in(|items| {
             if !items.is_empty() {
                 if let Some(rect) = tiny_skia::Rect::from_xywh(0.0, ypos + 4.0, width, 2.0) {
-                    ctx.canvas.fill_rect(rect, &line_paint, ctx.render_xform, None);
+                    if !ctx.measure_only {
+                        ctx.canvas.fill_rect(rect, &line_paint, ctx.render_xform);
+                    }
                 }
 
                 ypos += 9.0;
@@ -839,7 +917,9 @@ impl TrayPopup {
                 let indent = 2.0 + item.depth as f32 * 20.0;
                 if item.is_sep {
                     if let Some(rect) = tiny_skia::Rect::from_xywh(indent + 3.0, ypos + 3.0, width - indent - 5.0, 1.0) {
-                        ctx.canvas.fill_rect(rect, &line_paint, ctx.render_xform, None);
+                        if !ctx.measure_only {
+                            ctx.canvas.fill_rect(rect, &line_paint, ctx.render_xform);
+                        }
                     }
 
                     ypos += 7.0;
@@ -853,6 +933,9 @@ impl TrayPopup {
             }
         });
         ctx.render_pos = tiny_skia::Point { x: xsize.ceil() + 2.0, y: ypos };
+        rendered_ids.iter()
+            .filter_map(|&(min, max, _)| tiny_skia::Rect::from_ltrb(0.0, min - 2.0, width, max + 2.0))
+            .collect()
     }
 
     pub fn button(&mut self, x : f64, y : f64, button : u32, _runtime : &mut Runtime) {
@@ -879,7 +962,7 @@ impl TrayPopup {
     }
 }
 
-pub fn show(ctx : &mut Render, rv : &mut EventSink, [passive, active, urgent]: [&Rc<Item>;3]) {
+pub fn show(ctx : &mut Render, rv : &mut EventSink, [passive, active, urgent]: [&Rc<Item>;3], overrides : &HashMap<Box<str>, EventSink>) {
     let items = DATA.with(|cell| {
         let tray = cell.get_or_init(Tray::init);
         tray.interested.take_in(|interest| interest.add(&ctx.runtime));
@@ -900,16 +983,20 @@ pub fn show(ctx : &mut Render, rv : &mut EventSink, [passive, active, urgent]: [
         render.render_clamped_item(ctx, rv, &iter_item);
         let x1 = ctx.render_pos.x;
         if x0 != x1 {
+            let mut es = EventSink::from_tray(tray_item.clone());
+            if let Some(mut actions) = tray_item.id.take_in(|id| overrides.get(&**id).cloned()) {
+                actions.set_item(&iter_item);
+                es.merge(actions);
+            }
+            es.offset_clamp(0.0, x0, x1);
             if let Some(menu) = tray_item.menu.take_in(|m| m.clone()) {
                 let title = tray_item.title.take_in(|t| t.clone());
                 let tooltip = tray_item.tooltip.take_in(|t| t.clone());
-                let mut es = EventSink::from_tray(tray_item.clone());
-                es.offset_clamp(0.0, x0, x1);
                 es.add_hover(x0, x1, PopupDesc::Tray(TrayPopup {
                     title, menu, tooltip, rendered_ids : Vec::new(),
                 }));
-                rv.merge(es);
             }
+            rv.merge(es);
         }
     }
 }
@@ -935,21 +1022,46 @@ pub fn read_in<F : FnOnce(Value) -> R, R>(_name : &str, item: &TrayItem, key : &
 }
 
 pub fn write(name : &str, item: &TrayItem, key : &str, value : Value, rt : &Runtime) {
-    // Ignored for now, see EventSink::from_tray
-    let _ = (name, item, key, value, rt);
+    let _ = rt;
+    let how = match key {
+        "activate" => 0,
+        "context-menu" => 1,
+        "secondary-activate" => 2,
+        "scroll" => match &*value.into_text() {
+            "up" => 5,
+            "down" => 6,
+            "left" => 7,
+            "right" => 8,
+            v => {
+                error!("Invalid scroll direction '{}' for {}: use up, down, left, or right", v, name);
+                return;
+            }
+        }
+        _ => {
+            error!("Ignoring write to {}.{}", name, key);
+            return;
+        }
+    };
+    do_click(item, how);
 }
 
 /// A click or scroll on the tray icon itself
-pub fn do_click(item : &Rc<TrayItem>, how : u32) {
-    let method = match how {
-        0 => "Activate",
-        1 => "ContextMenu",
-        2 => "SecondaryActivate",
-        5 | 6 => "vertical",
-        7 | 8 => "horizontal",
+pub fn do_click(item : &TrayItem, how : u32) {
+    // Scrolling down or right is positive, as in libappindicator; 120 is one wheel notch
+    let (method, delta) = match how {
+        0 => ("Activate", 0),
+        1 => ("ContextMenu", 0),
+        2 => ("SecondaryActivate", 0),
+        5 => ("vertical", -120),
+        6 => ("vertical", 120),
+        7 => ("horizontal", -120),
+        8 => ("horizontal", 120),
         _ => return,
     };
 
+    // Items that are only a menu may not implement Activate
+    let method = if method == "Activate" && item.is_menu.get() { "ContextMenu" } else { method };
+
     let dbus = DBus::get_session();
     let sni_path = if item.is_kde { "org.kde.StatusNotifierItem" } else { "org.freedesktop.StatusNotifierItem" };
 
@@ -967,7 +1079,7 @@ pub fn do_click(item : &Rc<TrayItem>, how : u32) {
                 .destination(&*item.owner)?
                 .interface(sni_path)?
                 .with_flags(zbus::MessageFlags::NoReplyExpected)?
-                .build(&(15i32,method))?
+                .build(&(delta,method))?
             );
         }
         Ok(())
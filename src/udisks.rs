@@ -0,0 +1,414 @@
+//! Removable drives and their volumes from udisks2, with mount, unmount, and eject actions
+use crate::data::{Periodic,Value};
+use crate::dbus::{DBus,SignalWatch,typed,unwrap_variant};
+use crate::registry::{Entry,Source};
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,toml_to_f64};
+use log::{debug,error};
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::{Rc,Weak};
+use std::sync::Arc;
+use zbus::zvariant::{ObjectPath,OwnedObjectPath,OwnedValue};
+use zbus::zvariant::Value as Variant;
+
+const UDISKS : &str = "org.freedesktop.UDisks2";
+const UDISKS_PATH : &str = "/org/freedesktop/UDisks2";
+const BLOCK_IFACE : &str = "org.freedesktop.UDisks2.Block";
+const DRIVE_IFACE : &str = "org.freedesktop.UDisks2.Drive";
+const FILESYSTEM_IFACE : &str = "org.freedesktop.UDisks2.Filesystem";
+
+type Properties = HashMap<String, OwnedValue>;
+
+fn prop<'a, T>(props : &'a Properties, key : &str) -> Option<T>
+    where T : TryFrom<&'a Variant<'a>>
+{
+    props.get(key).and_then(|v| typed(v, key))
+}
+
+/// A string sent as a NUL-terminated byte array, like device paths
+fn bytes(value : &Variant) -> String {
+    match unwrap_variant(value) {
+        Variant::Array(a) => {
+            let bytes : Vec<u8> = a.get().iter().filter_map(|b| match b {
+                Variant::U8(b) if *b != 0 => Some(*b),
+                _ => None,
+            }).collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+        _ => String::new(),
+    }
+}
+
+fn object_path(props : &Properties, key : &str) -> Option<String> {
+    prop::<&ObjectPath>(props, key).map(|p| p.as_str().to_owned()).filter(|p| p != "/")
+}
+
+/// Split a key like `size-gib` into `size` and the divisor for its unit
+fn scaled(key : &str) -> (&str, f64) {
+    for (suffix, div) in [("-mb", 1e6), ("-gb", 1e9), ("-tb", 1e12), ("-mib", 1048576.0), ("-gib", 1073741824.0), ("-tib", 1099511627776.0)] {
+        if let Some(base) = key.strip_suffix(suffix) {
+            return (base, div);
+        }
+    }
+    (key, 1.0)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct Info {
+    path : String,
+    drive : Option<String>,
+    label : String,
+    device : String,
+    drive_name : String,
+    fs : String,
+    size : u64,
+    mount_point : Option<String>,
+    /// Total, free, and available bytes of the filesystem, if mounted
+    usage : Option<(u64, u64, u64)>,
+    ejectable : bool,
+    can_power_off : bool,
+}
+
+fn statvfs(path : &str) -> Option<(u64, u64, u64)> {
+    let cstr = std::ffi::CString::new(path.as_bytes()).ok()?;
+    let mut vfs : libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cstr.as_ptr(), &mut vfs) } != 0 {
+        return None;
+    }
+    // The block counts are all in units of the fragment size, not the block size
+    let unit = vfs.f_frsize as u64;
+    Some((unit * vfs.f_blocks as u64, unit * vfs.f_bfree as u64, unit * vfs.f_bavail as u64))
+}
+
+/// Read the usage of a mounted filesystem on another thread, as a slow or unresponsive drive can
+/// block statvfs for a long time
+async fn usage(path : String) -> Option<(u64, u64, u64)> {
+    tokio::task::spawn_blocking(move || statvfs(&path)).await.ok().flatten()
+}
+
+/// One filesystem on a drive
+#[derive(Debug)]
+pub struct Volume {
+    info : Info,
+    state : Weak<State>,
+}
+
+impl Volume {
+    async fn call(path : &str, iface : &str, method : &str) -> Result<Arc<zbus::Message>, Box<dyn Error>> {
+        let zbus = DBus::get_system().connection().await;
+        let options : HashMap<&str, Variant> = HashMap::new();
+        Ok(zbus.call_method(Some(UDISKS), path, Some(iface), method, &(options,)).await?)
+    }
+
+    /// Mount the volume, returning where it was mounted
+    async fn mount(&self) -> Result<String, Box<dyn Error>> {
+        if let Some(dir) = &self.info.mount_point {
+            return Ok(dir.clone());
+        }
+        let reply = Self::call(&self.info.path, FILESYSTEM_IFACE, "Mount").await?;
+        Ok(reply.body()?)
+    }
+
+    async fn unmount(&self) -> Result<(), Box<dyn Error>> {
+        if self.info.mount_point.is_some() {
+            Self::call(&self.info.path, FILESYSTEM_IFACE, "Unmount").await?;
+        }
+        Ok(())
+    }
+
+    /// Unmount every volume on the drive, then eject it (or power it off, for USB sticks)
+    async fn eject(&self) -> Result<(), Box<dyn Error>> {
+        let siblings = match self.state.upgrade() {
+            Some(state) => state.volumes.take_in(|v| v.clone()),
+            None => return Ok(()),
+        };
+        for vol in siblings.iter().filter(|v| v.info.drive == self.info.drive) {
+            vol.unmount().await?;
+        }
+        match &self.info.drive {
+            Some(drive) if self.info.ejectable => Self::call(drive, DRIVE_IFACE, "Eject").await?,
+            Some(drive) if self.info.can_power_off => Self::call(drive, DRIVE_IFACE, "PowerOff").await?,
+            _ => Err("the volume was unmounted, but its drive can neither be ejected nor powered off")?,
+        };
+        Ok(())
+    }
+
+    async fn open(self : Rc<Self>, scope : bool) -> Result<(), Box<dyn Error>> {
+        let dir = self.mount().await?;
+        let open = match self.state.upgrade() {
+            Some(state) => state.open.clone(),
+            None => return Ok(()),
+        };
+        match util::command(&open, scope).arg(&dir).spawn() {
+            Ok(child) => drop(child),
+            Err(e) => error!("Could not execute {}: {}", open, e),
+        }
+        Ok(())
+    }
+}
+
+impl Entry for Volume {
+    fn kind(&self) -> &'static str {
+        "volume"
+    }
+
+    fn read<'a>(&'a self, key : &str, _rt : &Runtime) -> Value<'a> {
+        let info = &self.info;
+        let (key, div) = scaled(key);
+        let usage = info.usage.filter(|&(total, _, _)| total != 0);
+        match key {
+            "" | "text" | "label" => Value::Borrow(&info.label),
+            "device" => Value::Borrow(&info.device),
+            "drive" => Value::Borrow(&info.drive_name),
+            "fs" => Value::Borrow(&info.fs),
+            "mounted" => Value::Bool(info.mount_point.is_some()),
+            "mount-point" => info.mount_point.as_deref().map_or(Value::Null, Value::Borrow),
+            "ejectable" => Value::Bool(info.ejectable || info.can_power_off),
+            "size" => Value::Float(info.size as f64 / div),
+            "free" => usage.map_or(Value::Null, |(_, free, _)| Value::Float(free as f64 / div)),
+            "avail" => usage.map_or(Value::Null, |(_, _, avail)| Value::Float(avail as f64 / div)),
+            "percent-used" => usage.map_or(Value::Null, |(total, free, _)| {
+                Value::Float(100.0 - free as f64 / total as f64 * 100.0)
+            }),
+            _ => Value::Null,
+        }
+    }
+
+    fn write(self : Rc<Self>, key : &str, value : Value, rt : &Runtime) {
+        let vol = self.clone();
+        match (key, value.as_str_fast()) {
+            ("", "" | "toggle") if self.info.mount_point.is_some() => util::spawn("Unmounting", async move { vol.unmount().await }),
+            ("", "" | "toggle" | "mount") => util::spawn("Mounting", async move { vol.mount().await.map(drop) }),
+            ("", "unmount") => util::spawn("Unmounting", async move { vol.unmount().await }),
+            ("", "eject") => util::spawn("Ejecting", async move { vol.eject().await }),
+            ("", "open") => util::spawn("Opening a volume", vol.open(rt.exec_scope)),
+            (key, value) => error!("Ignoring write of '{}' to volume {}.{}", value, self.info.label, key),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    all : bool,
+    open : Rc<str>,
+    /// Sorted by device
+    volumes : Cell<Vec<Rc<Volume>>>,
+    watching : Cell<bool>,
+    signals : Cell<Vec<SignalWatch>>,
+    running : Cell<bool>,
+    /// Something changed while reading
+    dirty : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl State {
+    /// Read again whenever a drive is added or removed, or a volume is mounted
+    fn watch(self : &Rc<Self>) {
+        let dbus = DBus::get_system();
+        let signals = ["org.freedesktop.DBus.ObjectManager.InterfacesAdded", "org.freedesktop.DBus.ObjectManager.InterfacesRemoved"]
+            .into_iter()
+            .map(|signal| {
+                let state = Rc::downgrade(self);
+                dbus.watch_signal(signal, Some(UDISKS_PATH), move |_| {
+                    if let Some(state) = state.upgrade() {
+                        util::spawn_noerr(state.refresh());
+                    }
+                })
+            })
+            .collect();
+        self.signals.set(signals);
+        let state = Rc::downgrade(self);
+        dbus.add_property_change_watcher(move |hdr, iface, _changed, _inval| {
+            let path = hdr.path().ok().flatten().map_or("", |p| p.as_str());
+            if iface.starts_with("org.freedesktop.UDisks2.") && path.starts_with(UDISKS_PATH) && !path.contains("/jobs/") {
+                if let Some(state) = state.upgrade() {
+                    util::spawn_noerr(state.refresh());
+                }
+            }
+        });
+    }
+
+    async fn query(&self) -> Result<Vec<Info>, Box<dyn Error>> {
+        let zbus = DBus::get_system().connection().await;
+        let reply = zbus.call_method(Some(UDISKS), UDISKS_PATH, Some("org.freedesktop.DBus.ObjectManager"), "GetManagedObjects", &()).await?;
+        let objects : HashMap<OwnedObjectPath, HashMap<String, Properties>> = reply.body()?;
+        let objects : HashMap<String, HashMap<String, Properties>> = objects.into_iter()
+            .map(|(path, ifaces)| (path.as_str().to_owned(), ifaces))
+            .collect();
+        let iface = |path : &str, iface : &str| objects.get(path).and_then(|i| i.get(iface));
+
+        let mut volumes = Vec::new();
+        for (path, ifaces) in &objects {
+            let (block, fs) = match (ifaces.get(BLOCK_IFACE), ifaces.get(FILESYSTEM_IFACE)) {
+                (Some(block), Some(fs)) => (block, fs),
+                _ => continue,
+            };
+            if prop(block, "HintIgnore").unwrap_or(false) {
+                continue;
+            }
+            // an unlocked encrypted volume belongs to the drive of its backing device
+            let drive = object_path(block, "Drive").or_else(|| {
+                let backing = object_path(block, "CryptoBackingDevice")?;
+                object_path(iface(&backing, BLOCK_IFACE)?, "Drive")
+            });
+            let drive_props = drive.as_deref().and_then(|d| iface(d, DRIVE_IFACE));
+            let drive_flag = |key| drive_props.and_then(|d| prop(d, key)).unwrap_or(false);
+            if !self.all && !(drive_flag("Removable") || drive_flag("MediaRemovable")) {
+                continue;
+            }
+
+            let device = block.get("PreferredDevice").or_else(|| block.get("Device")).map(|v| bytes(v)).unwrap_or_default();
+            let drive_name = drive_props.map_or(String::new(), |d| {
+                let vendor = prop::<&str>(d, "Vendor").unwrap_or_default();
+                let model = prop::<&str>(d, "Model").unwrap_or_default();
+                format!("{} {}", vendor, model).trim().to_owned()
+            });
+            let label = [prop::<&str>(block, "IdLabel"), prop::<&str>(block, "HintName")].into_iter().flatten()
+                .find(|l| !l.is_empty())
+                .map(str::to_owned)
+                .or_else(|| Some(drive_name.clone()).filter(|n| !n.is_empty()))
+                .unwrap_or_else(|| device.rsplit('/').next().unwrap_or_default().to_owned());
+            let mount_point = match fs.get("MountPoints").map(|v| unwrap_variant(v)) {
+                Some(Variant::Array(a)) => a.get().first().map(bytes),
+                _ => None,
+            };
+            volumes.push(Info {
+                path : path.clone(),
+                usage : None,
+                drive,
+                label,
+                device,
+                drive_name,
+                fs : prop::<&str>(block, "IdType").unwrap_or_default().to_owned(),
+                size : prop(block, "Size").unwrap_or(0),
+                mount_point,
+                ejectable : drive_flag("Ejectable"),
+                can_power_off : drive_flag("CanPowerOff"),
+            });
+        }
+        for info in &mut volumes {
+            if let Some(dir) = &info.mount_point {
+                info.usage = usage(dir.clone()).await;
+            }
+        }
+        volumes.sort_by(|a, b| a.device.cmp(&b.device));
+        Ok(volumes)
+    }
+
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            self.dirty.set(true);
+            return;
+        }
+        loop {
+            self.dirty.set(false);
+            let infos = match self.query().await {
+                Ok(infos) => infos,
+                Err(e) => {
+                    debug!("Could not read drives from udisks: {}", e);
+                    Vec::new()
+                }
+            };
+            let old = self.volumes.take_in(|v| v.clone());
+            if old.len() != infos.len() || old.iter().zip(&infos).any(|(v, i)| v.info != *i) {
+                let state = Rc::downgrade(&self);
+                self.volumes.set(infos.into_iter().map(|info| Rc::new(Volume { info, state : state.clone() })).collect());
+                self.interested.take().notify_data("removable-media");
+            }
+            if !self.dirty.get() {
+                break;
+            }
+        }
+        self.running.set(false);
+    }
+}
+
+/// The "removable-media" module
+#[derive(Debug)]
+pub struct RemovableMedia {
+    poll : Periodic<Rc<State>>,
+}
+
+impl RemovableMedia {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let state = State {
+            all : config.get("all").and_then(|v| v.as_bool()).unwrap_or(false),
+            open : config.get("open-command").and_then(|v| v.as_str()).unwrap_or("xdg-open").into(),
+            volumes : Cell::default(),
+            watching : Cell::new(false),
+            signals : Cell::default(),
+            running : Cell::new(false),
+            dirty : Cell::new(false),
+            interested : Cell::default(),
+        };
+        RemovableMedia {
+            // only the free space needs polling
+            poll : Periodic::new(toml_to_f64(config.get("poll")).unwrap_or(60.0), Rc::new(state)),
+        }
+    }
+
+    fn volumes(&self, rt : &Runtime) -> Vec<Rc<Volume>> {
+        let state = self.poll.data();
+        if !state.watching.replace(true) {
+            state.watch();
+        }
+        self.poll.read_refresh_async(rt, |state| state.clone().refresh());
+        state.interested.take_in(|i| i.add(rt));
+        state.volumes.take_in(|v| v.clone())
+    }
+}
+
+impl Source for RemovableMedia {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        let volumes = self.volumes(rt);
+        match key {
+            "" | "text" | "count" => Value::Float(volumes.len() as f64),
+            "mounted" => Value::Float(volumes.iter().filter(|v| v.info.mount_point.is_some()).count() as f64),
+            "list" | "tooltip" => {
+                let lines : Vec<_> = volumes.iter().map(|v| match &v.info.mount_point {
+                    Some(dir) => format!("{} ({}): {}", v.info.label, v.info.device, dir),
+                    None => format!("{} ({})", v.info.label, v.info.device),
+                }).collect();
+                Value::Owned(lines.join("\n"))
+            }
+            _ => Value::Null,
+        }
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.refresh();
+        true
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, _rt : &Runtime) {
+        let volumes = self.poll.data().volumes.take_in(|v| v.clone());
+        match (key, value.as_str_fast()) {
+            ("", "unmount-all") => util::spawn("Unmounting", async move {
+                for vol in volumes {
+                    vol.unmount().await?;
+                }
+                Ok(())
+            }),
+            ("", "eject-all") => util::spawn("Ejecting", async move {
+                let mut ejected = Vec::new();
+                for vol in volumes {
+                    if !ejected.contains(&vol.info.drive) {
+                        if let Err(e) = vol.eject().await {
+                            error!("Could not eject {}: {}", vol.info.label, e);
+                        }
+                        ejected.push(vol.info.drive.clone());
+                    }
+                }
+                Ok(())
+            }),
+            (key, value) => error!("Ignoring write of '{}' to {}.{}", value, name, key),
+        }
+    }
+
+    fn read_focus_list(&self, rt : &Runtime, f : &mut dyn FnMut(bool, Rc<dyn Entry>)) {
+        for vol in self.volumes(rt) {
+            f(vol.info.mount_point.is_some(), vol);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Pending package update checkers
+use crate::data::{Periodic,Value};
+use crate::state::{NotifierList,Runtime};
+use crate::util::{Cell,read_lines,toml_to_f64};
+use log::{debug,warn,error};
+use std::os::unix::io::AsRawFd;
+use std::process::{Command,Stdio};
+use std::rc::Rc;
+
+/// A source of pending updates
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Checker {
+    Apt,
+    Dnf,
+    Flatpak,
+    Pacman,
+}
+
+impl Checker {
+    fn from_name(name : &str) -> Option<Self> {
+        match name {
+            "apt" => Some(Checker::Apt),
+            "dnf" => Some(Checker::Dnf),
+            "flatpak" => Some(Checker::Flatpak),
+            "pacman" => Some(Checker::Pacman),
+            _ => None,
+        }
+    }
+
+    fn command(&self) -> &'static str {
+        match self {
+            Checker::Apt => "apt list --upgradable",
+            Checker::Dnf => "dnf check-update -q",
+            // checkupdates (from pacman-contrib) uses a separate sync database
+            Checker::Pacman => "checkupdates",
+            Checker::Flatpak => "flatpak remote-ls --updates --columns=application",
+        }
+    }
+
+    /// Convert one line of output to a package name, if it describes an update
+    fn parse_line<'a>(&self, line : &'a str) -> Option<&'a str> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        match self {
+            Checker::Apt => {
+                // "name/suite version arch [upgradable from: old]"
+                if !line.contains("[upgradable") {
+                    return None;
+                }
+                line.split('/').next()
+            }
+            Checker::Dnf => {
+                // "name.arch version repo", followed by an optional obsoletes section
+                if line.starts_with("Obsoleting") || line.starts_with(char::is_whitespace) {
+                    return None;
+                }
+                line.split_whitespace().next()
+            }
+            Checker::Flatpak => Some(line),
+            Checker::Pacman => line.split_whitespace().next(),
+        }
+    }
+}
+
+#[derive(Debug,Default)]
+struct UpdateList {
+    checkers : Vec<(Option<Checker>, Box<str>)>,
+    list : Cell<Vec<String>>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl UpdateList {
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let mut list = Vec::new();
+        for (checker, command) in &self.checkers {
+            let child = Command::new("/bin/sh")
+                .arg("-c").arg(&**command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) => {
+                    error!("Could not execute {}: {}", command, e);
+                    continue;
+                }
+            };
+            let stdout = child.stdout.take().unwrap();
+            let fd = stdout.as_raw_fd();
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+            let rv = read_lines(fd, command, |line| {
+                let pkg = match checker {
+                    Some(checker) => checker.parse_line(line),
+                    None => Some(line.trim()).filter(|l| !l.is_empty()),
+                };
+                if let Some(pkg) = pkg {
+                    list.push(pkg.to_owned());
+                }
+            }).await;
+            if let Err(e) = rv {
+                warn!("Error reading output of {}: {}", command, e);
+            }
+        }
+        debug!("Found {} pending updates", list.len());
+        let changed = self.list.take_in(|prev| *prev != list);
+        if changed {
+            self.list.set(list);
+            self.interested.take().notify_data("updates");
+        }
+        self.running.set(false);
+    }
+}
+
+/// The "updates" module
+#[derive(Debug)]
+pub struct Updates {
+    poll : Periodic<Rc<UpdateList>>,
+}
+
+impl Updates {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let mut checkers = Vec::new();
+        let names = match config.get("checkers") {
+            None => vec!["pacman"],
+            Some(toml::Value::String(s)) => vec![&s[..]],
+            Some(toml::Value::Array(a)) => a.iter().filter_map(|v| v.as_str()).collect(),
+            Some(_) => return Err("'checkers' must be a string or list of strings".into()),
+        };
+        if let Some(command) = config.get("command").and_then(|v| v.as_str()) {
+            checkers.push((None, command.into()));
+        } else {
+            for name in names {
+                match Checker::from_name(name) {
+                    Some(c) => checkers.push((Some(c), c.command().into())),
+                    None => return Err(format!("Unknown update checker '{name}'")),
+                }
+            }
+        }
+        let poll = Periodic::new(
+            toml_to_f64(config.get("poll")).unwrap_or(3600.0),
+            Rc::new(UpdateList { checkers, ..Default::default() }),
+        );
+        Ok(Updates { poll })
+    }
+
+    pub fn refresh(&self) {
+        self.poll.refresh();
+    }
+
+    pub fn read_in<F : FnOnce(Value) -> R,R>(&self, _name : &str, key : &str, rt : &Runtime, f : F) -> R {
+        self.poll.read_refresh_async(rt, |data| data.clone().refresh());
+        let data = self.poll.data();
+        data.interested.take_in(|i| i.add(rt));
+        data.list.take_in(|list| {
+            match key {
+                "" | "text" | "count" => f(Value::Float(list.len() as f64)),
+                "list" | "tooltip" => f(Value::Owned(list.join("\n"))),
+                _ => f(Value::Null),
+            }
+        })
+    }
+}
@@ -0,0 +1,46 @@
+//! Battery state from UPower
+use crate::dbus::DBus;
+use crate::util;
+use std::rc::Rc;
+use zbus::dbus_proxy;
+
+#[dbus_proxy(
+    interface = "org.freedesktop.UPower",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower",
+)]
+trait UPower {
+    /// OnBattery property
+    #[dbus_proxy(property)]
+    fn on_battery(&self) -> zbus::Result<bool>;
+}
+
+/// Call the function with the current value of UPower's OnBattery property, and again each time it
+/// changes.
+pub fn watch_on_battery<F : Fn(bool) + 'static>(f : F) {
+    let f = Rc::new(f);
+    util::spawn("UPower setup", async move {
+        let dbus = DBus::get_system();
+        let zbus = dbus.connection().await;
+        let proxy = UPowerProxy::builder(&zbus)
+            .cache_properties(zbus::CacheProperties::No)
+            .build().await?;
+
+        let target = proxy.clone();
+        let cb = f.clone();
+        dbus.add_property_change_watcher(move |hdr, iface, _changed, _inval| {
+            let path = hdr.path().ok().flatten().map(|p| p.as_str());
+            if path == Some("/org/freedesktop/UPower") && iface == "org.freedesktop.UPower" {
+                let proxy = target.clone();
+                let cb = cb.clone();
+                util::spawn("UPower query", async move {
+                    cb(proxy.on_battery().await?);
+                    Ok(())
+                });
+            }
+        });
+
+        f(proxy.on_battery().await?);
+        Ok(())
+    });
+}
@@ -1,14 +1,20 @@
-use log::error;
+//! Helpers shared by the modules
+use log::{debug,info,error};
 use futures_util::FutureExt;
 use futures_util::future::RemoteHandle;
+use std::any::Any;
 use std::error::Error;
 use std::borrow::Cow;
 use std::fmt;
 use std::fs;
+use std::io::{self,Read};
 use std::future::Future;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd,FromRawFd};
 use std::os::unix::io::RawFd;
-use std::path::PathBuf;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path,PathBuf};
+use tokio::io::unix::AsyncFd;
 
 pub fn toml_to_string(value : Option<&toml::Value>) -> Option<String> {
     value.and_then(|value| {
@@ -36,34 +42,6 @@ pub fn toml_to_f64(value : Option<&toml::Value>) -> Option<f64> {
     })
 }
 
-#[derive(Default,Copy,Clone,Eq,PartialEq,Ord,PartialOrd,Hash)]
-pub struct ImplDebug<T>(pub T);
-
-impl<T> fmt::Debug for ImplDebug<T> {
-    fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "{}", std::any::type_name::<T>())
-    }
-}
-
-impl<T> From<T> for ImplDebug<T> {
-    fn from(t : T) -> Self {
-        Self(t)
-    }
-}
-
-impl<T> std::ops::Deref for ImplDebug<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        &self.0
-    }
-}
-
-impl<T> std::ops::DerefMut for ImplDebug<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        &mut self.0
-    }
-}
-
 /// Wrapper around [std::cell::Cell] that implements [fmt::Debug] and has a few more useful utility
 /// funcitons.
 #[derive(Default)]
@@ -121,36 +99,266 @@ impl AsRawFd for Fd {
     }
 }
 
+/// Create an anonymous file, for passing data to the compositor
+pub fn memfd(name : &std::ffi::CStr) -> io::Result<fs::File> {
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+/// Split a command line into arguments.  Double quotes group words, and a backslash inside them
+/// escapes the next character, as in the Exec key of desktop entries.
+pub fn split_args(exec : &str) -> Option<Vec<String>> {
+    split_args_in(exec, false)
+}
+
+/// Split a format string into arguments like [split_args], without splitting inside `{}`
+/// expansions, so that each argument can be expanded on its own
+pub fn split_format_args(format : &str) -> Option<Vec<String>> {
+    split_args_in(format, true)
+}
+
+fn split_args_in(exec : &str, braces : bool) -> Option<Vec<String>> {
+    let mut rv = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    let mut depth = 0usize;
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if depth == 0 => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            '\\' if quoted && depth == 0 => arg.push(chars.next()?),
+            '{' if braces => {
+                depth += 1;
+                arg.push(c);
+                in_arg = true;
+            }
+            '}' if braces && depth > 0 => {
+                depth -= 1;
+                arg.push(c);
+            }
+            ' ' | '\t' if !quoted && depth == 0 => {
+                if in_arg {
+                    rv.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quoted {
+        return None;
+    }
+    if in_arg {
+        rv.push(arg);
+    }
+    Some(rv)
+}
+
+/// A command for starting the program, in a transient systemd user scope if `scope` is set and
+/// systemd-run is available, so that it does not share the bar's cgroup or die with it
+pub fn command(program : &str, scope : bool) -> std::process::Command {
+    thread_local! {
+        static SYSTEMD_RUN : bool = std::env::var_os("PATH")
+            .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join("systemd-run").is_file()));
+    }
+    if scope && SYSTEMD_RUN.with(|v| *v) {
+        let mut cmd = std::process::Command::new("systemd-run");
+        cmd.args(["--user", "--scope", "--collect", "--quiet", "--"]).arg(program);
+        cmd
+    } else {
+        std::process::Command::new(program)
+    }
+}
+
 pub fn spawn_noerr(fut : impl Future<Output=()> + 'static) {
     tokio::task::spawn_local(fut);
 }
 
-pub fn spawn(owner : &'static str, fut : impl Future<Output=Result<(), Box<dyn Error>>> + 'static) {
-    spawn_noerr(async move {
-        match fut.await {
-            Ok(()) => {}
-            Err(e) => {
-                error!("{}: {}", owner, e);
-            }
+/// Get the message from the payload of a caught panic
+pub fn panic_message(payload : &(dyn Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown panic")
+}
+
+/// Run the future, logging any error it returns or panic it raises instead of propagating it
+async fn run_logged(owner : &'static str, fut : impl Future<Output=Result<(), Box<dyn Error>>>) {
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("{}: {}", owner, e);
+        }
+        Err(panic) => {
+            error!("{}: crashed: {}", owner, panic_message(&*panic));
         }
-    });
+    }
+}
+
+pub fn spawn(owner : &'static str, fut : impl Future<Output=Result<(), Box<dyn Error>>> + 'static) {
+    spawn_noerr(run_logged(owner, fut));
 }
 
 pub fn spawn_handle(owner : &'static str, fut : impl Future<Output=Result<(), Box<dyn Error>>> + 'static)
     -> RemoteHandle<()>
 {
-    let (task, rh) = async move {
-        match fut.await {
-            Ok(()) => {}
-            Err(e) => {
-                error!("{}: {}", owner, e);
-            }
-        }
-    }.remote_handle();
+    let (task, rh) = run_logged(owner, fut).remote_handle();
     spawn_noerr(task);
     rh
 }
 
+/// Read newline-delimited UTF-8 from a non-blocking file descriptor until EOF
+pub async fn read_lines(fd : i32, name : &str, mut f : impl FnMut(&str)) -> io::Result<()> {
+    let afd = AsyncFd::new(Fd(fd))?;
+    let mut buffer : Vec<u8> = Vec::with_capacity(1024);
+
+    'waiting : loop {
+        let mut rh = afd.readable().await?;
+        'reading : loop {
+            if buffer.len() == buffer.capacity() {
+                buffer.reserve(2048);
+            }
+            unsafe { // pipe read into vec spare capacity
+                let start = buffer.len();
+                let max_len = buffer.capacity() - start;
+                let rv = libc::read(fd, buffer.as_mut_ptr().add(start) as *mut _, max_len);
+                match rv {
+                    0 => {
+                        return Ok(());
+                    }
+                    len if rv > 0 && rv <= max_len as _ => {
+                        buffer.set_len(start + len as usize);
+                    }
+                    _ => {
+                        let e = io::Error::last_os_error();
+                        match e.kind() {
+                            io::ErrorKind::Interrupted => continue 'reading,
+                            io::ErrorKind::WouldBlock => {
+                                rh.clear_ready();
+                                continue 'waiting;
+                            }
+                            _ => return Err(e),
+                        }
+                    }
+                }
+            }
+            while let Some(eol) = buffer.iter().position(|&c| c == b'\n') {
+                match std::str::from_utf8(&buffer[..eol]) {
+                    Err(_) => info!("Ignoring bad UTF8 from '{}'", name),
+                    Ok(v) => {
+                        debug!("'{}': {}", name, v);
+                        f(v);
+                    }
+                }
+                // Note: this is optimized for the normal case where the writer sends one line
+                // at a time, so this drain would empty the buffer.
+                buffer.drain(..eol + 1);
+            }
+        }
+    }
+}
+
+/// Run a shell command with the given arguments appended, returning everything it writes to stdout
+pub async fn shell_output(cmd : &str, args : &[&str]) -> io::Result<String> {
+    // The echo ends the output with a newline, so the last line is not lost
+    let mut child = std::process::Command::new("/bin/sh")
+        .arg("-c").arg(format!("{} \"$@\"; echo", cmd))
+        .arg("sh").args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let fd = stdout.as_raw_fd();
+    unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+
+    // Stop the command if the caller stops waiting for it, for example after a timeout
+    struct Kill(Option<std::process::Child>);
+    impl Drop for Kill {
+        fn drop(&mut self) {
+            if let Some(child) = &mut self.0 {
+                let _ = child.kill();
+            }
+        }
+    }
+    let mut child = Kill(Some(child));
+
+    let mut text = String::new();
+    read_lines(fd, cmd, |line| {
+        text.push_str(line);
+        text.push('\n');
+    }).await?;
+    child.0 = None;
+    Ok(text)
+}
+
+/// Call `f` each time the file is written or replaced.
+///
+/// The directory is watched instead of the file itself so that editors which save by renaming a
+/// new file over the old one are noticed.  Changes within a short interval are combined.
+pub async fn watch_file(path : PathBuf, mut f : impl FnMut()) -> Result<(), Box<dyn Error>> {
+    let path = fs::canonicalize(&path).unwrap_or(path);
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.as_bytes().to_owned()),
+        _ => return Err(format!("Cannot watch {}", path.display()).into()),
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let file = unsafe {
+        let fd = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        fs::File::from_raw_fd(fd)
+    };
+    let dir_c = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    if unsafe { libc::inotify_add_watch(file.as_raw_fd(), dir_c.as_ptr(), libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) } < 0 {
+        return Err(format!("Cannot watch {}: {}", dir.display(), io::Error::last_os_error()).into());
+    }
+    debug!("Watching {} for changes", path.display());
+
+    // Read the pending events, returning true if any of them were for our file
+    let read_events = |mut file : &fs::File| -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        let mut matched = false;
+        loop {
+            let len = match file.read(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(matched),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            // struct inotify_event: wd, mask, cookie, len, then a NUL-padded name
+            let mut pos = 0;
+            while pos + 16 <= len {
+                let name_len = u32::from_ne_bytes(buf[pos + 12..pos + 16].try_into().unwrap()) as usize;
+                let end = (pos + 16 + name_len).min(len);
+                let event_name = buf[pos + 16..end].split(|&c| c == 0).next().unwrap_or_default();
+                matched |= event_name == &name[..];
+                pos = end;
+            }
+        }
+    };
+
+    let afd = AsyncFd::new(file)?;
+    loop {
+        let mut guard = afd.readable().await?;
+        guard.clear_ready();
+        if read_events(afd.get_ref())? {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            read_events(afd.get_ref())?;
+            f();
+        }
+    }
+}
+
 pub fn glob_expand<'a>(file: impl Into<Cow<'a, str>>) -> Option<(Cow<'a, str>, bool)> {
     let file = file.into();
     if !file.contains('*') {
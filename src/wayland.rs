@@ -1,3 +1,4 @@
+//! Connection to the compositor and its layer-shell and popup surfaces
 use log::{debug,error};
 use std::cell::RefCell;
 use std::convert::Infallible;
@@ -12,11 +13,13 @@ use smithay_client_toolkit::output::OutputHandling;
 use smithay_client_toolkit::output::OutputInfo;
 use smithay_client_toolkit::output::OutputStatusListener;
 use smithay_client_toolkit::seat::SeatData;
+use smithay_client_toolkit::seat::keyboard;
 use smithay_client_toolkit::seat::SeatHandling;
 use smithay_client_toolkit::seat::SeatListener;
 use tokio::io::unix::AsyncFd;
 use wayland_client::Attached;
 use wayland_client::DispatchData;
+use wayland_client::Main;
 use wayland_client::protocol::wl_compositor::WlCompositor;
 use wayland_client::protocol::wl_display::WlDisplay;
 use wayland_client::protocol::wl_output::WlOutput;
@@ -32,9 +35,11 @@ use wayland_protocols::wlr::unstable::layer_shell::v1::client::zwlr_layer_surfac
 use wayland_protocols::wlr::unstable::layer_shell::v1::client::zwlr_layer_surface_v1::ZwlrLayerSurfaceV1;
 use wayland_protocols::xdg_shell::client::xdg_popup::XdgPopup;
 use wayland_protocols::xdg_shell::client::xdg_surface::XdgSurface;
+use wayland_protocols::xdg_shell::client::xdg_positioner::XdgPositioner;
 use wayland_protocols::xdg_shell::client::xdg_wm_base::XdgWmBase;
 
 use crate::bar::Bar;
+use crate::render::BufferPool;
 use crate::state::State;
 use crate::util;
 
@@ -71,6 +76,7 @@ environment!(Globals,
 pub struct WaylandClient {
     pub env : Environment<Globals>,
     pub wl_display : Attached<WlDisplay>,
+    display : wayland_client::Display,
     #[allow(unused)] // need to hold this handle for the callback to remain alive
     seat_watcher : SeatListener,
     flush : Option<task::Waker>,
@@ -105,6 +111,7 @@ impl WaylandClient {
         let client = WaylandClient {
             env,
             wl_display,
+            display,
             seat_watcher,
             flush : None,
             need_flush : true,
@@ -126,9 +133,18 @@ impl WaylandClient {
         self.flush.take().map(|f| f.wake());
     }
 
+    /// Send queued requests immediately, for use after the event queue has stopped running
+    pub fn flush_now(&mut self) {
+        if let Err(e) = self.display.flush() {
+            error!("Could not flush the wayland connection: {}", e);
+        }
+        self.need_flush = false;
+    }
+
     fn add_seat(seat : &Attached<WlSeat>, si : &SeatData) {
         if si.has_pointer {
             let mouse = seat.get_pointer();
+            let seat = seat.detach();
             let mut over = None;
             let mut x = 0.0;
             let mut y = 0.0;
@@ -166,7 +182,7 @@ impl WaylandClient {
                         }
                     }
                     Event::Button {
-                        button, state : ButtonState::Pressed, ..
+                        button, serial, state : ButtonState::Pressed, ..
                     } => {
                         let button_id = match button {
                             0x110 => 0, // BTN_LEFT
@@ -182,7 +198,7 @@ impl WaylandClient {
                         };
                         for bar in &mut state.bars {
                             if Some(bar.ls.surf.wl.as_ref().id()) == over {
-                                bar.sink.button(x as f32, y as f32, button_id, &mut state.runtime);
+                                bar.button(x, y, button_id, Some((&seat, serial)), &mut state.runtime);
                             }
                             if bar.popup.as_ref().map(|p| p.wl.surf.wl.as_ref().id()) == over {
                                 bar.popup_button(x,y,button_id, &mut state.runtime);
@@ -233,7 +249,7 @@ impl WaylandClient {
                         }
                         for bar in &mut state.bars {
                             if Some(bar.ls.surf.wl.as_ref().id()) == over {
-                                bar.sink.button(x as f32, y as f32, button_id, &mut state.runtime);
+                                bar.button(x, y, button_id, None, &mut state.runtime);
                             }
                             if bar.popup.as_ref().map(|p| p.wl.surf.wl.as_ref().id()) == over {
                                 bar.popup_button(x,y,button_id, &mut state.runtime);
@@ -259,18 +275,62 @@ impl WaylandClient {
                 }
             });
         }
+        if si.has_keyboard {
+            let mut focus = None;
+            let rv = keyboard::map_keyboard(seat, None, move |event, _kbd, mut data| {
+                use keyboard::Event;
+                let state : &mut State = data.get().unwrap();
+                let (keysym, text) = match event {
+                    Event::Enter { surface, .. } => {
+                        focus = Some(surface.as_ref().id());
+                        return;
+                    }
+                    Event::Leave { surface, .. } => {
+                        if focus == Some(surface.as_ref().id()) {
+                            focus = None;
+                        }
+                        // modifiers are only reported while a bar has focus
+                        state.runtime.modifiers = 0;
+                        return;
+                    }
+                    Event::Modifiers { modifiers } => {
+                        use crate::event::{MOD_ALT,MOD_CTRL,MOD_SHIFT,MOD_SUPER};
+                        state.runtime.modifiers = [
+                            (modifiers.shift, MOD_SHIFT),
+                            (modifiers.ctrl, MOD_CTRL),
+                            (modifiers.alt, MOD_ALT),
+                            (modifiers.logo, MOD_SUPER),
+                        ].iter().filter(|m| m.0).fold(0, |mask, m| mask | m.1);
+                        return;
+                    }
+                    Event::Key { keysym, utf8, state : keyboard::KeyState::Pressed, .. } => (keysym, utf8),
+                    Event::Repeat { keysym, utf8, .. } => (keysym, utf8),
+                    _ => return,
+                };
+                for bar in &mut state.bars {
+                    let on_popup = bar.popup.as_ref().map(|p| p.wl.surf.wl.as_ref().id()) == focus;
+                    if on_popup || Some(bar.ls.surf.wl.as_ref().id()) == focus {
+                        bar.popup_key(keysym, text.as_deref(), &mut state.runtime);
+                    }
+                }
+            });
+            if let Err(e) = rv {
+                error!("Could not set up keyboard: {:?}", e);
+            }
+        }
         if si.has_touch {
             let finger = seat.get_touch();
+            let seat = seat.detach();
             finger.quick_assign(move |finger, event, mut data| {
                 use wayland_client::protocol::wl_touch::Event;
                 let state : &mut State = data.get().unwrap();
                 drop(finger);
                 match event {
-                    Event::Down { surface, x, y, .. } => {
+                    Event::Down { surface, serial, x, y, .. } => {
                         // TODO support gestures?  Wait for Up, detect Cancel
                         for bar in &mut state.bars {
                             if surface == *bar.ls.surf.wl {
-                                bar.sink.button(x as f32, y as f32, 9, &mut state.runtime);
+                                bar.button(x, y, 9, Some((&seat, serial)), &mut state.runtime);
                                 break;
                             }
                             if bar.popup.as_ref().map_or(false, |p| *p.wl.surf.wl == surface) {
@@ -284,26 +344,43 @@ impl WaylandClient {
         }
     }
 
-    pub fn new_popup(&self, bar : &Bar, anchor : (i32, i32, i32, i32), size : (i32, i32)) -> Popup {
-        self.new_popup_on(&bar.ls.ls_surf, !bar.anchor_top, anchor, size, bar.ls.surf.scale)
+    /// Create a popup for the bar.
+    ///
+    /// If a seat and the serial of the input event that opened it are given, the popup grabs
+    /// input, and the compositor dismisses it when the user clicks outside of it.
+    pub fn new_popup(&self, bar : &Bar, anchor : (i32, i32, i32, i32), size : (i32, i32), prefer_top : bool, halign : f32, grab : Option<(&WlSeat, u32)>) -> Popup {
+        self.new_popup_on(&bar.ls.ls_surf, prefer_top, halign, anchor, size, bar.ls.surf.scale, grab)
     }
 
-    fn new_popup_on(&self, ls_surf : &ZwlrLayerSurfaceV1, prefer_top : bool, anchor : (i32, i32, i32, i32), size : (i32, i32), scale : i32) -> Popup {
+    /// Create a positioner for a popup.
+    ///
+    /// The popup is placed above or below the anchor, with its left edge (halign=0), center, or
+    /// right edge (halign=1) aligned with the same part of the anchor.
+    fn popup_positioner(&self, prefer_top : bool, halign : f32, anchor : (i32, i32, i32, i32), size : (i32, i32)) -> Main<XdgPositioner> {
         use wayland_protocols::xdg_shell::client::xdg_positioner::{Anchor,Gravity};
-        let mut surf = Surface::new(self);
         let wmb : Attached<XdgWmBase> = self.env.require_global();
         let pos = wmb.create_positioner();
         pos.set_size(size.0, size.1);
         pos.set_anchor_rect(anchor.0, anchor.1, anchor.2, anchor.3);
         pos.set_offset(0, 0);
-        if prefer_top {
-            pos.set_anchor(Anchor::Top);
-            pos.set_gravity(Gravity::Top);
-        } else {
-            pos.set_anchor(Anchor::Bottom);
-            pos.set_gravity(Gravity::Bottom);
-        }
+        let (anchor, gravity) = match (prefer_top, halign) {
+            (true, a) if a < 0.25 => (Anchor::TopLeft, Gravity::TopRight),
+            (true, a) if a > 0.75 => (Anchor::TopRight, Gravity::TopLeft),
+            (true, _) => (Anchor::Top, Gravity::Top),
+            (false, a) if a < 0.25 => (Anchor::BottomLeft, Gravity::BottomRight),
+            (false, a) if a > 0.75 => (Anchor::BottomRight, Gravity::BottomLeft),
+            (false, _) => (Anchor::Bottom, Gravity::Bottom),
+        };
+        pos.set_anchor(anchor);
+        pos.set_gravity(gravity);
         pos.set_constraint_adjustment(0xF); // allow moving but not resizing
+        pos
+    }
+
+    fn new_popup_on(&self, ls_surf : &ZwlrLayerSurfaceV1, prefer_top : bool, halign : f32, anchor : (i32, i32, i32, i32), size : (i32, i32), scale : i32, grab : Option<(&WlSeat, u32)>) -> Popup {
+        let mut surf = Surface::new(self);
+        let wmb : Attached<XdgWmBase> = self.env.require_global();
+        let pos = self.popup_positioner(prefer_top, halign, anchor, size);
 
         let as_xdg = wmb.get_xdg_surface(&surf.wl);
         as_xdg.quick_assign(move |as_xdg, event, mut data| {
@@ -352,6 +429,9 @@ impl WaylandClient {
         });
 
         ls_surf.get_popup(&as_popup);
+        if let Some((seat, serial)) = grab {
+            as_popup.grab(seat, serial);
+        }
         as_xdg.set_window_geometry(0, 0, size.0, size.1);
         surf.set_buffer_scale(scale);
         surf.wl.commit();
@@ -362,27 +442,15 @@ impl WaylandClient {
             anchor,
             req_size: size,
             prefer_top,
+            halign,
             waiting_on_configure : true,
         }
     }
 
     pub fn resize_popup(&self, ls_surf : &ZwlrLayerSurfaceV1, popup : &mut Popup, size : (i32, i32), scale : i32) {
         if popup.as_popup.as_ref().version() >= wayland_protocols::xdg_shell::client::xdg_popup::REQ_REPOSITION_SINCE {
-            use wayland_protocols::xdg_shell::client::xdg_positioner::{Anchor,Gravity};
             popup.as_xdg.set_window_geometry(0, 0, size.0, size.1);
-            let wmb : Attached<XdgWmBase> = self.env.require_global();
-            let pos = wmb.create_positioner();
-            pos.set_size(size.0, size.1);
-            pos.set_anchor_rect(popup.anchor.0, popup.anchor.1, popup.anchor.2, popup.anchor.3);
-            pos.set_offset(0, 0);
-            if popup.prefer_top {
-                pos.set_anchor(Anchor::Top);
-                pos.set_gravity(Gravity::Top);
-            } else {
-                pos.set_anchor(Anchor::Bottom);
-                pos.set_gravity(Gravity::Bottom);
-            }
-            pos.set_constraint_adjustment(0xF); // allow moving but not resizing
+            let pos = self.popup_positioner(popup.prefer_top, popup.halign, popup.anchor, size);
             popup.as_popup.reposition(&pos, 0);
             popup.req_size = size;
         } else {
@@ -390,7 +458,7 @@ impl WaylandClient {
             popup.as_popup.destroy();
             popup.as_xdg.destroy();
             popup.surf.wl.destroy();
-            *popup = self.new_popup_on(ls_surf, popup.prefer_top, popup.anchor, size, scale);
+            *popup = self.new_popup_on(ls_surf, popup.prefer_top, popup.halign, popup.anchor, size, scale, None);
         }
     }
 }
@@ -408,9 +476,9 @@ pub async fn run_queue(mut wl_queue : wayland_client::EventQueue, state : Rc<Ref
 
         futures_util::future::poll_fn(|ctx| {
             let mut state = state.borrow_mut();
-            match &state.runtime.wayland.flush {
+            match &state.runtime.wayland().flush {
                 Some(w) if w.will_wake(ctx.waker()) => (),
-                _ => state.runtime.wayland.flush = Some(ctx.waker().clone()),
+                _ => state.runtime.wayland_mut().flush = Some(ctx.waker().clone()),
             }
 
             rg = match fd.poll_read_ready(ctx) {
@@ -423,7 +491,7 @@ pub async fn run_queue(mut wl_queue : wayland_client::EventQueue, state : Rc<Ref
                 task::Poll::Pending => None,
             };
 
-            if state.runtime.wayland.need_flush && wg.is_some() {
+            if state.runtime.wayland().need_flush && wg.is_some() {
                 task::Poll::Ready(Ok(()))
             } else if rg.is_some() {
                 task::Poll::Ready(Ok(()))
@@ -435,7 +503,7 @@ pub async fn run_queue(mut wl_queue : wayland_client::EventQueue, state : Rc<Ref
         if let Some(g) = &mut wg {
             match wl_queue.display().flush() {
                 Ok(()) => {
-                    state.borrow_mut().runtime.wayland.need_flush = false;
+                    state.borrow_mut().runtime.wayland_mut().need_flush = false;
                 }
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                     g.clear_ready();
@@ -470,6 +538,7 @@ pub async fn run_queue(mut wl_queue : wayland_client::EventQueue, state : Rc<Ref
 pub struct Surface {
     pub wl: Attached<WlSurface>,
     pub scale: i32,
+    pub buffers: BufferPool,
 
     width: u32,
     height: u32,
@@ -481,6 +550,7 @@ impl Surface {
         Self {
             wl,
             scale: 1,
+            buffers: BufferPool::new(wayland.env.require_global()),
             width: 0,
             height: 0,
         }
@@ -491,10 +561,6 @@ impl Surface {
         self.wl.set_buffer_scale(scale);
     }
 
-    pub fn scale_transform(&self) -> tiny_skia::Transform {
-        tiny_skia::Transform::from_scale(self.scale as f32, self.scale as f32)
-    }
-
     pub fn pixel_width(&self) -> i32 {
         self.width as i32 * self.scale
     }
@@ -511,6 +577,7 @@ pub struct LayerSurface {
     anchor: layer_surface::Anchor,
     #[allow(unused)]
     layer: layer_shell::Layer,
+    keyboard: bool,
 }
 
 impl LayerSurface {
@@ -556,7 +623,8 @@ impl LayerSurface {
             surf,
             ls_surf: ls_surf.into(),
             anchor: layer_surface::Anchor::empty(),
-            layer
+            layer,
+            keyboard: false,
         }
     }
 
@@ -572,18 +640,29 @@ impl LayerSurface {
         self.surf.height
     }
 
-    pub fn pixel_width(&self) -> i32 {
-        self.surf.width as i32 * self.surf.scale
-    }
-
-    pub fn pixel_height(&self) -> i32 {
-        self.surf.height as i32 * self.surf.scale
-    }
-
     pub fn set_anchor(&mut self, anchor: layer_surface::Anchor) {
         self.anchor = anchor;
         self.ls_surf.set_anchor(anchor);
     }
+
+    /// Allow the compositor to give this surface keyboard focus when it is clicked.
+    ///
+    /// Returns true if the surface state changed and needs to be committed.
+    pub fn set_keyboard_focusable(&mut self, focusable: bool) -> bool {
+        use layer_surface::KeyboardInteractivity;
+        // on-demand focus was added in version 4; older versions only allow exclusive focus,
+        // which would make the bar steal all keyboard input.
+        if self.keyboard == focusable || self.ls_surf.as_ref().version() < 4 {
+            return false;
+        }
+        self.keyboard = focusable;
+        if focusable {
+            self.ls_surf.set_keyboard_interactivity(KeyboardInteractivity::OnDemand);
+        } else {
+            self.ls_surf.set_keyboard_interactivity(KeyboardInteractivity::None);
+        }
+        true
+    }
 }
 
 impl Drop for LayerSurface {
@@ -602,6 +681,7 @@ pub struct Popup {
     pub req_size : (i32, i32), // requested logical size; may be rejected by compositor
     pub waiting_on_configure : bool,
     pub prefer_top : bool,
+    pub halign : f32,
 }
 
 impl Popup {
@@ -0,0 +1,428 @@
+//! WireGuard interface status, read using generic netlink
+use crate::data::{Periodic,Value};
+use crate::registry::Source;
+use crate::state::{NotifierList,Runtime};
+use crate::util::{self,Cell,read_lines,spawn_noerr,toml_to_f64};
+use log::{error,info,warn};
+use std::fs;
+use std::io;
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr,SocketAddr};
+use std::os::unix::io::{AsRawFd,FromRawFd,OwnedFd};
+use std::process::Stdio;
+use std::rc::Rc;
+use std::time::{Duration,SystemTime};
+
+const NLM_F_REQUEST : u16 = 0x1;
+const NLM_F_DUMP : u16 = 0x300;
+const NLMSG_ERROR : u16 = 2;
+const NLMSG_DONE : u16 = 3;
+const NLA_TYPE_MASK : u16 = 0x3fff;
+
+const GENL_ID_CTRL : u16 = 0x10;
+const CTRL_CMD_GETFAMILY : u8 = 3;
+const CTRL_ATTR_FAMILY_ID : u16 = 1;
+const CTRL_ATTR_FAMILY_NAME : u16 = 2;
+
+const WG_CMD_GET_DEVICE : u8 = 0;
+const WGDEVICE_A_IFNAME : u16 = 2;
+const WGDEVICE_A_PUBLIC_KEY : u16 = 4;
+const WGDEVICE_A_LISTEN_PORT : u16 = 6;
+const WGDEVICE_A_PEERS : u16 = 8;
+const WGPEER_A_PUBLIC_KEY : u16 = 1;
+const WGPEER_A_ENDPOINT : u16 = 4;
+const WGPEER_A_LAST_HANDSHAKE_TIME : u16 = 6;
+const WGPEER_A_RX_BYTES : u16 = 7;
+const WGPEER_A_TX_BYTES : u16 = 8;
+
+/// Peers without a handshake in this long can no longer send data (REJECT_AFTER_TIME)
+const HANDSHAKE_EXPIRY : Duration = Duration::from_secs(180);
+
+fn put_attr(msg : &mut Vec<u8>, ty : u16, data : &[u8]) {
+    msg.extend_from_slice(&((data.len() + 4) as u16).to_ne_bytes());
+    msg.extend_from_slice(&ty.to_ne_bytes());
+    msg.extend_from_slice(data);
+    while !msg.len().is_multiple_of(4) {
+        msg.push(0);
+    }
+}
+
+fn parse_attrs(mut data : &[u8]) -> Vec<(u16, &[u8])> {
+    let mut rv = Vec::new();
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let ty = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        rv.push((ty, &data[4..len]));
+        data = &data[((len + 3) & !3).min(data.len())..];
+    }
+    rv
+}
+
+fn read_u64(data : &[u8]) -> u64 {
+    data.get(..8).map_or(0, |b| u64::from_ne_bytes(b.try_into().unwrap()))
+}
+
+fn parse_endpoint(data : &[u8]) -> Option<SocketAddr> {
+    let family = u16::from_ne_bytes(data.get(..2)?.try_into().ok()?);
+    let port = u16::from_be_bytes(data.get(2..4)?.try_into().ok()?);
+    let ip = match family as i32 {
+        libc::AF_INET => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data.get(4..8)?).ok()?)),
+        libc::AF_INET6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data.get(8..24)?).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn base64(data : &[u8]) -> String {
+    const CHARS : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut rv = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                rv.push(CHARS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                rv.push('=');
+            }
+        }
+    }
+    rv
+}
+
+/// A generic netlink socket
+struct Netlink {
+    fd : OwnedFd,
+    seq : u32,
+}
+
+impl Netlink {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_GENERIC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // The kernel answers right away; this only guards against a stuck reader thread
+        let tv = libc::timeval { tv_sec : 1, tv_usec : 0 };
+        unsafe {
+            libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+                &tv as *const _ as *const libc::c_void, std::mem::size_of_val(&tv) as libc::socklen_t);
+        }
+        Ok(Netlink { fd, seq : 0 })
+    }
+
+    /// Send a request, returning the attributes of each reply
+    fn request(&mut self, family : u16, flags : u16, cmd : u8, attrs : &[(u16, &[u8])]) -> io::Result<Vec<Vec<u8>>> {
+        self.seq += 1;
+        let mut msg = vec![0u8; 20];
+        for &(ty, data) in attrs {
+            put_attr(&mut msg, ty, data);
+        }
+        let len = msg.len() as u32;
+        msg[0..4].copy_from_slice(&len.to_ne_bytes());
+        msg[4..6].copy_from_slice(&family.to_ne_bytes());
+        msg[6..8].copy_from_slice(&flags.to_ne_bytes());
+        msg[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+        msg[16] = cmd;
+        msg[17] = 1; // version
+        let rv = unsafe { libc::send(self.fd.as_raw_fd(), msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if rv < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; 32768];
+        let mut replies = Vec::new();
+        loop {
+            let n = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut data = &buf[..n as usize];
+            while data.len() >= 16 {
+                let len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+                let ty = u16::from_ne_bytes(data[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+                if len < 16 || len > data.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message"));
+                }
+                let body = &data[16..len];
+                if seq == self.seq {
+                    match ty {
+                        NLMSG_DONE => return Ok(replies),
+                        NLMSG_ERROR => {
+                            let err = body.get(..4).map_or(0, |b| i32::from_ne_bytes(b.try_into().unwrap()));
+                            return match err {
+                                0 => Ok(replies),
+                                e => Err(io::Error::from_raw_os_error(-e)),
+                            };
+                        }
+                        // skip the genetlink header
+                        _ if body.len() >= 4 => replies.push(body[4..].to_vec()),
+                        _ => {}
+                    }
+                }
+                data = &data[((len + 3) & !3).min(data.len())..];
+            }
+            if flags & NLM_F_DUMP == 0 && !replies.is_empty() {
+                return Ok(replies);
+            }
+        }
+    }
+
+    fn family(&mut self, name : &str) -> io::Result<u16> {
+        let name = format!("{}\0", name);
+        let replies = self.request(GENL_ID_CTRL, NLM_F_REQUEST, CTRL_CMD_GETFAMILY, &[(CTRL_ATTR_FAMILY_NAME, name.as_bytes())])?;
+        replies.iter()
+            .flat_map(|r| parse_attrs(r))
+            .find(|&(ty, _)| ty == CTRL_ATTR_FAMILY_ID)
+            .and_then(|(_, v)| Some(u16::from_ne_bytes(v.get(..2)?.try_into().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no family ID"))
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Peer {
+    public_key : String,
+    endpoint : Option<SocketAddr>,
+    last_handshake : Option<SystemTime>,
+    rx : u64,
+    tx : u64,
+}
+
+impl Peer {
+    fn handshake_age(&self) -> Option<Duration> {
+        self.last_handshake.map(|t| t.elapsed().unwrap_or_default())
+    }
+}
+
+#[derive(Debug,Default,Clone)]
+struct Device {
+    exists : bool,
+    up : bool,
+    rx : u64,
+    tx : u64,
+    /// Only known if the netlink query was permitted
+    public_key : Option<String>,
+    listen_port : Option<u16>,
+    peers : Option<Vec<Peer>>,
+}
+
+impl Device {
+    /// Read the state and traffic counters, which are readable by anyone
+    fn from_sysfs(iface : &str) -> Self {
+        let dir = format!("/sys/class/net/{}", iface);
+        let read = |file : &str| fs::read_to_string(format!("{}/{}", dir, file)).ok();
+        let flags = read("flags").and_then(|f| u32::from_str_radix(f.trim().trim_start_matches("0x"), 16).ok());
+        let stat = |name : &str| read(name).and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+        Device {
+            exists : flags.is_some(),
+            up : flags.is_some_and(|f| f & libc::IFF_UP as u32 != 0),
+            rx : stat("statistics/rx_bytes"),
+            tx : stat("statistics/tx_bytes"),
+            ..Default::default()
+        }
+    }
+
+    /// Add the keys and peers, which needs CAP_NET_ADMIN
+    fn read_netlink(&mut self, iface : &str) -> io::Result<()> {
+        let mut nl = Netlink::open()?;
+        let family = nl.family("wireguard")?;
+        let name = format!("{}\0", iface);
+        let replies = nl.request(family, NLM_F_REQUEST | NLM_F_DUMP, WG_CMD_GET_DEVICE, &[(WGDEVICE_A_IFNAME, name.as_bytes())])?;
+        let mut peers : Vec<Peer> = Vec::new();
+        for reply in &replies {
+            for (ty, value) in parse_attrs(reply) {
+                match ty {
+                    WGDEVICE_A_PUBLIC_KEY => self.public_key = Some(base64(value)),
+                    WGDEVICE_A_LISTEN_PORT => self.listen_port = value.get(..2).map(|b| u16::from_ne_bytes([b[0], b[1]])),
+                    WGDEVICE_A_PEERS => {
+                        for (_, peer) in parse_attrs(value) {
+                            let mut p = Peer { public_key : String::new(), endpoint : None, last_handshake : None, rx : 0, tx : 0 };
+                            for (ty, value) in parse_attrs(peer) {
+                                match ty {
+                                    WGPEER_A_PUBLIC_KEY => p.public_key = base64(value),
+                                    WGPEER_A_ENDPOINT => p.endpoint = parse_endpoint(value),
+                                    WGPEER_A_LAST_HANDSHAKE_TIME => {
+                                        let secs = read_u64(value);
+                                        p.last_handshake = (secs != 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+                                    }
+                                    WGPEER_A_RX_BYTES => p.rx = read_u64(value),
+                                    WGPEER_A_TX_BYTES => p.tx = read_u64(value),
+                                    _ => {}
+                                }
+                            }
+                            // a peer with many allowed IPs is continued in the next message
+                            if !peers.iter().any(|q| q.public_key == p.public_key) {
+                                peers.push(p);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.peers = Some(peers);
+        Ok(())
+    }
+
+    fn state(&self) -> &'static str {
+        match (self.exists, self.up) {
+            (false, _) => "absent",
+            (true, false) => "down",
+            (true, true) => "up",
+        }
+    }
+}
+
+#[derive(Debug,Default)]
+struct Shared {
+    iface : Box<str>,
+    device : Cell<Device>,
+    /// Netlink queries were refused, so only sysfs is used
+    denied : Cell<bool>,
+    running : Cell<bool>,
+    interested : Cell<NotifierList>,
+}
+
+impl Shared {
+    /// Read the device on another thread, as netlink and sysfs reads block
+    async fn refresh(self : Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        let iface = String::from(&*self.iface);
+        let denied = self.denied.get();
+        let rv = tokio::task::spawn_blocking(move || {
+            let mut device = Device::from_sysfs(&iface);
+            let rv = if device.exists && !denied { device.read_netlink(&iface) } else { Ok(()) };
+            (device, rv)
+        }).await;
+        self.running.set(false);
+        let device = match rv {
+            Ok((device, Ok(()))) => device,
+            Ok((device, Err(e))) if e.raw_os_error() == Some(libc::EPERM) => {
+                info!("Reading the peers of {} needs CAP_NET_ADMIN; only showing its state", self.iface);
+                self.denied.set(true);
+                device
+            }
+            Ok((device, Err(e))) => {
+                warn!("Could not read WireGuard interface {}: {}", self.iface, e);
+                device
+            }
+            Err(e) => return error!("Could not read WireGuard interface {}: {}", self.iface, e),
+        };
+        self.device.set(device);
+        self.interested.take().notify_data("wireguard");
+    }
+}
+
+/// The "wireguard" module
+#[derive(Debug)]
+pub struct WireGuard {
+    up_cmd : Box<str>,
+    down_cmd : Box<str>,
+    poll : Rc<Periodic<Rc<Shared>>>,
+}
+
+impl WireGuard {
+    pub fn from_toml(config : &toml::Value) -> Result<Self, String> {
+        let iface = config.get("interface").and_then(|v| v.as_str())
+            .ok_or("The 'interface' key is required")?;
+        let cmd = |key : &str, verb : &str| config.get(key).and_then(|v| v.as_str())
+            .map_or_else(|| format!("nmcli connection {} {}", verb, iface).into(), Into::into);
+        Ok(WireGuard {
+            up_cmd : cmd("up-command", "up"),
+            down_cmd : cmd("down-command", "down"),
+            poll : Rc::new(Periodic::new(
+                toml_to_f64(config.get("poll")).unwrap_or(5.0),
+                Rc::new(Shared { iface : iface.into(), ..Default::default() }),
+            )),
+        })
+    }
+
+    fn read_refresh(&self, rt : &Runtime) {
+        self.poll.read_refresh_async(rt, |shared| shared.clone().refresh());
+        self.poll.data().interested.take_in(|i| i.add(rt));
+    }
+
+    /// Bring the tunnel up or down, reading its state again once the command exits
+    fn run(&self, up : bool, rt : &Runtime) {
+        let (cmd, key) = if up { (&self.up_cmd, "up-command") } else { (&self.down_cmd, "down-command") };
+        let cmd = rt.format_or(cmd, key).into_text().into_owned();
+        let child = util::command("/bin/sh", rt.exec_scope)
+            .arg("-c").arg(&cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn();
+        let stdout = match child {
+            Ok(mut child) => child.stdout.take().unwrap(),
+            Err(e) => return error!("Could not execute {}: {}", cmd, e),
+        };
+        let fd = stdout.as_raw_fd();
+        unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+        let poll = self.poll.clone();
+        spawn_noerr(async move {
+            // children are not waited for, so the end of the output marks the exit
+            if let Err(e) = read_lines(fd, &cmd, |line| info!("{}: {}", cmd, line)).await {
+                warn!("Error reading output of {}: {}", cmd, e);
+            }
+            drop(stdout);
+            poll.refresh();
+        });
+    }
+}
+
+impl Source for WireGuard {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.read_refresh(rt);
+        self.poll.data().device.take_in(|dev| {
+            let peers = dev.peers.as_deref();
+            let newest = peers.and_then(|p| p.iter().filter_map(Peer::handshake_age).min());
+            match key {
+                "" | "text" | "state" => Value::Borrow(dev.state()),
+                "up" => Value::Bool(dev.up),
+                "rx" => Value::Float(dev.rx as f64),
+                "tx" => Value::Float(dev.tx as f64),
+                "rx-mib" => Value::Float(dev.rx as f64 / 1048576.0),
+                "tx-mib" => Value::Float(dev.tx as f64 / 1048576.0),
+                "public-key" => dev.public_key.clone().map_or(Value::Null, Value::Owned),
+                "listen-port" => dev.listen_port.map_or(Value::Null, |p| Value::Float(p as f64)),
+                "peers" => peers.map_or(Value::Null, |p| Value::Float(p.len() as f64)),
+                "connected" => peers.map_or(Value::Null, |p| {
+                    let n = p.iter().filter(|p| p.handshake_age().is_some_and(|a| a < HANDSHAKE_EXPIRY)).count();
+                    Value::Float(n as f64)
+                }),
+                "handshake-age" => newest.map_or(Value::Null, |a| Value::Float(a.as_secs() as f64)),
+                "peer-list" => peers.map_or(Value::Null, |p| {
+                    let lines : Vec<_> = p.iter().map(|p| {
+                        let endpoint = p.endpoint.map_or_else(|| "(no endpoint)".into(), |e| e.to_string());
+                        let age = p.handshake_age().map_or_else(|| "never".into(), |a| format!("{}s ago", a.as_secs()));
+                        format!("{} {} handshake {}", &p.public_key[..p.public_key.len().min(8)], endpoint, age)
+                    }).collect();
+                    Value::Owned(lines.join("\n"))
+                }),
+                _ => Value::Null,
+            }
+        })
+    }
+
+    fn write(&self, name : &str, key : &str, value : Value, rt : &Runtime) {
+        let up = match (key, value.as_str_fast()) {
+            ("", "up") => true,
+            ("", "down") => false,
+            ("", "" | "toggle") => {
+                self.read_refresh(rt);
+                !self.poll.data().device.take_in(|d| d.up)
+            }
+            (_, value) => return error!("Ignoring write of '{}' to {}.{}", value, name, key),
+        };
+        self.run(up, rt);
+    }
+
+    fn refresh(&self, _name : &str, _rt : &Runtime) -> bool {
+        self.poll.refresh();
+        true
+    }
+}
@@ -1,13 +1,21 @@
 use crate::data::Value;
+use crate::font::render_font;
+use crate::render::Render;
 use crate::state::{NotifierList,Runtime};
 use crate::util::{Cell,spawn};
+use crate::wayland::WaylandClient;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap,HashSet,VecDeque};
+use std::io::Write;
 use std::rc::{Rc,Weak};
 use bytes::{Bytes,BytesMut};
+use smithay_client_toolkit::output::with_output_info;
+use wayland_client::protocol::wl_output::WlOutput;
 use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_protocols::wlr::unstable::data_control::v1::client::zwlr_data_control_device_v1::ZwlrDataControlDeviceV1;
 use wayland_protocols::wlr::unstable::data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1;
 use wayland_protocols::wlr::unstable::data_control::v1::client::zwlr_data_control_offer_v1::ZwlrDataControlOfferV1;
+use wayland_protocols::wlr::unstable::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1;
 use futures_channel::oneshot;
 use futures_util::future::{Either,select};
 
@@ -32,6 +40,7 @@ struct OfferData {
 
 struct Clipboard {
     seat: WlSeat,
+    device: Option<ZwlrDataControlDeviceV1>,
     selection: bool,
     contents: Option<ZwlrDataControlOfferV1>,
     interested: Vec<Weak<ClipboardData>>,
@@ -43,21 +52,27 @@ thread_local! {
 
 fn start_dcm(rt: &Runtime) -> VecDeque<Clipboard> {
     let mut rv = VecDeque::new();
-    if let Some(dcm) = rt.wayland.env.get_global::<ZwlrDataControlManagerV1>() {
-        for seat in rt.wayland.env.get_all_seats() {
+    let wayland = match &rt.wayland {
+        Some(wayland) => wayland,
+        None => return rv,
+    };
+    if let Some(dcm) = wayland.env.get_global::<ZwlrDataControlManagerV1>() {
+        for seat in wayland.env.get_all_seats() {
+            let dcd = dcm.get_data_device(&seat);
             rv.push_back(Clipboard {
                 seat: seat.detach(),
+                device: Some(dcd.detach()),
                 selection: true,
                 contents: None,
                 interested: Vec::new(),
             });
             rv.push_back(Clipboard {
                 seat: seat.detach(),
+                device: Some(dcd.detach()),
                 selection: false,
                 contents: None,
                 interested: Vec::new(),
             });
-            let dcd = dcm.get_data_device(&seat);
             dcd.quick_assign(move |dcd, event, _data| {
                 use wayland_protocols::wlr::unstable::data_control::v1::client::zwlr_data_control_device_v1::Event;
                 match event {
@@ -110,6 +125,7 @@ fn set_seat_offer(seat: &WlSeat, contents: Option<ZwlrDataControlOfferV1>, selec
     CLIPBOARDS.with(|clips| {
         let mut clips = clips.borrow_mut();
         let clips = clips.as_mut().unwrap();
+        let mut device = None;
         for i in 0..clips.len() {
             let clip = &clips[i];
             if clip.seat != *seat || clip.selection != selection {
@@ -131,11 +147,12 @@ fn set_seat_offer(seat: &WlSeat, contents: Option<ZwlrDataControlOfferV1>, selec
                 }
                 view.interested.take().notify_data("empty-clipboard");
             }
-            clips.remove(i);
+            device = clips.remove(i).and_then(|clip| clip.device);
             break;
         }
         clips.push_front(Clipboard {
             seat: seat.clone(),
+            device,
             selection,
             contents,
             interested: Vec::new(),
@@ -143,19 +160,46 @@ fn set_seat_offer(seat: &WlSeat, contents: Option<ZwlrDataControlOfferV1>, selec
     });
 }
 
+const TEXT_MIMES : &[&str] = &["text/plain;charset=utf-8", "text/plain", "UTF8_STRING", "STRING", "TEXT"];
+
 #[derive(Debug)]
 pub struct ClipboardData {
     pub seat: Option<Box<str>>,
     pub mime_list: Vec<Box<str>>,
     pub selection: bool,
+    /// Maximum number of text entries to remember
+    pub history_len: usize,
+    /// Maximum number of characters shown in previews
+    pub preview_len: usize,
+    pub popup: bool,
+    pub history: RefCell<VecDeque<Rc<str>>>,
     pub interested: Cell<NotifierList>,
 }
 
+/// Collapse whitespace and limit the text to the given number of characters
+fn preview(text : &str, len : usize) -> String {
+    let mut rv = String::new();
+    for (i, word) in text.split_whitespace().enumerate() {
+        if i != 0 {
+            rv.push(' ');
+        }
+        rv.push_str(word);
+        if rv.len() > len * 4 {
+            break;
+        }
+    }
+    if let Some((pos, _)) = rv.char_indices().nth(len) {
+        rv.truncate(pos);
+        rv.push('…');
+    }
+    rv
+}
+
 impl ClipboardData {
     fn find_best_mime(&self, data: &OfferData) -> Option<usize> {
         let offered = data.mimes.borrow();
         if self.mime_list.is_empty() {
-            for &mime in &["text/plain;charset=utf-8", "text/plain", "UTF8_STRING", "STRING", "TEXT"] {
+            for &mime in TEXT_MIMES {
                 if let Some(i) = offered.iter().position(|t| &*t.mime == mime) {
                     return Some(i);
                 }
@@ -170,6 +214,17 @@ impl ClipboardData {
         None
     }
 
+    fn watches(&self, clip: &Clipboard) -> bool {
+        if clip.selection != self.selection {
+            return false;
+        }
+        match &self.seat {
+            Some(seat) => smithay_client_toolkit::seat::with_seat_data(&clip.seat, |data| {
+                data.name == **seat
+            }) == Some(true),
+            None => true,
+        }
+    }
     fn start_read(&self, contents: &ZwlrDataControlOfferV1, offer: &mut OfferType) {
         match &mut offer.value {
             OfferValue::Available => {
@@ -229,22 +284,32 @@ impl ClipboardData {
         }
     }
 
+    /// Add a text value to the front of the history
+    fn record(&self, value: &[u8]) {
+        let text = match std::str::from_utf8(value) {
+            Ok(text) if !text.trim().is_empty() => text,
+            _ => return,
+        };
+        let mut history = self.history.borrow_mut();
+        if history.front().is_some_and(|e| **e == *text) {
+            return;
+        }
+        history.retain(|e| **e != *text);
+        history.push_front(text.into());
+        history.truncate(self.history_len);
+    }
+
     pub fn read_in<F : FnOnce(Value) -> R, R>(self: &Rc<Self>, _name : &str, key : &str, rt : &Runtime, f : F) -> R {
         self.interested.take_in(|i| i.add(rt));
+        let mut mime = None;
+        let mut value = None;
         CLIPBOARDS.with(|clips| {
             let mut clips = clips.borrow_mut();
             let clips = clips.get_or_insert_with(|| start_dcm(rt));
             for clip in &mut *clips {
-                if clip.selection != self.selection {
+                if !self.watches(clip) {
                     continue;
                 }
-                if let Some(seat) = &self.seat {
-                    if smithay_client_toolkit::seat::with_seat_data(&clip.seat, |data| {
-                        data.name == &**seat
-                    }) != Some(true) {
-                        continue;
-                    }
-                }
 
                 if clip.interested.iter().all(|e| e.as_ptr() != Rc::as_ptr(self)) {
                     clip.interested.push(Rc::downgrade(self));
@@ -255,23 +320,216 @@ impl ClipboardData {
                         if let Some(idx) = self.find_best_mime(data) {
                             let mut mimes = data.mimes.borrow_mut();
                             let best = &mut mimes[idx];
+                            mime = Some(best.mime.clone());
                             if key == "mime" {
-                                return f(Value::Borrow(&best.mime));
+                                return;
                             }
 
                             self.start_read(contents, best);
 
                             if let OfferValue::Finished(v) = &best.value {
-                                return f(String::from_utf8_lossy(&v).into());
-                            } else {
-                                return f(Value::Null);
+                                value = Some(v.clone());
                             }
                         }
                     }
                 }
-                return f(Value::Null);
+                return;
+            }
+        });
+        if let Some(v) = &value {
+            if self.history_len > 0 {
+                self.record(v);
+            }
+        }
+        match key {
+            "mime" => f(mime.as_deref().map_or(Value::Null, Value::Borrow)),
+            "count" => f(Value::Float(self.history.borrow().len() as f64)),
+            "preview" => match value {
+                Some(v) => f(preview(&String::from_utf8_lossy(&v), self.preview_len).into()),
+                None => f(Value::Null),
+            }
+            _ => match value {
+                Some(v) => f(String::from_utf8_lossy(&v).into()),
+                None => f(Value::Null),
+            }
+        }
+    }
+
+    /// Make the given text the current contents of the clipboard
+    pub fn restore(&self, text: Rc<str>, rt: &Runtime) {
+        let dcm = match rt.wayland.as_ref().and_then(|w| w.env.get_global::<ZwlrDataControlManagerV1>()) {
+            Some(dcm) => dcm,
+            None => return,
+        };
+        CLIPBOARDS.with(|clips| {
+            let clips = clips.borrow();
+            for clip in clips.iter().flatten() {
+                let device = match &clip.device {
+                    Some(device) if self.watches(clip) => device,
+                    _ => continue,
+                };
+                let source = dcm.create_data_source();
+                for &mime in TEXT_MIMES {
+                    source.offer(mime.into());
+                }
+                let text = text.clone();
+                source.quick_assign(move |source, event, _data| {
+                    use wayland_protocols::wlr::unstable::data_control::v1::client::zwlr_data_control_source_v1::Event;
+                    match event {
+                        Event::Send { fd, .. } => {
+                            use std::os::unix::io::FromRawFd;
+                            let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+                            let data = text.as_bytes().to_vec();
+                            // Our own clipboard watcher may be the one reading this, so the write
+                            // must not block the main thread.
+                            std::thread::spawn(move || {
+                                let _ = file.write_all(&data);
+                            });
+                        }
+                        Event::Cancelled => source.destroy(),
+                        _ => {}
+                    }
+                });
+                if self.selection {
+                    device.set_primary_selection(Some(&source));
+                } else {
+                    device.set_selection(Some(&source));
+                }
+            }
+        });
+    }
+
+    pub fn popup(self: &Rc<Self>) -> ClipboardPopup {
+        ClipboardPopup {
+            state : self.clone(),
+            rendered : Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct ClipboardPopup {
+    state : Rc<ClipboardData>,
+    /// The vertical extent of each history entry from the last render, and its index
+    rendered : Vec<(f32, f32, usize)>,
+}
+
+impl PartialEq for ClipboardPopup {
+    fn eq(&self, rhs : &Self) -> bool {
+        Rc::ptr_eq(&self.state, &rhs.state)
+    }
+}
+
+impl ClipboardPopup {
+    pub fn render(&mut self, ctx : &mut Render) -> Vec<tiny_skia::Rect> {
+        let width = ctx.render_extents.1.x;
+        let history = self.state.history.borrow();
+
+        self.rendered.clear();
+        let mut xsize : f32 = 0.0;
+        let mut ypos = 2.0;
+        if history.is_empty() {
+            let tsize = render_font(ctx, (2.0, ypos), "Clipboard history is empty", false);
+            xsize = tsize.0;
+            ypos += tsize.1.ceil() + 5.0;
+        }
+        for (i, entry) in history.iter().enumerate() {
+            let text = preview(entry, self.state.preview_len);
+            let tsize = render_font(ctx, (2.0, ypos), &text, false);
+            let end = ypos + tsize.1.ceil();
+            xsize = xsize.max(2.0 + tsize.0);
+            self.rendered.push((ypos, end, i));
+            ypos = end + 5.0;
+        }
+        ctx.render_pos = tiny_skia::Point { x: xsize.ceil() + 2.0, y: ypos };
+        self.rendered.iter()
+            .filter_map(|&(min, max, _)| tiny_skia::Rect::from_ltrb(0.0, min - 2.0, width, max + 2.0))
+            .collect()
+    }
+
+    /// Restore (left click) or forget (right click) an entry; returns true if the popup should be closed
+    pub fn button(&mut self, _x : f64, y : f64, button : u32, runtime : &mut Runtime) -> bool {
+        let y = y as f32;
+        let i = match self.rendered.iter().find(|&&(min, max, _)| y >= min - 2.0 && y <= max + 2.0) {
+            Some(&(_, _, i)) => i,
+            None => return false,
+        };
+        match button {
+            0 | 9 => {
+                let text = self.state.history.borrow().get(i).cloned();
+                if let Some(text) = text {
+                    self.state.restore(text, runtime);
+                }
+                true
+            }
+            1 => {
+                self.state.history.borrow_mut().remove(i);
+                self.state.interested.take().notify_data("clipboard-history");
+                false
             }
-            f(Value::Null)
-        })
+            _ => false,
+        }
     }
 }
+
+#[derive(Default)]
+struct Toplevel {
+    fullscreen: bool,
+    outputs: Vec<WlOutput>,
+}
+
+/// Call the function with the names of the outputs showing a fullscreen window, and again after
+/// any toplevel changes state.  Returns false if the compositor does not support
+/// wlr-foreign-toplevel-management.
+pub fn watch_fullscreen(wayland: &WaylandClient, f: impl FnMut(HashSet<String>) + 'static) -> bool {
+    let manager = match wayland.env.manager.instantiate_range::<ZwlrForeignToplevelManagerV1>(2, 3) {
+        Ok(manager) => manager,
+        Err(_) => return false,
+    };
+    let toplevels: Rc<RefCell<HashMap<u32, Toplevel>>> = Default::default();
+    let f = Rc::new(RefCell::new(f));
+    manager.quick_assign(move |_manager, event, _data| {
+        use wayland_protocols::wlr::unstable::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::Event;
+        if let Event::Toplevel { toplevel } = event {
+            let id = toplevel.as_ref().id();
+            toplevels.borrow_mut().insert(id, Toplevel::default());
+            let toplevels = toplevels.clone();
+            let f = f.clone();
+            toplevel.quick_assign(move |toplevel, event, _data| {
+                use wayland_protocols::wlr::unstable::foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::Event;
+                let mut list = toplevels.borrow_mut();
+                match event {
+                    Event::OutputEnter { output } => {
+                        list.entry(id).or_default().outputs.push(output);
+                        return;
+                    }
+                    Event::OutputLeave { output } => {
+                        list.entry(id).or_default().outputs.retain(|o| *o != output);
+                        return;
+                    }
+                    Event::State { state } => {
+                        // An array of native-endian u32 values; 3 is fullscreen
+                        list.entry(id).or_default().fullscreen = state.chunks_exact(4)
+                            .any(|v| u32::from_ne_bytes([v[0], v[1], v[2], v[3]]) == 3);
+                        return;
+                    }
+                    Event::Done => {}
+                    Event::Closed => {
+                        list.remove(&id);
+                        toplevel.destroy();
+                    }
+                    _ => return,
+                }
+                let mut outputs = HashSet::new();
+                for output in list.values().filter(|t| t.fullscreen).flat_map(|t| &t.outputs) {
+                    with_output_info(output, |oi| {
+                        outputs.insert(oi.name.clone());
+                    });
+                }
+                drop(list);
+                (*f.borrow_mut())(outputs);
+            });
+        }
+    });
+    true
+}
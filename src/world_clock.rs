@@ -0,0 +1,158 @@
+//! The time in several time zones, as a list for a focus-list
+use crate::data::Value;
+use crate::locale;
+use crate::registry::{Entry,Source};
+use crate::state::Runtime;
+use crate::timer::schedule_tick;
+use crate::util::Cell;
+use chrono::{DateTime,Local,TimeZone,Timelike,Utc};
+use chrono_tz::Tz;
+use futures_util::future::RemoteHandle;
+use log::error;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// One time zone in the list
+#[derive(Debug)]
+pub struct Zone {
+    label : Box<str>,
+    tz : Tz,
+    format : Rc<str>,
+    /// Start and end of the working day, in hours
+    work : (u32, u32),
+}
+
+impl Zone {
+    fn text<T : TimeZone>(&self, t : &DateTime<T>) -> String {
+        let t = t.with_timezone(&self.tz);
+        let fmt = locale::localize_format(&self.format, &t, Some(t.hour()));
+        t.format(&fmt).to_string()
+    }
+
+    fn working(&self, t : &DateTime<Tz>) -> bool {
+        let (start, end) = self.work;
+        let h = t.hour();
+        match start <= end {
+            true => h >= start && h < end,
+            false => h >= start || h < end,
+        }
+    }
+}
+
+impl Entry for Zone {
+    fn kind(&self) -> &'static str {
+        "world-clock"
+    }
+
+    fn read<'a>(&'a self, key : &str, _rt : &Runtime) -> Value<'a> {
+        let utc = Utc::now();
+        let now = utc.with_timezone(&self.tz);
+        match key {
+            "" | "text" | "time" => Value::Owned(self.text(&utc)),
+            "label" => Value::Borrow(&self.label),
+            "timezone" => Value::Borrow(self.tz.name()),
+            "date" => Value::Owned(now.format("%Y-%m-%d").to_string()),
+            "hour" => Value::Float(now.hour() as f64),
+            "minute" => Value::Float(now.minute() as f64),
+            "offset" => Value::Owned(now.format("%:z").to_string()),
+            "day" => {
+                // the date there compared to here: "+1", "-1", or empty
+                let here = utc.with_timezone(&Local).naive_local().date();
+                let days = (now.naive_local().date() - here).num_days();
+                match days {
+                    0 => Value::Null,
+                    d => Value::Owned(format!("{:+}", d)),
+                }
+            }
+            "working" => Value::Bool(self.working(&now)),
+            _ => Value::Null,
+        }
+    }
+}
+
+/// The "world-clock" module
+#[derive(Debug)]
+pub struct WorldClock {
+    zones : Vec<Rc<Zone>>,
+    tick : Cell<Option<RemoteHandle<()>>>,
+}
+
+impl WorldClock {
+    pub fn from_toml(config : &toml::Value) -> Self {
+        let format : Rc<str> = config.get("format").and_then(|v| v.as_str()).unwrap_or("%H:%M").into();
+        let work = match config.get("work-hours").and_then(|v| v.as_array()).map(|a| &a[..]) {
+            Some([toml::Value::Integer(s), toml::Value::Integer(e)]) => (*s as u32 % 24, *e as u32 % 24),
+            _ => (9, 17),
+        };
+        let mut zones = Vec::new();
+        for zone in config.get("zones").and_then(|v| v.as_array()).map_or(&[][..], |a| &a[..]) {
+            let (name, label) = match zone {
+                toml::Value::String(name) => (&name[..], None),
+                toml::Value::Table(t) => match t.get("timezone").and_then(|v| v.as_str()) {
+                    Some(name) => (name, t.get("label").and_then(|v| v.as_str())),
+                    None => {
+                        error!("A world-clock zone needs a 'timezone'");
+                        continue;
+                    }
+                },
+                _ => continue,
+            };
+            let tz = match name.parse::<Tz>() {
+                Ok(tz) => tz,
+                Err(e) => {
+                    error!("Could not find timezone '{}': {}", name, e);
+                    continue;
+                }
+            };
+            // "America/New_York" is shown as "New York"
+            let label = label.map_or_else(|| name.rsplit('/').next().unwrap_or(name).replace('_', " "), String::from);
+            zones.push(Rc::new(Zone {
+                label : label.into(),
+                tz,
+                format : format.clone(),
+                work,
+            }));
+        }
+        WorldClock { zones, tick : Cell::default() }
+    }
+
+    /// Redraw when the text next changes: each second if the format shows seconds, otherwise
+    /// at the start of each minute
+    fn schedule(&self, rt : &Runtime) {
+        let now = Utc::now() + chrono::Duration::milliseconds(5);
+        let next = now + chrono::Duration::seconds(1);
+        let subsec = now.nanosecond() as u64;
+        let delay = match self.zones.iter().any(|z| z.text(&now) != z.text(&next)) {
+            true => 1_000_999_999u64.checked_sub(subsec),
+            false => (1_000_000_000 * (60 - now.second() as u64) + 999_999).checked_sub(subsec),
+        };
+        schedule_tick(&self.tick, Some(delay.map_or(Duration::from_secs(1), Duration::from_nanos)), rt);
+    }
+}
+
+impl Source for WorldClock {
+    fn read<'a>(&'a self, _name : &str, key : &str, rt : &Runtime) -> Value<'a> {
+        self.schedule(rt);
+        let now = Utc::now();
+        match key {
+            "" | "text" => {
+                let text : Vec<_> = self.zones.iter().map(|z| format!("{} {}", z.label, z.text(&now))).collect();
+                Value::Owned(text.join("  "))
+            }
+            "count" => Value::Float(self.zones.len() as f64),
+            // the time in one zone, by label or name
+            key => match self.zones.iter().find(|z| &*z.label == key || z.tz.name() == key) {
+                Some(zone) => Value::Owned(zone.text(&now)),
+                None => Value::Null,
+            },
+        }
+    }
+
+    fn read_focus_list(&self, rt : &Runtime, f : &mut dyn FnMut(bool, Rc<dyn Entry>)) {
+        self.schedule(rt);
+        let now = Utc::now();
+        for zone in &self.zones {
+            f(zone.working(&now.with_timezone(&zone.tz)), zone.clone());
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! Output backend for X11 sessions
+//!
+//! Each bar is a dock window with struts reserving its space, drawn using the same item rendering
+//! as the wayland bars.  Popups and the control socket are not available on X11.
+use crate::bar::{self,BarStyle};
+use crate::event::EventSink;
+use crate::item::Item;
+use crate::render;
+use crate::state::Runtime;
+use crate::util::{self,spawn,spawn_noerr};
+use log::{debug,info,warn,error};
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::error::Error;
+use std::os::unix::io::{AsRawFd,RawFd};
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+use tokio::io::unix::AsyncFd;
+use x11rb::connection::{Connection,RequestConnection};
+use x11rb::protocol::Event;
+use x11rb::protocol::randr::ConnectionExt as _;
+use x11rb::protocol::xproto::*;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+
+/// Shortest time between frames.  X11 has no frame callbacks to pace redraws (such as those of
+/// an animation), so this stands in for them.
+const MIN_FRAME_INTERVAL : Duration = Duration::from_millis(16);
+
+x11rb::atom_manager! {
+    Atoms : AtomsCookie {
+        _NET_WM_DESKTOP,
+        _NET_WM_NAME,
+        _NET_WM_STATE,
+        _NET_WM_STATE_ABOVE,
+        _NET_WM_STATE_STICKY,
+        _NET_WM_STRUT,
+        _NET_WM_STRUT_PARTIAL,
+        _NET_WM_WINDOW_TYPE,
+        _NET_WM_WINDOW_TYPE_DOCK,
+        UTF8_STRING,
+    }
+}
+
+/// True if there is an X11 display to fall back to
+pub fn available() -> bool {
+    std::env::var_os("DISPLAY").is_some()
+}
+
+#[derive(Debug,Clone)]
+struct Monitor {
+    name : String,
+    primary : bool,
+    x : i16,
+    y : i16,
+    width : u16,
+    height : u16,
+}
+
+struct X11Bar {
+    window : Window,
+    gc : Gcontext,
+    colormap : Option<Colormap>,
+    depth : u8,
+    size : (u32, u32),
+    item : Rc<Item>,
+    style : BarStyle,
+    sink : EventSink,
+    dirty : bool,
+}
+
+struct X11State {
+    conn : RustConnection,
+    screen : usize,
+    atoms : Atoms,
+    runtime : Runtime,
+    bars : Vec<X11Bar>,
+}
+
+/// Wrapper so the connection's socket can be registered with tokio
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl X11State {
+    fn monitors(&self) -> Vec<Monitor> {
+        let screen = &self.conn.setup().roots[self.screen];
+        let reply = self.conn.randr_get_monitors(screen.root, true)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|cookie| Ok(cookie.reply()?));
+        match reply {
+            Ok(reply) if !reply.monitors.is_empty() => {
+                reply.monitors.iter().map(|m| {
+                    let name = self.conn.get_atom_name(m.name).ok()
+                        .and_then(|cookie| cookie.reply().ok())
+                        .map(|r| String::from_utf8_lossy(&r.name).into_owned())
+                        .unwrap_or_default();
+                    Monitor { name, primary : m.primary, x : m.x, y : m.y, width : m.width, height : m.height }
+                }).collect()
+            }
+            rv => {
+                if let Err(e) = rv {
+                    debug!("Could not list RandR monitors, using the whole screen: {}", e);
+                }
+                vec![Monitor {
+                    name : "screen".into(),
+                    primary : true,
+                    x : 0,
+                    y : 0,
+                    width : screen.width_in_pixels,
+                    height : screen.height_in_pixels,
+                }]
+            }
+        }
+    }
+
+    fn bar_matches(cfg : &toml::Value, monitor : &Monitor, monitors : &[Monitor]) -> bool {
+        match cfg.get("output").and_then(|v| v.as_str()) {
+            None => {}
+            Some("primary") | Some("focused") => {
+                // X11 has no notion of a focused output; follow the primary one instead
+                let primary = monitors.iter().position(|m| m.primary).unwrap_or(0);
+                if monitors[primary].name != monitor.name {
+                    return false;
+                }
+            }
+            Some(output) => {
+                error!("Ignoring invalid bar.output '{}': use primary or focused", output);
+            }
+        }
+        if let Some(name) = cfg.get("name").and_then(|v| v.as_str()) {
+            if name != monitor.name {
+                return false;
+            }
+        }
+        for key in ["make", "model", "description"] {
+            if cfg.get(key).is_some() {
+                warn!("Ignoring bar.{}: not available on X11", key);
+            }
+        }
+        true
+    }
+
+    fn add_bar(&mut self, cfg : toml::Value, monitor : &Monitor) -> Result<X11Bar, Box<dyn Error>> {
+        let conn = &self.conn;
+        let screen = &conn.setup().roots[self.screen];
+        let height = bar::config_size(&cfg);
+        let excl = cfg.get("size-exclusive")
+            .and_then(|v| v.as_integer())
+            .filter(|&v| v >= 0 && v < i32::MAX as _)
+            .map_or(height, |v| v as u32);
+        let anchor_top = bar::config_anchor_top(&cfg);
+        let override_redirect = cfg.get("x11-override-redirect").and_then(|v| v.as_bool()).unwrap_or(false);
+        let width = monitor.width as u32;
+        let y = if anchor_top {
+            monitor.y as i32
+        } else {
+            monitor.y as i32 + monitor.height as i32 - height as i32
+        };
+
+        // Prefer a visual with an alpha channel so transparent parts of the bar work with a
+        // compositing manager
+        let argb = screen.allowed_depths.iter()
+            .filter(|d| d.depth == 32)
+            .flat_map(|d| d.visuals.iter())
+            .find(|v| v.class == VisualClass::TRUE_COLOR);
+        let window = conn.generate_id()?;
+        let mut aux = CreateWindowAux::new()
+            .event_mask(EventMask::EXPOSURE | EventMask::BUTTON_PRESS | EventMask::STRUCTURE_NOTIFY)
+            .override_redirect(override_redirect as u32);
+        let (depth, visual, colormap) = match argb {
+            Some(visual) => {
+                let colormap = conn.generate_id()?;
+                conn.create_colormap(ColormapAlloc::NONE, colormap, screen.root, visual.visual_id)?;
+                aux = aux.colormap(colormap).background_pixel(0).border_pixel(0);
+                (32, visual.visual_id, Some(colormap))
+            }
+            None => (screen.root_depth, screen.root_visual, None),
+        };
+        conn.create_window(depth, window, screen.root, monitor.x, y as i16, width as u16, height as u16,
+            0, WindowClass::INPUT_OUTPUT, visual, &aux)?;
+
+        let atoms = &self.atoms;
+        conn.change_property32(PropMode::REPLACE, window, atoms._NET_WM_WINDOW_TYPE, AtomEnum::ATOM,
+            &[atoms._NET_WM_WINDOW_TYPE_DOCK])?;
+        conn.change_property32(PropMode::REPLACE, window, atoms._NET_WM_STATE, AtomEnum::ATOM,
+            &[atoms._NET_WM_STATE_STICKY, atoms._NET_WM_STATE_ABOVE])?;
+        conn.change_property32(PropMode::REPLACE, window, atoms._NET_WM_DESKTOP, AtomEnum::CARDINAL, &[u32::MAX])?;
+        conn.change_property8(PropMode::REPLACE, window, atoms._NET_WM_NAME, atoms.UTF8_STRING, b"rwaybar")?;
+        conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_NAME, AtomEnum::STRING, b"rwaybar")?;
+        conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_CLASS, AtomEnum::STRING, b"rwaybar\0rwaybar\0")?;
+
+        if excl > 0 {
+            // Struts are relative to the edges of the whole screen, not the monitor
+            let x0 = monitor.x.max(0) as u32;
+            let x1 = x0 + width - 1;
+            let mut partial = [0; 12];
+            if anchor_top {
+                partial[2] = monitor.y.max(0) as u32 + excl;
+                partial[8] = x0;
+                partial[9] = x1;
+            } else {
+                let bottom = screen.height_in_pixels as i32 - monitor.y as i32 - monitor.height as i32;
+                partial[3] = bottom.max(0) as u32 + excl;
+                partial[10] = x0;
+                partial[11] = x1;
+            }
+            conn.change_property32(PropMode::REPLACE, window, atoms._NET_WM_STRUT_PARTIAL, AtomEnum::CARDINAL, &partial)?;
+            conn.change_property32(PropMode::REPLACE, window, atoms._NET_WM_STRUT, AtomEnum::CARDINAL, &partial[..4])?;
+        }
+
+        let gc = conn.generate_id()?;
+        conn.create_gc(gc, window, &CreateGCAux::new())?;
+        conn.map_window(window)?;
+
+        Ok(X11Bar {
+            window,
+            gc,
+            colormap,
+            depth,
+            size : (width, height),
+            style : BarStyle::from_toml(&cfg, anchor_top),
+            item : Rc::new(Item::new_bar(cfg)),
+            sink : EventSink::default(),
+            dirty : true,
+        })
+    }
+
+    fn load_config(&mut self) -> Result<(), Box<dyn Error>> {
+        let bar_config = self.runtime.load_config()?;
+        for bar in self.bars.drain(..) {
+            let _ = self.conn.destroy_window(bar.window);
+            let _ = self.conn.free_gc(bar.gc);
+            if let Some(colormap) = bar.colormap {
+                let _ = self.conn.free_colormap(colormap);
+            }
+        }
+        let monitors = self.monitors();
+        for monitor in &monitors {
+            info!("Monitor name='{}' at {}x{}+{}+{}", monitor.name, monitor.width, monitor.height, monitor.x, monitor.y);
+        }
+        for cfg in bar_config {
+            for monitor in &monitors {
+                if !Self::bar_matches(&cfg, monitor, &monitors) {
+                    continue;
+                }
+                let mut cfg = cfg.clone();
+                if let Some(table) = cfg.as_table_mut() {
+                    table.insert("name".into(), monitor.name.clone().into());
+                }
+                match self.add_bar(cfg, monitor) {
+                    Ok(bar) => self.bars.push(bar),
+                    Err(e) => error!("Could not create a bar on {}: {}", monitor.name, e),
+                }
+            }
+        }
+        if self.bars.is_empty() {
+            error!("No bars matched this monitor configuration");
+        }
+        self.conn.flush()?;
+        self.dispatch()?;
+        self.runtime.request_draw();
+        Ok(())
+    }
+
+    /// Handle all events that have already been received
+    fn dispatch(&mut self) -> Result<(), Box<dyn Error>> {
+        while let Some(event) = self.conn.poll_for_event()? {
+            match event {
+                Event::Expose(e) if e.count == 0 => {
+                    if let Some(bar) = self.bars.iter_mut().find(|b| b.window == e.window) {
+                        bar.dirty = true;
+                        self.runtime.request_draw();
+                    }
+                }
+                Event::ConfigureNotify(e) => {
+                    if let Some(bar) = self.bars.iter_mut().find(|b| b.window == e.window) {
+                        let size = (e.width as u32, e.height as u32);
+                        if bar.size != size {
+                            bar.size = size;
+                            bar.dirty = true;
+                            self.runtime.request_draw();
+                        }
+                    }
+                }
+                Event::ButtonPress(e) => {
+                    let button = match e.detail {
+                        1 => 0, // left
+                        3 => 1, // right
+                        2 => 2, // middle
+                        8 => 3, // back
+                        9 => 4, // forward
+                        4 => 5, // scroll up
+                        5 => 6, // scroll down
+                        6 => 7, // scroll left
+                        7 => 8, // scroll right
+                        _ => continue,
+                    };
+                    if let Some(bar) = self.bars.iter_mut().find(|b| b.window == e.event) {
+                        bar.sink.button(e.event_x as f32, e.event_y as f32, button, &mut self.runtime);
+                        if self.runtime.take_popup_toggle() {
+                            debug!("Popups are not supported on X11");
+                        }
+                    }
+                }
+                Event::Error(e) => warn!("X11 error: {:?}", e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn draw_now(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.runtime.begin_frame() {
+            for bar in &mut self.bars {
+                bar.dirty = true;
+            }
+        }
+        let begin = Instant::now();
+        let mut frames = Vec::new();
+        for (i, bar) in self.bars.iter_mut().enumerate() {
+            if !bar.dirty || bar.size.0 == 0 || bar.size.1 == 0 {
+                continue;
+            }
+            bar.dirty = false;
+            let (canvas, sink) = bar::draw(&mut self.runtime, &mut bar.item, &bar.style, bar.size, 1);
+            bar.sink = sink;
+            frames.push((i, canvas));
+        }
+        for (i, pixmap) in render::rasterize(frames) {
+            if let Some(pixmap) = pixmap {
+                self.put_pixmap(&self.bars[i], pixmap)?;
+            }
+        }
+        self.conn.flush()?;
+        self.runtime.cache.prune(begin);
+        Ok(())
+    }
+
+    fn put_pixmap(&self, bar : &X11Bar, pixmap : tiny_skia::Pixmap) -> Result<(), Box<dyn Error>> {
+        let width = pixmap.width();
+        let mut data = pixmap.take();
+        // X11 uses premultiplied BGRA in native (little-endian) byte order
+        for pixel in data.chunks_mut(4) {
+            pixel.swap(0, 2);
+        }
+        let stride = width as usize * 4;
+        let max_rows = ((self.conn.maximum_request_bytes() - 64) / stride).max(1);
+        for (i, rows) in data.chunks(stride * max_rows).enumerate() {
+            let height = rows.len() / stride;
+            self.conn.put_image(ImageFormat::Z_PIXMAP, bar.window, bar.gc, width as u16, height as u16,
+                0, (i * max_rows) as i16, 0, bar.depth, rows)?;
+        }
+        Ok(())
+    }
+}
+
+/// Show the bars on an X11 display instead of a wayland compositor
+pub async fn run() -> Result<Infallible, Box<dyn Error>> {
+    let (conn, screen) = x11rb::connect(None)?;
+    let atoms = Atoms::new(&conn)?.reply()?;
+    let fd = AsyncFd::new(Fd(conn.stream().as_raw_fd()))?;
+    let state = Rc::new(RefCell::new(X11State {
+        conn,
+        screen,
+        atoms,
+        runtime : Runtime::new(None)?,
+        bars : Vec::new(),
+    }));
+    state.borrow_mut().load_config()?;
+
+    let draw_state = state.clone();
+    spawn_noerr(async move {
+        loop {
+            let wait = draw_state.borrow().runtime.wait_for_draw();
+            wait.await;
+            let begin = Instant::now();
+            let interval = {
+                let mut state = draw_state.borrow_mut();
+                if let Err(e) = state.draw_now() {
+                    error!("Error drawing on X11: {}", e);
+                }
+                state.runtime.frame_interval().map_or(MIN_FRAME_INTERVAL, |i| i.max(MIN_FRAME_INTERVAL))
+            };
+            tokio::time::sleep_until((begin + interval).into()).await;
+        }
+    });
+
+    let reload_state = state.clone();
+    spawn("Config reload", async move {
+        let mut hups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        while let Some(()) = hups.recv().await {
+            match reload_state.borrow_mut().load_config() {
+                Ok(()) => (),
+                Err(e) => error!("Config reload failed: {}", e),
+            }
+        }
+        Ok(())
+    });
+
+    let config_path = state.borrow().runtime.config_path.clone();
+    if let Some(path) = config_path {
+        let watch_state = Rc::downgrade(&state);
+        spawn("Config watch", util::watch_file(path, move || {
+            if let Some(state) = watch_state.upgrade() {
+                info!("Configuration file changed, reloading");
+                if let Err(e) = state.borrow_mut().load_config() {
+                    error!("Config reload failed: {}", e);
+                }
+            }
+        }));
+    }
+
+    loop {
+        let mut guard = fd.readable().await?;
+        guard.clear_ready();
+        state.borrow_mut().dispatch()?;
+    }
+}
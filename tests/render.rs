@@ -0,0 +1,113 @@
+//! Snapshot tests for the render pipeline
+//!
+//! Each test renders a `tests/snapshots/*.toml` configuration using [rwaybar::headless::render]
+//! and compares every bar against `tests/snapshots/TEST-N.png`.  Set `RWAYBAR_UPDATE_SNAPSHOTS=1`
+//! to write the current output as the new snapshots instead; images that don't match are also
+//! copied to `target/snapshot-failures` for inspection.
+//!
+//! The configurations use `@FONT@` as the path of their only font, which is the copy of DejaVu
+//! Sans in `tests/fonts` so that the output does not depend on the fonts that are installed.
+use rwaybar::headless::{self,Options};
+use std::fs::{self,File};
+use std::path::Path;
+use std::time::Duration;
+
+/// Largest difference allowed in any channel of a pixel before it counts as changed
+const CHANNEL_TOLERANCE : u8 = 2;
+
+/// Number of changed pixels allowed before the images count as different
+const PIXEL_TOLERANCE : usize = 4;
+
+fn read_png(path : &Path) -> (u32, u32, Vec<u8>) {
+    let file = File::open(path).unwrap_or_else(|e| panic!("Could not open {}: {}", path.display(), e));
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).unwrap();
+    assert_eq!(info.color_type, png::ColorType::Rgba, "{} is not an RGBA image", path.display());
+    buf.truncate(info.buffer_size());
+    (info.width, info.height, buf)
+}
+
+/// Count the pixels that differ by more than the tolerance, or None if the sizes differ
+fn compare(a : &Path, b : &Path) -> Option<usize> {
+    let (aw, ah, a) = read_png(a);
+    let (bw, bh, b) = read_png(b);
+    if (aw, ah) != (bw, bh) {
+        return None;
+    }
+    Some(a.chunks(4).zip(b.chunks(4))
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > CHANNEL_TOLERANCE))
+        .count())
+}
+
+fn check(name : &str, config : &str, scale : i32) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let snapshots = root.join("tests/snapshots");
+    let font = root.join("tests/fonts/DejaVuSans.ttf");
+    let work = root.join("target/snapshot-work").join(name);
+    let _ = fs::remove_dir_all(&work);
+    fs::create_dir_all(&work).unwrap();
+
+    let config = fs::read_to_string(snapshots.join(config)).unwrap()
+        .replace("@FONT@", &font.to_string_lossy());
+    let config_path = work.join("rwaybar.toml");
+    fs::write(&config_path, config).unwrap();
+
+    let opts = Options {
+        dir : work.clone(),
+        width : 400,
+        scale,
+        delay : Duration::ZERO,
+    };
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let images = tokio::task::LocalSet::new().block_on(&rt, headless::render(config_path, &opts))
+        .unwrap_or_else(|e| panic!("Render of '{}' failed: {}", name, e));
+    assert!(!images.is_empty(), "Render of '{}' produced no images", name);
+
+    let update = std::env::var_os("RWAYBAR_UPDATE_SNAPSHOTS").is_some();
+    let mut failures = Vec::new();
+    for (i, image) in images.into_iter().enumerate() {
+        let image = image.unwrap_or_else(|| panic!("Bar {} of '{}' has an empty size", i, name));
+        let actual = work.join(format!("bar-{}.png", i));
+        image.save_png(&actual).unwrap();
+        let expected = snapshots.join(format!("{}-{}.png", name, i));
+        if update {
+            fs::copy(&actual, &expected).unwrap();
+            continue;
+        }
+        if !expected.exists() {
+            failures.push(format!("{} has no snapshot (set RWAYBAR_UPDATE_SNAPSHOTS=1 to create it)", expected.display()));
+            continue;
+        }
+        let result = match compare(&actual, &expected) {
+            None => Some("the size changed".to_owned()),
+            Some(n) if n > PIXEL_TOLERANCE => Some(format!("{} pixels changed", n)),
+            Some(_) => None,
+        };
+        if let Some(why) = result {
+            let dir = root.join("target/snapshot-failures");
+            fs::create_dir_all(&dir).unwrap();
+            let copy = dir.join(format!("{}-{}.png", name, i));
+            fs::copy(&actual, &copy).unwrap();
+            failures.push(format!("{}: {}; the new image is {}", expected.display(), why, copy.display()));
+        }
+    }
+    assert!(failures.is_empty(), "Snapshot mismatch:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn basic() {
+    check("basic", "basic.toml", 1);
+}
+
+#[test]
+fn styled() {
+    check("styled", "styled.toml", 1);
+}
+
+#[test]
+fn styled_scaled() {
+    check("styled-scaled", "styled.toml", 2);
+}